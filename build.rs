@@ -0,0 +1,10 @@
+// Generate the gRPC service from its definition.  `protox` parses the
+// definition in Rust, so building doesn't need `protoc` installed.  The
+// client's `connect` helper relies on the 2021 prelude, so it is left out;
+// clients are built from a `Channel` instead.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=proto/cavey.proto");
+    let descriptors = protox::compile(["proto/cavey.proto"], ["proto"])?;
+    tonic_build::configure().build_transport(false).compile_fds(descriptors)?;
+    Ok(())
+}
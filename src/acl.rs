@@ -0,0 +1,252 @@
+//! Authentication and access control for the native protocol.
+//!
+//! An ACL file lists principals, each with a secret token and the grants it
+//! holds.  A grant allows some operations on the keys starting with a prefix
+//! in one keyspace:
+//!
+//! ```json
+//! {
+//!   "principals": [
+//!     {
+//!       "name": "team-a",
+//!       "token": "...",
+//!       "grants": [
+//!         {"prefix": "team-a/", "ops": ["get", "put", "remove", "scan"]},
+//!         {"keyspace": "shared", "ops": ["get", "scan"]},
+//!         {"keyspace": "team-a", "ops": ["admin"]}
+//!       ]
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! A grant without a `keyspace` applies to the default keyspace, and one
+//! with `"keyspace": "*"` to every keyspace.  A missing `prefix` covers every
+//! key.  `admin` allows creating and dropping the keyspace, and on every
+//! keyspace, changing the members of a cluster and backing up and restoring
+//! the server.
+//!
+//! Tokens are compared in constant time, but are stored as given, so keep
+//! the file readable only by caveyd.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::protocol::ClientMessage;
+use crate::{CaveyError, Result, ServerConfig};
+
+/// Which principals may do what.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Acl {
+    principals: Vec<Principal>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Principal {
+    name: String,
+    token: String,
+    grants: Vec<Grant>,
+}
+
+// Keep tokens out of logs.
+impl fmt::Debug for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Principal")
+            .field("name", &self.name)
+            .field("grants", &self.grants)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Grant {
+    keyspace: Option<String>,
+    #[serde(default)]
+    prefix: String,
+    ops: Vec<Op>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Op {
+    Get,
+    Put,
+    Remove,
+    Scan,
+    Admin,
+}
+
+impl Acl {
+    /// Read an ACL from a JSON file.
+    pub fn load(path: &Path) -> Result<Acl> {
+        Acl::from_json(&fs::read_to_string(path)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Acl> {
+        let acl: Acl = serde_json::from_str(json).map_err(invalid_acl)?;
+        let mut tokens = HashSet::new();
+        for principal in &acl.principals {
+            if principal.token.is_empty() {
+                return Err(invalid_acl(format!("{} has an empty token", principal.name)));
+            }
+            if !tokens.insert(&principal.token) {
+                return Err(invalid_acl(format!("{} shares its token with another principal", principal.name)));
+            }
+        }
+        Ok(acl)
+    }
+
+    /// The principal holding `token`, if any.
+    fn authenticate(&self, token: &str) -> Option<usize> {
+        // Check every principal, so the time taken doesn't reveal which
+        // token was nearly right.
+        let mut found = None;
+        for (index, principal) in self.principals.iter().enumerate() {
+            if constant_time_eq(principal.token.as_bytes(), token.as_bytes()) {
+                found = Some(index);
+            }
+        }
+        found
+    }
+}
+
+fn invalid_acl<E: ToString>(err: E) -> CaveyError {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("invalid acl: {}", err.to_string())).into()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+impl Principal {
+    fn grants<'a>(&'a self, keyspace: Option<&'a str>) -> impl Iterator<Item = &'a Grant> {
+        self.grants.iter().filter(move |grant| match grant.keyspace.as_deref() {
+            Some("*") => true,
+            other => other == keyspace,
+        })
+    }
+
+    fn check(&self, op: Op, keyspace: Option<&str>, key: &str) -> Result<()> {
+        let allowed = self
+            .grants(keyspace)
+            .any(|grant| grant.ops.contains(&op) && key.starts_with(&grant.prefix[..]));
+        if allowed {
+            Ok(())
+        } else {
+            Err(denied(op, keyspace, key))
+        }
+    }
+
+    fn check_admin(&self, keyspace: &str) -> Result<()> {
+        if self.grants(Some(keyspace)).any(|grant| grant.ops.contains(&Op::Admin)) {
+            Ok(())
+        } else {
+            Err(CaveyError::PermissionDenied(format!("admin on keyspace {}", keyspace)))
+        }
+    }
+}
+
+fn denied(op: Op, keyspace: Option<&str>, key: &str) -> CaveyError {
+    let op = format!("{:?}", op).to_lowercase();
+    CaveyError::PermissionDenied(match keyspace {
+        Some(keyspace) => format!("{} on {:?} in keyspace {}", op, key, keyspace),
+        None => format!("{} on {:?}", op, key),
+    })
+}
+
+/// What a connection may do: anything if the server has no ACL, otherwise
+/// nothing until it authenticates, and then what its principal is granted.
+#[derive(Clone)]
+pub(crate) enum Access {
+    Open,
+    Unauthenticated(Arc<Acl>),
+    Principal(Arc<Acl>, usize),
+}
+
+impl Access {
+    pub(crate) fn new(config: &ServerConfig) -> Access {
+        match &config.acl {
+            Some(acl) => Access::Unauthenticated(acl.clone()),
+            None => Access::Open,
+        }
+    }
+
+    /// Authenticate as the principal holding `token`.  Without an ACL any
+    /// token is accepted.
+    pub(crate) fn authenticate(&mut self, token: &str) -> Result<()> {
+        let acl = match self {
+            Access::Open => return Ok(()),
+            Access::Unauthenticated(acl) | Access::Principal(acl, _) => acl.clone(),
+        };
+        match acl.authenticate(token) {
+            Some(index) => {
+                *self = Access::Principal(acl, index);
+                Ok(())
+            }
+            None => {
+                *self = Access::Unauthenticated(acl);
+                Err(CaveyError::Unauthenticated)
+            }
+        }
+    }
+
+    /// Check that the connection may make a request.
+    pub(crate) fn authorize(&self, msg: &ClientMessage) -> Result<()> {
+        let principal = match self {
+            Access::Open => return Ok(()),
+            _ if matches!(msg, ClientMessage::Ping | ClientMessage::Authenticate { .. }) => return Ok(()),
+            Access::Unauthenticated(_) => return Err(CaveyError::Unauthenticated),
+            Access::Principal(acl, index) => &acl.principals[*index],
+        };
+        match msg {
+            ClientMessage::Get { keyspace, key } | ClientMessage::GetVersioned { keyspace, key } => {
+                principal.check(Op::Get, keyspace.as_deref(), key)
+            }
+            ClientMessage::Put { keyspace, key, .. } => principal.check(Op::Put, keyspace.as_deref(), key),
+            ClientMessage::Remove { keyspace, key } => principal.check(Op::Remove, keyspace.as_deref(), key),
+            // These reveal the old value too.
+            ClientMessage::PutReturningOld { keyspace, key, .. } => {
+                principal.check(Op::Put, keyspace.as_deref(), key)?;
+                principal.check(Op::Get, keyspace.as_deref(), key)
+            }
+            ClientMessage::RemoveReturningOld { keyspace, key } => {
+                principal.check(Op::Remove, keyspace.as_deref(), key)?;
+                principal.check(Op::Get, keyspace.as_deref(), key)
+            }
+            ClientMessage::Commit { keyspace, changeset } => {
+                for key in changeset.reads.keys() {
+                    principal.check(Op::Get, keyspace.as_deref(), key)?;
+                }
+                for (key, value) in &changeset.writes {
+                    let op = if value.is_some() { Op::Put } else { Op::Remove };
+                    principal.check(op, keyspace.as_deref(), key)?;
+                }
+                Ok(())
+            }
+            // Every key a scan could return starts with its prefix.
+            ClientMessage::Scan { keyspace, prefix, .. } | ClientMessage::Watch { keyspace, prefix, .. } => {
+                principal.check(Op::Scan, keyspace.as_deref(), prefix)
+            }
+            ClientMessage::CreateKeyspace { keyspace } | ClientMessage::DropKeyspace { keyspace } => {
+                principal.check_admin(keyspace)
+            }
+            ClientMessage::AddMember { .. }
+            | ClientMessage::RemoveMember { .. }
+            | ClientMessage::Backup { .. }
+            | ClientMessage::Restore { .. } => principal.check_admin("*"),
+            ClientMessage::ListKeyspaces
+            | ClientMessage::Ping
+            | ClientMessage::Authenticate { .. }
+            | ClientMessage::ClusterStatus => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,327 @@
+//! Offline inspection and repair of `CaveyStore` data directories, for
+//! `cavey-admin`.  Stop the server using a directory before repairing it.
+//!
+//! Neither segment format carries checksums, so checking a segment means
+//! checking that every record parses and is framed as its format expects,
+//! and that SSTable keys ascend.  A crash part way through a write leaves a
+//! torn record at the end of the log, which stops the store opening it;
+//! repairing truncates the log back to its last whole record, dropping the
+//! write, which was never acknowledged.  A torn batch is dropped whole.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*, BufReader, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::sstable::{self, SSTable};
+use crate::store::LogRecord;
+use crate::{CaveyError, Result};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SegmentFormat {
+    /// JSON lines of puts, removes and batch headers, as `CaveyStore`
+    /// writes.
+    Log,
+    SSTable,
+}
+
+/// The first bad record in a segment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Problem {
+    /// Where the record starts.
+    pub offset: u64,
+    pub message: String,
+    /// Whether no whole record follows, so the segment can be repaired by
+    /// truncating it at `offset`.
+    pub tail: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct SegmentReport {
+    pub path: PathBuf,
+    /// The keyspace the segment holds, or `None` for the default keyspace.
+    pub keyspace: Option<String>,
+    pub format: SegmentFormat,
+    pub size: u64,
+    /// Records read before any problem.
+    pub records: u64,
+    /// Records holding a key's current value, and their size.
+    pub live_records: u64,
+    pub live_bytes: u64,
+    /// Whether the store reads the segment.  Others are left by an
+    /// interrupted compaction, and hold nothing live.
+    pub active: bool,
+    pub problem: Option<Problem>,
+}
+
+/// A record read from a segment, with the offset it starts at.
+#[derive(Clone, Debug)]
+pub enum SegmentRecord {
+    Log { offset: u64, record: LogRecord },
+    Table { offset: u64, key: Vec<u8>, value: Vec<u8> },
+}
+
+/// Read and check every segment in the data directory `dir`, default
+/// keyspace first.
+pub fn inspect(dir: &Path) -> Result<Vec<SegmentReport>> {
+    let mut reports = inspect_keyspace(dir, None)?;
+    let keyspace_dir = dir.join("keyspaces");
+    if keyspace_dir.exists() {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&keyspace_dir)? {
+            names.push(entry?.file_name().to_string_lossy().into_owned());
+        }
+        names.sort();
+        for name in names {
+            reports.extend(inspect_keyspace(&keyspace_dir.join(&name), Some(name))?);
+        }
+    }
+    Ok(reports)
+}
+
+fn inspect_keyspace(dir: &Path, keyspace: Option<String>) -> Result<Vec<SegmentReport>> {
+    let datadir = dir.join("data");
+    match fs::read(datadir.join(".engine")) {
+        Ok(ref engine) if engine == b"cavey" => {}
+        Ok(engine) => return Err(CaveyError::WrongEngine(String::from_utf8_lossy(&engine).into_owned())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let msg = format!("no data in {}", dir.display());
+            return Err(io::Error::new(io::ErrorKind::NotFound, msg).into());
+        }
+        Err(err) => return Err(err.into()),
+    }
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(&datadir)? {
+        let entry = entry?;
+        if entry.file_name() != ".engine" {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    let mut reports = Vec::with_capacity(paths.len());
+    for (i, path) in paths.into_iter().enumerate() {
+        let mut report = read_segment(&path, |_| {})?;
+        report.keyspace = keyspace.clone();
+        // Like the store, only read the first segment.
+        report.active = i == 0;
+        if !report.active {
+            report.live_records = 0;
+            report.live_bytes = 0;
+        }
+        reports.push(report);
+    }
+    Ok(reports)
+}
+
+/// Read the segment at `path`, passing `visit` each record, and report on
+/// it.  Reading stops at the first bad record.
+pub fn read_segment<F: FnMut(SegmentRecord)>(path: &Path, visit: F) -> Result<SegmentReport> {
+    let size = fs::metadata(path)?.len();
+    let mut input = BufReader::new(File::open(path)?);
+    let format = if input.fill_buf()?.starts_with(sstable::MAGIC) {
+        SegmentFormat::SSTable
+    } else {
+        SegmentFormat::Log
+    };
+    let mut report = SegmentReport {
+        path: path.to_owned(),
+        keyspace: None,
+        format,
+        size,
+        records: 0,
+        live_records: 0,
+        live_bytes: 0,
+        active: true,
+        problem: None,
+    };
+    match format {
+        SegmentFormat::Log => read_log(input, &mut report, visit)?,
+        SegmentFormat::SSTable => read_table(input, &mut report, visit)?,
+    }
+    Ok(report)
+}
+
+fn read_log<R: BufRead, F: FnMut(SegmentRecord)>(mut input: R, report: &mut SegmentReport, mut visit: F) -> Result<()> {
+    // The length of each live key's latest put.
+    let mut live = HashMap::new();
+    let mut offset = 0;
+    // Where the batch being read starts, and how many of its records are
+    // still to come.
+    let mut batch: Option<(u64, usize)> = None;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let len = input.read_until(b'\n', &mut line)? as u64;
+        if len == 0 {
+            break;
+        }
+        let record = match parse_line(&line) {
+            Ok(record) => record,
+            Err(message) => {
+                let tail = !has_records(&mut input)?;
+                // A torn batch is dropped whole, so repair from its start.
+                let offset = match batch {
+                    Some((start, _)) if tail => start,
+                    _ => offset,
+                };
+                report.problem = Some(Problem { offset, message, tail });
+                break;
+            }
+        };
+        match &record {
+            LogRecord::Put { key, .. } => {
+                live.insert(key.clone(), len);
+            }
+            LogRecord::Remove { key } => {
+                live.remove(key);
+            }
+            LogRecord::Batch { len } => {
+                if batch.is_some() {
+                    report.problem = Some(Problem {
+                        offset,
+                        message: "batch inside a batch".to_owned(),
+                        tail: false,
+                    });
+                    break;
+                }
+                batch = Some((offset, *len + 1));
+            }
+        }
+        batch = match batch {
+            Some((_, 1)) => None,
+            Some((start, remaining)) => Some((start, remaining - 1)),
+            None => None,
+        };
+        visit(SegmentRecord::Log { offset, record });
+        report.records += 1;
+        offset += len;
+    }
+    if let (Some((start, _)), None) = (batch, &report.problem) {
+        report.problem = Some(Problem {
+            offset: start,
+            message: "incomplete batch".to_owned(),
+            tail: true,
+        });
+    }
+    report.live_records = live.len() as u64;
+    report.live_bytes = live.values().sum();
+    Ok(())
+}
+
+fn parse_line(line: &[u8]) -> std::result::Result<LogRecord, String> {
+    if !line.ends_with(b"\n") {
+        return Err("incomplete record".to_owned());
+    }
+    serde_json::from_slice(line).map_err(|err| err.to_string())
+}
+
+/// Whether a whole record follows a bad one, which a torn write can't
+/// explain.
+fn has_records<R: BufRead>(input: &mut R) -> Result<bool> {
+    let mut line = Vec::new();
+    while input.read_until(b'\n', &mut line)? > 0 {
+        if parse_line(&line).is_ok() {
+            return Ok(true);
+        }
+        line.clear();
+    }
+    Ok(false)
+}
+
+fn read_table<R: Read + Seek, F: FnMut(SegmentRecord)>(mut input: R, report: &mut SegmentReport, mut visit: F) -> Result<()> {
+    let table = match SSTable::from_file(&report.path) {
+        Ok(table) => table,
+        Err(err) => {
+            report.problem = Some(Problem {
+                offset: 0,
+                message: format!("unreadable header or footer: {}", err),
+                tail: false,
+            });
+            return Ok(());
+        }
+    };
+    let offsets = table.offsets();
+    let end = report.size.saturating_sub(8 * offsets.len() as u64);
+    let mut offset = sstable::HEADER_SIZE;
+    let mut last_key: Option<Vec<u8>> = None;
+    input.seek(SeekFrom::Start(offset))?;
+    for &expected in offsets {
+        let (key, value) = match read_entry(&mut input, offset, expected, end) {
+            Ok(entry) => entry,
+            Err(message) => {
+                report.problem = Some(Problem { offset, message, tail: false });
+                return Ok(());
+            }
+        };
+        if last_key.as_ref().is_some_and(|last| *last >= key) {
+            report.problem = Some(Problem {
+                offset,
+                message: "key out of order".to_owned(),
+                tail: false,
+            });
+            return Ok(());
+        }
+        let len = 8 + (key.len() + value.len()) as u64;
+        last_key = Some(key.clone());
+        visit(SegmentRecord::Table { offset, key, value });
+        report.records += 1;
+        report.live_records += 1;
+        report.live_bytes += len;
+        offset += len;
+    }
+    if offset != end {
+        report.problem = Some(Problem {
+            offset,
+            message: format!("entries end at {}, but the footer starts at {}", offset, end),
+            tail: false,
+        });
+    }
+    Ok(())
+}
+
+/// Read the entry at `offset`, which the footer says is at `expected`, and
+/// must end by `end`.
+fn read_entry<R: Read>(input: &mut R, offset: u64, expected: u64, end: u64) -> std::result::Result<(Vec<u8>, Vec<u8>), String> {
+    if offset != expected {
+        return Err(format!("the footer puts the entry at {}", expected));
+    }
+    let mut read = || -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let key_len = u64::from(input.read_u32::<LittleEndian>()?);
+        let value_len = u64::from(input.read_u32::<LittleEndian>()?);
+        // Check the lengths before trusting them with an allocation.
+        if offset + 8 + key_len + value_len > end {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "entry runs into the footer"));
+        }
+        let mut key = vec![0; key_len as usize];
+        let mut value = vec![0; value_len as usize];
+        input.read_exact(&mut key)?;
+        input.read_exact(&mut value)?;
+        Ok((key, value))
+    };
+    read().map_err(|err| err.to_string())
+}
+
+/// Truncate the log segment at `path` back to its last whole record if it
+/// ends in a torn one, returning how many bytes were dropped.
+pub fn repair(path: &Path) -> Result<u64> {
+    let report = read_segment(path, |_| {})?;
+    let problem = match report.problem {
+        Some(problem) => problem,
+        None => return Ok(0),
+    };
+    if report.format == SegmentFormat::SSTable {
+        return Err(CaveyError::Unsupported("repairing SSTables".to_owned()));
+    }
+    if !problem.tail {
+        return Err(CaveyError::Corruption(format!(
+            "{} at offset {} in {} is followed by whole records, so truncating would lose them",
+            problem.message,
+            problem.offset,
+            path.display()
+        )));
+    }
+    OpenOptions::new().write(true).open(path)?.set_len(problem.offset)?;
+    Ok(report.size - problem.offset)
+}
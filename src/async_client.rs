@@ -0,0 +1,167 @@
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use log::debug;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::protocol::{decode_frame, encode_frame, ClientMessage, Handshake, ServerMessage, Token, MAX_FRAME_SIZE};
+use crate::{CaveyError, Result};
+
+type Responder = oneshot::Sender<Result<ServerMessage>>;
+
+/// Requests whose responses have not arrived yet, oldest first.  The server
+/// answers requests in the order it receives them, so each response belongs
+/// to the request at the front of the queue.  `None` once the connection has
+/// closed.
+type Pending = Arc<Mutex<Option<VecDeque<Responder>>>>;
+
+/// An async client, speaking the same protocol as `CaveyClient`.
+///
+/// The client is a cheap handle onto a single connection, which is driven by
+/// background tasks on the tokio runtime.  Clones share the connection, and
+/// any number of requests may be in flight on it at once.
+#[derive(Clone)]
+pub struct AsyncCaveyClient {
+    requests: mpsc::UnboundedSender<(ClientMessage, Responder)>,
+    keyspace: Option<String>,
+}
+
+impl AsyncCaveyClient {
+    pub async fn connect<S: Into<SocketAddr>>(sockaddr: S) -> Result<AsyncCaveyClient> {
+        let mut stream = TcpStream::connect(sockaddr.into()).await?;
+        let local = Handshake::local(MAX_FRAME_SIZE);
+        stream.write_all(&local.encode()).await?;
+        let mut hello = [0; Handshake::ENCODED_LEN];
+        stream.read_exact(&mut hello).await?;
+        let server = Handshake::decode(&hello)?;
+        local.check_compatible(&server)?;
+
+        let (reader, writer) = stream.into_split();
+        let (requests, outgoing) = mpsc::unbounded_channel();
+        let pending = Arc::new(Mutex::new(Some(VecDeque::new())));
+        tokio::spawn(write_requests(writer, outgoing, pending.clone(), server));
+        tokio::spawn(read_responses(reader, pending));
+        Ok(AsyncCaveyClient {
+            requests,
+            keyspace: None,
+        })
+    }
+
+    /// Direct subsequent requests from this handle to the named keyspace, or
+    /// to the default keyspace if `None`.
+    pub fn set_keyspace(&mut self, keyspace: Option<String>) {
+        self.keyspace = keyspace;
+    }
+
+    /// Authenticate the connection, for servers with an ACL.  Call before
+    /// making other requests.
+    pub async fn authenticate(&self, token: String) -> Result<()> {
+        let request = ClientMessage::Authenticate { token: Token(token) };
+        self.request(request).await?.into_empty()
+    }
+
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        let request = ClientMessage::Get { keyspace: self.keyspace.clone(), key };
+        self.request(request).await?.into_value()
+    }
+
+    pub async fn put(&self, key: String, value: String) -> Result<()> {
+        let request = ClientMessage::Put { keyspace: self.keyspace.clone(), key, value };
+        self.request(request).await?.into_empty()
+    }
+
+    /// Remove `key`, returning whether it was present.
+    pub async fn remove(&self, key: String) -> Result<bool> {
+        let request = ClientMessage::Remove { keyspace: self.keyspace.clone(), key };
+        self.request(request).await?.into_removed()
+    }
+
+    /// Set `key` to `value`, returning the value it replaced.
+    pub async fn put_returning_old(&self, key: String, value: String) -> Result<Option<String>> {
+        let request = ClientMessage::PutReturningOld { keyspace: self.keyspace.clone(), key, value };
+        self.request(request).await?.into_value()
+    }
+
+    /// Remove `key`, returning the value it held.
+    pub async fn remove_returning_old(&self, key: String) -> Result<Option<String>> {
+        let request = ClientMessage::RemoveReturningOld { keyspace: self.keyspace.clone(), key };
+        self.request(request).await?.into_value()
+    }
+
+    async fn request(&self, msg: ClientMessage) -> Result<ServerMessage> {
+        let (responder, response) = oneshot::channel();
+        self.requests
+            .send((msg, responder))
+            .map_err(|_| connection_closed())?;
+        response.await.map_err(|_| connection_closed())?
+    }
+}
+
+fn connection_closed() -> CaveyError {
+    CaveyError::Io(io::Error::new(io::ErrorKind::BrokenPipe, "connection closed"))
+}
+
+async fn write_requests(
+    mut writer: OwnedWriteHalf,
+    mut outgoing: mpsc::UnboundedReceiver<(ClientMessage, Responder)>,
+    pending: Pending,
+    server: Handshake,
+) {
+    while let Some((msg, responder)) = outgoing.recv().await {
+        debug!("sending_message: {:?}", msg);
+        let bytes = match encode_frame(&msg, server.max_frame_size) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                responder.send(Err(err)).ok();
+                continue;
+            }
+        };
+        // Queue the responder before writing, so the response can't arrive
+        // ahead of it.
+        match pending.lock().unwrap().as_mut() {
+            Some(queue) => queue.push_back(responder),
+            None => break,
+        }
+        if let Err(err) = writer.write_all(&bytes).await {
+            debug!("connection failed: {}", err);
+            break;
+        }
+    }
+}
+
+async fn read_responses(mut reader: OwnedReadHalf, pending: Pending) {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+    loop {
+        match decode_frame::<ServerMessage>(&mut buf, MAX_FRAME_SIZE) {
+            Ok(Some(msg)) => {
+                debug!("received message: {:?}", msg);
+                let responder = pending.lock().unwrap().as_mut().and_then(VecDeque::pop_front);
+                if let Some(responder) = responder {
+                    responder.send(Ok(msg)).ok();
+                }
+                continue;
+            }
+            Ok(None) => {}
+            Err(err) => {
+                debug!("malformed response: {}", err);
+                break;
+            }
+        }
+        match reader.read(&mut chunk).await {
+            Ok(0) => break,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(err) => {
+                debug!("connection failed: {}", err);
+                break;
+            }
+        }
+    }
+    // Dropping the remaining responders fails their requests.
+    pending.lock().unwrap().take();
+}
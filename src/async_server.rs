@@ -0,0 +1,132 @@
+use std::sync::{Arc, Mutex};
+
+use log::{debug, error, trace};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::task;
+
+use crate::protocol::{decode_frame, ClientMessage, Handshake, ServerMessage};
+use crate::acl::Access;
+use crate::server::{authenticate, dispatch, encode_response};
+use crate::watch::{open_watch, stream_events_async};
+use crate::utils::lock;
+use crate::{AsyncListener, CaveyEngine, CaveyError, Result, ServerConfig};
+
+/// An engine shared between connections, and perhaps other frontends.
+pub type SharedEngine = Arc<Mutex<Box<dyn CaveyEngine>>>;
+
+/// Serve clients on `listener` with the default `ServerConfig`.
+pub async fn run_async_server<L: AsyncListener>(listener: L, engine: Box<dyn CaveyEngine>) -> Result<()> {
+    run_async_server_with_config(listener, engine, ServerConfig::default()).await
+}
+
+/// Serve clients on `listener`, a tokio `TcpListener` or `UnixListener`,
+/// from the tokio runtime, so idle connections cost a task rather than a
+/// thread.
+///
+/// Engine calls block, so they run on the runtime's blocking pool, one at a
+/// time.  Bound that pool with `Builder::max_blocking_threads`.
+/// Watches send heartbeats, so the runtime needs its timer: build it with
+/// `Builder::enable_time`.
+pub async fn run_async_server_with_config<L: AsyncListener>(
+    listener: L,
+    engine: Box<dyn CaveyEngine>,
+    config: ServerConfig,
+) -> Result<()> {
+    serve_async(listener, Arc::new(Mutex::new(engine)), config).await
+}
+
+/// Like `run_async_server_with_config`, but with an engine which other
+/// frontends, such as `serve_resp`, may share.
+pub async fn serve_async<L: AsyncListener>(listener: L, engine: SharedEngine, config: ServerConfig) -> Result<()> {
+    let config = Arc::new(config);
+    loop {
+        match listener.accept().await {
+            Ok(stream) => {
+                trace!("connection accepted");
+                let engine = engine.clone();
+                let config = config.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = handle_connection(stream, engine, &config).await {
+                        error!("connection failed: {}", err);
+                    }
+                });
+            }
+            Err(err) => {
+                error!("connection failed: {}", err);
+            }
+        }
+    }
+}
+
+/// Serve requests from one client until it closes the connection.  Requests
+/// are answered in order, so clients may pipeline them.
+async fn handle_connection<S>(mut stream: S, engine: SharedEngine, config: &ServerConfig) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let local = Handshake::local(config.max_frame_size);
+    let mut hello = [0; Handshake::ENCODED_LEN];
+    stream.read_exact(&mut hello).await?;
+    let peer = Handshake::decode(&hello)?;
+    // Answer even an incompatible client, so it can report the mismatch.
+    stream.write_all(&local.encode()).await?;
+    local.check_compatible(&peer)?;
+
+    let mut access = Access::new(config);
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+    loop {
+        loop {
+            let msg = match decode_frame::<ClientMessage>(&mut buf, local.max_frame_size) {
+                Ok(Some(msg)) => msg,
+                Ok(None) => break,
+                // The rest of the stream can't be trusted after a bad frame.
+                Err(err) => {
+                    stream.write_all(&encode_response(&ServerMessage::from(&err), &peer)?).await.ok();
+                    return Err(err);
+                }
+            };
+            debug!("caveyd: received msg: {:?}", msg);
+            if let ClientMessage::Authenticate { token } = &msg {
+                stream.write_all(&encode_response(&authenticate(&mut access, &token.0), &peer)?).await?;
+                continue;
+            }
+            let msg = match config.check_limits(&msg).and_then(|()| config.resolve_backup(msg)) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    stream.write_all(&encode_response(&err.into(), &peer)?).await?;
+                    continue;
+                }
+            };
+            // A watch takes over the connection.
+            if let ClientMessage::Watch { .. } = &msg {
+                let engine = engine.clone();
+                let access = access.clone();
+                let watch = task::spawn_blocking(move || open_watch(&msg, &mut **lock(&engine), &access))
+                    .await
+                    .map_err(|err| CaveyError::Internal(err.to_string()))?;
+                match watch {
+                    Ok((feed, filter, after)) => {
+                        return stream_events_async(&mut stream, &feed, &filter, after, &peer).await;
+                    }
+                    Err(err) => {
+                        stream.write_all(&encode_response(&err.into(), &peer)?).await?;
+                        continue;
+                    }
+                }
+            }
+            let engine = engine.clone();
+            let access = access.clone();
+            let response = task::spawn_blocking(move || dispatch(msg, &mut **lock(&engine), &access))
+                .await
+                .map_err(|err| CaveyError::Internal(err.to_string()))?;
+            stream.write_all(&encode_response(&response, &peer)?).await?;
+        }
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            trace!("connection closed");
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..read]);
+    }
+}
@@ -0,0 +1,107 @@
+//! Backing up a live engine, and restoring from the backup.
+//!
+//! A backup is a data directory like the one the engine was opened on, so it
+//! can also be served directly.  Engines take it while their server holds
+//! the engine lock, so it reflects every write before it and none after, at
+//! the cost of stalling other requests while the data is copied.
+//!
+//! Restoring replaces the engine's keys and keyspaces with the backup's by
+//! ordinary writes, so wrappers such as `Primary` pass them on.  A restore
+//! that fails part way leaves a mix of old and restored data; running it
+//! again finishes it.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use log::info;
+
+use crate::{CaveyEngine, CaveyError, CaveyStore, Result, SledStore};
+
+/// Entries copied at a time while restoring.
+const RESTORE_PAGE: usize = 1000;
+
+/// Create the directory `dest` for a backup, failing if it holds anything
+/// already.
+pub(crate) fn create_dest(dest: &Path) -> Result<()> {
+    if dest.exists() && fs::read_dir(dest)?.next().is_some() {
+        let msg = format!("backup destination {} is not empty", dest.display());
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg).into());
+    }
+    fs::create_dir_all(dest)?;
+    Ok(())
+}
+
+/// Copy the files in the directory `from` to `to`, which is created.
+pub(crate) fn copy_files(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            fs::copy(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Open the backup in `src` with the engine that wrote it.
+fn open_backup(src: &Path) -> Result<Box<dyn CaveyEngine>> {
+    let engine = match fs::read(src.join("data").join(".engine")) {
+        Ok(engine) => engine,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let msg = format!("no backup in {}", src.display());
+            return Err(io::Error::new(io::ErrorKind::NotFound, msg).into());
+        }
+        Err(err) => return Err(err.into()),
+    };
+    match &engine[..] {
+        b"cavey" => Ok(Box::new(CaveyStore::open(src)?)),
+        b"sled" => Ok(Box::new(SledStore::open(src)?)),
+        other => Err(CaveyError::WrongEngine(String::from_utf8_lossy(other).into_owned())),
+    }
+}
+
+/// Replace the keys and keyspaces in `engine` with those in the backup in
+/// `src`.
+pub(crate) fn restore<E: CaveyEngine + ?Sized>(engine: &mut E, src: &Path) -> Result<()> {
+    let mut backup = open_backup(src)?;
+    info!("restoring from {}", src.display());
+    clear(engine)?;
+    copy_entries(&mut *backup, engine)?;
+    for name in backup.list_keyspaces()? {
+        engine.create_keyspace(&name)?;
+        copy_entries(backup.keyspace(&name)?, engine.keyspace(&name)?)?;
+    }
+    Ok(())
+}
+
+fn copy_entries<E: CaveyEngine + ?Sized>(from: &mut dyn CaveyEngine, to: &mut E) -> Result<()> {
+    let mut after = None;
+    loop {
+        let page = from.scan("", after.as_deref(), RESTORE_PAGE)?;
+        let last = match page.last() {
+            Some((key, _)) => key.clone(),
+            None => return Ok(()),
+        };
+        for (key, value) in page {
+            to.put(key, value)?;
+        }
+        after = Some(last);
+    }
+}
+
+/// Remove every key and keyspace.
+pub(crate) fn clear<E: CaveyEngine + ?Sized>(engine: &mut E) -> Result<()> {
+    for name in engine.list_keyspaces()? {
+        engine.drop_keyspace(&name)?;
+    }
+    loop {
+        let page = engine.scan("", None, RESTORE_PAGE)?;
+        if page.is_empty() {
+            return Ok(());
+        }
+        for (key, _) in page {
+            engine.remove(key)?;
+        }
+    }
+}
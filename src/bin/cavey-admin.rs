@@ -0,0 +1,119 @@
+use std::path::{Path, PathBuf};
+
+use failure::{err_msg, Error};
+use structopt::StructOpt;
+
+use cavey::{self, LogRecord, SegmentFormat, SegmentRecord, SegmentReport};
+
+/// Inspect and repair kvs data directories offline.  Stop the server using
+/// a directory first.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "cavey-admin")]
+enum Command {
+    /// List the segments in dir with their sizes, record counts and how much
+    /// of each is live.
+    #[structopt(name="inspect")]
+    Inspect {
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+    },
+    /// Print the records in a log segment or SSTable, each after its offset.
+    #[structopt(name="dump")]
+    Dump {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Check every segment in dir, failing if any is corrupt.
+    #[structopt(name="fsck")]
+    Fsck {
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+        /// Truncate torn writes off the ends of logs.
+        #[structopt(long = "repair")]
+        repair: bool,
+    },
+}
+
+fn main() -> Result<(), Error> {
+    match Command::from_args() {
+        Command::Inspect { dir } => {
+            let reports = cavey::inspect(&dir)?;
+            let (mut size, mut live) = (0, 0);
+            for report in &reports {
+                let format = match report.format {
+                    SegmentFormat::Log => "log",
+                    SegmentFormat::SSTable => "sstable",
+                };
+                print!(
+                    "{} {} {} bytes, {} records, {} live ({}% of bytes)",
+                    name(&dir, report),
+                    format,
+                    report.size,
+                    report.records,
+                    report.live_records,
+                    percent(report.live_bytes, report.size)
+                );
+                if !report.active {
+                    print!(", left by an interrupted compaction");
+                }
+                if let Some(problem) = &report.problem {
+                    print!(", corrupt at offset {}: {}", problem.offset, problem.message);
+                }
+                println!();
+                size += report.size;
+                live += report.live_bytes;
+            }
+            println!("total {} bytes, {}% live", size, percent(live, size));
+        },
+        Command::Dump { file } => {
+            let report = cavey::read_segment(&file, |record| match record {
+                SegmentRecord::Log { offset, record: LogRecord::Put { key, value } } => {
+                    println!("{} put {} {}", offset, key, value)
+                },
+                SegmentRecord::Log { offset, record: LogRecord::Remove { key } } => println!("{} rm {}", offset, key),
+                SegmentRecord::Log { offset, record: LogRecord::Batch { len } } => println!("{} batch {}", offset, len),
+                SegmentRecord::Table { offset, key, value } => {
+                    println!("{} {} {}", offset, String::from_utf8_lossy(&key), String::from_utf8_lossy(&value))
+                },
+            })?;
+            if let Some(problem) = report.problem {
+                return Err(err_msg(format!("corrupt at offset {}: {}", problem.offset, problem.message)));
+            }
+        },
+        Command::Fsck { dir, repair } => {
+            let reports = cavey::inspect(&dir)?;
+            let mut corrupt = 0;
+            for report in &reports {
+                let problem = match &report.problem {
+                    Some(problem) => problem,
+                    None => continue,
+                };
+                if repair && problem.tail {
+                    let dropped = cavey::repair(&report.path)?;
+                    println!("{}: truncated {} bytes from offset {}", name(&dir, report), dropped, problem.offset);
+                    continue;
+                }
+                corrupt += 1;
+                print!("{}: corrupt at offset {}: {}", name(&dir, report), problem.offset, problem.message);
+                if problem.tail {
+                    print!(" (a torn write, which --repair truncates)");
+                }
+                println!();
+            }
+            if corrupt > 0 {
+                return Err(err_msg(format!("{} of {} segments are corrupt", corrupt, reports.len())));
+            }
+            println!("{} segments ok", reports.len());
+        },
+    }
+    Ok(())
+}
+
+/// The segment's path within `dir`.
+fn name(dir: &Path, report: &SegmentReport) -> String {
+    report.path.strip_prefix(dir).unwrap_or(&report.path).display().to_string()
+}
+
+fn percent(part: u64, whole: u64) -> u64 {
+    (part * 100).checked_div(whole).unwrap_or(0)
+}
@@ -1,9 +1,17 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 
-use failure::Error;
+use failure::{err_msg, Error};
 use structopt::StructOpt;
 
-use cavey::{self, CaveyClient};
+use cavey::{self, CaveyClient, ClientConfig, DumpFormat, Member, ShardedClient, TlsAddr, Transport, UnixAddr};
+
+/// Exit status of `cavey rm` when the key did not exist.  Other failures
+/// exit with 1.
+const EXIT_KEY_NOT_FOUND: i32 = 2;
 
 #[derive(Debug, StructOpt)]
 enum Command {
@@ -20,6 +28,96 @@ enum Command {
     Remove {
         key: String,
     },
+    #[structopt(name="create-keyspace")]
+    CreateKeyspace {
+        name: String,
+    },
+    #[structopt(name="drop-keyspace")]
+    DropKeyspace {
+        name: String,
+    },
+    #[structopt(name="keyspaces")]
+    ListKeyspaces,
+    /// Print puts and removes of keys starting with prefix as they happen,
+    /// each after its sequence number.
+    #[structopt(name="watch")]
+    Watch {
+        #[structopt(default_value = "")]
+        prefix: String,
+        /// Resume after the event with this sequence number.
+        #[structopt(long = "after")]
+        after: Option<u64>,
+    },
+    #[structopt(name="cluster")]
+    Cluster {
+        #[structopt(subcommand)]
+        cmd: ClusterCommand,
+    },
+    /// Back up the server to dir, a relative path within the server's
+    /// --backup-dir.
+    #[structopt(name="backup")]
+    Backup {
+        dir: String,
+    },
+    /// Replace the server's data with the backup in dir, a relative path
+    /// within the server's --backup-dir.
+    #[structopt(name="restore")]
+    Restore {
+        dir: String,
+    },
+    /// Write every keyspace and entry to file, or to stdout, in a format
+    /// either engine can load.
+    #[structopt(name="dump")]
+    Dump {
+        #[structopt(parse(from_os_str))]
+        file: Option<PathBuf>,
+        /// json or binary.
+        #[structopt(long = "format", default_value = "json")]
+        format: DumpFormat,
+    },
+    /// Load a dump from file, or from stdin.
+    #[structopt(name="load")]
+    Load {
+        #[structopt(parse(from_os_str))]
+        file: Option<PathBuf>,
+    },
+    #[structopt(name="shard")]
+    Shard {
+        #[structopt(subcommand)]
+        cmd: ShardCommand,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum ClusterCommand {
+    #[structopt(name="status")]
+    Status,
+    /// Add the node serving raft on raft-addr, and clients on addr.
+    #[structopt(name="add")]
+    Add {
+        raft_addr: SocketAddr,
+        addr: SocketAddr,
+    },
+    #[structopt(name="remove")]
+    Remove {
+        raft_addr: SocketAddr,
+    },
+}
+
+/// Rebalancing the servers given by --shards.  Stop other writers first.
+#[derive(Debug, StructOpt)]
+enum ShardCommand {
+    /// Add the server at addr as a shard, moving the keys that now belong
+    /// to it there.
+    #[structopt(name="add")]
+    Add {
+        addr: SocketAddr,
+    },
+    /// Move the keys on the server at addr to the other shards.
+    #[structopt(name="remove")]
+    Remove {
+        addr: SocketAddr,
+    },
 }
 
 #[derive(Debug, StructOpt)]
@@ -27,6 +125,44 @@ struct Options {
     #[structopt(short = "a", long = "addr", default_value = "[::1]:4000")]
     addr: SocketAddr,
 
+    /// Connect to the server's Unix socket instead of --addr.
+    #[structopt(long = "unix", parse(from_os_str))]
+    unix: Option<PathBuf>,
+
+    /// Connect over TLS, trusting only servers with a certificate signed by
+    /// a CA in this PEM file.
+    #[structopt(long = "tls-ca", parse(from_os_str))]
+    tls_ca: Option<PathBuf>,
+
+    /// The name the server's certificate must be issued to.  Defaults to the
+    /// IP address in --addr.
+    #[structopt(long = "tls-server-name")]
+    tls_server_name: Option<String>,
+
+    /// PEM certificate chain to present to servers that require one.
+    #[structopt(long = "tls-cert", parse(from_os_str))]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert.
+    #[structopt(long = "tls-key", parse(from_os_str))]
+    tls_key: Option<PathBuf>,
+
+    /// Token to authenticate with, for servers with an ACL.
+    #[structopt(long = "token", env = "CAVEY_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// Spread keys over these servers by consistent hashing, instead of
+    /// using --addr.
+    #[structopt(long = "shards", raw(use_delimiter = "true"))]
+    shards: Vec<SocketAddr>,
+
+    #[structopt(short = "k", long = "keyspace")]
+    keyspace: Option<String>,
+
+    /// Seconds to wait for the server to connect or respond.
+    #[structopt(short = "t", long = "timeout")]
+    timeout: Option<u64>,
+
     #[structopt(subcommand)]
     cmd: Command,
 }
@@ -34,8 +170,44 @@ struct Options {
 fn main() -> Result<(), Error> {
     env_logger::from_env(env_logger::Env::default().default_filter_or("debug")).init();
     let options = Options::from_args();
-    let mut client = CaveyClient::new(options.addr)?;
-    match options.cmd {
+    let mut config = ClientConfig {
+        token: options.token.clone(),
+        ..ClientConfig::default()
+    };
+    if let Some(secs) = options.timeout {
+        let timeout = Some(Duration::from_secs(secs));
+        config.connect_timeout = timeout;
+        config.read_timeout = timeout;
+        config.write_timeout = timeout;
+    }
+    let client_cert = match (&options.tls_cert, &options.tls_key) {
+        (Some(cert), Some(key)) => Some((cert.as_path(), key.as_path())),
+        (None, None) => None,
+        _ => return Err(err_msg("a client certificate needs both --tls-cert and --tls-key")),
+    };
+    if !options.shards.is_empty() {
+        if options.unix.is_some() || options.tls_ca.is_some() {
+            return Err(err_msg("--shards is only supported over TCP"));
+        }
+        return run_sharded(ShardedClient::with_config(&options.shards, config)?, options.keyspace, options.cmd);
+    }
+    match (options.unix, &options.tls_ca) {
+        (Some(_), Some(_)) => Err(err_msg("TLS isn't supported on unix sockets")),
+        (Some(path), None) => run(CaveyClient::with_transport(UnixAddr::new(path), config)?, options.keyspace, options.cmd),
+        (None, Some(ca)) => {
+            let tls = cavey::client_tls_config(ca, client_cert)?;
+            let ip = options.addr.ip();
+            let server_name = options.tls_server_name.unwrap_or_else(|| ip.to_string());
+            let addr = TlsAddr::new(options.addr, &server_name, tls)?;
+            run(CaveyClient::with_transport(addr, config)?, options.keyspace, options.cmd)
+        }
+        (None, None) => run(CaveyClient::with_config(options.addr, config)?, options.keyspace, options.cmd),
+    }
+}
+
+fn run<T: Transport>(mut client: CaveyClient<T>, keyspace: Option<String>, cmd: Command) -> Result<(), Error> {
+    client.set_keyspace(keyspace);
+    match cmd {
         Command::Get { key } => match client.get(key)? {
             Some(value) => println!("{}", value),
             None => {
@@ -44,13 +216,101 @@ fn main() -> Result<(), Error> {
         },
 
         Command::Put { key, value } => client.put(key, value)?,
-        Command::Remove { key } => match client.remove(key) {
-            Ok(()) => {},
-            Err(err) => {
-                println!("{}", err);
-                std::process::exit(1)
+        Command::Remove { key } => {
+            if !client.remove(key)? {
+                eprintln!("Key not found");
+                std::process::exit(EXIT_KEY_NOT_FOUND)
+            }
+        },
+        Command::CreateKeyspace { name } => client.create_keyspace(name)?,
+        Command::DropKeyspace { name } => client.drop_keyspace(name)?,
+        Command::ListKeyspaces => {
+            for name in client.list_keyspaces()? {
+                println!("{}", name);
             }
         },
+        Command::Cluster { cmd: ClusterCommand::Status } => {
+            let status = client.cluster_status()?;
+            println!("node: {}", status.id);
+            println!("term: {}", status.term);
+            println!("leader: {}", status.leader.as_deref().unwrap_or("none"));
+            println!("commit index: {}", status.commit_index);
+            println!("applied index: {}", status.applied_index);
+            if let Some(failure) = &status.failure {
+                println!("failure: {}", failure);
+            }
+            for member in status.members {
+                println!("member: {} {}", member.id, member.addr);
+            }
+        },
+        Command::Cluster { cmd: ClusterCommand::Add { raft_addr, addr } } => {
+            client.add_member(Member { id: raft_addr.to_string(), addr: addr.to_string() })?
+        },
+        Command::Cluster { cmd: ClusterCommand::Remove { raft_addr } } => client.remove_member(raft_addr.to_string())?,
+        Command::Watch { prefix, after } => {
+            for event in client.watch(prefix, after)? {
+                let event = event?;
+                match event.value {
+                    Some(value) => println!("{} put {} {}", event.seq, event.key, value),
+                    None => println!("{} rm {}", event.seq, event.key),
+                }
+            }
+        },
+        Command::Backup { dir } => client.backup(dir)?,
+        Command::Restore { dir } => client.restore(dir)?,
+        Command::Dump { file: Some(path), format } => {
+            client.dump(BufWriter::new(File::create(path)?), format)?;
+        },
+        Command::Dump { file: None, format } => {
+            client.dump(io::stdout().lock(), format)?;
+        },
+        Command::Load { file: Some(path) } => {
+            client.load(File::open(path)?)?;
+        },
+        Command::Load { file: None } => {
+            client.load(io::stdin().lock())?;
+        },
+        Command::Shard { .. } => return Err(err_msg("shard commands need --shards")),
+    }
+    Ok(())
+}
+
+fn run_sharded(mut client: ShardedClient, keyspace: Option<String>, cmd: Command) -> Result<(), Error> {
+    client.set_keyspace(keyspace);
+    match cmd {
+        Command::Get { key } => match client.get(key)? {
+            Some(value) => println!("{}", value),
+            None => {
+                println!("Key not found");
+            }
+        },
+        Command::Put { key, value } => client.put(key, value)?,
+        Command::Remove { key } => {
+            if !client.remove(key)? {
+                eprintln!("Key not found");
+                std::process::exit(EXIT_KEY_NOT_FOUND)
+            }
+        },
+        Command::CreateKeyspace { name } => client.create_keyspace(name)?,
+        Command::DropKeyspace { name } => client.drop_keyspace(name)?,
+        Command::ListKeyspaces => {
+            for name in client.list_keyspaces()? {
+                println!("{}", name);
+            }
+        },
+        Command::Cluster { .. } => return Err(err_msg("cluster commands don't take --shards")),
+        Command::Watch { .. } => return Err(err_msg("watch doesn't take --shards")),
+        Command::Backup { .. } | Command::Restore { .. } | Command::Dump { .. } | Command::Load { .. } => {
+            return Err(err_msg("back up, restore, dump and load each shard with --addr"))
+        },
+        Command::Shard { cmd: ShardCommand::Add { addr } } => {
+            let moved = client.add_shard(addr.to_string(), addr)?;
+            println!("moved {} keys", moved);
+        },
+        Command::Shard { cmd: ShardCommand::Remove { addr } } => {
+            let moved = client.remove_shard(&addr.to_string())?;
+            println!("moved {} keys", moved);
+        },
     }
     Ok(())
 }
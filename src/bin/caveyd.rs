@@ -1,11 +1,19 @@
+use std::fs;
+use std::io;
 use std::net::{SocketAddr, TcpListener};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 use env_logger;
-use failure::Error;
-use log::info;
+use failure::{err_msg, Error};
+use log::{error, info};
 use structopt::StructOpt;
 
-use cavey::{CaveyEngine, CaveyStore, SledStore};
+use cavey::{Acl, CaveyEngine, CaveyError, CaveyStore, ClientConfig, Member, Primary, RaftConfig, RaftNode};
+use cavey::{ReadOnly, Replica, ReplicationLog, ServerConfig, SharedEngine, SledStore, TlsListener};
 
 #[derive(Debug, StructOpt)]
 struct Options {
@@ -15,9 +23,118 @@ struct Options {
     #[structopt(short = "a", long = "addr", default_value="[::1]:4000")]
     addr: SocketAddr,
 
+    // Serve the native protocol on this Unix socket instead of --addr
+    #[structopt(long = "unix", parse(from_os_str))]
+    unix: Option<PathBuf>,
+
+    // Serve the native protocol over TLS with this PEM certificate chain
+    #[structopt(long = "tls-cert", parse(from_os_str))]
+    tls_cert: Option<PathBuf>,
+
+    // PEM private key for --tls-cert
+    #[structopt(long = "tls-key", parse(from_os_str))]
+    tls_key: Option<PathBuf>,
+
+    // Require TLS clients to present a certificate signed by a CA in this
+    // PEM file
+    #[structopt(long = "tls-client-ca", parse(from_os_str))]
+    tls_client_ca: Option<PathBuf>,
+
+    // Require clients to authenticate, and limit what each may do, as set
+    // out in this JSON file
+    #[structopt(long = "acl", parse(from_os_str))]
+    acl: Option<PathBuf>,
+
+    // Also serve the Redis protocol on this address
+    #[structopt(long = "resp-addr")]
+    resp_addr: Option<SocketAddr>,
+
+    // Also serve the HTTP/JSON API on this address
+    #[structopt(long = "http-addr")]
+    http_addr: Option<SocketAddr>,
+
+    // Also serve the gRPC API on this address
+    #[structopt(long = "grpc-addr")]
+    grpc_addr: Option<SocketAddr>,
+
+    // Stream writes to replicas connecting on this address
+    #[structopt(long = "replication-addr")]
+    replication_addr: Option<SocketAddr>,
+
+    // Writes kept for replicas to catch up from.  Replicas further behind
+    // are sent a snapshot
+    #[structopt(long = "replication-log-size", default_value="100000")]
+    replication_log_size: usize,
+
+    // Serve reads only, following the primary whose --replication-addr
+    // this is
+    #[structopt(long = "replica-of")]
+    replica_of: Option<SocketAddr>,
+
+    // Join a Raft cluster, talking to the other nodes on this address
+    #[structopt(long = "raft-addr")]
+    raft_addr: Option<SocketAddr>,
+
+    // The first members of the cluster, as comma-separated
+    // raft-addr=addr pairs, the same on each of them.  Ignored once a node
+    // has joined; leave out to start a node to add to a running cluster
+    #[structopt(long = "cluster")]
+    cluster: Option<String>,
+
     // kvs or sled
     #[structopt(short = "e", long = "engine", default_value="")]
-    engine_name: String
+    engine_name: String,
+
+    // The directory `cavey backup` and `cavey restore` name backups within.
+    // Without it, clients can't back up or restore
+    #[structopt(long = "backup-dir", parse(from_os_str))]
+    backup_dir: Option<PathBuf>,
+
+    // Before serving, replace the data with the backup in this directory,
+    // taken by `cavey backup`
+    #[structopt(long = "restore-from", parse(from_os_str))]
+    restore_from: Option<PathBuf>,
+
+    // threaded or async
+    #[structopt(long = "server", default_value="threaded")]
+    server: String,
+
+    // Size of the async server's pool for engine calls
+    #[structopt(long = "blocking-threads", default_value="4")]
+    blocking_threads: usize,
+
+    // Largest request frame accepted, in bytes
+    #[structopt(long = "max-frame-size")]
+    max_frame_size: Option<u32>,
+
+    // Longest key accepted, in bytes
+    #[structopt(long = "max-key-size")]
+    max_key_size: Option<usize>,
+
+    // Longest value accepted, in bytes
+    #[structopt(long = "max-value-size")]
+    max_value_size: Option<usize>,
+
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    // Copy the data in dir, served by the --from engine, to the new
+    // directory dest for the --to engine, by default dir-<to>.  Stop the
+    // server using dir first
+    #[structopt(name = "migrate")]
+    Migrate {
+        #[structopt(long = "from")]
+        from: String,
+        #[structopt(long = "to")]
+        to: String,
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+        #[structopt(parse(from_os_str))]
+        dest: Option<PathBuf>,
+    },
 }
 
 #[derive(Debug, StructOpt)]
@@ -45,14 +162,299 @@ fn main() -> Result<(), Error> {
 
     env_logger::from_env(env_logger::Env::default().default_filter_or("info")).init();
     info!("version: {}", env!("CARGO_PKG_VERSION"));
+    if let Some(Command::Migrate { from, to, dir, dest }) = opts.cmd {
+        let dest = match dest {
+            Some(dest) => dest,
+            None => {
+                let dir = dir.canonicalize()?;
+                let mut name = dir.file_name().unwrap_or_default().to_owned();
+                name.push(format!("-{}", to));
+                dir.with_file_name(name)
+            }
+        };
+        cavey::migrate(&dir, &from, &dest, &to, |copied| info!("copied {} entries", copied))?;
+        return Ok(());
+    }
+    // The async server only speaks plain TCP and Unix sockets
+    if opts.server == "async" && (opts.tls_cert.is_some() || opts.tls_key.is_some()) {
+        return Err(err_msg("--server async can't be combined with --tls-cert or --tls-key"));
+    }
     info!("engine: {}", opts.engine_name);
     let mut engine: Box<dyn CaveyEngine> = match &opts.engine_name[..] {
         "kvs" => Box::new(CaveyStore::open(".")?),
         "sled" => Box::new(SledStore::open(".")?),
         _ => panic!(r#"unknown engine. Valid options are "kvs" and "sled""#),
     };
-    info!("binding to socket {}", opts.addr);
-    let mut server = TcpListener::bind(opts.addr)?;
-    cavey::run_server(&mut server, &mut *engine)?;
+    if let Some(src) = &opts.restore_from {
+        // Replicas and cluster nodes take their data from the others
+        if opts.replica_of.is_some() || opts.raft_addr.is_some() {
+            return Err(err_msg("--restore-from can't be combined with --replica-of or --raft-addr"));
+        }
+        engine.restore(src)?;
+        info!("restored from {}", src.display());
+    }
+    let mut replication = None;
+    match (opts.replication_addr, opts.replica_of) {
+        (Some(_), Some(_)) => return Err(err_msg("a replica can't have replicas of its own")),
+        (Some(addr), None) => {
+            info!("serving replication on {}", addr);
+            let log = Arc::new(ReplicationLog::open("replication.log", opts.replication_log_size)?);
+            engine = Box::new(Primary::new(engine, log.clone())?);
+            replication = Some((TcpListener::bind(addr)?, log));
+        }
+        (None, Some(primary)) => {
+            info!("replicating from {}", primary);
+            let store: SharedEngine = Arc::new(Mutex::new(engine));
+            engine = Box::new(ReadOnly::new(store.clone()));
+            let replica = Replica::new(primary, store, PathBuf::from("replica.json"), ClientConfig::default())?;
+            thread::spawn(move || replica.run());
+        }
+        (None, None) => {}
+    }
+    match opts.raft_addr {
+        Some(_) if opts.replication_addr.is_some() || opts.replica_of.is_some() => {
+            return Err(err_msg("a cluster node can't also be a primary or replica"));
+        }
+        Some(raft_addr) => {
+            let bootstrap = match &opts.cluster {
+                Some(cluster) => parse_cluster(cluster)?,
+                None => Vec::new(),
+            };
+            info!("serving raft on {}", raft_addr);
+            let listener = TcpListener::bind(raft_addr)?;
+            let config = RaftConfig::new(raft_addr.to_string(), opts.addr.to_string(), PathBuf::from("."));
+            let node = RaftNode::open(config, engine, &bootstrap)?;
+            engine = Box::new(node.engine());
+            thread::spawn(move || {
+                if let Err(err) = node.serve(&listener) {
+                    error!("raft failed: {}", err);
+                }
+            });
+        }
+        None if opts.cluster.is_some() => return Err(err_msg("--cluster needs --raft-addr")),
+        None => {}
+    }
+    let acl = match &opts.acl {
+        // The other frontends, replication and raft don't authenticate
+        // clients, so would bypass it
+        Some(_)
+            if opts.resp_addr.is_some()
+                || opts.http_addr.is_some()
+                || opts.grpc_addr.is_some()
+                || opts.replication_addr.is_some()
+                || opts.raft_addr.is_some() =>
+        {
+            return Err(err_msg(
+                "--acl can't be combined with --resp-addr, --http-addr, --grpc-addr, --replication-addr or --raft-addr",
+            ));
+        }
+        Some(path) => {
+            info!("enforcing acl from {}", path.display());
+            Some(Arc::new(Acl::load(path)?))
+        }
+        None => None,
+    };
+    let defaults = ServerConfig::default();
+    let config = ServerConfig {
+        max_frame_size: opts.max_frame_size.unwrap_or(defaults.max_frame_size),
+        max_key_size: opts.max_key_size.unwrap_or(defaults.max_key_size),
+        max_value_size: opts.max_value_size.unwrap_or(defaults.max_value_size),
+        acl,
+        backup_dir: opts.backup_dir.clone(),
+    };
+    let tls = match (&opts.tls_cert, &opts.tls_key) {
+        (Some(cert), Some(key)) => Some(cavey::server_tls_config(cert, key, opts.tls_client_ca.as_deref())?),
+        (None, None) if opts.tls_client_ca.is_none() => None,
+        _ => return Err(err_msg("TLS needs both --tls-cert and --tls-key")),
+    };
+    let native = match (&opts.unix, tls) {
+        (Some(_), Some(_)) => return Err(err_msg("TLS isn't supported on unix sockets")),
+        (Some(path), None) => {
+            info!("binding to unix socket {}", path.display());
+            Native::Unix(bind_unix(path)?)
+        }
+        (None, Some(tls)) => {
+            info!("binding to socket {} with tls", opts.addr);
+            Native::Tls(TlsListener::new(TcpListener::bind(opts.addr)?, tls))
+        }
+        (None, None) => {
+            info!("binding to socket {}", opts.addr);
+            Native::Tcp(TcpListener::bind(opts.addr)?)
+        }
+    };
+    let mut frontends = Vec::new();
+    if let Some(addr) = opts.resp_addr {
+        info!("serving redis protocol on {}", addr);
+        frontends.push((Frontend::Resp, TcpListener::bind(addr)?));
+    }
+    if let Some(addr) = opts.http_addr {
+        info!("serving http on {}", addr);
+        frontends.push((Frontend::Http, TcpListener::bind(addr)?));
+    }
+    let grpc = match opts.grpc_addr {
+        Some(addr) => {
+            info!("serving grpc on {}", addr);
+            let listener = TcpListener::bind(addr)?;
+            listener.set_nonblocking(true)?;
+            Some(listener)
+        }
+        None => None,
+    };
+    let engine = Arc::new(Mutex::new(engine));
+    if let Some((listener, log)) = replication {
+        let engine = engine.clone();
+        let config = config.clone();
+        thread::spawn(move || {
+            if let Err(err) = cavey::serve_replication(&listener, &*engine, &log, &config) {
+                error!("replication failed: {}", err);
+            }
+        });
+    }
+    match &opts.server[..] {
+        "threaded" => {
+            if let Some(listener) = grpc {
+                let engine = engine.clone();
+                let config = config.clone();
+                thread::spawn(move || {
+                    let result = tokio::runtime::Builder::new_multi_thread()
+                        .enable_io()
+                        .build()
+                        .map_err(Into::into)
+                        .and_then(|runtime| runtime.block_on(serve_grpc(listener, engine, config)));
+                    if let Err(err) = result {
+                        error!("grpc frontend failed: {}", err);
+                    }
+                });
+            }
+            let engine = &*engine;
+            let config = &config;
+            thread::scope(|scope| {
+                for (frontend, listener) in &frontends {
+                    scope.spawn(move || frontend.serve(listener, engine, config));
+                }
+                native.serve(engine, config)
+            })?;
+        }
+        "async" => {
+            for (frontend, listener) in frontends {
+                let engine = engine.clone();
+                let config = config.clone();
+                thread::spawn(move || frontend.serve(&listener, &*engine, &config));
+            }
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .enable_io()
+                .enable_time()
+                .max_blocking_threads(opts.blocking_threads)
+                .build()?;
+            if let Some(listener) = grpc {
+                let engine = engine.clone();
+                let config = config.clone();
+                runtime.spawn(async move {
+                    if let Err(err) = serve_grpc(listener, engine, config).await {
+                        error!("grpc frontend failed: {}", err);
+                    }
+                });
+            }
+            runtime.block_on(native.serve_async(engine, config))?;
+        }
+        _ => panic!(r#"unknown server. Valid options are "threaded" and "async""#),
+    }
     Ok(())
 }
+
+/// Parse `--cluster`: comma-separated `raft-addr=addr` pairs.
+fn parse_cluster(cluster: &str) -> Result<Vec<Member>, Error> {
+    cluster
+        .split(',')
+        .map(|pair| {
+            let (id, addr) = pair
+                .split_once('=')
+                .ok_or_else(|| err_msg(format!("expected raft-addr=addr, got {:?}", pair)))?;
+            // Normalized, as ids are compared as strings
+            let id: SocketAddr = id.trim().parse()?;
+            let addr: SocketAddr = addr.trim().parse()?;
+            Ok(Member {
+                id: id.to_string(),
+                addr: addr.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Bind a Unix socket at `path`, replacing any socket left there by a server
+/// that has exited.
+fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    match UnixListener::bind(path) {
+        Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+            let stale = fs::symlink_metadata(path)?.file_type().is_socket() && UnixStream::connect(path).is_err();
+            if !stale {
+                return Err(err);
+            }
+            fs::remove_file(path)?;
+            UnixListener::bind(path)
+        }
+        result => result,
+    }
+}
+
+/// The socket the native protocol is served on.
+enum Native {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+    Tls(TlsListener),
+}
+
+impl Native {
+    fn serve(&self, engine: &Mutex<Box<dyn CaveyEngine>>, config: &ServerConfig) -> cavey::Result<()> {
+        match self {
+            Native::Tcp(listener) => cavey::serve(listener, engine, config),
+            Native::Unix(listener) => cavey::serve(listener, engine, config),
+            Native::Tls(listener) => cavey::serve(listener, engine, config),
+        }
+    }
+
+    /// Serve from the tokio runtime.  Call from inside one.
+    async fn serve_async(self, engine: SharedEngine, config: ServerConfig) -> cavey::Result<()> {
+        match self {
+            Native::Tcp(listener) => {
+                listener.set_nonblocking(true)?;
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                cavey::serve_async(listener, engine, config).await
+            }
+            Native::Unix(listener) => {
+                listener.set_nonblocking(true)?;
+                let listener = tokio::net::UnixListener::from_std(listener)?;
+                cavey::serve_async(listener, engine, config).await
+            }
+            Native::Tls(_) => Err(CaveyError::Unsupported("tls with the async server".to_owned())),
+        }
+    }
+}
+
+/// Serve gRPC on `listener` until it fails.  Call from inside a tokio runtime.
+async fn serve_grpc(listener: TcpListener, engine: SharedEngine, config: ServerConfig) -> cavey::Result<()> {
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    cavey::serve_grpc(listener, engine, config).await
+}
+
+/// Protocols served on other ports, beside the native protocol.  gRPC is
+/// served separately, since it needs a tokio runtime.
+enum Frontend {
+    Resp,
+    Http,
+}
+
+impl Frontend {
+    fn serve<E>(&self, listener: &TcpListener, engine: &Mutex<E>, config: &ServerConfig)
+    where
+        E: std::ops::DerefMut<Target = dyn CaveyEngine> + Send,
+    {
+        let result = match self {
+            Frontend::Resp => cavey::serve_resp(listener, engine, config),
+            Frontend::Http => cavey::serve_http(listener, engine, config),
+        };
+        if let Err(err) = result {
+            error!("frontend failed: {}", err);
+        }
+    }
+}
@@ -1,71 +1,373 @@
+use std::cmp;
+use std::fmt;
+use std::io::{self, prelude::*};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
 
-use std::net::{SocketAddr, TcpStream};
-
-use bincode::{serialize_into, deserialize_from};
-use failure::format_err;
 use log::debug;
 
-use crate::Result;
-use crate::protocol::{ClientMessage, ServerMessage};
+use crate::{CaveyError, Changeset, ClusterStatus, DumpFormat, Member, Result, Transport, Version, Watch};
+use crate::dump::{dump_store, load_store, DumpStore};
+use crate::error::ErrorCode;
+use crate::protocol::{read_frame, write_frame, ClientMessage, Handshake, ServerMessage, Token, MAX_FRAME_SIZE};
+
+/// Leader redirects a request follows before giving up.
+const MAX_REDIRECTS: u32 = 5;
+
+/// Times a request is retried while a cluster has no leader.  The delay
+/// doubles as for other retries, so this covers a few elections.
+const MAX_LEADERLESS_RETRIES: u32 = 10;
+
+/// Settings for a `CaveyClient`.
+#[derive(Clone)]
+pub struct ClientConfig {
+    pub connect_timeout: Option<Duration>,
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub retry: RetryPolicy,
+    /// Sent to authenticate each connection, for servers with an ACL.
+    pub token: Option<String>,
+}
+
+// Keep the token out of logs.
+impl fmt::Debug for ClientConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ClientConfig")
+            .field("connect_timeout", &self.connect_timeout)
+            .field("read_timeout", &self.read_timeout)
+            .field("write_timeout", &self.write_timeout)
+            .field("retry", &self.retry)
+            .field("token", &self.token.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            connect_timeout: Some(Duration::from_secs(5)),
+            read_timeout: Some(Duration::from_secs(30)),
+            write_timeout: Some(Duration::from_secs(30)),
+            retry: RetryPolicy::default(),
+            token: None,
+        }
+    }
+}
+
+/// How idempotent requests are retried after a timeout or a broken
+/// connection.  The delay before each retry doubles, up to `max_backoff`.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
 
-pub struct CaveyClient {
-    socket: TcpStream,
+/// A client of the native protocol, over TCP by default, or over any other
+/// `Transport`.
+pub struct CaveyClient<T: Transport = SocketAddr> {
+    transport: T,
+    config: ClientConfig,
+    // `None` after a transport failure, until the next request reconnects.
+    // Kept with the server's handshake.
+    socket: Option<(T::Stream, Handshake)>,
+    keyspace: Option<String>,
 }
 
 impl CaveyClient {
     pub fn new<S: Into<SocketAddr>>(sockaddr: S) -> Result<CaveyClient> {
-        TcpStream::connect(sockaddr.into())
-            .map(|socket| CaveyClient { socket })
-            .map_err(|e| e.into())
+        CaveyClient::with_config(sockaddr, ClientConfig::default())
+    }
+
+    pub fn with_config<S: Into<SocketAddr>>(sockaddr: S, config: ClientConfig) -> Result<CaveyClient> {
+        CaveyClient::with_transport(sockaddr.into(), config)
     }
+}
 
+impl<T: Transport> CaveyClient<T> {
+    /// Connect over `transport`, such as a `UnixAddr`.
+    pub fn with_transport(transport: T, config: ClientConfig) -> Result<CaveyClient<T>> {
+        let mut client = CaveyClient {
+            transport,
+            config,
+            socket: None,
+            keyspace: None,
+        };
+        client.connect().map_err(timed_out)?;
+        Ok(client)
+    }
+
+    /// Direct subsequent requests to the named keyspace, or to the default
+    /// keyspace if `None`.
+    pub fn set_keyspace(&mut self, keyspace: Option<String>) {
+        self.keyspace = keyspace;
+    }
 
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        let request = ClientMessage::Get { key };
-        self.send(&request)?;
-        self.receive_value()
+        let request = ClientMessage::Get { keyspace: self.keyspace.clone(), key };
+        self.idempotent_request(&request)?.into_value()
     }
 
     pub fn put(&mut self, key: String, value: String) -> Result<()> {
-        let request = ClientMessage::Put { key, value };
-        self.send(&request)?;
-        self.receive_empty()
+        let request = ClientMessage::Put { keyspace: self.keyspace.clone(), key, value };
+        self.idempotent_request(&request)?.into_empty()
     }
 
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        let request = ClientMessage::Remove { key };
-        self.send(&request)?;
-        self.receive_empty()
+    /// Remove `key`, returning whether it was present.
+    pub fn remove(&mut self, key: String) -> Result<bool> {
+        let request = ClientMessage::Remove { keyspace: self.keyspace.clone(), key };
+        self.request(&request)?.into_removed()
     }
 
-    fn send(&mut self, msg: &ClientMessage) -> Result<()> {
-        debug!("sending_message: {:?}", msg);
-        Ok(serialize_into(&mut self.socket, msg)?)
+    /// Set `key` to `value`, returning the value it replaced.
+    pub fn put_returning_old(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let request = ClientMessage::PutReturningOld { keyspace: self.keyspace.clone(), key, value };
+        self.request(&request)?.into_value()
+    }
+
+    /// Remove `key`, returning the value it held.
+    pub fn remove_returning_old(&mut self, key: String) -> Result<Option<String>> {
+        let request = ClientMessage::RemoveReturningOld { keyspace: self.keyspace.clone(), key };
+        self.request(&request)?.into_value()
+    }
+
+    /// Get the value of `key` along with its version, for use in transactions.
+    pub fn get_versioned(&mut self, key: String) -> Result<(Option<String>, Option<Version>)> {
+        let request = ClientMessage::GetVersioned { keyspace: self.keyspace.clone(), key };
+        self.idempotent_request(&request)?.into_versioned()
+    }
+
+    /// Submit the reads and writes of a transaction for commit.
+    pub fn commit(&mut self, changeset: Changeset) -> Result<()> {
+        let request = ClientMessage::Commit { keyspace: self.keyspace.clone(), changeset };
+        self.request(&request)?.into_empty()
+    }
+
+    /// Up to `limit` keys starting with `prefix`, with their values, in key
+    /// order.  Only keys ordered after `after` are returned, so passing the
+    /// last key of one page gets the next.  The server caps `limit`.
+    pub fn scan(&mut self, prefix: String, after: Option<String>, limit: u32) -> Result<Vec<(String, String)>> {
+        let request = ClientMessage::Scan { keyspace: self.keyspace.clone(), prefix, after, limit };
+        self.idempotent_request(&request)?.into_entries()
+    }
+
+    pub fn create_keyspace(&mut self, keyspace: String) -> Result<()> {
+        let request = ClientMessage::CreateKeyspace { keyspace };
+        self.request(&request)?.into_empty()
+    }
+
+    pub fn drop_keyspace(&mut self, keyspace: String) -> Result<()> {
+        let request = ClientMessage::DropKeyspace { keyspace };
+        self.request(&request)?.into_empty()
+    }
+
+    pub fn list_keyspaces(&mut self) -> Result<Vec<String>> {
+        let request = ClientMessage::ListKeyspaces;
+        self.idempotent_request(&request)?.into_keyspaces()
     }
 
-    fn receive_empty(&mut self) -> Result<()> {
-        let response: ServerMessage = self.receive()?;
-        match response {
-            ServerMessage::Success { value } => match value {
-                None => Ok(()),
-                Some(val) => Err(format_err!("cavey error: unexpected response {:?}", val)),
-            },
-            ServerMessage::Error { err } => Err(format_err!("cavey error: {}", err)),
+    /// Check that the server is responding.
+    pub fn ping(&mut self) -> Result<()> {
+        let request = ClientMessage::Ping;
+        self.request(&request)?.into_empty()
+    }
+
+    /// The status of the cluster the server belongs to.
+    pub fn cluster_status(&mut self) -> Result<ClusterStatus> {
+        let request = ClientMessage::ClusterStatus;
+        self.idempotent_request(&request)?.into_cluster()
+    }
+
+    /// Add a node to the server's cluster.
+    pub fn add_member(&mut self, member: Member) -> Result<()> {
+        let request = ClientMessage::AddMember { member };
+        self.request(&request)?.into_empty()
+    }
+
+    /// Remove the node with the given id from the server's cluster.
+    pub fn remove_member(&mut self, id: String) -> Result<()> {
+        let request = ClientMessage::RemoveMember { id };
+        self.request(&request)?.into_empty()
+    }
+
+    /// Back up the server's engine to the directory `dest`, a relative path
+    /// within the server's backup directory, which must be empty or not
+    /// exist.
+    pub fn backup(&mut self, dest: String) -> Result<()> {
+        let request = ClientMessage::Backup { dest };
+        self.request(&request)?.into_empty()
+    }
+
+    /// Replace the data in the server's engine with the backup in the
+    /// directory `src`, a relative path within the server's backup
+    /// directory.
+    pub fn restore(&mut self, src: String) -> Result<()> {
+        let request = ClientMessage::Restore { src };
+        self.request(&request)?.into_empty()
+    }
+
+    /// Write every keyspace and entry on the server to `out`, returning how
+    /// many entries were written.  Writes made during the dump may or may
+    /// not be included.
+    pub fn dump<W: Write>(&mut self, out: W, format: DumpFormat) -> Result<u64> {
+        dump_store(self, out, format)
+    }
+
+    /// Load the keyspaces and entries in a dump into the server, returning
+    /// how many entries were loaded.
+    pub fn load<R: Read>(&mut self, input: R) -> Result<u64> {
+        load_store(self, input)
+    }
+
+    /// Watch the keys starting with `prefix` change, from after the event
+    /// numbered `after`, or from now if `None`.  The watch has a connection
+    /// of its own, and the client connects again for its next request.
+    pub fn watch(&mut self, prefix: String, after: Option<u64>) -> Result<Watch<T>> {
+        let request = ClientMessage::Watch { keyspace: self.keyspace.clone(), prefix, after };
+        let (events, seq) = self.request(&request)?.into_events()?;
+        let (socket, _) = self.socket.take().expect("a request leaves the client connected");
+        Ok(Watch::new(socket, events, seq))
+    }
+
+    /// Send a request that is safe to repeat, retrying it according to the
+    /// retry policy if it times out or the connection breaks.
+    fn idempotent_request(&mut self, msg: &ClientMessage) -> Result<ServerMessage> {
+        let mut backoff = self.config.retry.initial_backoff;
+        let mut retries = 0;
+        loop {
+            match self.request(msg) {
+                Err(CaveyError::Timeout) | Err(CaveyError::Io(_)) if retries < self.config.retry.max_retries => {
+                    debug!("request failed, retrying in {:?}", backoff);
+                    thread::sleep(backoff);
+                    backoff = cmp::min(backoff * 2, self.config.retry.max_backoff);
+                    retries += 1;
+                }
+                result => return result,
+            }
         }
     }
 
-    fn receive_value(&mut self) -> Result<Option<String>> {
+    /// Send a request and wait for its response.  A cluster node that isn't
+    /// the leader names the leader, and the request is sent there instead,
+    /// if the transport can reach it.  During an election no node leads, so
+    /// the request is retried after a delay.  Either way the request was
+    /// never applied, so it is safe to send again.
+    fn request(&mut self, msg: &ClientMessage) -> Result<ServerMessage> {
+        let mut backoff = self.config.retry.initial_backoff;
+        let (mut redirects, mut retries) = (0, 0);
+        loop {
+            let resp = self.exchange(msg)?;
+            match &resp {
+                ServerMessage::Error { code: ErrorCode::NotLeader, detail } if detail.is_empty() => {
+                    if retries == MAX_LEADERLESS_RETRIES {
+                        return Ok(resp);
+                    }
+                    debug!("no leader, retrying in {:?}", backoff);
+                    thread::sleep(backoff);
+                    backoff = cmp::min(backoff * 2, self.config.retry.max_backoff);
+                    retries += 1;
+                }
+                ServerMessage::Error { code: ErrorCode::NotLeader, detail } if redirects < MAX_REDIRECTS => {
+                    match self.transport.redirect(detail) {
+                        Some(transport) => {
+                            debug!("redirected to leader at {}", detail);
+                            self.transport = transport;
+                            self.socket = None;
+                            redirects += 1;
+                        }
+                        None => return Ok(resp),
+                    }
+                }
+                _ => return Ok(resp),
+            }
+        }
+    }
 
-        let response: ServerMessage = self.receive()?;
-        match response {
-            ServerMessage::Success { value } => Ok(value),
-            ServerMessage::Error { err } => Err(format_err!("cavey error: {}", err)),
+    /// Send a request and wait for its response.  After a timeout, I/O error,
+    /// or malformed or oversized frame the connection may be out of step with
+    /// the server, so it is dropped and the next request reconnects.
+    fn exchange(&mut self, msg: &ClientMessage) -> Result<ServerMessage> {
+        let result = self.send(msg).and_then(|()| self.receive());
+        match result {
+            Err(err @ CaveyError::Protocol(_)) | Err(err @ CaveyError::TooLarge(_)) => {
+                self.socket = None;
+                Err(err)
+            }
+            Err(err @ CaveyError::Io(_)) => {
+                self.socket = None;
+                Err(timed_out(err))
+            }
+            result => result,
         }
     }
 
+    fn connect(&mut self) -> Result<&mut (T::Stream, Handshake)> {
+        if self.socket.is_none() {
+            let mut socket = self.transport.connect(&self.config)?;
+            let local = Handshake::local(MAX_FRAME_SIZE);
+            socket.write_all(&local.encode())?;
+            let server = Handshake::read_from(&mut socket)?;
+            local.check_compatible(&server)?;
+            if let Some(token) = &self.config.token {
+                let request = ClientMessage::Authenticate { token: Token(token.clone()) };
+                write_frame(&mut socket, &request, server.max_frame_size)?;
+                read_frame::<_, ServerMessage>(&mut socket, MAX_FRAME_SIZE)?.into_empty()?;
+            }
+            self.socket = Some((socket, server));
+        }
+        Ok(self.socket.as_mut().unwrap())
+    }
+
+    fn send(&mut self, msg: &ClientMessage) -> Result<()> {
+        debug!("sending_message: {:?}", msg);
+        let (socket, server) = self.connect()?;
+        write_frame(socket, msg, server.max_frame_size)
+    }
+
     fn receive(&mut self) -> Result<ServerMessage> {
-        let resp = deserialize_from(&mut self.socket)?;
+        let (socket, _) = self.connect()?;
+        let resp = read_frame(socket, MAX_FRAME_SIZE)?;
         debug!("received message: {:?}", resp);
         Ok(resp)
     }
 }
+
+/// Report an I/O error from a socket timeout as a `Timeout`.
+fn timed_out(err: CaveyError) -> CaveyError {
+    match err {
+        CaveyError::Io(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => CaveyError::Timeout,
+        err => err,
+    }
+}
+
+impl<T: Transport> DumpStore for CaveyClient<T> {
+    fn list_keyspaces(&mut self) -> Result<Vec<String>> {
+        CaveyClient::list_keyspaces(self)
+    }
+
+    fn create_keyspace(&mut self, name: &str) -> Result<()> {
+        CaveyClient::create_keyspace(self, name.to_owned())
+    }
+
+    fn scan(&mut self, keyspace: Option<&str>, after: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        let keyspace = keyspace.map(str::to_owned);
+        let request = ClientMessage::Scan { keyspace, prefix: String::new(), after, limit: limit as u32 };
+        self.idempotent_request(&request)?.into_entries()
+    }
+
+    fn put(&mut self, keyspace: Option<&str>, key: String, value: String) -> Result<()> {
+        let keyspace = keyspace.map(str::to_owned);
+        self.request(&ClientMessage::Put { keyspace, key, value })?.into_empty()
+    }
+}
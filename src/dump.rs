@@ -0,0 +1,291 @@
+//! A portable dump of every keyspace and entry, for moving data between
+//! engines, which can't open each other's data directories, or servers.
+//!
+//! There are two formats:
+//!
+//! - JSON lines: a `{"cavey_dump": 1}` header line, then one record per
+//!   line, for reading with other tools.
+//! - Binary: the bytes `CAVYDUMP` and a version byte, then records, each a
+//!   big-endian `u32` length, the CRC-32 of the record, and the record in
+//!   bincode.
+//!
+//! Both end with a record counting the entries, so a truncated dump is
+//! caught.  Loading tells the formats apart by their first bytes.
+
+use std::fmt;
+use std::io::{self, prelude::*, BufReader};
+use std::str::FromStr;
+
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{in_keyspace, CaveyEngine, CaveyError, Result};
+
+const MAGIC: &[u8; 8] = b"CAVYDUMP";
+
+/// The version of both formats, bumped by changes old releases can't load.
+const VERSION: u8 = 1;
+
+/// Entries read from the source at a time.
+const DUMP_PAGE: usize = 1000;
+
+/// The largest binary record loaded, so a corrupt length can't exhaust
+/// memory.
+const MAX_RECORD_SIZE: u32 = 256 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// JSON lines.
+    Json,
+    /// Checksummed bincode.
+    Binary,
+}
+
+impl FromStr for DumpFormat {
+    type Err = CaveyError;
+
+    fn from_str(s: &str) -> Result<DumpFormat> {
+        match s {
+            "json" => Ok(DumpFormat::Json),
+            "binary" => Ok(DumpFormat::Binary),
+            _ => Err(CaveyError::Unsupported(format!("dump format {:?}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Record {
+    /// Comes before the keyspace's entries.
+    Keyspace { name: String },
+    Entry { keyspace: Option<String>, key: String, value: String },
+    End { entries: u64 },
+}
+
+#[derive(Deserialize, Serialize)]
+struct JsonHeader {
+    cavey_dump: u8,
+}
+
+/// Where a dump is taken from or loaded into: an engine, or a server through
+/// a client.
+pub(crate) trait DumpStore {
+    fn list_keyspaces(&mut self) -> Result<Vec<String>>;
+    fn create_keyspace(&mut self, name: &str) -> Result<()>;
+    /// The next page of entries in `keyspace` after `after`.
+    fn scan(&mut self, keyspace: Option<&str>, after: Option<String>, limit: usize) -> Result<Vec<(String, String)>>;
+    fn put(&mut self, keyspace: Option<&str>, key: String, value: String) -> Result<()>;
+}
+
+impl DumpStore for dyn CaveyEngine + '_ {
+    fn list_keyspaces(&mut self) -> Result<Vec<String>> {
+        CaveyEngine::list_keyspaces(self)
+    }
+
+    fn create_keyspace(&mut self, name: &str) -> Result<()> {
+        CaveyEngine::create_keyspace(self, name)
+    }
+
+    fn scan(&mut self, keyspace: Option<&str>, after: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        in_keyspace(self, keyspace)?.scan("", after.as_deref(), limit)
+    }
+
+    fn put(&mut self, keyspace: Option<&str>, key: String, value: String) -> Result<()> {
+        in_keyspace(self, keyspace)?.put(key, value)
+    }
+}
+
+/// Write every keyspace and entry in `engine` to `out`, returning how many
+/// entries were written.  The engine is only locked by the caller, if at
+/// all, so a dump of an engine in use may mix data from before and after
+/// concurrent writes.
+pub fn dump<W: Write>(engine: &mut dyn CaveyEngine, out: W, format: DumpFormat) -> Result<u64> {
+    dump_store(engine, out, format)
+}
+
+/// Load the keyspaces and entries in a dump into `engine`, returning how
+/// many entries were loaded.  Keys already in the engine are kept unless the
+/// dump overwrites them.
+pub fn load<R: Read>(engine: &mut dyn CaveyEngine, input: R) -> Result<u64> {
+    load_store(engine, input)
+}
+
+pub(crate) fn dump_store<S: DumpStore + ?Sized, W: Write>(store: &mut S, out: W, format: DumpFormat) -> Result<u64> {
+    let mut writer = Writer { out, format };
+    writer.header()?;
+    let mut entries = 0;
+    let mut keyspaces = vec![None];
+    keyspaces.extend(store.list_keyspaces()?.into_iter().map(Some));
+    for keyspace in keyspaces {
+        if let Some(name) = &keyspace {
+            writer.write(&Record::Keyspace { name: name.clone() })?;
+        }
+        let mut after = None;
+        loop {
+            let page = store.scan(keyspace.as_deref(), after.take(), DUMP_PAGE)?;
+            // The source may return fewer entries than asked for, so only
+            // an empty page means the end.
+            if page.is_empty() {
+                break;
+            }
+            after = page.last().map(|(key, _)| key.clone());
+            for (key, value) in page {
+                writer.write(&Record::Entry { keyspace: keyspace.clone(), key, value })?;
+                entries += 1;
+            }
+        }
+    }
+    writer.write(&Record::End { entries })?;
+    writer.out.flush()?;
+    info!("dumped {} entries", entries);
+    Ok(entries)
+}
+
+pub(crate) fn load_store<S: DumpStore + ?Sized, R: Read>(store: &mut S, input: R) -> Result<u64> {
+    let mut reader = Reader::open(BufReader::new(input))?;
+    let mut entries = 0;
+    loop {
+        match reader.read()? {
+            Record::Keyspace { name } => match store.create_keyspace(&name) {
+                Ok(()) | Err(CaveyError::KeyspaceExists(_)) => {}
+                Err(err) => return Err(err),
+            },
+            Record::Entry { keyspace, key, value } => {
+                store.put(keyspace.as_deref(), key, value)?;
+                entries += 1;
+            }
+            Record::End { entries: expected } if expected == entries => {
+                info!("loaded {} entries", entries);
+                return Ok(entries);
+            }
+            Record::End { entries: expected } => {
+                return Err(CaveyError::Corruption(format!(
+                    "dump ends after {} entries, but read {}",
+                    expected, entries
+                )));
+            }
+        }
+    }
+}
+
+struct Writer<W> {
+    out: W,
+    format: DumpFormat,
+}
+
+impl<W: Write> Writer<W> {
+    fn header(&mut self) -> Result<()> {
+        match self.format {
+            DumpFormat::Json => {
+                serde_json::to_writer(&mut self.out, &JsonHeader { cavey_dump: VERSION })?;
+                self.out.write_all(b"\n")?;
+            }
+            DumpFormat::Binary => {
+                self.out.write_all(MAGIC)?;
+                self.out.write_all(&[VERSION])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, record: &Record) -> Result<()> {
+        match self.format {
+            DumpFormat::Json => {
+                serde_json::to_writer(&mut self.out, record)?;
+                self.out.write_all(b"\n")?;
+            }
+            DumpFormat::Binary => {
+                let payload = bincode::serialize(record)?;
+                let mut header = [0; 8];
+                BigEndian::write_u32(&mut header[..4], payload.len() as u32);
+                BigEndian::write_u32(&mut header[4..], checksum(&payload));
+                self.out.write_all(&header)?;
+                self.out.write_all(&payload)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Reader<R> {
+    input: R,
+    format: DumpFormat,
+    // Records read, for error messages.
+    records: u64,
+}
+
+impl<R: BufRead> Reader<R> {
+    /// Read the header, and with it the format.
+    fn open(mut input: R) -> Result<Reader<R>> {
+        let format = if input.fill_buf()?.starts_with(&MAGIC[..1]) {
+            let mut header = [0; 9];
+            input.read_exact(&mut header).map_err(truncated)?;
+            if header[..8] != MAGIC[..] {
+                return Err(CaveyError::Corruption("not a cavey dump".to_owned()));
+            }
+            check_version(header[8])?;
+            DumpFormat::Binary
+        } else {
+            let mut line = String::new();
+            input.read_line(&mut line)?;
+            match serde_json::from_str::<JsonHeader>(&line) {
+                Ok(header) => check_version(header.cavey_dump)?,
+                Err(_) => return Err(CaveyError::Corruption("not a cavey dump".to_owned())),
+            }
+            DumpFormat::Json
+        };
+        Ok(Reader { input, format, records: 0 })
+    }
+
+    fn read(&mut self) -> Result<Record> {
+        self.records += 1;
+        match self.format {
+            DumpFormat::Json => {
+                let mut line = String::new();
+                if self.input.read_line(&mut line)? == 0 {
+                    return Err(truncated(io::ErrorKind::UnexpectedEof.into()));
+                }
+                serde_json::from_str(&line).map_err(|err| self.corrupt(err))
+            }
+            DumpFormat::Binary => {
+                let len = self.input.read_u32::<BigEndian>().map_err(truncated)?;
+                let expected = self.input.read_u32::<BigEndian>().map_err(truncated)?;
+                if len > MAX_RECORD_SIZE {
+                    return Err(self.corrupt(format!("length {}", len)));
+                }
+                let mut payload = vec![0; len as usize];
+                self.input.read_exact(&mut payload).map_err(truncated)?;
+                if checksum(&payload) != expected {
+                    return Err(self.corrupt("checksum mismatch"));
+                }
+                bincode::deserialize(&payload).map_err(|err| self.corrupt(err))
+            }
+        }
+    }
+
+    fn corrupt<E: fmt::Display>(&self, err: E) -> CaveyError {
+        CaveyError::Corruption(format!("dump record {}: {}", self.records, err))
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(bytes);
+    hasher.finalize()
+}
+
+fn check_version(version: u8) -> Result<()> {
+    if version != VERSION {
+        return Err(CaveyError::Corruption(format!("unknown dump version {}", version)));
+    }
+    Ok(())
+}
+
+/// A dump ending early is corrupt, but other I/O errors are just that.
+fn truncated(err: io::Error) -> CaveyError {
+    match err.kind() {
+        io::ErrorKind::UnexpectedEof => CaveyError::Corruption("dump is truncated".to_owned()),
+        _ => err.into(),
+    }
+}
@@ -0,0 +1,212 @@
+use std::fmt;
+use std::io;
+use std::num::ParseIntError;
+use std::string::FromUtf8Error;
+
+use serde::{Deserialize, Serialize};
+
+/// Errors produced by cavey engines, servers and clients.
+///
+/// Errors returned by a server are carried across the wire as an
+/// `ErrorCode`, so `CaveyClient` returns the same variant the server's
+/// engine produced.
+#[derive(Debug)]
+pub enum CaveyError {
+    /// The key does not exist.
+    NotFound,
+    KeyspaceNotFound(String),
+    KeyspaceExists(String),
+    InvalidKeyspace(String),
+    /// A key read by a transaction changed before it committed.
+    Conflict(String),
+    /// Stored data could not be read back.
+    Corruption(String),
+    /// The data directory belongs to a different engine.
+    WrongEngine(String),
+    /// The operation is not supported by this engine.
+    Unsupported(String),
+    /// A malformed or unexpected message was received.
+    Protocol(String),
+    /// A message, key or value exceeds a configured size limit.
+    TooLarge(String),
+    /// The server requires a valid token before serving requests.
+    Unauthenticated,
+    /// The authenticated principal may not make the request.
+    PermissionDenied(String),
+    /// The server is a replica, which serves only reads.
+    ReadOnly,
+    /// The server is a cluster member other than the leader, which is at the
+    /// address given, if known.  The request was not applied.
+    NotLeader(Option<String>),
+    Io(io::Error),
+    /// The server did not respond in time.
+    Timeout,
+    /// Any other failure inside the storage engine.
+    Internal(String),
+    /// A watch asked to resume after an event the change feed no longer
+    /// keeps.
+    Expired(u64),
+}
+
+impl fmt::Display for CaveyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CaveyError::NotFound => write!(f, "Key not found"),
+            CaveyError::KeyspaceNotFound(name) => write!(f, "Keyspace not found: {}", name),
+            CaveyError::KeyspaceExists(name) => write!(f, "Keyspace already exists: {}", name),
+            CaveyError::InvalidKeyspace(name) => write!(f, "Invalid keyspace name: {:?}", name),
+            CaveyError::Conflict(key) => write!(f, "Transaction conflict on key {}", key),
+            CaveyError::Corruption(msg) => write!(f, "Corrupt data: {}", msg),
+            CaveyError::WrongEngine(engine) => write!(f, "Wrong data format: {}", engine),
+            CaveyError::Unsupported(msg) => write!(f, "Unsupported operation: {}", msg),
+            CaveyError::Protocol(msg) => write!(f, "Protocol error: {}", msg),
+            CaveyError::TooLarge(msg) => write!(f, "Too large: {}", msg),
+            CaveyError::Unauthenticated => write!(f, "Missing or invalid token"),
+            CaveyError::PermissionDenied(msg) => write!(f, "Permission denied: {}", msg),
+            CaveyError::ReadOnly => write!(f, "Read-only replica"),
+            CaveyError::NotLeader(Some(leader)) => write!(f, "Not the leader; the leader is {}", leader),
+            CaveyError::NotLeader(None) => write!(f, "Not the leader; no leader is elected"),
+            CaveyError::Io(err) => write!(f, "I/O error: {}", err),
+            CaveyError::Timeout => write!(f, "Request timed out"),
+            CaveyError::Internal(msg) => write!(f, "Internal error: {}", msg),
+            CaveyError::Expired(seq) => write!(f, "Events after {} are no longer kept", seq),
+        }
+    }
+}
+
+impl std::error::Error for CaveyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CaveyError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// The wire representation of a `CaveyError` variant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ErrorCode {
+    NotFound,
+    KeyspaceNotFound,
+    KeyspaceExists,
+    InvalidKeyspace,
+    Conflict,
+    Corruption,
+    WrongEngine,
+    Unsupported,
+    Protocol,
+    TooLarge,
+    Unauthenticated,
+    PermissionDenied,
+    Io,
+    Timeout,
+    Internal,
+    ReadOnly,
+    NotLeader,
+    Expired,
+}
+
+impl CaveyError {
+    /// Split the error into a code and a detail string for sending to a
+    /// client.
+    pub(crate) fn to_wire(&self) -> (ErrorCode, String) {
+        match self {
+            CaveyError::NotFound => (ErrorCode::NotFound, String::new()),
+            CaveyError::KeyspaceNotFound(name) => (ErrorCode::KeyspaceNotFound, name.clone()),
+            CaveyError::KeyspaceExists(name) => (ErrorCode::KeyspaceExists, name.clone()),
+            CaveyError::InvalidKeyspace(name) => (ErrorCode::InvalidKeyspace, name.clone()),
+            CaveyError::Conflict(key) => (ErrorCode::Conflict, key.clone()),
+            CaveyError::Corruption(msg) => (ErrorCode::Corruption, msg.clone()),
+            CaveyError::WrongEngine(engine) => (ErrorCode::WrongEngine, engine.clone()),
+            CaveyError::Unsupported(msg) => (ErrorCode::Unsupported, msg.clone()),
+            CaveyError::Protocol(msg) => (ErrorCode::Protocol, msg.clone()),
+            CaveyError::TooLarge(msg) => (ErrorCode::TooLarge, msg.clone()),
+            CaveyError::Unauthenticated => (ErrorCode::Unauthenticated, String::new()),
+            CaveyError::PermissionDenied(msg) => (ErrorCode::PermissionDenied, msg.clone()),
+            CaveyError::ReadOnly => (ErrorCode::ReadOnly, String::new()),
+            CaveyError::NotLeader(leader) => (ErrorCode::NotLeader, leader.clone().unwrap_or_default()),
+            CaveyError::Io(err) => (ErrorCode::Io, err.to_string()),
+            CaveyError::Timeout => (ErrorCode::Timeout, String::new()),
+            CaveyError::Internal(msg) => (ErrorCode::Internal, msg.clone()),
+            CaveyError::Expired(seq) => (ErrorCode::Expired, seq.to_string()),
+        }
+    }
+
+    /// Rebuild an error received from a server.
+    pub(crate) fn from_wire(code: ErrorCode, detail: String) -> CaveyError {
+        match code {
+            ErrorCode::NotFound => CaveyError::NotFound,
+            ErrorCode::KeyspaceNotFound => CaveyError::KeyspaceNotFound(detail),
+            ErrorCode::KeyspaceExists => CaveyError::KeyspaceExists(detail),
+            ErrorCode::InvalidKeyspace => CaveyError::InvalidKeyspace(detail),
+            ErrorCode::Conflict => CaveyError::Conflict(detail),
+            ErrorCode::Corruption => CaveyError::Corruption(detail),
+            ErrorCode::WrongEngine => CaveyError::WrongEngine(detail),
+            ErrorCode::Unsupported => CaveyError::Unsupported(detail),
+            ErrorCode::Protocol => CaveyError::Protocol(detail),
+            ErrorCode::TooLarge => CaveyError::TooLarge(detail),
+            ErrorCode::Unauthenticated => CaveyError::Unauthenticated,
+            ErrorCode::PermissionDenied => CaveyError::PermissionDenied(detail),
+            ErrorCode::ReadOnly => CaveyError::ReadOnly,
+            ErrorCode::NotLeader if detail.is_empty() => CaveyError::NotLeader(None),
+            ErrorCode::NotLeader => CaveyError::NotLeader(Some(detail)),
+            ErrorCode::Io => CaveyError::Io(io::Error::other(detail)),
+            ErrorCode::Timeout => CaveyError::Timeout,
+            ErrorCode::Internal => CaveyError::Internal(detail),
+            ErrorCode::Expired => match detail.parse() {
+                Ok(seq) => CaveyError::Expired(seq),
+                Err(_) => CaveyError::Protocol(format!("bad sequence number {:?}", detail)),
+            },
+        }
+    }
+}
+
+impl From<io::Error> for CaveyError {
+    fn from(err: io::Error) -> CaveyError {
+        CaveyError::Io(err)
+    }
+}
+
+impl From<bincode::Error> for CaveyError {
+    fn from(err: bincode::Error) -> CaveyError {
+        match *err {
+            bincode::ErrorKind::Io(err) => CaveyError::Io(err),
+            bincode::ErrorKind::SizeLimit => CaveyError::TooLarge("message exceeds size limit".to_owned()),
+            other => CaveyError::Protocol(other.to_string()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for CaveyError {
+    fn from(err: serde_json::Error) -> CaveyError {
+        if err.is_io() {
+            CaveyError::Io(err.into())
+        } else {
+            CaveyError::Corruption(err.to_string())
+        }
+    }
+}
+
+impl From<sled::Error> for CaveyError {
+    fn from(err: sled::Error) -> CaveyError {
+        match err {
+            sled::Error::Io(err) => CaveyError::Io(err),
+            sled::Error::Corruption { .. } => CaveyError::Corruption(err.to_string()),
+            sled::Error::Unsupported(msg) => CaveyError::Unsupported(msg),
+            other => CaveyError::Internal(other.to_string()),
+        }
+    }
+}
+
+impl From<FromUtf8Error> for CaveyError {
+    fn from(err: FromUtf8Error) -> CaveyError {
+        CaveyError::Corruption(err.to_string())
+    }
+}
+
+impl From<ParseIntError> for CaveyError {
+    fn from(err: ParseIntError) -> CaveyError {
+        CaveyError::Corruption(err.to_string())
+    }
+}
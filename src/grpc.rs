@@ -0,0 +1,165 @@
+//! A gRPC frontend, for clients generated from `proto/cavey.proto` in any
+//! language.
+
+use std::sync::Arc;
+
+use log::error;
+use tokio::net::TcpListener;
+use tokio::task;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{Code, Request, Response, Status};
+
+use self::proto::cavey_server::{Cavey, CaveyServer};
+use self::proto::*;
+use crate::{in_keyspace, CaveyEngine, CaveyError, Changeset, Result, ServerConfig, SharedEngine};
+use crate::utils::lock;
+
+/// The messages, client and server generated from `proto/cavey.proto`.
+pub mod proto {
+    tonic::include_proto!("cavey.v1");
+}
+
+/// Entries returned per scan unless the request asks for fewer.
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// Serve gRPC clients on `listener`, applying their requests to `engine`,
+/// which other frontends may share.  Like `serve_async`, engine calls run on
+/// the runtime's blocking pool.
+pub async fn serve_grpc(listener: TcpListener, engine: SharedEngine, config: ServerConfig) -> Result<()> {
+    let max_message_size = config.max_frame_size as usize;
+    let service = Service {
+        engine,
+        config: Arc::new(config),
+    };
+    tonic::transport::Server::builder()
+        .add_service(
+            CaveyServer::new(service)
+                .max_decoding_message_size(max_message_size)
+                .max_encoding_message_size(max_message_size),
+        )
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await
+        .map_err(|err| CaveyError::Internal(err.to_string()))
+}
+
+struct Service {
+    engine: SharedEngine,
+    config: Arc<ServerConfig>,
+}
+
+impl Service {
+    /// Run `f` against the engine serving `keyspace`.
+    async fn call<T, F>(&self, keyspace: String, f: F) -> std::result::Result<Response<T>, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut dyn CaveyEngine) -> Result<T> + Send + 'static,
+    {
+        let engine = self.engine.clone();
+        let result = task::spawn_blocking(move || {
+            let mut engine = lock(&engine);
+            let keyspace = if keyspace.is_empty() { None } else { Some(&keyspace[..]) };
+            f(in_keyspace(&mut **engine, keyspace)?)
+        })
+        .await
+        .map_err(|err| CaveyError::Internal(err.to_string()))
+        .and_then(|result| result);
+        result.map(Response::new).map_err(status_of)
+    }
+}
+
+fn status_of(err: CaveyError) -> Status {
+    let code = match err {
+        CaveyError::NotFound | CaveyError::KeyspaceNotFound(_) => Code::NotFound,
+        CaveyError::KeyspaceExists(_) => Code::AlreadyExists,
+        CaveyError::Conflict(_) => Code::Aborted,
+        CaveyError::InvalidKeyspace(_) | CaveyError::Protocol(_) => Code::InvalidArgument,
+        CaveyError::TooLarge(_) => Code::ResourceExhausted,
+        CaveyError::Unauthenticated => Code::Unauthenticated,
+        CaveyError::PermissionDenied(_) => Code::PermissionDenied,
+        CaveyError::ReadOnly => Code::FailedPrecondition,
+        CaveyError::NotLeader(_) => Code::Unavailable,
+        CaveyError::Expired(_) => Code::OutOfRange,
+        CaveyError::Unsupported(_) => Code::Unimplemented,
+        CaveyError::Timeout => Code::DeadlineExceeded,
+        CaveyError::Corruption(_) | CaveyError::WrongEngine(_) | CaveyError::Io(_) | CaveyError::Internal(_) => {
+            error!("grpc request failed: {}", err);
+            Code::Internal
+        }
+    };
+    Status::new(code, err.to_string())
+}
+
+#[tonic::async_trait]
+impl Cavey for Service {
+    async fn get(&self, request: Request<GetRequest>) -> std::result::Result<Response<GetResponse>, Status> {
+        let GetRequest { keyspace, key } = request.into_inner();
+        self.config.check_key(&key).map_err(status_of)?;
+        self.call(keyspace, move |engine| Ok(GetResponse { value: engine.get(key)? }))
+            .await
+    }
+
+    async fn put(&self, request: Request<PutRequest>) -> std::result::Result<Response<PutResponse>, Status> {
+        let PutRequest { keyspace, key, value } = request.into_inner();
+        self.config.check_key(&key).map_err(status_of)?;
+        self.config.check_value(&value).map_err(status_of)?;
+        self.call(keyspace, move |engine| {
+            engine.put(key, value)?;
+            Ok(PutResponse {})
+        })
+        .await
+    }
+
+    async fn remove(&self, request: Request<RemoveRequest>) -> std::result::Result<Response<RemoveResponse>, Status> {
+        let RemoveRequest { keyspace, key } = request.into_inner();
+        self.config.check_key(&key).map_err(status_of)?;
+        self.call(keyspace, move |engine| {
+            Ok(RemoveResponse {
+                removed: engine.remove(key)?,
+            })
+        })
+        .await
+    }
+
+    async fn scan(&self, request: Request<ScanRequest>) -> std::result::Result<Response<ScanResponse>, Status> {
+        let ScanRequest {
+            keyspace,
+            prefix,
+            after,
+            limit,
+        } = request.into_inner();
+        let limit = match limit as usize {
+            0 => MAX_PAGE_SIZE,
+            limit => limit.min(MAX_PAGE_SIZE),
+        };
+        self.call(keyspace, move |engine| {
+            let entries = engine.scan(&prefix, after.as_deref(), limit)?;
+            let next = if entries.len() == limit {
+                entries.last().map(|(key, _)| key.clone())
+            } else {
+                None
+            };
+            Ok(ScanResponse {
+                entries: entries.into_iter().map(|(key, value)| Entry { key, value }).collect(),
+                next,
+            })
+        })
+        .await
+    }
+
+    async fn batch(&self, request: Request<BatchRequest>) -> std::result::Result<Response<BatchResponse>, Status> {
+        let BatchRequest { keyspace, writes } = request.into_inner();
+        let mut changeset = Changeset::default();
+        for Write { key, value } in writes {
+            self.config.check_key(&key).map_err(status_of)?;
+            if let Some(value) = &value {
+                self.config.check_value(value).map_err(status_of)?;
+            }
+            changeset.writes.insert(key, value);
+        }
+        self.call(keyspace, move |engine| {
+            engine.apply_changeset(changeset)?;
+            Ok(BatchResponse {})
+        })
+        .await
+    }
+}
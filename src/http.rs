@@ -0,0 +1,412 @@
+//! An HTTP frontend with a JSON API, for clients that can't link a Rust
+//! client:
+//!
+//! - `GET /v1/kv/{key}` returns `{"key": ..., "value": ...}`.
+//! - `PUT /v1/kv/{key}` sets the key to the `value` of a JSON body.
+//! - `DELETE /v1/kv/{key}` removes the key.
+//! - `GET /v1/kv?prefix=...&after=...&limit=...` lists keys with a prefix, a
+//!   page at a time, with the key to pass as `after` for the next page.
+//!
+//! Every endpoint takes an optional `keyspace` query parameter.  Errors are
+//! returned as `{"error": {"code": ..., "message": ...}}`, where engine
+//! errors have the same codes as in the native protocol.
+//!
+//! Only the parts of HTTP/1.1 that curl and common clients need are
+//! supported: request bodies must have a `Content-Length`.
+
+use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::net::{TcpListener, TcpStream};
+use std::ops::DerefMut;
+use std::sync::Mutex;
+use std::thread;
+
+use log::{debug, error, trace};
+use serde::{Deserialize, Serialize};
+
+use crate::error::ErrorCode;
+use crate::{in_keyspace, CaveyEngine, CaveyError, Result, ServerConfig};
+use crate::utils::lock;
+
+/// The longest request line and headers accepted.
+const MAX_HEADER_SIZE: usize = 64 << 10;
+
+/// Keys listed per page unless the request asks for fewer.
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// Serve HTTP clients on `socket`, with a thread for each connection,
+/// applying their requests to `engine`.  The engine may be shared with the
+/// native protocol's server.
+pub fn serve_http<'e, E>(socket: &TcpListener, engine: &Mutex<E>, config: &ServerConfig) -> Result<()>
+where
+    E: DerefMut<Target = dyn CaveyEngine + 'e> + Send,
+{
+    thread::scope(|scope| {
+        for stream in socket.incoming() {
+            match stream {
+                Ok(stream) => {
+                    trace!("http connection accepted");
+                    scope.spawn(move || {
+                        if let Err(err) = handle_connection(stream, engine, config) {
+                            error!("http connection failed: {}", err);
+                        }
+                    });
+                }
+                Err(err) => {
+                    error!("http connection failed: {}", err);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    body: Vec<u8>,
+    keep_alive: bool,
+}
+
+impl Request {
+    fn param(&self, name: &str) -> Option<&str> {
+        self.query.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+}
+
+struct Response {
+    status: u16,
+    // JSON, or `None` for an empty body.
+    body: Option<String>,
+}
+
+impl Response {
+    fn json<T: Serialize>(status: u16, body: &T) -> Response {
+        match serde_json::to_string(body) {
+            Ok(body) => Response { status, body: Some(body) },
+            Err(err) => Response::error(500, Code::Engine(ErrorCode::Internal), &err.to_string()),
+        }
+    }
+
+    fn no_content() -> Response {
+        Response { status: 204, body: None }
+    }
+
+    fn error(status: u16, code: Code, message: &str) -> Response {
+        Response::json(status, &ErrorBody { error: ErrorDetail { code, message } })
+    }
+}
+
+impl From<CaveyError> for Response {
+    fn from(err: CaveyError) -> Response {
+        let (code, _) = err.to_wire();
+        Response::error(status_of(&err), Code::Engine(code), &err.to_string())
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: ErrorDetail<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorDetail<'a> {
+    code: Code,
+    message: &'a str,
+}
+
+/// An error code: an engine error's wire code, or one for a request that
+/// never reached the engine.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Code {
+    Engine(ErrorCode),
+    Http(&'static str),
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    key: &'a str,
+    value: &'a str,
+}
+
+#[derive(Deserialize)]
+struct PutBody {
+    value: String,
+}
+
+#[derive(Serialize)]
+struct Page<'a> {
+    items: Vec<Entry<'a>>,
+    /// Pass as `after` to get the next page, or `None` on the last page.
+    next: Option<&'a str>,
+}
+
+fn status_of(err: &CaveyError) -> u16 {
+    match err {
+        CaveyError::NotFound | CaveyError::KeyspaceNotFound(_) => 404,
+        CaveyError::KeyspaceExists(_) | CaveyError::Conflict(_) => 409,
+        CaveyError::InvalidKeyspace(_) | CaveyError::Protocol(_) => 400,
+        CaveyError::TooLarge(_) => 413,
+        CaveyError::Unauthenticated => 401,
+        CaveyError::PermissionDenied(_) | CaveyError::ReadOnly => 403,
+        CaveyError::Unsupported(_) => 501,
+        CaveyError::NotLeader(_) => 503,
+        CaveyError::Expired(_) => 410,
+        CaveyError::Timeout => 504,
+        CaveyError::Corruption(_) | CaveyError::WrongEngine(_) | CaveyError::Io(_) | CaveyError::Internal(_) => 500,
+    }
+}
+
+fn bad_request(message: &str) -> Response {
+    Response::error(400, Code::Http("bad_request"), message)
+}
+
+/// Serve requests from one client until it closes the connection.
+fn handle_connection<'e, E>(stream: TcpStream, engine: &Mutex<E>, config: &ServerConfig) -> Result<()>
+where
+    E: DerefMut<Target = dyn CaveyEngine + 'e>,
+{
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let request = match read_request(&mut reader, &mut writer, config.max_frame_size as usize) {
+            Ok(Some(request)) => request,
+            Ok(None) => {
+                trace!("http connection closed");
+                return Ok(());
+            }
+            // The rest of the stream can't be trusted after a bad request.
+            Err(response) => {
+                write_response(&mut writer, &response, false)?;
+                return Ok(());
+            }
+        };
+        debug!("http: {} {}", request.method, request.path);
+        let response = route(&request, &mut **lock(engine), config);
+        write_response(&mut writer, &response, request.keep_alive)?;
+        if !request.keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+fn route(request: &Request, engine: &mut dyn CaveyEngine, config: &ServerConfig) -> Response {
+    let engine = match in_keyspace(engine, request.param("keyspace")) {
+        Ok(engine) => engine,
+        Err(err) => return err.into(),
+    };
+    if request.path == "/v1/kv" {
+        return match request.method.as_str() {
+            "GET" => list(request, engine),
+            _ => Response::error(405, Code::Http("method_not_allowed"), "method not allowed"),
+        };
+    }
+    let key = match request.path.strip_prefix("/v1/kv/") {
+        Some(key) if !key.is_empty() => match percent_decode(key, false) {
+            Some(key) => key,
+            None => return bad_request("invalid percent-encoding in key"),
+        },
+        _ => return Response::error(404, Code::Http("no_such_route"), "no such route"),
+    };
+    let result = match request.method.as_str() {
+        "GET" => engine.get(key.clone()).and_then(|value| match value {
+            Some(value) => Ok(Response::json(200, &Entry { key: &key, value: &value })),
+            None => Err(CaveyError::NotFound),
+        }),
+        "PUT" => {
+            let body: PutBody = match serde_json::from_slice(&request.body) {
+                Ok(body) => body,
+                Err(err) => return bad_request(&format!("expected a body like {{\"value\": \"...\"}}: {}", err)),
+            };
+            config
+                .check_key(&key)
+                .and_then(|()| config.check_value(&body.value))
+                .and_then(|()| engine.put(key, body.value))
+                .map(|()| Response::no_content())
+        }
+        "DELETE" => engine.remove(key).and_then(|removed| match removed {
+            true => Ok(Response::no_content()),
+            false => Err(CaveyError::NotFound),
+        }),
+        _ => return Response::error(405, Code::Http("method_not_allowed"), "method not allowed"),
+    };
+    result.unwrap_or_else(Response::from)
+}
+
+/// GET /v1/kv?prefix=...&after=...&limit=...
+fn list(request: &Request, engine: &mut dyn CaveyEngine) -> Response {
+    let limit = match request.param("limit").map(str::parse::<usize>) {
+        None => MAX_PAGE_SIZE,
+        Some(Ok(limit)) if limit > 0 => limit.min(MAX_PAGE_SIZE),
+        Some(_) => return bad_request("limit must be a positive integer"),
+    };
+    let prefix = request.param("prefix").unwrap_or("");
+    let entries = match engine.scan(prefix, request.param("after"), limit) {
+        Ok(entries) => entries,
+        Err(err) => return err.into(),
+    };
+    let next = match entries.last() {
+        Some((last, _)) if entries.len() == limit => Some(last.as_str()),
+        _ => None,
+    };
+    let items = entries.iter().map(|(key, value)| Entry { key, value }).collect();
+    Response::json(200, &Page { items, next })
+}
+
+/// Read a request, or `None` if the client closed the connection between
+/// requests.  A malformed request gets an error response to send before
+/// closing the connection.
+fn read_request<R: BufRead, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    max_body_size: usize,
+) -> std::result::Result<Option<Request>, Response> {
+    let mut remaining = MAX_HEADER_SIZE;
+    let request_line = match read_line(reader, &mut remaining)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) if version.starts_with("HTTP/1.") => {
+            (method.to_owned(), target, version)
+        }
+        _ => return Err(bad_request("malformed request line")),
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_owned(), parse_query(query).ok_or_else(|| bad_request("malformed query"))?),
+        None => (target.to_owned(), Vec::new()),
+    };
+
+    let mut keep_alive = version != "HTTP/1.0";
+    let mut content_length = 0;
+    let mut expect_continue = false;
+    loop {
+        let line = read_line(reader, &mut remaining)?.ok_or_else(|| bad_request("truncated headers"))?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| bad_request("malformed header"))?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = value.parse().map_err(|_| bad_request("invalid Content-Length"))?;
+            }
+            "transfer-encoding" => {
+                return Err(Response::error(
+                    411,
+                    Code::Http("length_required"),
+                    "request bodies must have a Content-Length",
+                ));
+            }
+            "connection" => keep_alive = !value.eq_ignore_ascii_case("close"),
+            "expect" => expect_continue = value.eq_ignore_ascii_case("100-continue"),
+            _ => {}
+        }
+    }
+
+    if content_length > max_body_size {
+        let message = format!("body of {} bytes exceeds limit of {} bytes", content_length, max_body_size);
+        return Err(Response::error(413, Code::Engine(ErrorCode::TooLarge), &message));
+    }
+    if expect_continue && content_length > 0 {
+        writer
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .and_then(|()| writer.flush())
+            .map_err(|_| bad_request("connection failed"))?;
+    }
+    // Grow the body as it arrives, rather than trusting the length.
+    let mut body = Vec::new();
+    reader.take(content_length as u64).read_to_end(&mut body).map_err(|_| bad_request("truncated body"))?;
+    if body.len() < content_length {
+        return Err(bad_request("truncated body"));
+    }
+    Ok(Some(Request { method, path, query, body, keep_alive }))
+}
+
+/// Read a line of the request head, without its line ending.
+fn read_line<R: BufRead>(reader: &mut R, remaining: &mut usize) -> std::result::Result<Option<String>, Response> {
+    let mut line = Vec::new();
+    if reader.take(*remaining as u64).read_until(b'\n', &mut line).is_err() || line.is_empty() {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(if line.len() == *remaining {
+            Response::error(431, Code::Http("headers_too_large"), "request headers too large")
+        } else {
+            bad_request("truncated request")
+        });
+    }
+    *remaining -= line.len();
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    String::from_utf8(line).map(Some).map_err(|_| bad_request("request head is not valid UTF-8"))
+}
+
+fn parse_query(query: &str) -> Option<Vec<(String, String)>> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Some((percent_decode(name, true)?, percent_decode(value, true)?))
+        })
+        .collect()
+}
+
+/// Decode `%XX` escapes, and in query strings `+` as a space.  `None` if
+/// the escapes are malformed or decode to invalid UTF-8.
+fn percent_decode(s: &str, plus_as_space: bool) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut input = s.bytes();
+    while let Some(b) = input.next() {
+        match b {
+            b'%' => {
+                let hex = [input.next()?, input.next()?];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b'+' if plus_as_space => bytes.push(b' '),
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn write_response<W: Write>(writer: &mut W, response: &Response, keep_alive: bool) -> io::Result<()> {
+    let body = response.body.as_deref().unwrap_or("");
+    write!(writer, "HTTP/1.1 {} {}\r\n", response.status, reason(response.status))?;
+    if response.body.is_some() {
+        writer.write_all(b"Content-Type: application/json\r\n")?;
+    }
+    write!(writer, "Content-Length: {}\r\n", body.len())?;
+    if !keep_alive {
+        writer.write_all(b"Connection: close\r\n")?;
+    }
+    writer.write_all(b"\r\n")?;
+    writer.write_all(body.as_bytes())?;
+    writer.flush()
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Internal Server Error",
+    }
+}
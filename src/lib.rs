@@ -1,23 +1,154 @@
-use failure::Error;
+use std::path::Path;
+use std::sync::Arc;
 
-pub use client::CaveyClient;
+pub use acl::Acl;
+pub use admin::{inspect, read_segment, repair, Problem, SegmentFormat, SegmentRecord, SegmentReport};
+pub use async_client::AsyncCaveyClient;
+pub use client::{CaveyClient, ClientConfig, RetryPolicy};
+pub use dump::{dump, load, DumpFormat};
+pub use error::CaveyError;
+pub use pool::{CaveyPool, PoolConfig};
 pub use sled_store::SledStore;
-pub use store::CaveyStore;
-pub use server::run_server;
+pub use store::{CaveyStore, LogRecord};
+pub use async_server::{run_async_server, run_async_server_with_config, serve_async, SharedEngine};
+pub use grpc::{proto, serve_grpc};
+pub use migrate::{migrate, open_engine};
+pub use http::serve_http;
+pub use replication::{serve_replication, Position, Primary, ReadOnly, Replica, ReplicationLog};
+pub use raft::{ClusterStatus, Member, RaftConfig, RaftEngine, RaftNode};
+pub use resp::serve_resp;
+pub use server::{run_server, run_server_with_config, serve, ServerConfig};
+pub use shard::ShardedClient;
+pub use tls::{client_tls_config, server_tls_config, TlsAddr, TlsListener};
+pub use transaction::{Changeset, Transaction, TransactionalStore, Version};
+pub use transport::{AsyncListener, Listener, Transport, UnixAddr};
+pub use watch::{ChangeFeed, Event, Watch};
 
+mod acl;
+mod admin;
+mod backup;
+mod async_client;
+mod async_server;
 mod client;
+mod dump;
+mod error;
+mod grpc;
+mod http;
+mod migrate;
 mod store;
 mod server;
+mod shard;
+mod pool;
 mod protocol;
+mod raft;
+mod replication;
+mod resp;
 mod sled_store;
 mod sstable;
+mod tls;
+mod transaction;
+mod transport;
 mod utils;
+mod watch;
 
-pub type Result<T> = std::result::Result<T, Error>;
+pub type Result<T> = std::result::Result<T, CaveyError>;
 
-pub trait CaveyEngine {
+pub trait CaveyEngine: Send {
     fn get(&mut self, key: String) -> Result<Option<String>>;
     fn put(&mut self, key: String, value: String) -> Result<()>;
-    fn remove(&mut self, key: String) -> Result<()>;
+    /// Remove `key`, returning whether it was present.
+    fn remove(&mut self, key: String) -> Result<bool>;
 
+    /// Set `key` to `value`, returning the value it replaced.
+    fn put_returning_old(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let old = self.get(key.clone())?;
+        self.put(key, value)?;
+        Ok(old)
+    }
+
+    /// Remove `key`, returning the value it held.
+    fn remove_returning_old(&mut self, key: String) -> Result<Option<String>> {
+        let old = self.get(key.clone())?;
+        if old.is_some() {
+            self.remove(key)?;
+        }
+        Ok(old)
+    }
+
+    /// The version of the latest write to `key`, or `None` if it is absent.
+    fn version(&mut self, key: &str) -> Result<Option<Version>>;
+
+    /// Up to `limit` keys starting with `prefix`, with their values, in key
+    /// order.  Only keys ordered after `after` are returned, so passing the
+    /// last key of one page gets the next.
+    fn scan(&mut self, prefix: &str, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>>;
+
+    /// Commit a transaction: fail if any key in the read set has changed
+    /// version, otherwise apply all writes.  Both engines apply the writes
+    /// atomically; the default applies them one at a time.
+    fn apply_changeset(&mut self, changeset: Changeset) -> Result<()> {
+        transaction::apply(self, changeset)
+    }
+
+    /// Get the engine serving the named keyspace.
+    fn keyspace(&mut self, name: &str) -> Result<&mut dyn CaveyEngine> {
+        Err(CaveyError::KeyspaceNotFound(name.to_owned()))
+    }
+
+    fn create_keyspace(&mut self, _name: &str) -> Result<()> {
+        Err(CaveyError::Unsupported("nested keyspaces".to_owned()))
+    }
+
+    fn drop_keyspace(&mut self, _name: &str) -> Result<()> {
+        Err(CaveyError::Unsupported("nested keyspaces".to_owned()))
+    }
+
+    fn list_keyspaces(&mut self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    /// The status of the Raft cluster the engine is replicated through.
+    fn cluster_status(&mut self) -> Result<ClusterStatus> {
+        Err(CaveyError::Unsupported("clustering".to_owned()))
+    }
+
+    /// Add a node to the cluster.  Only the leader can.
+    fn add_member(&mut self, _member: Member) -> Result<()> {
+        Err(CaveyError::Unsupported("clustering".to_owned()))
+    }
+
+    /// Remove the node with the given id from the cluster.  Only the leader
+    /// can.
+    fn remove_member(&mut self, _id: &str) -> Result<()> {
+        Err(CaveyError::Unsupported("clustering".to_owned()))
+    }
+
+    /// Write a consistent copy of the engine's data, keyspaces included, to
+    /// the directory `dest`, which must be empty or not exist.
+    fn backup(&mut self, _dest: &Path) -> Result<()> {
+        Err(CaveyError::Unsupported("backup".to_owned()))
+    }
+
+    /// Replace the engine's keys and keyspaces with those in the backup in
+    /// the directory `src`.
+    fn restore(&mut self, src: &Path) -> Result<()> {
+        backup::restore(self, src)
+    }
+
+    /// The feed of puts and removes made to the engine and its keyspaces.
+    fn change_feed(&mut self) -> Result<Arc<ChangeFeed>> {
+        Err(CaveyError::Unsupported("watch".to_owned()))
+    }
+}
+
+/// Get the engine serving `keyspace`, or `engine` itself for the default
+/// keyspace.
+pub(crate) fn in_keyspace<'a>(
+    engine: &'a mut dyn CaveyEngine,
+    keyspace: Option<&str>,
+) -> Result<&'a mut dyn CaveyEngine> {
+    match keyspace {
+        Some(name) => engine.keyspace(name),
+        None => Ok(engine),
+    }
 }
@@ -0,0 +1,133 @@
+//! Converting a data directory from one engine to the other, since neither
+//! can open the other's directories.
+//!
+//! The data is copied into a staging directory next to the destination,
+//! checked against the source, and only then renamed into place, so the
+//! destination, and the engine marker in it, only ever hold a complete copy.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+use crate::{in_keyspace, CaveyEngine, CaveyError, CaveyStore, Result, SledStore};
+
+/// Entries read from the source at a time.
+const MIGRATE_PAGE: usize = 1000;
+
+/// How often, in entries copied, progress is reported.
+const PROGRESS_INTERVAL: u64 = 10_000;
+
+/// Open the engine named `name`, `kvs` or `sled`, on `path`.
+pub fn open_engine(name: &str, path: &Path) -> Result<Box<dyn CaveyEngine>> {
+    match name {
+        "kvs" => Ok(Box::new(CaveyStore::open(path)?)),
+        "sled" => Ok(Box::new(SledStore::open(path)?)),
+        _ => Err(CaveyError::Unsupported(format!("engine {:?}", name))),
+    }
+}
+
+/// Copy the data in `src`, served by the engine named `from`, to the new
+/// directory `dest` for the engine named `to`, returning how many entries
+/// were copied.  `progress` is told the count every so often.
+///
+/// `src` is left as it was.  Stop the server using it first: the engines
+/// don't guard against being opened twice.
+pub fn migrate<F: FnMut(u64)>(src: &Path, from: &str, dest: &Path, to: &str, progress: F) -> Result<u64> {
+    if !src.join("data").join(".engine").exists() {
+        let msg = format!("no data in {}", src.display());
+        return Err(io::Error::new(io::ErrorKind::NotFound, msg).into());
+    }
+    if dest.exists() {
+        let msg = format!("{} already exists", dest.display());
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg).into());
+    }
+    let staging = staging_path(dest);
+    if staging.exists() {
+        let msg = format!("{} is left from an interrupted migration; remove it first", staging.display());
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg).into());
+    }
+    info!("migrating {} from {} to {} in {}", src.display(), from, to, dest.display());
+    let mut source = open_engine(from, src)?;
+    let result = open_engine(to, &staging).and_then(|mut target| {
+        let copied = copy(&mut *source, &mut *target, progress)?;
+        info!("copied {} entries, verifying", copied);
+        verify(&mut *source, &mut *target)?;
+        Ok(copied)
+    });
+    match result {
+        Ok(copied) => {
+            fs::rename(&staging, dest)?;
+            info!("migrated {} entries to {}", copied, dest.display());
+            Ok(copied)
+        }
+        Err(err) => {
+            fs::remove_dir_all(&staging).ok();
+            Err(err)
+        }
+    }
+}
+
+fn staging_path(dest: &Path) -> PathBuf {
+    let mut name = dest.file_name().unwrap_or_default().to_owned();
+    name.push(".migrating");
+    dest.with_file_name(name)
+}
+
+fn keyspaces(engine: &mut dyn CaveyEngine) -> Result<Vec<Option<String>>> {
+    let mut keyspaces = vec![None];
+    keyspaces.extend(engine.list_keyspaces()?.into_iter().map(Some));
+    Ok(keyspaces)
+}
+
+fn copy<F: FnMut(u64)>(source: &mut dyn CaveyEngine, target: &mut dyn CaveyEngine, mut progress: F) -> Result<u64> {
+    let mut copied = 0;
+    for keyspace in keyspaces(source)? {
+        if let Some(name) = &keyspace {
+            info!("copying keyspace {}", name);
+            target.create_keyspace(name)?;
+        }
+        let mut after = None;
+        loop {
+            let page = in_keyspace(source, keyspace.as_deref())?.scan("", after.as_deref(), MIGRATE_PAGE)?;
+            if page.is_empty() {
+                break;
+            }
+            after = page.last().map(|(key, _)| key.clone());
+            let target = in_keyspace(target, keyspace.as_deref())?;
+            for (key, value) in page {
+                target.put(key, value)?;
+                copied += 1;
+                if copied % PROGRESS_INTERVAL == 0 {
+                    progress(copied);
+                }
+            }
+        }
+    }
+    Ok(copied)
+}
+
+/// Check that `target` holds exactly the keyspaces and entries in `source`.
+fn verify(source: &mut dyn CaveyEngine, target: &mut dyn CaveyEngine) -> Result<()> {
+    let names = keyspaces(source)?;
+    if names != keyspaces(target)? {
+        return Err(CaveyError::Corruption("keyspaces differ after copying".to_owned()));
+    }
+    for keyspace in names {
+        let mut after = None;
+        loop {
+            let expected = in_keyspace(source, keyspace.as_deref())?.scan("", after.as_deref(), MIGRATE_PAGE)?;
+            let actual = in_keyspace(target, keyspace.as_deref())?.scan("", after.as_deref(), MIGRATE_PAGE)?;
+            if expected != actual {
+                let name = keyspace.as_deref().unwrap_or("default");
+                return Err(CaveyError::Corruption(format!("keyspace {} differs after copying", name)));
+            }
+            after = match expected.last() {
+                Some((key, _)) => Some(key.clone()),
+                None => break,
+            };
+        }
+    }
+    Ok(())
+}
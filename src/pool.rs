@@ -0,0 +1,190 @@
+use std::net::SocketAddr;
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::utils::lock;
+use crate::{CaveyClient, CaveyError, ClientConfig, Result};
+
+/// Settings for a `CaveyPool`.
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    /// Connections opened up front, and kept open however long they idle.
+    pub min_connections: usize,
+    /// Callers wait for a free connection once this many are open.
+    pub max_connections: usize,
+    /// Connections beyond `min_connections` idle for longer are closed.
+    pub idle_timeout: Duration,
+    /// Connections idle for longer are pinged before being handed out.
+    pub health_check_interval: Duration,
+    /// Keyspace for requests made through the pool.
+    pub keyspace: Option<String>,
+    /// Settings for each connection in the pool.
+    pub client: ClientConfig,
+}
+
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig {
+            min_connections: 1,
+            max_connections: 8,
+            idle_timeout: Duration::from_secs(300),
+            health_check_interval: Duration::from_secs(30),
+            keyspace: None,
+            client: ClientConfig::default(),
+        }
+    }
+}
+
+/// A pool of connections to one server, which can be shared between threads.
+///
+/// Each request checks out a connection for its duration.  Connections that
+/// fail with an I/O error or time out are discarded, and `get` and `put` are
+/// retried once on a fresh connection, so the pool recovers from a server
+/// restart.
+pub struct CaveyPool {
+    addr: SocketAddr,
+    config: PoolConfig,
+    state: Mutex<PoolState>,
+    released: Condvar,
+}
+
+struct PoolState {
+    // Idle connections with the time they were returned, most recent last.
+    idle: Vec<(CaveyClient, Instant)>,
+    // Connections open, whether idle or checked out.
+    open: usize,
+}
+
+impl CaveyPool {
+    pub fn new<S: Into<SocketAddr>>(sockaddr: S, config: PoolConfig) -> Result<CaveyPool> {
+        let pool = CaveyPool {
+            addr: sockaddr.into(),
+            state: Mutex::new(PoolState {
+                idle: Vec::with_capacity(config.max_connections),
+                open: 0,
+            }),
+            released: Condvar::new(),
+            config,
+        };
+        for _ in 0..pool.config.min_connections {
+            let client = pool.connect()?;
+            let mut state = lock(&pool.state);
+            state.open += 1;
+            state.idle.push((client, Instant::now()));
+        }
+        Ok(pool)
+    }
+
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.with_connection(true, |client| client.get(key.clone()))
+    }
+
+    pub fn put(&self, key: String, value: String) -> Result<()> {
+        self.with_connection(true, |client| client.put(key.clone(), value.clone()))
+    }
+
+    /// Remove `key`, returning whether it was present.
+    pub fn remove(&self, key: String) -> Result<bool> {
+        self.with_connection(false, |client| client.remove(key.clone()))
+    }
+
+    /// The number of connections currently open.
+    pub fn connections(&self) -> usize {
+        lock(&self.state).open
+    }
+
+    fn with_connection<T, F>(&self, retry: bool, op: F) -> Result<T>
+    where
+        F: Fn(&mut CaveyClient) -> Result<T>,
+    {
+        let mut client = self.checkout()?;
+        match op(&mut client) {
+            Err(err @ CaveyError::Io(_)) | Err(err @ CaveyError::Timeout) => {
+                debug!("discarding broken connection: {}", err);
+                self.discard_broken();
+                if !retry {
+                    return Err(err);
+                }
+                let mut client = self.checkout()?;
+                let result = op(&mut client);
+                self.release(client, &result);
+                result
+            }
+            result => {
+                self.release(client, &result);
+                result
+            }
+        }
+    }
+
+    fn checkout(&self) -> Result<CaveyClient> {
+        let mut state = lock(&self.state);
+        loop {
+            self.close_expired(&mut state);
+            if let Some((mut client, since)) = state.idle.pop() {
+                if since.elapsed() < self.config.health_check_interval {
+                    return Ok(client);
+                }
+                // Don't hold the lock across a round trip to the server.
+                drop(state);
+                if client.ping().is_ok() {
+                    return Ok(client);
+                }
+                debug!("discarding connection that failed health check");
+                self.discard();
+                state = lock(&self.state);
+            } else if state.open < self.config.max_connections {
+                state.open += 1;
+                drop(state);
+                return self.connect().inspect_err(|_| self.discard());
+            } else {
+                state = self.released.wait(state).unwrap_or_else(PoisonError::into_inner);
+            }
+        }
+    }
+
+    fn release<T>(&self, client: CaveyClient, result: &Result<T>) {
+        if let Err(CaveyError::Io(_)) | Err(CaveyError::Timeout) = result {
+            self.discard_broken();
+        } else {
+            lock(&self.state).idle.push((client, Instant::now()));
+            self.released.notify_one();
+        }
+    }
+
+    /// Forget a connection that was checked out, making room for a new one.
+    fn discard(&self) {
+        lock(&self.state).open -= 1;
+        self.released.notify_one();
+    }
+
+    /// Close a broken connection.  That usually means the server went away,
+    /// so close the idle ones too, rather than find out one at a time.
+    fn discard_broken(&self) {
+        let mut state = lock(&self.state);
+        state.open -= 1 + state.idle.len();
+        state.idle.clear();
+        self.released.notify_all();
+    }
+
+    fn close_expired(&self, state: &mut MutexGuard<PoolState>) {
+        while state.open > self.config.min_connections {
+            // The least recently used connection is at the front.
+            match state.idle.first() {
+                Some((_, since)) if since.elapsed() >= self.config.idle_timeout => {
+                    state.idle.remove(0);
+                    state.open -= 1;
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn connect(&self) -> Result<CaveyClient> {
+        let mut client = CaveyClient::with_config(self.addr, self.config.client.clone())?;
+        client.set_keyspace(self.config.keyspace.clone());
+        Ok(client)
+    }
+}
@@ -1,17 +1,308 @@
-use serde::{Deserialize, Serialize};
+//! The client/server wire protocol.
+//!
+//! A connection opens with each side sending a `Handshake`, client first.
+//! After that, every message is a frame: a big-endian `u32` length followed
+//! by that many bytes of bincode-encoded `ClientMessage` or `ServerMessage`.
+//! A server with an ACL expects the client's first frame to be
+//! `Authenticate`.
 
+use std::fmt;
+use std::io::{self, prelude::*};
 
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{CaveyError, Changeset, ClusterStatus, Event, Member, Result, Version};
+use crate::error::ErrorCode;
+
+/// Requests from a client.  `keyspace` selects a named keyspace, or the
+/// default keyspace if `None`.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all="snake_case")]
 pub(crate) enum ClientMessage {
-    Get { key: String },
-    Put { key: String, value: String },
-    Remove { key: String },
+    Get { keyspace: Option<String>, key: String },
+    Put { keyspace: Option<String>, key: String, value: String },
+    Remove { keyspace: Option<String>, key: String },
+    PutReturningOld { keyspace: Option<String>, key: String, value: String },
+    RemoveReturningOld { keyspace: Option<String>, key: String },
+    GetVersioned { keyspace: Option<String>, key: String },
+    Commit { keyspace: Option<String>, changeset: Changeset },
+    CreateKeyspace { keyspace: String },
+    DropKeyspace { keyspace: String },
+    ListKeyspaces,
+    Ping,
+    /// Sent first on a connection to a server with an ACL.
+    Authenticate { token: Token },
+    Scan { keyspace: Option<String>, prefix: String, after: Option<String>, limit: u32 },
+    ClusterStatus,
+    AddMember { member: Member },
+    RemoveMember { id: String },
+    /// Stream events for keys starting with `prefix`, after the event
+    /// numbered `after`, or from now if `None`.  The server answers with
+    /// `Events` frames until the connection closes.
+    Watch { keyspace: Option<String>, prefix: String, after: Option<u64> },
+    /// Back up the engine to, or restore it from, a directory on the
+    /// server's machine.
+    Backup { dest: String },
+    Restore { src: String },
+}
+
+/// A secret, kept out of logs.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(transparent)]
+pub(crate) struct Token(pub String);
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Token(..)")
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all="snake_case")]
 pub(crate) enum ServerMessage {
     Success { value: Option<String> },
-    Error { err: String },
-}
\ No newline at end of file
+    Versioned { value: Option<String>, version: Option<Version> },
+    Removed { removed: bool },
+    Keyspaces { names: Vec<String> },
+    Entries { entries: Vec<(String, String)> },
+    Error { code: ErrorCode, detail: String },
+    Cluster { status: ClusterStatus },
+    /// Events for a watch, and the sequence number to resume after.
+    Events { events: Vec<Event>, seq: u64 },
+}
+
+impl From<&CaveyError> for ServerMessage {
+    fn from(err: &CaveyError) -> ServerMessage {
+        let (code, detail) = err.to_wire();
+        ServerMessage::Error { code, detail }
+    }
+}
+
+impl From<CaveyError> for ServerMessage {
+    fn from(err: CaveyError) -> ServerMessage {
+        ServerMessage::from(&err)
+    }
+}
+
+/// Unpack the response to a request.  Errors from the server are turned back
+/// into `CaveyError`s, and responses of the wrong kind are protocol errors.
+impl ServerMessage {
+    pub(crate) fn into_empty(self) -> Result<()> {
+        match self.into_value()? {
+            None => Ok(()),
+            Some(val) => Err(CaveyError::Protocol(format!("unexpected response {:?}", val))),
+        }
+    }
+
+    pub(crate) fn into_value(self) -> Result<Option<String>> {
+        match self {
+            ServerMessage::Success { value } => Ok(value),
+            other => Err(other.unexpected()),
+        }
+    }
+
+    pub(crate) fn into_removed(self) -> Result<bool> {
+        match self {
+            ServerMessage::Removed { removed } => Ok(removed),
+            other => Err(other.unexpected()),
+        }
+    }
+
+    pub(crate) fn into_versioned(self) -> Result<(Option<String>, Option<Version>)> {
+        match self {
+            ServerMessage::Versioned { value, version } => Ok((value, version)),
+            other => Err(other.unexpected()),
+        }
+    }
+
+    pub(crate) fn into_keyspaces(self) -> Result<Vec<String>> {
+        match self {
+            ServerMessage::Keyspaces { names } => Ok(names),
+            other => Err(other.unexpected()),
+        }
+    }
+
+    pub(crate) fn into_entries(self) -> Result<Vec<(String, String)>> {
+        match self {
+            ServerMessage::Entries { entries } => Ok(entries),
+            other => Err(other.unexpected()),
+        }
+    }
+
+    pub(crate) fn into_cluster(self) -> Result<ClusterStatus> {
+        match self {
+            ServerMessage::Cluster { status } => Ok(status),
+            other => Err(other.unexpected()),
+        }
+    }
+
+    pub(crate) fn into_events(self) -> Result<(Vec<Event>, u64)> {
+        match self {
+            ServerMessage::Events { events, seq } => Ok((events, seq)),
+            other => Err(other.unexpected()),
+        }
+    }
+
+    fn unexpected(self) -> CaveyError {
+        match self {
+            ServerMessage::Error { code, detail } => CaveyError::from_wire(code, detail),
+            other => CaveyError::Protocol(format!("unexpected response {:?}", other)),
+        }
+    }
+}
+
+/// Bytes opening every handshake, so that a connection from something other
+/// than a cavey peer is rejected at once.
+const MAGIC: [u8; 4] = *b"CAVY";
+
+/// Bumped whenever `ClientMessage` or `ServerMessage` change incompatibly.
+pub(crate) const PROTOCOL_VERSION: u16 = 1;
+
+/// Optional protocol features this build supports, as bit flags.  None are
+/// defined yet.
+pub(crate) const FEATURES: u32 = 0;
+
+/// The largest frame either side accepts unless configured otherwise.
+pub(crate) const MAX_FRAME_SIZE: u32 = 16 << 20;
+
+const FRAME_HEADER_LEN: usize = 4;
+
+/// The first message each side sends on a new connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Handshake {
+    pub version: u16,
+    /// Features the sender supports.  A feature may be used on the
+    /// connection only if both sides set its flag.
+    pub features: u32,
+    /// The largest frame the sender will accept.
+    pub max_frame_size: u32,
+}
+
+impl Handshake {
+    pub(crate) const ENCODED_LEN: usize = 14;
+
+    pub(crate) fn local(max_frame_size: u32) -> Handshake {
+        Handshake {
+            version: PROTOCOL_VERSION,
+            features: FEATURES,
+            max_frame_size,
+        }
+    }
+
+    pub(crate) fn encode(&self) -> [u8; Handshake::ENCODED_LEN] {
+        let mut buf = [0; Handshake::ENCODED_LEN];
+        buf[..4].copy_from_slice(&MAGIC);
+        BigEndian::write_u16(&mut buf[4..6], self.version);
+        BigEndian::write_u32(&mut buf[6..10], self.features);
+        BigEndian::write_u32(&mut buf[10..14], self.max_frame_size);
+        buf
+    }
+
+    pub(crate) fn decode(buf: &[u8; Handshake::ENCODED_LEN]) -> Result<Handshake> {
+        if buf[..4] != MAGIC {
+            return Err(CaveyError::Protocol("peer is not speaking the cavey protocol".to_owned()));
+        }
+        Ok(Handshake {
+            version: BigEndian::read_u16(&buf[4..6]),
+            features: BigEndian::read_u32(&buf[6..10]),
+            max_frame_size: BigEndian::read_u32(&buf[10..14]),
+        })
+    }
+
+    pub(crate) fn read_from<R: Read>(reader: &mut R) -> Result<Handshake> {
+        let mut buf = [0; Handshake::ENCODED_LEN];
+        reader.read_exact(&mut buf)?;
+        Handshake::decode(&buf)
+    }
+
+    /// Check that a peer which sent `peer` can talk to us.
+    pub(crate) fn check_compatible(&self, peer: &Handshake) -> Result<()> {
+        if peer.version != self.version {
+            return Err(CaveyError::Protocol(format!(
+                "protocol version mismatch: we speak version {}, peer speaks version {}",
+                self.version, peer.version
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Encode `msg` as a frame, failing if it is larger than the peer accepts.
+pub(crate) fn encode_frame<T: Serialize>(msg: &T, max_frame_size: u32) -> Result<Vec<u8>> {
+    let len = bincode::serialized_size(msg)?;
+    if len > u64::from(max_frame_size) {
+        return Err(CaveyError::TooLarge(format!(
+            "message of {} bytes exceeds maximum frame size of {} bytes",
+            len, max_frame_size
+        )));
+    }
+    let mut buf = Vec::with_capacity(FRAME_HEADER_LEN + len as usize);
+    buf.extend_from_slice(&(len as u32).to_be_bytes());
+    bincode::serialize_into(&mut buf, msg)?;
+    Ok(buf)
+}
+
+/// Write `msg` as a frame.  The frame goes out in one write, so small
+/// messages aren't held back by Nagle's algorithm.
+pub(crate) fn write_frame<W: Write, T: Serialize>(writer: &mut W, msg: &T, max_frame_size: u32) -> Result<()> {
+    writer.write_all(&encode_frame(msg, max_frame_size)?)?;
+    Ok(())
+}
+
+/// Read a frame, failing if it is larger than `max_frame_size`.  A
+/// connection closed between frames gives an `UnexpectedEof` I/O error.
+pub(crate) fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R, max_frame_size: u32) -> Result<T> {
+    let len = reader.read_u32::<BigEndian>()?;
+    check_frame_size(len, max_frame_size)?;
+    // The buffer grows as the payload arrives, so a peer announcing a large
+    // frame and sending nothing more doesn't pin the whole allocation.
+    let mut payload = Vec::new();
+    reader.take(u64::from(len)).read_to_end(&mut payload)?;
+    if payload.len() < len as usize {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    decode_payload(&payload, max_frame_size)
+}
+
+/// Decode one frame from the front of `buf` if it holds a complete one,
+/// removing the bytes it used.  This lets async readers, which cannot block
+/// inside `read_frame`, accumulate input until a frame is ready.
+pub(crate) fn decode_frame<T: DeserializeOwned>(buf: &mut Vec<u8>, max_frame_size: u32) -> Result<Option<T>> {
+    if buf.len() < FRAME_HEADER_LEN {
+        return Ok(None);
+    }
+    let len = BigEndian::read_u32(&buf[..FRAME_HEADER_LEN]);
+    check_frame_size(len, max_frame_size)?;
+    let end = FRAME_HEADER_LEN + len as usize;
+    if buf.len() < end {
+        return Ok(None);
+    }
+    let msg = decode_payload(&buf[FRAME_HEADER_LEN..end], max_frame_size)?;
+    buf.drain(..end);
+    Ok(Some(msg))
+}
+
+/// Decode a frame's payload.  The bincode limit stops a string or sequence
+/// claiming a huge length from allocating it before the payload runs out.
+fn decode_payload<T: DeserializeOwned>(payload: &[u8], max_frame_size: u32) -> Result<T> {
+    bincode::config()
+        .limit(u64::from(max_frame_size))
+        .deserialize(payload)
+        .map_err(|err| match *err {
+            // Running out of payload is a malformed message, not a closed
+            // connection.
+            bincode::ErrorKind::Io(err) => CaveyError::Protocol(format!("truncated message: {}", err)),
+            other => Box::new(other).into(),
+        })
+}
+
+fn check_frame_size(len: u32, max_frame_size: u32) -> Result<()> {
+    if len > max_frame_size {
+        return Err(CaveyError::TooLarge(format!(
+            "frame of {} bytes exceeds maximum frame size of {} bytes",
+            len, max_frame_size
+        )));
+    }
+    Ok(())
+}
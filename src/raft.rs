@@ -0,0 +1,1484 @@
+//! A cluster of nodes replicating writes through Raft.
+//!
+//! Each node keeps a log of writes in `<dir>/raft`, and applies the entries
+//! a majority of the cluster has stored to its own engine, its state
+//! machine.  Clients talk to the leader through `RaftEngine`: writes are
+//! appended to the log and answered once applied, and reads confirm with a
+//! majority that the node is still the leader before reading, so both are
+//! linearizable.  Other nodes answer with `NotLeader`, naming the leader.
+//!
+//! Nodes talk to each other on a separate address, their id.  A cluster is
+//! bootstrapped by starting each of its first members with the full list of
+//! them.  Members are then added or removed one at a time, through the
+//! leader; a node being added starts with no members and waits to hear from
+//! the leader.
+//!
+//! A node's engine doubles as its snapshot: it holds the effect of every
+//! entry applied, and the index of the last is kept beside the log.  Once
+//! the log holds `RaftConfig::compact_threshold` entries, those applied are
+//! dropped from it, and the first line of its file records the last one
+//! dropped, its term and the members then.  A follower that needs entries
+//! the leader has dropped, such as a new member, is sent the leader's
+//! engine instead, a page at a time.  Like a replication snapshot, it isn't
+//! of one moment, but reflects every entry up to the index it was sent at,
+//! and replaying the entries after that brings the follower into line.  It
+//! is received into a separate store, and only copied over the follower's
+//! engine once complete.
+//!
+//! A node whose engine fails to apply a committed entry no longer matches
+//! the others, so it stops: it steps down, refuses to vote or store
+//! entries, fails requests, and reports why in its status.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, trace, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::acl::Access;
+use crate::error::ErrorCode;
+use crate::protocol::{read_frame, write_frame, ClientMessage, Handshake, ServerMessage, MAX_FRAME_SIZE};
+use crate::server::dispatch;
+use crate::utils::{lock, write_atomic};
+use crate::replication::chunks;
+use crate::{in_keyspace, CaveyEngine, CaveyError, CaveyStore, ChangeFeed, Changeset, Listener, Result, SharedEngine, Version};
+
+/// Entries sent to a follower at a time.
+const MAX_APPEND: usize = 500;
+
+/// Keys read from the engine at a time while sending a snapshot.
+const SNAPSHOT_PAGE: usize = 1000;
+
+/// A node in a cluster: `id` is the address it talks to other nodes on,
+/// and `addr` the one it serves clients on.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+pub struct Member {
+    pub id: String,
+    pub addr: String,
+}
+
+/// A node's view of its cluster.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ClusterStatus {
+    pub id: String,
+    pub term: u64,
+    /// The id of the leader, if the node knows of one.
+    pub leader: Option<String>,
+    pub members: Vec<Member>,
+    /// Entries known to be stored by a majority.
+    pub commit_index: u64,
+    /// Entries applied to the node's engine.
+    pub applied_index: u64,
+    /// Why the node stopped taking part in the cluster, if it has.
+    #[serde(default)]
+    pub failure: Option<String>,
+}
+
+/// Settings for a `RaftNode`.
+#[derive(Clone, Debug)]
+pub struct RaftConfig {
+    /// The address other nodes reach this one on.
+    pub id: String,
+    /// The address this node serves clients on, to redirect them to when it
+    /// leads.
+    pub addr: String,
+    /// Where the log and the node's vote are kept.
+    pub dir: PathBuf,
+    /// A follower that hears nothing from a leader for between one and two
+    /// times this long stands for election.
+    pub election_timeout: Duration,
+    /// How often a leader reminds followers it is there.
+    pub heartbeat_interval: Duration,
+    /// How long a request waits to be committed or confirmed by a majority.
+    pub request_timeout: Duration,
+    /// Entries the log holds before those already applied are dropped.
+    pub compact_threshold: usize,
+}
+
+impl RaftConfig {
+    pub fn new(id: String, addr: String, dir: PathBuf) -> RaftConfig {
+        RaftConfig {
+            id,
+            addr,
+            dir,
+            election_timeout: Duration::from_millis(500),
+            heartbeat_interval: Duration::from_millis(100),
+            request_timeout: Duration::from_secs(5),
+            compact_threshold: 10_000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Command {
+    /// Appended by each new leader, so it can commit the entries of earlier
+    /// terms.
+    Noop,
+    /// The members from this entry on.
+    Config { members: Vec<Member> },
+    Request(ClientMessage),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Entry {
+    term: u64,
+    command: Command,
+}
+
+/// Where the log starts: the last entry dropped from it, that entry's term,
+/// and the members as of it.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct LogBase {
+    index: u64,
+    term: u64,
+    members: Vec<Member>,
+}
+
+/// Messages between nodes.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum RaftRequest {
+    RequestVote {
+        term: u64,
+        candidate: String,
+        last_index: u64,
+        last_term: u64,
+    },
+    AppendEntries {
+        term: u64,
+        leader: String,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+    },
+    /// Part of the leader's engine, standing in for the entries up to
+    /// `last_index`.
+    InstallSnapshot {
+        term: u64,
+        leader: String,
+        last_index: u64,
+        chunk: SnapshotChunk,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum SnapshotChunk {
+    /// Starts a snapshot, with the term of its last entry and the members
+    /// as of it.
+    Begin { last_term: u64, members: Vec<Member> },
+    Keyspace { name: String },
+    Entries { keyspace: Option<String>, entries: Vec<(String, String)> },
+    End,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum RaftResponse {
+    Vote { term: u64, granted: bool },
+    /// On failure, `last_index` is where the follower's log may first
+    /// differ from the leader's.
+    Appended { term: u64, success: bool, last_index: u64 },
+    /// `last_index` is the follower's commit index, which covers the
+    /// snapshot once installed, or before it starts if it needs none.
+    Installed { term: u64, last_index: u64 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// What a node must not forget across restarts, besides its log.
+#[derive(Default, Deserialize, Serialize)]
+struct HardState {
+    term: u64,
+    voted_for: Option<String>,
+}
+
+/// A leader's view of one follower.
+struct Progress {
+    next_index: u64,
+    match_index: u64,
+    last_sent: Option<Instant>,
+    /// The latest read round the follower has acknowledged.
+    acked_round: u64,
+    sent_round: u64,
+}
+
+/// A snapshot being received from the leader.
+struct Staged {
+    base: LogBase,
+    store: CaveyStore,
+}
+
+struct Core {
+    term: u64,
+    voted_for: Option<String>,
+    /// The entries after `base`.
+    log: Vec<Entry>,
+    base: LogBase,
+    log_file: BufWriter<File>,
+    members: Vec<Member>,
+    /// The index of the entry `members` came from.
+    config_index: u64,
+    commit: u64,
+    applied: u64,
+    role: Role,
+    leader: Option<String>,
+    last_contact: Instant,
+    election_deadline: Instant,
+    votes: HashSet<String>,
+    vote_requested: HashSet<String>,
+    progress: HashMap<String, Progress>,
+    /// Bumped for each linearizable read, which waits until a majority has
+    /// answered a message sent after the bump.
+    read_round: u64,
+    /// Proposers waiting for their entries to be applied, by index, with
+    /// the term they were appended in.
+    waiters: HashMap<u64, (u64, mpsc::Sender<ServerMessage>)>,
+    /// Nodes with a thread sending them messages.
+    peers: HashSet<String>,
+    /// Why the node stopped, if applying an entry failed.
+    failure: Option<String>,
+    staged: Option<Staged>,
+}
+
+struct Shared {
+    config: RaftConfig,
+    engine: SharedEngine,
+    core: Mutex<Core>,
+    changed: Condvar,
+}
+
+/// A member of a Raft cluster.  Serve other nodes with `serve`, and clients
+/// with the engine from `engine`.
+#[derive(Clone)]
+pub struct RaftNode {
+    shared: Arc<Shared>,
+}
+
+impl RaftNode {
+    /// Start a node with `engine` as its state machine.  If the node has no
+    /// log yet, the cluster starts with `bootstrap` as its members, which
+    /// must be the same on each of them.  A node to be added to a running
+    /// cluster starts with none.
+    ///
+    /// A snapshot received in full but not yet copied over `engine` when the
+    /// node last stopped is copied over it first.
+    pub fn open(config: RaftConfig, mut engine: Box<dyn CaveyEngine>, bootstrap: &[Member]) -> Result<RaftNode> {
+        let dir = config.dir.join("raft");
+        fs::create_dir_all(&dir)?;
+        let hard_state: HardState = match fs::read(dir.join("state.json")) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HardState::default(),
+            Err(err) => return Err(err.into()),
+        };
+        let applied = match fs::read_to_string(dir.join("applied")) {
+            Ok(applied) => applied.trim().parse()?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err.into()),
+        };
+        let (base, log) = read_log(&dir.join("log"))?;
+        let log_file = BufWriter::new(OpenOptions::new().create(true).append(true).open(dir.join("log"))?);
+        let now = Instant::now();
+        let mut core = Core {
+            term: hard_state.term,
+            voted_for: hard_state.voted_for,
+            log,
+            base,
+            log_file,
+            members: Vec::new(),
+            config_index: 0,
+            // Only committed entries are applied.
+            commit: applied,
+            applied,
+            role: Role::Follower,
+            leader: None,
+            last_contact: now,
+            election_deadline: now,
+            votes: HashSet::new(),
+            vote_requested: HashSet::new(),
+            progress: HashMap::new(),
+            read_round: 0,
+            waiters: HashMap::new(),
+            peers: HashSet::new(),
+            failure: None,
+            staged: None,
+        };
+        core.install_snapshot(&mut *engine, &config)?;
+        if core.last_index() == 0 && !bootstrap.is_empty() {
+            let mut members = bootstrap.to_vec();
+            members.sort();
+            info!("bootstrapping cluster of {:?}", members);
+            core.append(vec![Entry { term: 0, command: Command::Config { members } }])?;
+        }
+        core.load_config();
+        core.reset_election_deadline(&config);
+        let node = RaftNode {
+            shared: Arc::new(Shared {
+                config,
+                engine: Arc::new(Mutex::new(engine)),
+                core: Mutex::new(core),
+                changed: Condvar::new(),
+            }),
+        };
+        let shared = node.shared.clone();
+        thread::spawn(move || shared.tick());
+        let shared = node.shared.clone();
+        thread::spawn(move || {
+            if let Err(err) = shared.apply_committed() {
+                shared.stop(err);
+            }
+        });
+        Ok(node)
+    }
+
+    /// Answer other nodes connecting on `socket`, with a thread for each.
+    pub fn serve<L: Listener>(&self, socket: &L) -> Result<()> {
+        thread::scope(|scope| loop {
+            match socket.accept() {
+                Ok(mut stream) => {
+                    scope.spawn(move || {
+                        if let Err(err) = self.shared.answer(&mut stream) {
+                            debug!("raft connection closed: {}", err);
+                        }
+                    });
+                }
+                Err(err) => warn!("raft connection failed: {}", err),
+            }
+        });
+        Ok(())
+    }
+
+    /// The engine clients use, which applies their requests through the
+    /// cluster.
+    pub fn engine(&self) -> RaftEngine {
+        RaftEngine {
+            node: self.clone(),
+            keyspace: None,
+            selected: None,
+        }
+    }
+
+    pub fn status(&self) -> ClusterStatus {
+        let core = self.shared.lock();
+        ClusterStatus {
+            id: self.shared.config.id.clone(),
+            term: core.term,
+            leader: core.leader.clone(),
+            members: core.members.clone(),
+            commit_index: core.commit,
+            applied_index: core.applied,
+            failure: core.failure.clone(),
+        }
+    }
+}
+
+/// Read the log at `path`, cutting off a partial last line so later
+/// entries are appended after whole ones.
+fn read_log(path: &Path) -> Result<(LogBase, Vec<Entry>)> {
+    let file = match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Default::default()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut reader = BufReader::new(&file);
+    let mut base = None;
+    let mut log = Vec::new();
+    let mut len = 0;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        reader.read_until(b'\n', &mut line)?;
+        // A crash while appending leaves a partial last line, for an
+        // entry that was never acknowledged.
+        if !line.ends_with(b"\n") {
+            break;
+        }
+        // Until the log is first compacted, it has no base line.
+        if len == 0 {
+            base = serde_json::from_slice(&line).ok();
+        }
+        if len > 0 || base.is_none() {
+            log.push(serde_json::from_slice(&line)?);
+        }
+        len += line.len() as u64;
+    }
+    file.set_len(len)?;
+    Ok((base.unwrap_or_default(), log))
+}
+
+/// Replace the log at `path` with `base` and the `entries` after it,
+/// returning the file to append to.
+fn write_log(path: &Path, base: &LogBase, entries: &[Entry]) -> Result<BufWriter<File>> {
+    let mut contents = serde_json::to_vec(base)?;
+    contents.push(b'\n');
+    for entry in entries {
+        serde_json::to_writer(&mut contents, entry)?;
+        contents.push(b'\n');
+    }
+    write_atomic(path, &contents)?;
+    Ok(BufWriter::new(OpenOptions::new().append(true).open(path)?))
+}
+
+/// A random duration between `base` and twice that.
+fn jitter(base: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    base + base.mul_f64((random % 1000) as f64 / 1000.0)
+}
+
+impl Core {
+    fn last_index(&self) -> u64 {
+        self.base.index + self.log.len() as u64
+    }
+
+    /// The term of the entry at `index`, which must not be before the base.
+    fn term_at(&self, index: u64) -> u64 {
+        if index == self.base.index {
+            self.base.term
+        } else {
+            self.log[(index - self.base.index) as usize - 1].term
+        }
+    }
+
+    /// The entries after `after`, up to and including `end`.
+    fn entries(&self, after: u64, end: u64) -> &[Entry] {
+        &self.log[(after - self.base.index) as usize..(end - self.base.index) as usize]
+    }
+
+    fn is_member(&self, id: &str) -> bool {
+        self.members.iter().any(|member| member.id == id)
+    }
+
+    fn leader_addr(&self) -> Option<String> {
+        let leader = self.leader.as_ref()?;
+        self.members.iter().find(|member| &member.id == leader).map(|member| member.addr.clone())
+    }
+
+    /// Whether a majority of members satisfy `has`.
+    fn quorum<F: Fn(&str) -> bool>(&self, has: F) -> bool {
+        let count = self.members.iter().filter(|member| has(&member.id)).count();
+        count * 2 > self.members.len()
+    }
+
+    /// The members are those in the latest config entry, committed or not.
+    fn load_config(&mut self) {
+        let (config_index, members) = self.config_at(self.last_index());
+        self.config_index = config_index;
+        self.members = members;
+    }
+
+    /// The latest config entry up to `index`, with its index, or the base's
+    /// members if the log holds none.
+    fn config_at(&self, index: u64) -> (u64, Vec<Member>) {
+        let mut entries = self.entries(self.base.index, index).iter().enumerate().rev();
+        let latest = entries.find_map(|(offset, entry)| match &entry.command {
+            Command::Config { members } => Some((self.base.index + offset as u64 + 1, members.clone())),
+            _ => None,
+        });
+        latest.unwrap_or_else(|| (self.base.index, self.base.members.clone()))
+    }
+
+    /// Fail if the node has stopped.
+    fn check_running(&self) -> Result<()> {
+        match &self.failure {
+            Some(failure) => Err(CaveyError::Internal(format!("raft node stopped: {}", failure))),
+            None => Ok(()),
+        }
+    }
+
+    fn reset_election_deadline(&mut self, config: &RaftConfig) {
+        self.election_deadline = Instant::now() + jitter(config.election_timeout);
+    }
+
+    fn save_hard_state(&self, config: &RaftConfig) -> Result<()> {
+        let state = HardState {
+            term: self.term,
+            voted_for: self.voted_for.clone(),
+        };
+        write_atomic(&config.dir.join("raft").join("state.json"), &serde_json::to_vec(&state)?)
+    }
+
+    /// Append entries to the log, on disk before in memory.
+    fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+        for entry in &entries {
+            serde_json::to_writer(&mut self.log_file, entry)?;
+            self.log_file.write_all(b"\n")?;
+        }
+        self.log_file.flush()?;
+        self.log_file.get_ref().sync_data()?;
+        let configs = entries.iter().any(|entry| matches!(entry.command, Command::Config { .. }));
+        self.log.extend(entries);
+        if configs {
+            self.load_config();
+        }
+        Ok(())
+    }
+
+    /// Discard the entries from `index` on, which conflict with the
+    /// leader's, rewriting the log file.
+    fn truncate(&mut self, index: u64, config: &RaftConfig) -> Result<()> {
+        self.log.truncate((index - self.base.index) as usize - 1);
+        self.log_file = write_log(&config.dir.join("raft").join("log"), &self.base, &self.log)?;
+        self.load_config();
+        Ok(())
+    }
+
+    /// Drop the entries up to `index`, which the engine has applied, from
+    /// the log.
+    fn compact(&mut self, index: u64, config: &RaftConfig) -> Result<()> {
+        let base = LogBase {
+            index,
+            term: self.term_at(index),
+            members: self.config_at(index).1,
+        };
+        let kept = (index - self.base.index) as usize;
+        self.log_file = write_log(&config.dir.join("raft").join("log"), &base, &self.log[kept..])?;
+        debug!("{} compacted its log up to {}", config.id, index);
+        self.log.drain(..kept);
+        self.base = base;
+        Ok(())
+    }
+
+    fn staging_dir(config: &RaftConfig) -> PathBuf {
+        config.dir.join("raft").join("snapshot")
+    }
+
+    /// Copy a snapshot received in full over `engine`, and start the log
+    /// after it, unless the node already has every entry it stands for.  A
+    /// crash part way leaves it to be copied again.
+    fn install_snapshot(&mut self, engine: &mut dyn CaveyEngine, config: &RaftConfig) -> Result<()> {
+        let staging = Core::staging_dir(config);
+        let base: LogBase = match fs::read(staging.join("base.json")) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        if base.index > self.commit {
+            info!("{} installing snapshot at {}", config.id, base.index);
+            engine.restore(&staging)?;
+            let dir = config.dir.join("raft");
+            self.log_file = write_log(&dir.join("log"), &base, &[])?;
+            write_atomic(&dir.join("applied"), base.index.to_string().as_bytes())?;
+            self.log.clear();
+            self.commit = base.index;
+            self.applied = base.index;
+            self.base = base;
+            self.load_config();
+            // Their entries are gone, whether or not they were committed.
+            for (_, (_, waiter)) in self.waiters.drain() {
+                waiter.send(CaveyError::Timeout.into()).ok();
+            }
+        }
+        fs::remove_dir_all(&staging)?;
+        Ok(())
+    }
+
+    fn become_follower(&mut self, term: u64, config: &RaftConfig) -> Result<()> {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.save_hard_state(config)?;
+        }
+        if self.role != Role::Follower {
+            debug!("{} became follower in term {}", config.id, self.term);
+        }
+        self.role = Role::Follower;
+        self.progress.clear();
+        Ok(())
+    }
+
+    fn start_election(&mut self, config: &RaftConfig) -> Result<()> {
+        self.term += 1;
+        self.voted_for = Some(config.id.clone());
+        self.save_hard_state(config)?;
+        info!("{} standing for election in term {}", config.id, self.term);
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.votes = HashSet::new();
+        self.votes.insert(config.id.clone());
+        self.vote_requested.clear();
+        self.reset_election_deadline(config);
+        if self.quorum(|id| self.votes.contains(id)) {
+            self.become_leader(config)?;
+        }
+        Ok(())
+    }
+
+    fn become_leader(&mut self, config: &RaftConfig) -> Result<()> {
+        info!("{} became leader in term {}", config.id, self.term);
+        self.role = Role::Leader;
+        self.leader = Some(config.id.clone());
+        self.progress.clear();
+        self.append(vec![Entry {
+            term: self.term,
+            command: Command::Noop,
+        }])?;
+        self.advance_commit(config);
+        Ok(())
+    }
+
+    fn progress(&mut self, peer: &str) -> &mut Progress {
+        let next_index = self.last_index() + 1;
+        self.progress.entry(peer.to_owned()).or_insert(Progress {
+            next_index,
+            match_index: 0,
+            last_sent: None,
+            acked_round: 0,
+            sent_round: 0,
+        })
+    }
+
+    fn match_index(&self, id: &str, config: &RaftConfig) -> u64 {
+        if id == config.id {
+            self.last_index()
+        } else {
+            self.progress.get(id).map_or(0, |progress| progress.match_index)
+        }
+    }
+
+    /// Commit the latest entry of the current term stored by a majority,
+    /// and the entries before it.
+    fn advance_commit(&mut self, config: &RaftConfig) {
+        for index in (self.commit + 1..=self.last_index()).rev() {
+            if self.term_at(index) != self.term {
+                break;
+            }
+            if self.quorum(|id| self.match_index(id, config) >= index) {
+                trace!("committed up to {}", index);
+                self.commit = index;
+                break;
+            }
+        }
+        // A leader removed from the cluster leads until its removal is
+        // committed.
+        if self.role == Role::Leader && !self.is_member(&config.id) && self.config_index <= self.commit {
+            info!("{} left the cluster", config.id);
+            self.role = Role::Follower;
+            self.leader = None;
+        }
+    }
+
+    /// The next message to send `peer`, if any is due.
+    fn next_request(&mut self, peer: &str, config: &RaftConfig) -> Option<(RaftRequest, u64)> {
+        match self.role {
+            Role::Candidate if !self.vote_requested.contains(peer) => {
+                self.vote_requested.insert(peer.to_owned());
+                Some((
+                    RaftRequest::RequestVote {
+                        term: self.term,
+                        candidate: config.id.clone(),
+                        last_index: self.last_index(),
+                        last_term: self.term_at(self.last_index()),
+                    },
+                    0,
+                ))
+            }
+            // The entries the follower needs next have been dropped.
+            Role::Leader if self.progress.get(peer).is_some_and(|progress| progress.next_index <= self.base.index) => {
+                let last_index = self.applied;
+                let (_, members) = self.config_at(last_index);
+                let last_term = self.term_at(last_index);
+                self.progress(peer).last_sent = Some(Instant::now());
+                Some((
+                    RaftRequest::InstallSnapshot {
+                        term: self.term,
+                        leader: config.id.clone(),
+                        last_index,
+                        chunk: SnapshotChunk::Begin { last_term, members },
+                    },
+                    0,
+                ))
+            }
+            Role::Leader => {
+                let last_index = self.last_index();
+                let read_round = self.read_round;
+                let progress = self.progress(peer);
+                let due = progress.next_index <= last_index
+                    || progress.sent_round < read_round
+                    || progress.last_sent.is_none_or(|sent| sent.elapsed() >= config.heartbeat_interval);
+                if !due {
+                    return None;
+                }
+                progress.last_sent = Some(Instant::now());
+                progress.sent_round = read_round;
+                let prev_index = progress.next_index - 1;
+                let end = last_index.min(prev_index + MAX_APPEND as u64);
+                Some((
+                    RaftRequest::AppendEntries {
+                        term: self.term,
+                        leader: config.id.clone(),
+                        prev_index,
+                        prev_term: self.term_at(prev_index),
+                        entries: self.entries(prev_index, end).to_vec(),
+                        commit: self.commit,
+                    },
+                    read_round,
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    fn handle_response(
+        &mut self,
+        peer: &str,
+        request: &RaftRequest,
+        round: u64,
+        response: RaftResponse,
+        config: &RaftConfig,
+    ) -> Result<()> {
+        let (term, sent_term) = match (&response, request) {
+            (RaftResponse::Vote { term, .. }, RaftRequest::RequestVote { term: sent, .. })
+            | (RaftResponse::Appended { term, .. }, RaftRequest::AppendEntries { term: sent, .. })
+            | (RaftResponse::Installed { term, .. }, RaftRequest::InstallSnapshot { term: sent, .. }) => (*term, *sent),
+            _ => return Err(CaveyError::Protocol("mismatched raft response".to_owned())),
+        };
+        if term > self.term {
+            self.leader = None;
+            return self.become_follower(term, config);
+        }
+        if sent_term != self.term {
+            return Ok(());
+        }
+        match (response, request) {
+            (RaftResponse::Vote { granted: true, .. }, _) if self.role == Role::Candidate => {
+                self.votes.insert(peer.to_owned());
+                if self.quorum(|id| self.votes.contains(id)) {
+                    self.become_leader(config)?;
+                }
+            }
+            (RaftResponse::Appended { success, last_index, .. }, RaftRequest::AppendEntries { prev_index, entries, .. })
+                if self.role == Role::Leader =>
+            {
+                let progress = self.progress(peer);
+                if success {
+                    progress.match_index = progress.match_index.max(prev_index + entries.len() as u64);
+                    progress.next_index = progress.match_index + 1;
+                    progress.acked_round = progress.acked_round.max(round);
+                    self.advance_commit(config);
+                } else {
+                    progress.next_index = (progress.next_index - 1).min(last_index + 1).max(1);
+                    // Try again at once
+                    progress.last_sent = None;
+                }
+            }
+            (RaftResponse::Installed { last_index, .. }, _) if self.role == Role::Leader => {
+                // Entries up to the follower's commit index match the leader's.
+                let progress = self.progress(peer);
+                progress.match_index = progress.match_index.max(last_index);
+                progress.next_index = progress.match_index + 1;
+                progress.last_sent = None;
+                self.advance_commit(config);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_request(&mut self, request: RaftRequest, engine: &SharedEngine, config: &RaftConfig) -> Result<RaftResponse> {
+        self.check_running()?;
+        match request {
+            RaftRequest::RequestVote {
+                term,
+                candidate,
+                last_index,
+                last_term,
+            } => {
+                // While a leader is in touch, a node that has lost touch
+                // with it, or has been removed, can't disrupt the cluster.
+                let leader_alive = self.role == Role::Leader
+                    || (self.leader.is_some() && self.last_contact.elapsed() < config.election_timeout);
+                if term < self.term || leader_alive {
+                    return Ok(RaftResponse::Vote { term: self.term, granted: false });
+                }
+                if term > self.term {
+                    self.leader = None;
+                    self.become_follower(term, config)?;
+                }
+                let up_to_date = (last_term, last_index) >= (self.term_at(self.last_index()), self.last_index());
+                let granted = up_to_date && self.voted_for.as_ref().is_none_or(|voted| *voted == candidate);
+                if granted {
+                    debug!("{} voted for {} in term {}", config.id, candidate, term);
+                    self.voted_for = Some(candidate);
+                    self.save_hard_state(config)?;
+                    self.reset_election_deadline(config);
+                }
+                Ok(RaftResponse::Vote { term: self.term, granted })
+            }
+            RaftRequest::AppendEntries {
+                term,
+                leader,
+                mut prev_index,
+                mut prev_term,
+                mut entries,
+                commit,
+            } => {
+                if term < self.term {
+                    return Ok(RaftResponse::Appended {
+                        term: self.term,
+                        success: false,
+                        last_index: self.last_index(),
+                    });
+                }
+                self.follow(term, leader, config)?;
+                let end = prev_index + entries.len() as u64;
+                // Entries up to the base are committed, so match the leader's.
+                if prev_index < self.base.index {
+                    let skip = entries.len().min((self.base.index - prev_index) as usize);
+                    entries.drain(..skip);
+                    prev_index += skip as u64;
+                    prev_term = self.base.term;
+                    if prev_index < self.base.index {
+                        return Ok(RaftResponse::Appended {
+                            term: self.term,
+                            success: true,
+                            last_index: end,
+                        });
+                    }
+                }
+                if prev_index > self.last_index() || self.term_at(prev_index) != prev_term {
+                    return Ok(RaftResponse::Appended {
+                        term: self.term,
+                        success: false,
+                        last_index: self.last_index().min(prev_index.saturating_sub(1)),
+                    });
+                }
+                // Skip the entries already held, and drop any that conflict.
+                let mut new = Vec::new();
+                for (index, entry) in (prev_index + 1..).zip(entries) {
+                    if !new.is_empty() || index > self.last_index() {
+                        new.push(entry);
+                    } else if self.term_at(index) != entry.term {
+                        if index <= self.commit {
+                            return Err(CaveyError::Internal(format!("leader conflicts with committed entry {}", index)));
+                        }
+                        self.truncate(index, config)?;
+                        new.push(entry);
+                    }
+                }
+                if !new.is_empty() {
+                    self.append(new)?;
+                }
+                self.commit = self.commit.max(commit.min(end));
+                Ok(RaftResponse::Appended {
+                    term: self.term,
+                    success: true,
+                    last_index: end,
+                })
+            }
+            RaftRequest::InstallSnapshot {
+                term,
+                leader,
+                last_index,
+                chunk,
+            } => {
+                if term < self.term {
+                    return Ok(RaftResponse::Installed { term: self.term, last_index: self.commit });
+                }
+                self.follow(term, leader, config)?;
+                if last_index <= self.commit {
+                    self.staged = None;
+                    return Ok(RaftResponse::Installed { term: self.term, last_index: self.commit });
+                }
+                let staging = Core::staging_dir(config);
+                match chunk {
+                    SnapshotChunk::Begin { last_term, members } => {
+                        info!("{} receiving snapshot at {}", config.id, last_index);
+                        self.staged = None;
+                        if staging.exists() {
+                            fs::remove_dir_all(&staging)?;
+                        }
+                        self.staged = Some(Staged {
+                            base: LogBase {
+                                index: last_index,
+                                term: last_term,
+                                members,
+                            },
+                            store: CaveyStore::open(&staging)?,
+                        });
+                    }
+                    SnapshotChunk::Keyspace { name } => self.staged(last_index)?.create_keyspace(&name)?,
+                    SnapshotChunk::Entries { keyspace, entries } => {
+                        let store = in_keyspace(self.staged(last_index)?, keyspace.as_deref())?;
+                        for (key, value) in entries {
+                            store.put(key, value)?;
+                        }
+                    }
+                    SnapshotChunk::End => {
+                        self.staged(last_index)?;
+                        let Staged { base, store } = self.staged.take().unwrap();
+                        drop(store);
+                        write_atomic(&staging.join("base.json"), &serde_json::to_vec(&base)?)?;
+                        if let Err(err) = self.install_snapshot(&mut **lock(engine), config) {
+                            // The engine may hold part of the snapshot.
+                            error!("{} stopped: installing a snapshot failed: {}", config.id, err);
+                            self.fail(&err);
+                            return Err(err);
+                        }
+                    }
+                }
+                Ok(RaftResponse::Installed { term: self.term, last_index: self.commit })
+            }
+        }
+    }
+
+    /// Hear from the leader of `term`, following it.
+    fn follow(&mut self, term: u64, leader: String, config: &RaftConfig) -> Result<()> {
+        self.become_follower(term, config)?;
+        if self.leader.as_ref() != Some(&leader) {
+            info!("{} following {} in term {}", config.id, leader, term);
+        }
+        self.leader = Some(leader);
+        self.last_contact = Instant::now();
+        self.reset_election_deadline(config);
+        Ok(())
+    }
+
+    /// The store receiving the snapshot at `last_index`.
+    fn staged(&mut self, last_index: u64) -> Result<&mut CaveyStore> {
+        match &mut self.staged {
+            Some(staged) if staged.base.index == last_index => Ok(&mut staged.store),
+            _ => Err(CaveyError::Protocol("snapshot chunk out of order".to_owned())),
+        }
+    }
+
+    /// Stop taking part in the cluster, as `err` left the engine in doubt.
+    fn fail(&mut self, err: &CaveyError) {
+        self.failure = Some(err.to_string());
+        self.role = Role::Follower;
+        self.leader = None;
+        self.progress.clear();
+        let failed = self.check_running().unwrap_err();
+        for (_, (_, waiter)) in self.waiters.drain() {
+            waiter.send(ServerMessage::from(&failed)).ok();
+        }
+    }
+
+    /// Append a client request as the leader, returning where to wait for
+    /// its result.
+    fn propose(&mut self, command: Command, config: &RaftConfig) -> Result<mpsc::Receiver<ServerMessage>> {
+        self.check_running()?;
+        if self.role != Role::Leader {
+            return Err(CaveyError::NotLeader(self.leader_addr()));
+        }
+        self.append(vec![Entry {
+            term: self.term,
+            command,
+        }])?;
+        let (sender, receiver) = mpsc::channel();
+        self.waiters.insert(self.last_index(), (self.term, sender));
+        self.advance_commit(config);
+        Ok(receiver)
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Core> {
+        lock(&self.core)
+    }
+
+    /// Hold elections when the leader goes quiet, and keep a thread
+    /// sending messages to each other member.
+    fn tick(self: Arc<Shared>) {
+        let config = &self.config;
+        loop {
+            thread::sleep(config.heartbeat_interval / 4);
+            let mut core = self.lock();
+            let electable = core.failure.is_none() && core.role != Role::Leader && core.is_member(&config.id);
+            if electable && Instant::now() >= core.election_deadline {
+                if let Err(err) = core.start_election(config) {
+                    warn!("election failed: {}", err);
+                }
+            }
+            let new_peers: Vec<String> = core
+                .members
+                .iter()
+                .map(|member| member.id.clone())
+                .filter(|id| *id != config.id && !core.peers.contains(id))
+                .collect();
+            for peer in new_peers {
+                core.peers.insert(peer.clone());
+                let shared = self.clone();
+                thread::spawn(move || shared.send_to_peer(peer));
+            }
+            drop(core);
+            self.changed.notify_all();
+        }
+    }
+
+    /// Send `peer` whatever messages are due, until it leaves the cluster.
+    fn send_to_peer(&self, peer: String) {
+        let config = &self.config;
+        let mut connection = None;
+        loop {
+            let (request, round) = {
+                let mut core = self.lock();
+                loop {
+                    if !core.is_member(&peer) {
+                        core.peers.remove(&peer);
+                        return;
+                    }
+                    if let Some(request) = core.next_request(&peer, config) {
+                        break request;
+                    }
+                    core = self.changed.wait_timeout(core, config.heartbeat_interval).unwrap().0;
+                }
+            };
+            let response = match request {
+                RaftRequest::InstallSnapshot { .. } => self.send_snapshot(&mut connection, &peer, &request),
+                _ => self.call(&mut connection, &peer, &request),
+            };
+            match response {
+                Ok(response) => {
+                    let mut core = self.lock();
+                    if let Err(err) = core.handle_response(&peer, &request, round, response, config) {
+                        warn!("handling response from {} failed: {}", peer, err);
+                    }
+                    drop(core);
+                    self.changed.notify_all();
+                }
+                Err(err) => {
+                    trace!("message to {} failed: {}", peer, err);
+                    connection = None;
+                    thread::sleep(config.heartbeat_interval);
+                }
+            }
+        }
+    }
+
+    fn call(&self, connection: &mut Option<(TcpStream, Handshake)>, peer: &str, request: &RaftRequest) -> Result<RaftResponse> {
+        if connection.is_none() {
+            let addr: SocketAddr = peer
+                .parse()
+                .map_err(|_| CaveyError::Protocol(format!("invalid node address {}", peer)))?;
+            let mut stream = TcpStream::connect_timeout(&addr, self.config.election_timeout)?;
+            stream.set_read_timeout(Some(self.config.election_timeout))?;
+            stream.set_write_timeout(Some(self.config.election_timeout))?;
+            stream.set_nodelay(true)?;
+            let local = Handshake::local(MAX_FRAME_SIZE);
+            stream.write_all(&local.encode())?;
+            let remote = Handshake::read_from(&mut stream)?;
+            local.check_compatible(&remote)?;
+            *connection = Some((stream, remote));
+        }
+        let (stream, remote) = connection.as_mut().unwrap();
+        write_frame(stream, request, remote.max_frame_size)?;
+        read_frame(stream, MAX_FRAME_SIZE)
+    }
+
+    /// Send `peer` the engine, after `begin`, a page at a time, returning
+    /// the last response.  Stops early if the peer turns out not to need
+    /// it, or to know of a later term.
+    fn send_snapshot(&self, connection: &mut Option<(TcpStream, Handshake)>, peer: &str, begin: &RaftRequest) -> Result<RaftResponse> {
+        let (term, last_index) = match begin {
+            RaftRequest::InstallSnapshot { term, last_index, .. } => (*term, *last_index),
+            _ => return Err(CaveyError::Internal("not a snapshot".to_owned())),
+        };
+        info!("sending snapshot at {} to {}", last_index, peer);
+        // The peer wants the rest while it answers in the same term without
+        // having reached the snapshot.
+        let wants_more = |response: &RaftResponse| {
+            matches!(response, RaftResponse::Installed { term: seen, last_index: has } if *seen == term && *has < last_index)
+        };
+        let response = self.call(connection, peer, begin)?;
+        if !wants_more(&response) {
+            return Ok(response);
+        }
+        let names = lock(&self.engine).list_keyspaces()?;
+        for keyspace in std::iter::once(None).chain(names.into_iter().map(Some)) {
+            if let Some(name) = &keyspace {
+                let response = self.send_chunk(connection, peer, begin, SnapshotChunk::Keyspace { name: name.clone() })?;
+                if !wants_more(&response) {
+                    return Ok(response);
+                }
+            }
+            let mut after = None;
+            loop {
+                let page = {
+                    let mut engine = lock(&self.engine);
+                    match in_keyspace(&mut **engine, keyspace.as_deref()) {
+                        Ok(engine) => engine.scan("", after.as_deref(), SNAPSHOT_PAGE)?,
+                        // Dropped since it was listed, as the log will tell the peer
+                        Err(CaveyError::KeyspaceNotFound(_)) => Vec::new(),
+                        Err(err) => return Err(err),
+                    }
+                };
+                let last = match page.last() {
+                    Some((key, _)) => key.clone(),
+                    None => break,
+                };
+                let done = page.len() < SNAPSHOT_PAGE;
+                let budget = connection.as_ref().map_or(MAX_FRAME_SIZE, |(_, remote)| remote.max_frame_size) as usize / 2;
+                for entries in chunks(page, |(key, value)| key.len() + value.len(), budget) {
+                    let keyspace = keyspace.clone();
+                    let response = self.send_chunk(connection, peer, begin, SnapshotChunk::Entries { keyspace, entries })?;
+                    if !wants_more(&response) {
+                        return Ok(response);
+                    }
+                }
+                if done {
+                    break;
+                }
+                after = Some(last);
+            }
+        }
+        self.send_chunk(connection, peer, begin, SnapshotChunk::End)
+    }
+
+    /// Send `chunk` of the snapshot `begin` started.
+    fn send_chunk(
+        &self,
+        connection: &mut Option<(TcpStream, Handshake)>,
+        peer: &str,
+        begin: &RaftRequest,
+        chunk: SnapshotChunk,
+    ) -> Result<RaftResponse> {
+        let request = match begin {
+            RaftRequest::InstallSnapshot { term, leader, last_index, .. } => RaftRequest::InstallSnapshot {
+                term: *term,
+                leader: leader.clone(),
+                last_index: *last_index,
+                chunk,
+            },
+            _ => return Err(CaveyError::Internal("not a snapshot".to_owned())),
+        };
+        self.call(connection, peer, &request)
+    }
+
+    /// Answer a node's messages until it disconnects.
+    fn answer<S: Read + Write>(&self, stream: &mut S) -> Result<()> {
+        let local = Handshake::local(MAX_FRAME_SIZE);
+        let peer = Handshake::read_from(stream)?;
+        stream.write_all(&local.encode())?;
+        local.check_compatible(&peer)?;
+        loop {
+            let request: RaftRequest = read_frame(stream, local.max_frame_size)?;
+            let response = self.lock().handle_request(request, &self.engine, &self.config)?;
+            self.changed.notify_all();
+            write_frame(stream, &response, peer.max_frame_size)?;
+        }
+    }
+
+    /// Apply committed entries to the engine, in order, and hand the results
+    /// to whoever proposed them.  Once the log holds enough entries, drop
+    /// those applied.
+    fn apply_committed(&self) -> Result<()> {
+        let config = &self.config;
+        let applied_path = config.dir.join("raft").join("applied");
+        loop {
+            let (start, entries) = {
+                let mut core = self.lock();
+                while core.applied >= core.commit {
+                    core = self.changed.wait(core).unwrap();
+                }
+                (core.applied + 1, core.entries(core.applied, core.commit).to_vec())
+            };
+            for (index, entry) in (start..).zip(entries) {
+                let core = self.lock();
+                // A snapshot installed since replaced the entries.
+                if core.applied + 1 != index {
+                    break;
+                }
+                let mut engine = lock(&self.engine);
+                drop(core);
+                let result = match entry.command {
+                    Command::Request(msg) => dispatch(msg, &mut **engine, &Access::Open),
+                    Command::Noop | Command::Config { .. } => ServerMessage::Success { value: None },
+                };
+                drop(engine);
+                // Other errors are answers every node gives alike, but these
+                // mean the engine may not have applied the entry.
+                if let ServerMessage::Error {
+                    code: code @ (ErrorCode::Io | ErrorCode::Corruption | ErrorCode::WrongEngine | ErrorCode::Internal),
+                    detail,
+                } = &result
+                {
+                    return Err(CaveyError::from_wire(*code, detail.clone()));
+                }
+                let mut core = self.lock();
+                if core.applied + 1 != index {
+                    break;
+                }
+                core.applied = index;
+                if let Some((term, waiter)) = core.waiters.remove(&index) {
+                    // Another leader's entry replaced the proposer's, which
+                    // was never committed.
+                    let result = if term == entry.term {
+                        result
+                    } else {
+                        CaveyError::NotLeader(core.leader_addr()).into()
+                    };
+                    waiter.send(result).ok();
+                }
+            }
+            let mut core = self.lock();
+            write_atomic(&applied_path, core.applied.to_string().as_bytes())?;
+            if core.log.len() >= config.compact_threshold && core.applied > core.base.index {
+                let applied = core.applied;
+                if let Err(err) = core.compact(applied, config) {
+                    warn!("compacting the raft log failed: {}", err);
+                }
+            }
+            drop(core);
+            self.changed.notify_all();
+        }
+    }
+
+    /// Stop taking part in the cluster, as applying an entry failed with
+    /// `err` and the engine may no longer match the log.
+    fn stop(&self, err: CaveyError) {
+        error!("{} stopped: applying the raft log failed: {}", self.config.id, err);
+        self.lock().fail(&err);
+        self.changed.notify_all();
+    }
+
+    /// Wait until `ready` holds, failing if the request times out first.
+    fn wait_until<'a, F>(&'a self, mut core: MutexGuard<'a, Core>, deadline: Instant, ready: F) -> Result<MutexGuard<'a, Core>>
+    where
+        F: Fn(&Core) -> Result<bool>,
+    {
+        while !ready(&core)? {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(CaveyError::Timeout);
+            }
+            core = self.changed.wait_timeout(core, deadline - now).unwrap().0;
+        }
+        Ok(core)
+    }
+
+    fn check_leader(&self, core: &Core, term: u64) -> Result<()> {
+        core.check_running()?;
+        if core.role == Role::Leader && core.term == term {
+            Ok(())
+        } else {
+            Err(CaveyError::NotLeader(core.leader_addr()))
+        }
+    }
+
+    /// Wait until the leader has applied every entry before it, and a
+    /// majority have confirmed it still leads, so that a read sees every
+    /// write acknowledged before it.
+    fn read_barrier(&self) -> Result<()> {
+        let deadline = Instant::now() + self.config.request_timeout;
+        let mut core = self.lock();
+        let term = core.term;
+        self.check_leader(&core, term)?;
+        // Until it commits an entry of its own term, a new leader doesn't
+        // know which entries are committed.
+        core = self.wait_until(core, deadline, |core| {
+            self.check_leader(core, term)?;
+            Ok(core.term_at(core.commit) == term)
+        })?;
+        let read_index = core.commit;
+        core.read_round += 1;
+        let round = core.read_round;
+        self.changed.notify_all();
+        let id = &self.config.id;
+        self.wait_until(core, deadline, |core| {
+            self.check_leader(core, term)?;
+            let acked = core.quorum(|peer| peer == id || core.progress.get(peer).is_some_and(|p| p.acked_round >= round));
+            Ok(acked && core.applied >= read_index)
+        })
+        .map(drop)
+    }
+
+    /// Append a request to the log and wait for its result.
+    fn propose(&self, command: Command) -> Result<ServerMessage> {
+        let receiver = self.lock().propose(command, &self.config)?;
+        self.changed.notify_all();
+        self.wait_for(receiver)
+    }
+
+    fn wait_for(&self, receiver: mpsc::Receiver<ServerMessage>) -> Result<ServerMessage> {
+        // The outcome of a request that times out is unknown.
+        receiver.recv_timeout(self.config.request_timeout).map_err(|_| CaveyError::Timeout)
+    }
+
+    /// Commit a transaction.  Followers can't check its reads, as their
+    /// engines may version keys differently, so the leader checks them
+    /// against its engine once every earlier entry is applied, and appends
+    /// only the writes.
+    fn commit(&self, keyspace: Option<String>, changeset: Changeset) -> Result<()> {
+        let deadline = Instant::now() + self.config.request_timeout;
+        let core = self.lock();
+        let term = core.term;
+        self.check_leader(&core, term)?;
+        let mut core = self.wait_until(core, deadline, |core| {
+            self.check_leader(core, term)?;
+            Ok(core.applied == core.last_index())
+        })?;
+        {
+            let mut engine = lock(&self.engine);
+            let engine = in_keyspace(&mut **engine, keyspace.as_deref())?;
+            for (key, version) in &changeset.reads {
+                if engine.version(key)? != *version {
+                    return Err(CaveyError::Conflict(key.clone()));
+                }
+            }
+        }
+        let changeset = Changeset {
+            reads: Default::default(),
+            writes: changeset.writes,
+        };
+        let receiver = core.propose(Command::Request(ClientMessage::Commit { keyspace, changeset }), &self.config)?;
+        drop(core);
+        self.changed.notify_all();
+        self.wait_for(receiver)?.into_empty()
+    }
+
+    /// Change the members, once any earlier change is committed.
+    fn reconfigure<F: FnOnce(&mut Vec<Member>) -> Result<()>>(&self, change: F) -> Result<()> {
+        let deadline = Instant::now() + self.config.request_timeout;
+        let core = self.lock();
+        let term = core.term;
+        self.check_leader(&core, term)?;
+        let mut core = self.wait_until(core, deadline, |core| {
+            self.check_leader(core, term)?;
+            Ok(core.config_index <= core.commit)
+        })?;
+        let mut members = core.members.clone();
+        change(&mut members)?;
+        members.sort();
+        info!("changing members to {:?}", members);
+        let receiver = core.propose(Command::Config { members }, &self.config)?;
+        drop(core);
+        self.changed.notify_all();
+        self.wait_for(receiver)?.into_empty()
+    }
+}
+
+/// The engine of a `RaftNode`, as its clients see it.
+pub struct RaftEngine {
+    node: RaftNode,
+    keyspace: Option<String>,
+    selected: Option<Box<RaftEngine>>,
+}
+
+impl RaftEngine {
+    fn shared(&self) -> &Shared {
+        &self.node.shared
+    }
+
+    fn read<T, F: FnOnce(&mut dyn CaveyEngine) -> Result<T>>(&self, f: F) -> Result<T> {
+        self.shared().read_barrier()?;
+        let mut engine = lock(&self.shared().engine);
+        f(in_keyspace(&mut **engine, self.keyspace.as_deref())?)
+    }
+
+    fn propose(&self, msg: ClientMessage) -> Result<ServerMessage> {
+        self.shared().propose(Command::Request(msg))
+    }
+}
+
+impl CaveyEngine for RaftEngine {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.read(|engine| engine.get(key))
+    }
+
+    fn put(&mut self, key: String, value: String) -> Result<()> {
+        let keyspace = self.keyspace.clone();
+        self.propose(ClientMessage::Put { keyspace, key, value })?.into_empty()
+    }
+
+    fn remove(&mut self, key: String) -> Result<bool> {
+        let keyspace = self.keyspace.clone();
+        self.propose(ClientMessage::Remove { keyspace, key })?.into_removed()
+    }
+
+    fn put_returning_old(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let keyspace = self.keyspace.clone();
+        self.propose(ClientMessage::PutReturningOld { keyspace, key, value })?.into_value()
+    }
+
+    fn remove_returning_old(&mut self, key: String) -> Result<Option<String>> {
+        let keyspace = self.keyspace.clone();
+        self.propose(ClientMessage::RemoveReturningOld { keyspace, key })?.into_value()
+    }
+
+    fn version(&mut self, key: &str) -> Result<Option<Version>> {
+        self.read(|engine| engine.version(key))
+    }
+
+    fn scan(&mut self, prefix: &str, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.read(|engine| engine.scan(prefix, after, limit))
+    }
+
+    fn apply_changeset(&mut self, changeset: Changeset) -> Result<()> {
+        self.shared().commit(self.keyspace.clone(), changeset)
+    }
+
+    fn keyspace(&mut self, name: &str) -> Result<&mut dyn CaveyEngine> {
+        if self.keyspace.is_some() {
+            return Err(CaveyError::Unsupported("nested keyspaces".to_owned()));
+        }
+        self.read(|engine| engine.keyspace(name).map(|_| ()))?;
+        let view = self.selected.insert(Box::new(RaftEngine {
+            node: self.node.clone(),
+            keyspace: Some(name.to_owned()),
+            selected: None,
+        }));
+        Ok(&mut **view)
+    }
+
+    fn create_keyspace(&mut self, name: &str) -> Result<()> {
+        let keyspace = name.to_owned();
+        self.propose(ClientMessage::CreateKeyspace { keyspace })?.into_empty()
+    }
+
+    fn drop_keyspace(&mut self, name: &str) -> Result<()> {
+        let keyspace = name.to_owned();
+        self.propose(ClientMessage::DropKeyspace { keyspace })?.into_empty()
+    }
+
+    fn list_keyspaces(&mut self) -> Result<Vec<String>> {
+        self.read(|engine| engine.list_keyspaces())
+    }
+
+    /// Backs up the leader's engine.
+    fn backup(&mut self, dest: &Path) -> Result<()> {
+        self.read(|engine| engine.backup(dest))
+    }
+
+    fn change_feed(&mut self) -> Result<Arc<ChangeFeed>> {
+        self.read(|engine| engine.change_feed())
+    }
+
+    fn cluster_status(&mut self) -> Result<ClusterStatus> {
+        Ok(self.node.status())
+    }
+
+    fn add_member(&mut self, member: Member) -> Result<()> {
+        self.shared().reconfigure(|members| {
+            if !members.iter().any(|existing| existing.id == member.id) {
+                members.push(member);
+            }
+            Ok(())
+        })
+    }
+
+    fn remove_member(&mut self, id: &str) -> Result<()> {
+        self.shared().reconfigure(|members| {
+            members.retain(|member| member.id != id);
+            if members.is_empty() {
+                return Err(CaveyError::Unsupported("removing the last member".to_owned()));
+            }
+            Ok(())
+        })
+    }
+}
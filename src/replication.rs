@@ -0,0 +1,919 @@
+//! Primary/replica replication by log shipping.
+//!
+//! A primary wraps its engine in `Primary`, which numbers every write and
+//! keeps the latest ones in a `ReplicationLog` on disk.  `serve_replication`
+//! streams that log to replicas, each of which runs a `Replica` to apply it
+//! to its own engine.  A replica records how far it has got in a position
+//! file, so after a disconnect or a restart of either side it resumes where
+//! it left off.  A replica that has fallen further behind than the log
+//! reaches, or that last followed another primary, is sent a snapshot
+//! instead.
+//!
+//! Replicas serve reads through `ReadOnly`, which refuses writes.  A
+//! snapshot is received into a separate store, and only copied over the
+//! replica's data once complete, with the engine locked, so readers see the
+//! old data or the new but never a mix.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, prelude::*, BufReader};
+use std::net::SocketAddr;
+use std::ops::DerefMut;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, trace, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{read_frame, write_frame, Handshake, MAX_FRAME_SIZE};
+use crate::store::LogRecord;
+use crate::utils::{lock, write_atomic};
+use crate::{in_keyspace, CaveyEngine, CaveyError, CaveyStore, Changeset, ClientConfig, Listener, Result, ServerConfig};
+use crate::{ChangeFeed, SharedEngine, Transport, Version};
+
+/// Changes read from the log at a time.
+const MAX_BATCH: usize = 1000;
+
+/// Entries read from the engine at a time while taking a snapshot.
+const SNAPSHOT_PAGE: usize = 1000;
+
+/// How often an idle primary tells its replicas it is still there.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a primary waits to read from or write to a replica before
+/// dropping it.
+const REPLICA_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How far a replica has got: the number of changes it has applied from the
+/// log `log_id`.  Each primary's log has its own id, kept across restarts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Position {
+    pub log_id: u64,
+    pub index: u64,
+}
+
+/// One write to a primary.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Change {
+    Write { keyspace: Option<String>, record: LogRecord },
+    /// A transaction's writes, applied together.
+    Commit { keyspace: Option<String>, writes: BTreeMap<String, Option<String>> },
+    CreateKeyspace { name: String },
+    DropKeyspace { name: String },
+}
+
+impl Change {
+    /// Roughly how many bytes the change takes on the wire.
+    fn size(&self) -> usize {
+        match self {
+            Change::Write { keyspace, record } => {
+                let record = match record {
+                    LogRecord::Put { key, value } => key.len() + value.len(),
+                    LogRecord::Remove { key } => key.len(),
+                    LogRecord::Batch { .. } => 0,
+                };
+                keyspace.as_ref().map_or(0, String::len) + record
+            }
+            Change::Commit { keyspace, writes } => {
+                let writes: usize = writes.iter().map(|(key, value)| key.len() + value.as_ref().map_or(0, String::len)).sum();
+                keyspace.as_ref().map_or(0, String::len) + writes
+            }
+            Change::CreateKeyspace { name } | Change::DropKeyspace { name } => name.len(),
+        }
+    }
+
+    /// Apply the change to a replica's engine.  Changes may be applied
+    /// again after a crash, or on top of a snapshot that already reflects
+    /// them, so applying one twice is harmless.
+    fn apply(self, engine: &mut dyn CaveyEngine) -> Result<()> {
+        let result = match self {
+            Change::Write { keyspace, record } => in_keyspace(engine, keyspace.as_deref()).and_then(|engine| {
+                match record {
+                    LogRecord::Put { key, value } => engine.put(key, value),
+                    LogRecord::Remove { key } => engine.remove(key).map(|_| ()),
+                    // Only store logs hold batch headers.
+                    LogRecord::Batch { .. } => Ok(()),
+                }
+            }),
+            Change::Commit { keyspace, writes } => in_keyspace(engine, keyspace.as_deref())
+                .and_then(|engine| engine.apply_changeset(Changeset { reads: BTreeMap::new(), writes })),
+            Change::CreateKeyspace { name } => engine.create_keyspace(&name),
+            Change::DropKeyspace { name } => engine.drop_keyspace(&name),
+        };
+        match result {
+            // A keyspace missing from a snapshot was dropped later in the
+            // log, so writes to it can be skipped.
+            Err(CaveyError::KeyspaceExists(_)) | Err(CaveyError::KeyspaceNotFound(_)) => Ok(()),
+            result => result,
+        }
+    }
+}
+
+/// The latest writes to a primary, kept for replicas to catch up from.
+///
+/// The log is a file of JSON lines: a header with the log's id and the
+/// index of the first change, then the changes.  It is rewritten without
+/// the changes no longer kept once it holds twice as many as that.
+pub struct ReplicationLog {
+    log_id: u64,
+    capacity: usize,
+    path: PathBuf,
+    state: Mutex<LogState>,
+    appended: Condvar,
+}
+
+#[derive(Deserialize, Serialize)]
+struct LogHeader {
+    log_id: u64,
+    first: u64,
+}
+
+struct LogState {
+    /// The index of the oldest change kept.
+    first: u64,
+    changes: VecDeque<Change>,
+    file: File,
+    /// The number of changes in the file, which holds those before `first`
+    /// until it is rewritten.
+    on_disk: usize,
+    /// The length of the file before a change written ahead of the engine,
+    /// until it is published or discarded.
+    pending: Option<u64>,
+}
+
+impl LogState {
+    fn next(&self) -> u64 {
+        self.first + self.changes.len() as u64
+    }
+}
+
+enum LogRead {
+    Changes(Vec<Change>),
+    /// Nothing after the position yet.
+    Idle,
+    /// The position is no longer in the log.
+    Behind,
+}
+
+impl ReplicationLog {
+    /// Open the log in the file `path`, creating it if it doesn't exist,
+    /// keeping the latest `capacity` changes.  Replicas further behind than
+    /// that are sent a snapshot.
+    pub fn open<P: AsRef<Path>>(path: P, capacity: usize) -> Result<ReplicationLog> {
+        let path = path.as_ref().to_owned();
+        if !path.exists() {
+            let header = LogHeader {
+                log_id: RandomState::new().build_hasher().finish(),
+                first: 0,
+            };
+            let mut contents = serde_json::to_vec(&header)?;
+            contents.push(b'\n');
+            write_atomic(&path, &contents)?;
+        }
+        let mut reader = BufReader::new(File::open(&path)?);
+        let mut line = Vec::new();
+        reader.read_until(b'\n', &mut line)?;
+        let header: LogHeader = serde_json::from_slice(&line)?;
+        let mut len = line.len() as u64;
+        let mut changes = VecDeque::new();
+        loop {
+            line.clear();
+            reader.read_until(b'\n', &mut line)?;
+            // A crash while appending leaves a partial last line, for a
+            // change that was never acknowledged.
+            if !line.ends_with(b"\n") {
+                break;
+            }
+            changes.push_back(serde_json::from_slice(&line)?);
+            len += line.len() as u64;
+        }
+        let file = OpenOptions::new().append(true).open(&path)?;
+        file.set_len(len)?;
+        let on_disk = changes.len();
+        let mut first = header.first;
+        while changes.len() > capacity {
+            changes.pop_front();
+            first += 1;
+        }
+        Ok(ReplicationLog {
+            log_id: header.log_id,
+            capacity,
+            path,
+            state: Mutex::new(LogState {
+                first,
+                changes,
+                file,
+                on_disk,
+                pending: None,
+            }),
+            appended: Condvar::new(),
+        })
+    }
+
+    /// The position after the latest change.
+    pub fn position(&self) -> Position {
+        Position {
+            log_id: self.log_id,
+            index: lock(&self.state).next(),
+        }
+    }
+
+    /// The latest change, which the engine may not have applied if the
+    /// primary stopped while applying it.
+    fn last(&self) -> Option<Change> {
+        lock(&self.state).changes.back().cloned()
+    }
+
+    /// Write `change` to disk, before the engine applies it.  Replicas
+    /// aren't sent it until it is published.
+    fn write_ahead(&self, change: &Change) -> Result<()> {
+        let mut state = lock(&self.state);
+        // Left by a write that panicked
+        if let Some(len) = state.pending.take() {
+            state.file.set_len(len)?;
+        }
+        let len = state.file.metadata()?.len();
+        let mut line = serde_json::to_vec(change)?;
+        line.push(b'\n');
+        if let Err(err) = state.file.write_all(&line).and_then(|()| state.file.sync_data()) {
+            state.file.set_len(len)?;
+            return Err(err.into());
+        }
+        state.pending = Some(len);
+        Ok(())
+    }
+
+    /// Send replicas `change`, written ahead and now applied by the engine.
+    fn publish(&self, change: Change) {
+        let mut state = lock(&self.state);
+        state.pending = None;
+        state.changes.push_back(change);
+        state.on_disk += 1;
+        while state.changes.len() > self.capacity {
+            state.changes.pop_front();
+            state.first += 1;
+        }
+        if state.on_disk > 2 * self.capacity.max(1) {
+            // The changes are safe on disk already, so this can wait for
+            // the next write.
+            if let Err(err) = self.rewrite(&mut state) {
+                warn!("rewriting replication log failed: {}", err);
+            }
+        }
+        self.appended.notify_all();
+    }
+
+    /// Remove the change written ahead, which the engine didn't apply or
+    /// which changed nothing.
+    fn discard(&self) -> Result<()> {
+        let mut state = lock(&self.state);
+        if let Some(len) = state.pending.take() {
+            state.file.set_len(len)?;
+        }
+        Ok(())
+    }
+
+    /// Replace the file with one holding only the changes kept.
+    fn rewrite(&self, state: &mut LogState) -> Result<()> {
+        let header = LogHeader {
+            log_id: self.log_id,
+            first: state.first,
+        };
+        let mut contents = serde_json::to_vec(&header)?;
+        contents.push(b'\n');
+        for change in &state.changes {
+            serde_json::to_writer(&mut contents, change)?;
+            contents.push(b'\n');
+        }
+        write_atomic(&self.path, &contents)?;
+        state.file = OpenOptions::new().append(true).open(&self.path)?;
+        state.on_disk = state.changes.len();
+        Ok(())
+    }
+
+    /// Changes from index `from` on, waiting up to `timeout` for one if
+    /// there are none yet.
+    fn read(&self, from: u64, timeout: Duration) -> LogRead {
+        let mut state = lock(&self.state);
+        if from == state.next() && !timeout.is_zero() {
+            state = self
+                .appended
+                .wait_timeout_while(state, timeout, |state| state.next() == from)
+                .unwrap()
+                .0;
+        }
+        if from < state.first || from > state.next() {
+            LogRead::Behind
+        } else if from == state.next() {
+            LogRead::Idle
+        } else {
+            let start = (from - state.first) as usize;
+            let end = state.changes.len().min(start + MAX_BATCH);
+            LogRead::Changes(state.changes.range(start..end).cloned().collect())
+        }
+    }
+}
+
+/// A primary's engine: `engine`, with every write that changes something
+/// appended to `log`.
+pub struct Primary {
+    engine: SharedEngine,
+    keyspace: Option<String>,
+    log: Arc<ReplicationLog>,
+    // The view of a keyspace last handed out by `keyspace`.
+    selected: Option<Box<Primary>>,
+}
+
+impl Primary {
+    /// Wrap `engine`, first applying the latest change in `log` again, in
+    /// case the primary stopped after logging it but before applying it.
+    pub fn new(mut engine: Box<dyn CaveyEngine>, log: Arc<ReplicationLog>) -> Result<Primary> {
+        if let Some(change) = log.last() {
+            change.apply(&mut *engine)?;
+        }
+        Ok(Primary {
+            engine: Arc::new(Mutex::new(engine)),
+            keyspace: None,
+            log,
+            selected: None,
+        })
+    }
+
+    fn with<T, F: FnOnce(&mut dyn CaveyEngine) -> Result<T>>(&self, f: F) -> Result<T> {
+        let mut engine = lock(&self.engine);
+        f(in_keyspace(&mut **engine, self.keyspace.as_deref())?)
+    }
+
+    /// Log `change`, then run the write `f`, and send replicas the change
+    /// if `changed` says it changed anything.  The engine stays locked
+    /// throughout, so the log is in the order the engine applied its
+    /// changes.
+    fn write<T, F, C>(&self, change: Change, f: F, changed: C) -> Result<T>
+    where
+        F: FnOnce(&mut dyn CaveyEngine) -> Result<T>,
+        C: FnOnce(&T) -> bool,
+    {
+        let mut engine = lock(&self.engine);
+        let engine = in_keyspace(&mut **engine, self.keyspace.as_deref())?;
+        self.log.write_ahead(&change)?;
+        match f(engine) {
+            Ok(result) if changed(&result) => {
+                self.log.publish(change);
+                Ok(result)
+            }
+            result => {
+                self.log.discard()?;
+                result
+            }
+        }
+    }
+
+    fn record(&self, record: LogRecord) -> Change {
+        Change::Write {
+            keyspace: self.keyspace.clone(),
+            record,
+        }
+    }
+}
+
+impl CaveyEngine for Primary {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.with(|engine| engine.get(key))
+    }
+
+    fn put(&mut self, key: String, value: String) -> Result<()> {
+        let change = self.record(LogRecord::Put { key: key.clone(), value: value.clone() });
+        self.write(change, |engine| engine.put(key, value), |_| true)
+    }
+
+    fn remove(&mut self, key: String) -> Result<bool> {
+        let change = self.record(LogRecord::Remove { key: key.clone() });
+        self.write(change, |engine| engine.remove(key), |removed| *removed)
+    }
+
+    fn put_returning_old(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let change = self.record(LogRecord::Put { key: key.clone(), value: value.clone() });
+        self.write(change, |engine| engine.put_returning_old(key, value), |_| true)
+    }
+
+    fn remove_returning_old(&mut self, key: String) -> Result<Option<String>> {
+        let change = self.record(LogRecord::Remove { key: key.clone() });
+        self.write(change, |engine| engine.remove_returning_old(key), Option::is_some)
+    }
+
+    fn version(&mut self, key: &str) -> Result<Option<Version>> {
+        self.with(|engine| engine.version(key))
+    }
+
+    fn scan(&mut self, prefix: &str, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.with(|engine| engine.scan(prefix, after, limit))
+    }
+
+    fn apply_changeset(&mut self, changeset: Changeset) -> Result<()> {
+        let change = Change::Commit {
+            keyspace: self.keyspace.clone(),
+            writes: changeset.writes.clone(),
+        };
+        self.write(change, |engine| engine.apply_changeset(changeset), |_| true)
+    }
+
+    fn keyspace(&mut self, name: &str) -> Result<&mut dyn CaveyEngine> {
+        if self.keyspace.is_some() {
+            return Err(CaveyError::Unsupported("nested keyspaces".to_owned()));
+        }
+        lock(&self.engine).keyspace(name)?;
+        let view = self.selected.insert(Box::new(Primary {
+            engine: self.engine.clone(),
+            keyspace: Some(name.to_owned()),
+            log: self.log.clone(),
+            selected: None,
+        }));
+        Ok(&mut **view)
+    }
+
+    fn create_keyspace(&mut self, name: &str) -> Result<()> {
+        let change = Change::CreateKeyspace { name: name.to_owned() };
+        self.write(change, |engine| engine.create_keyspace(name), |_| true)
+    }
+
+    fn drop_keyspace(&mut self, name: &str) -> Result<()> {
+        let change = Change::DropKeyspace { name: name.to_owned() };
+        self.write(change, |engine| engine.drop_keyspace(name), |_| true)
+    }
+
+    fn list_keyspaces(&mut self) -> Result<Vec<String>> {
+        self.with(|engine| engine.list_keyspaces())
+    }
+
+    fn backup(&mut self, dest: &Path) -> Result<()> {
+        self.with(|engine| engine.backup(dest))
+    }
+
+    fn change_feed(&mut self) -> Result<Arc<ChangeFeed>> {
+        self.with(|engine| engine.change_feed())
+    }
+}
+
+/// A replica's engine as its clients see it: reads go to `engine`, which a
+/// `Replica` keeps up to date, and writes fail with `ReadOnly`.
+pub struct ReadOnly {
+    engine: SharedEngine,
+    keyspace: Option<String>,
+    selected: Option<Box<ReadOnly>>,
+}
+
+impl ReadOnly {
+    pub fn new(engine: SharedEngine) -> ReadOnly {
+        ReadOnly {
+            engine,
+            keyspace: None,
+            selected: None,
+        }
+    }
+
+    fn with<T, F: FnOnce(&mut dyn CaveyEngine) -> Result<T>>(&self, f: F) -> Result<T> {
+        let mut engine = lock(&self.engine);
+        f(in_keyspace(&mut **engine, self.keyspace.as_deref())?)
+    }
+}
+
+impl CaveyEngine for ReadOnly {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.with(|engine| engine.get(key))
+    }
+
+    fn put(&mut self, _key: String, _value: String) -> Result<()> {
+        Err(CaveyError::ReadOnly)
+    }
+
+    fn remove(&mut self, _key: String) -> Result<bool> {
+        Err(CaveyError::ReadOnly)
+    }
+
+    fn put_returning_old(&mut self, _key: String, _value: String) -> Result<Option<String>> {
+        Err(CaveyError::ReadOnly)
+    }
+
+    fn remove_returning_old(&mut self, _key: String) -> Result<Option<String>> {
+        Err(CaveyError::ReadOnly)
+    }
+
+    fn version(&mut self, key: &str) -> Result<Option<Version>> {
+        self.with(|engine| engine.version(key))
+    }
+
+    fn scan(&mut self, prefix: &str, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.with(|engine| engine.scan(prefix, after, limit))
+    }
+
+    fn apply_changeset(&mut self, _changeset: Changeset) -> Result<()> {
+        Err(CaveyError::ReadOnly)
+    }
+
+    fn keyspace(&mut self, name: &str) -> Result<&mut dyn CaveyEngine> {
+        if self.keyspace.is_some() {
+            return Err(CaveyError::Unsupported("nested keyspaces".to_owned()));
+        }
+        lock(&self.engine).keyspace(name)?;
+        let view = self.selected.insert(Box::new(ReadOnly {
+            engine: self.engine.clone(),
+            keyspace: Some(name.to_owned()),
+            selected: None,
+        }));
+        Ok(&mut **view)
+    }
+
+    fn create_keyspace(&mut self, _name: &str) -> Result<()> {
+        Err(CaveyError::ReadOnly)
+    }
+
+    fn drop_keyspace(&mut self, _name: &str) -> Result<()> {
+        Err(CaveyError::ReadOnly)
+    }
+
+    fn list_keyspaces(&mut self) -> Result<Vec<String>> {
+        self.with(|engine| engine.list_keyspaces())
+    }
+
+    fn backup(&mut self, dest: &Path) -> Result<()> {
+        self.with(|engine| engine.backup(dest))
+    }
+
+    fn restore(&mut self, _src: &Path) -> Result<()> {
+        Err(CaveyError::ReadOnly)
+    }
+
+    fn change_feed(&mut self) -> Result<Arc<ChangeFeed>> {
+        self.with(|engine| engine.change_feed())
+    }
+}
+
+/// Messages from a replica.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ReplicaMessage {
+    /// Send the changes after `position`, or a snapshot if the primary
+    /// can't.
+    Subscribe { position: Option<Position> },
+}
+
+/// Messages from a primary.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum PrimaryMessage {
+    /// A snapshot follows, after which changes resume from `position`.  The
+    /// replica should discard everything it holds.
+    SnapshotBegin { position: Position },
+    SnapshotKeyspace { name: String },
+    SnapshotEntries { keyspace: Option<String>, entries: Vec<(String, String)> },
+    SnapshotEnd,
+    /// Changes numbered from `start`.
+    Changes { start: u64, changes: Vec<Change> },
+    /// The replica has every change so far.  Repeated while the primary is
+    /// idle, so replicas can tell it is still there.
+    CaughtUp,
+}
+
+/// Stream `log` to replicas connecting on `socket`, with a thread for each.
+/// `engine` is the `Primary` writing to the log, which is read to send
+/// snapshots.
+pub fn serve_replication<'e, L, E>(socket: &L, engine: &Mutex<E>, log: &ReplicationLog, config: &ServerConfig) -> Result<()>
+where
+    L: Listener,
+    E: DerefMut<Target = dyn CaveyEngine + 'e> + Send,
+{
+    thread::scope(|scope| loop {
+        match socket.accept() {
+            Ok(mut stream) => {
+                trace!("replica connected");
+                scope.spawn(move || {
+                    let result = L::set_timeout(&stream, Some(REPLICA_TIMEOUT))
+                        .map_err(CaveyError::from)
+                        .and_then(|()| stream_to_replica(&mut stream, engine, log, config));
+                    if let Err(err) = result {
+                        warn!("replica disconnected: {}", err);
+                    }
+                });
+            }
+            Err(err) => {
+                error!("replica connection failed: {}", err);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn stream_to_replica<'e, S, E>(stream: &mut S, engine: &Mutex<E>, log: &ReplicationLog, config: &ServerConfig) -> Result<()>
+where
+    S: Read + Write,
+    E: DerefMut<Target = dyn CaveyEngine + 'e>,
+{
+    let local = Handshake::local(config.max_frame_size);
+    let peer = Handshake::read_from(stream)?;
+    stream.write_all(&local.encode())?;
+    local.check_compatible(&peer)?;
+    let ReplicaMessage::Subscribe { position } = read_frame(stream, local.max_frame_size)?;
+    let mut sender = Sender { stream, peer };
+    let mut index = match position {
+        Some(position) if position.log_id == log.log_id => position.index,
+        // Out of the log, so `read` finds it behind
+        _ => u64::MAX,
+    };
+    let mut caught_up = false;
+    loop {
+        let timeout = if caught_up { HEARTBEAT_INTERVAL } else { Duration::ZERO };
+        match log.read(index, timeout) {
+            LogRead::Changes(changes) => {
+                for changes in chunks(changes, Change::size, sender.budget()) {
+                    let start = index;
+                    index += changes.len() as u64;
+                    sender.send(&PrimaryMessage::Changes { start, changes })?;
+                }
+                caught_up = false;
+            }
+            LogRead::Idle => {
+                sender.send(&PrimaryMessage::CaughtUp)?;
+                caught_up = true;
+            }
+            LogRead::Behind => {
+                index = send_snapshot(&mut sender, engine, log)?;
+                caught_up = false;
+            }
+        }
+    }
+}
+
+struct Sender<'a, S> {
+    stream: &'a mut S,
+    peer: Handshake,
+}
+
+impl<S: Write> Sender<'_, S> {
+    fn send(&mut self, msg: &PrimaryMessage) -> Result<()> {
+        write_frame(self.stream, msg, self.peer.max_frame_size)
+    }
+
+    /// Bytes of keys and values to send in one frame, leaving room for
+    /// encoding overhead.
+    fn budget(&self) -> usize {
+        self.peer.max_frame_size as usize / 2
+    }
+}
+
+/// Split `items` into runs of at most `budget` bytes, each with at least one
+/// item.
+pub(crate) fn chunks<T>(items: Vec<T>, size: impl Fn(&T) -> usize, budget: usize) -> Vec<Vec<T>> {
+    let mut chunks = Vec::new();
+    let mut chunk = Vec::new();
+    let mut used = 0;
+    for item in items {
+        let item_size = size(&item);
+        if !chunk.is_empty() && used + item_size > budget {
+            chunks.push(std::mem::take(&mut chunk));
+            used = 0;
+        }
+        used += item_size;
+        chunk.push(item);
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+/// Send every keyspace, a page at a time, returning the index changes
+/// resume from.
+///
+/// The engine is only locked for a page at a time, so the snapshot isn't
+/// of one moment.  But it reflects every change before the position taken
+/// at its start, and replaying the changes after that brings the replica
+/// into line.
+fn send_snapshot<'e, S, E>(sender: &mut Sender<S>, engine: &Mutex<E>, log: &ReplicationLog) -> Result<u64>
+where
+    S: Write,
+    E: DerefMut<Target = dyn CaveyEngine + 'e>,
+{
+    let position = log.position();
+    info!("sending snapshot at {:?}", position);
+    sender.send(&PrimaryMessage::SnapshotBegin { position })?;
+    send_entries(sender, engine, None)?;
+    let names = lock(engine).list_keyspaces()?;
+    for name in names {
+        sender.send(&PrimaryMessage::SnapshotKeyspace { name: name.clone() })?;
+        match send_entries(sender, engine, Some(name)) {
+            // Dropped since it was listed, as the log will tell the replica
+            Err(CaveyError::KeyspaceNotFound(_)) => {}
+            result => result?,
+        }
+    }
+    sender.send(&PrimaryMessage::SnapshotEnd)?;
+    Ok(position.index)
+}
+
+fn send_entries<'e, S, E>(sender: &mut Sender<S>, engine: &Mutex<E>, keyspace: Option<String>) -> Result<()>
+where
+    S: Write,
+    E: DerefMut<Target = dyn CaveyEngine + 'e>,
+{
+    let mut after = None;
+    loop {
+        let page = {
+            let mut engine = lock(engine);
+            in_keyspace(&mut **engine, keyspace.as_deref())?.scan("", after.as_deref(), SNAPSHOT_PAGE)?
+        };
+        let last = match page.last() {
+            Some((key, _)) => key.clone(),
+            None => return Ok(()),
+        };
+        let done = page.len() < SNAPSHOT_PAGE;
+        for entries in chunks(page, |(key, value)| key.len() + value.len(), sender.budget()) {
+            let keyspace = keyspace.clone();
+            sender.send(&PrimaryMessage::SnapshotEntries { keyspace, entries })?;
+        }
+        if done {
+            return Ok(());
+        }
+        after = Some(last);
+    }
+}
+
+/// Keeps `engine` up to date with a primary reached over `primary`.  How
+/// far it has got is kept in the file `position_file`, and snapshots are
+/// received into a store in the directory beside it with the extension
+/// `snapshot`.
+pub struct Replica<T: Transport = SocketAddr> {
+    primary: T,
+    engine: SharedEngine,
+    position_file: PathBuf,
+    config: ClientConfig,
+}
+
+impl<T: Transport> Replica<T> {
+    /// `config.read_timeout` bounds how long the replica waits to hear from
+    /// the primary before reconnecting, and `config.retry` how long it
+    /// waits between attempts.
+    ///
+    /// A snapshot received in full but not yet copied over `engine` when the
+    /// replica last stopped is copied over it first.
+    pub fn new(primary: T, engine: SharedEngine, position_file: PathBuf, config: ClientConfig) -> Result<Replica<T>> {
+        let replica = Replica {
+            primary,
+            engine,
+            position_file,
+            config,
+        };
+        replica.install_snapshot()?;
+        Ok(replica)
+    }
+
+    /// The position the replica has reached, if it has ever finished
+    /// catching up.
+    pub fn position(&self) -> Result<Option<Position>> {
+        match fs::read(&self.position_file) {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Connect to the primary and apply changes until the replica has every
+    /// change the primary had, returning the position reached.
+    pub fn catch_up(&self) -> Result<Position> {
+        self.sync(false)
+    }
+
+    /// Follow the primary forever, reconnecting whenever the connection
+    /// fails.
+    pub fn run(&self) -> ! {
+        let retry = &self.config.retry;
+        let mut backoff = retry.initial_backoff;
+        loop {
+            let started = Instant::now();
+            if let Err(err) = self.sync(true) {
+                warn!("replication from primary failed: {}", err);
+            }
+            // Only back off further if the primary keeps failing at once.
+            if started.elapsed() > retry.max_backoff {
+                backoff = retry.initial_backoff;
+            }
+            debug!("reconnecting to primary in {:?}", backoff);
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(retry.max_backoff);
+        }
+    }
+
+    /// Apply changes from the primary until caught up, or, if `follow`,
+    /// until the connection fails.
+    fn sync(&self, follow: bool) -> Result<Position> {
+        let mut position = self.position()?;
+        let mut stream = self.primary.connect(&self.config)?;
+        let local = Handshake::local(MAX_FRAME_SIZE);
+        stream.write_all(&local.encode())?;
+        let primary = Handshake::read_from(&mut stream)?;
+        local.check_compatible(&primary)?;
+        write_frame(&mut stream, &ReplicaMessage::Subscribe { position }, primary.max_frame_size)?;
+        let mut snapshot = None;
+        loop {
+            let msg = match read_frame(&mut stream, local.max_frame_size) {
+                Err(CaveyError::Io(err)) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    return Err(CaveyError::Timeout);
+                }
+                result => result?,
+            };
+            match msg {
+                PrimaryMessage::SnapshotBegin { position: at } => {
+                    info!("receiving snapshot at {:?}", at);
+                    // Until the snapshot is installed, the primary's changes
+                    // follow data the replica doesn't have.
+                    position = None;
+                    let staging = self.staging_dir();
+                    if staging.exists() {
+                        fs::remove_dir_all(&staging)?;
+                    }
+                    snapshot = Some((at, CaveyStore::open(staging)?));
+                }
+                PrimaryMessage::SnapshotKeyspace { name } => {
+                    let (_, staged) = snapshot.as_mut().ok_or_else(|| unexpected("snapshot keyspace"))?;
+                    staged.create_keyspace(&name)?;
+                }
+                PrimaryMessage::SnapshotEntries { keyspace, entries } => {
+                    let (_, staged) = snapshot.as_mut().ok_or_else(|| unexpected("snapshot entries"))?;
+                    let engine = in_keyspace(staged, keyspace.as_deref())?;
+                    for (key, value) in entries {
+                        engine.put(key, value)?;
+                    }
+                }
+                PrimaryMessage::SnapshotEnd => {
+                    let (at, staged) = snapshot.take().ok_or_else(|| unexpected("snapshot end"))?;
+                    drop(staged);
+                    write_atomic(&self.staging_dir().join("position.json"), &serde_json::to_vec(&at)?)?;
+                    position = self.install_snapshot()?;
+                }
+                PrimaryMessage::Changes { start, changes } => {
+                    let at = match &mut position {
+                        Some(at) if at.index == start => at,
+                        _ => return Err(unexpected("changes")),
+                    };
+                    at.index += changes.len() as u64;
+                    let mut engine = lock(&self.engine);
+                    for change in changes {
+                        change.apply(&mut **engine)?;
+                    }
+                    drop(engine);
+                    self.save_position(position)?;
+                }
+                PrimaryMessage::CaughtUp => match position {
+                    Some(position) if !follow => return Ok(position),
+                    Some(_) => trace!("replica is up to date"),
+                    None => return Err(unexpected("caught up")),
+                },
+            }
+        }
+    }
+
+    fn staging_dir(&self) -> PathBuf {
+        self.position_file.with_extension("snapshot")
+    }
+
+    /// Copy a snapshot received in full over the engine, returning the
+    /// position it was taken at.  The engine stays locked until the
+    /// position is saved, and a crash part way leaves the snapshot to be
+    /// copied again.
+    fn install_snapshot(&self) -> Result<Option<Position>> {
+        let staging = self.staging_dir();
+        let position = match fs::read(staging.join("position.json")) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        info!("installing snapshot at {:?}", position);
+        let mut engine = lock(&self.engine);
+        engine.restore(&staging)?;
+        self.save_position(Some(position))?;
+        drop(engine);
+        fs::remove_dir_all(&staging)?;
+        Ok(Some(position))
+    }
+
+    /// Record the position, replacing the file so that a crash leaves
+    /// either the old position or the new one.
+    fn save_position(&self, position: Option<Position>) -> Result<()> {
+        match position {
+            Some(position) => write_atomic(&self.position_file, &serde_json::to_vec(&position)?)?,
+            None => match fs::remove_file(&self.position_file) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            },
+        }
+        Ok(())
+    }
+}
+
+fn unexpected(what: &str) -> CaveyError {
+    CaveyError::Protocol(format!("unexpected {} from primary", what))
+}
@@ -0,0 +1,560 @@
+//! A frontend speaking a subset of the Redis protocol, RESP, so that
+//! redis-cli and Redis client libraries can be pointed at cavey.
+//!
+//! The supported commands are GET, SET (with EX or PX), DEL, EXISTS, MGET,
+//! MSET, SCAN (with MATCH and COUNT), PING, EXPIRE and QUIT, all acting on
+//! the default keyspace.  Engines have no notion of expiry, so expiry times
+//! are kept in memory by the frontend: they are lost when the server
+//! restarts, and are not cleared by writes made through the native protocol.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::net::{TcpListener, TcpStream};
+use std::ops::DerefMut;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, trace};
+
+use crate::{CaveyEngine, CaveyError, Result, ServerConfig};
+use crate::utils::lock;
+
+/// How often keys past their expiry time are removed.
+const SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// SCAN cursors remembered at once.  Clients holding older cursors get an
+/// error and must start their scan again.
+const MAX_CURSORS: usize = 1024;
+
+/// Keys returned by SCAN without a COUNT.
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Serve RESP clients on `socket`, with a thread for each connection,
+/// applying their commands to `engine`.  The engine may be shared with the
+/// native protocol's server.
+pub fn serve_resp<'e, E>(socket: &TcpListener, engine: &Mutex<E>, config: &ServerConfig) -> Result<()>
+where
+    E: DerefMut<Target = dyn CaveyEngine + 'e> + Send,
+{
+    let frontend = Frontend {
+        engine,
+        config,
+        state: Mutex::new(State::default()),
+    };
+    let frontend = &frontend;
+    thread::scope(|scope| {
+        scope.spawn(move || loop {
+            thread::sleep(SWEEP_INTERVAL);
+            if let Err(err) = frontend.sweep() {
+                error!("failed to remove expired keys: {}", err);
+            }
+        });
+        for stream in socket.incoming() {
+            match stream {
+                Ok(stream) => {
+                    trace!("resp connection accepted");
+                    scope.spawn(move || {
+                        if let Err(err) = frontend.handle_connection(stream) {
+                            error!("resp connection failed: {}", err);
+                        }
+                    });
+                }
+                Err(err) => {
+                    error!("resp connection failed: {}", err);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+struct Frontend<'a, E> {
+    engine: &'a Mutex<E>,
+    config: &'a ServerConfig,
+    // Locked after `engine`, never before.
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    // Keys given an expiry time by EXPIRE or SET.
+    expiries: HashMap<String, Instant>,
+    // Redis clients expect numeric SCAN cursors, while engines page by key,
+    // so each cursor handed out stands for the last key returned.
+    cursors: BTreeMap<u64, String>,
+    last_cursor: u64,
+}
+
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+impl<'a, 'e, E> Frontend<'a, E>
+where
+    E: DerefMut<Target = dyn CaveyEngine + 'e>,
+{
+    /// Serve commands from one client until it closes the connection.
+    fn handle_connection(&self, stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        loop {
+            let args = match read_command(&mut reader, self.config.max_frame_size as usize) {
+                Ok(Some(args)) => args,
+                Ok(None) => {
+                    trace!("resp connection closed");
+                    return Ok(());
+                }
+                Err(err @ CaveyError::Io(_)) => return Err(err),
+                // The rest of the stream can't be trusted after a bad command.
+                Err(err) => {
+                    write_reply(&mut writer, &err_reply(&err)).ok();
+                    writer.flush().ok();
+                    return Err(err);
+                }
+            };
+            let args = match args.into_iter().map(String::from_utf8).collect::<std::result::Result<Vec<_>, _>>() {
+                Ok(args) => args,
+                Err(_) => {
+                    write_reply(&mut writer, &Reply::Error("ERR arguments must be valid UTF-8".to_owned()))?;
+                    writer.flush()?;
+                    continue;
+                }
+            };
+            let (command, args) = match args.split_first() {
+                Some((command, args)) => (command.to_ascii_uppercase(), args),
+                None => continue,
+            };
+            debug!("resp: received command: {} {:?}", command, args);
+            if command == "QUIT" {
+                write_reply(&mut writer, &Reply::Status("OK"))?;
+                writer.flush()?;
+                return Ok(());
+            }
+            let reply = self.execute(&command, args);
+            write_reply(&mut writer, &reply)?;
+            // Send replies to pipelined commands together.
+            if reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+        }
+    }
+
+    fn execute(&self, command: &str, args: &[String]) -> Reply {
+        let mut engine = lock(self.engine);
+        let mut state = lock(&self.state);
+        let mut context = Context {
+            engine: &mut **engine,
+            state: &mut state,
+            config: self.config,
+        };
+        match context.execute(command, args) {
+            Ok(reply) => reply,
+            Err(err) => err_reply(&err),
+        }
+    }
+
+    fn sweep(&self) -> Result<()> {
+        if lock(&self.state).expiries.is_empty() {
+            return Ok(());
+        }
+        let mut engine = lock(self.engine);
+        let mut state = lock(&self.state);
+        let now = Instant::now();
+        let expired: Vec<String> = state
+            .expiries
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            state.expiries.remove(&key);
+            engine.remove(key)?;
+        }
+        Ok(())
+    }
+}
+
+/// The engine and frontend state, locked for one command.
+struct Context<'a> {
+    engine: &'a mut dyn CaveyEngine,
+    state: &'a mut State,
+    config: &'a ServerConfig,
+}
+
+impl<'a> Context<'a> {
+    fn execute(&mut self, command: &str, args: &[String]) -> Result<Reply> {
+        match (command, args) {
+            ("PING", []) => Ok(Reply::Status("PONG")),
+            ("PING", [message]) => Ok(Reply::Bulk(Some(message.clone()))),
+            ("GET", [key]) => Ok(Reply::Bulk(self.get(key)?)),
+            ("SET", [key, value, options @ ..]) => self.set(key, value, options),
+            ("DEL", keys) if !keys.is_empty() => {
+                let mut removed = 0;
+                for key in keys {
+                    if self.exists(key)? {
+                        self.state.expiries.remove(key);
+                        self.engine.remove(key.clone())?;
+                        removed += 1;
+                    }
+                }
+                Ok(Reply::Integer(removed))
+            }
+            ("EXISTS", keys) if !keys.is_empty() => {
+                let mut found = 0;
+                for key in keys {
+                    if self.exists(key)? {
+                        found += 1;
+                    }
+                }
+                Ok(Reply::Integer(found))
+            }
+            ("MGET", keys) if !keys.is_empty() => {
+                let mut values = Vec::with_capacity(keys.len());
+                for key in keys {
+                    values.push(Reply::Bulk(self.get(key)?));
+                }
+                Ok(Reply::Array(values))
+            }
+            ("MSET", pairs) if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+                for pair in pairs.chunks(2) {
+                    self.config.check_key(&pair[0])?;
+                    self.config.check_value(&pair[1])?;
+                }
+                for pair in pairs.chunks(2) {
+                    self.state.expiries.remove(&pair[0]);
+                    self.engine.put(pair[0].clone(), pair[1].clone())?;
+                }
+                Ok(Reply::Status("OK"))
+            }
+            ("SCAN", [cursor, options @ ..]) => self.scan(cursor, options),
+            ("EXPIRE", [key, seconds]) => {
+                let seconds: i64 = match seconds.parse() {
+                    Ok(seconds) => seconds,
+                    Err(_) => return Ok(not_an_integer()),
+                };
+                let deadline = match seconds {
+                    seconds if seconds <= 0 => None,
+                    seconds => match deadline_after(Duration::from_secs(seconds as u64)) {
+                        Some(deadline) => Some(deadline),
+                        None => return Ok(invalid_expire_time("expire")),
+                    },
+                };
+                if !self.exists(key)? {
+                    return Ok(Reply::Integer(0));
+                }
+                match deadline {
+                    Some(deadline) => {
+                        self.state.expiries.insert(key.clone(), deadline);
+                    }
+                    None => {
+                        self.state.expiries.remove(key);
+                        self.engine.remove(key.clone())?;
+                    }
+                }
+                Ok(Reply::Integer(1))
+            }
+            ("PING", _) | ("GET", _) | ("SET", _) | ("DEL", _) | ("EXISTS", _) | ("MGET", _) | ("MSET", _)
+            | ("SCAN", _) | ("EXPIRE", _) => Ok(Reply::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                command.to_ascii_lowercase()
+            ))),
+            _ => Ok(Reply::Error(format!("ERR unknown command '{}'", command.to_ascii_lowercase()))),
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Result<Option<String>> {
+        self.remove_if_expired(key)?;
+        self.engine.get(key.to_owned())
+    }
+
+    fn exists(&mut self, key: &str) -> Result<bool> {
+        self.remove_if_expired(key)?;
+        Ok(self.engine.version(key)?.is_some())
+    }
+
+    /// SET key value [EX seconds | PX milliseconds]
+    fn set(&mut self, key: &str, value: &str, options: &[String]) -> Result<Reply> {
+        let deadline = match options {
+            [] => None,
+            [unit, amount] => {
+                let amount: u64 = match amount.parse() {
+                    Ok(amount) if amount > 0 => amount,
+                    _ => return Ok(invalid_expire_time("set")),
+                };
+                let ttl = match unit.to_ascii_uppercase().as_str() {
+                    "EX" => Duration::from_secs(amount),
+                    "PX" => Duration::from_millis(amount),
+                    _ => return Ok(syntax_error()),
+                };
+                match deadline_after(ttl) {
+                    Some(deadline) => Some(deadline),
+                    None => return Ok(invalid_expire_time("set")),
+                }
+            }
+            _ => return Ok(syntax_error()),
+        };
+        self.config.check_key(key)?;
+        self.config.check_value(value)?;
+        self.engine.put(key.to_owned(), value.to_owned())?;
+        match deadline {
+            Some(deadline) => self.state.expiries.insert(key.to_owned(), deadline),
+            None => self.state.expiries.remove(key),
+        };
+        Ok(Reply::Status("OK"))
+    }
+
+    /// SCAN cursor [MATCH pattern] [COUNT count]
+    fn scan(&mut self, cursor: &str, options: &[String]) -> Result<Reply> {
+        let mut pattern = None;
+        let mut count = DEFAULT_SCAN_COUNT;
+        for option in options.chunks(2) {
+            match (option[0].to_ascii_uppercase().as_str(), option.get(1)) {
+                ("MATCH", Some(arg)) => pattern = Some(arg.as_str()),
+                ("COUNT", Some(arg)) => match arg.parse() {
+                    Ok(arg) if arg > 0 => count = arg,
+                    _ => return Ok(not_an_integer()),
+                },
+                _ => return Ok(syntax_error()),
+            }
+        }
+        let after = match cursor.parse::<u64>() {
+            Ok(0) => None,
+            Ok(cursor) => match self.state.cursors.get(&cursor) {
+                Some(key) => Some(key.clone()),
+                None => return Ok(Reply::Error("ERR invalid cursor".to_owned())),
+            },
+            Err(_) => return Ok(Reply::Error("ERR invalid cursor".to_owned())),
+        };
+
+        let prefix = pattern.map(literal_prefix).unwrap_or("");
+        let entries = self.engine.scan(prefix, after.as_deref(), count)?;
+        let next = match entries.last() {
+            Some((last, _)) if entries.len() == count => self.state.save_cursor(last.clone()),
+            _ => 0,
+        };
+        let mut keys = Vec::new();
+        for (key, _) in entries {
+            if pattern.is_none_or(|pattern| glob_match(pattern.as_bytes(), key.as_bytes()))
+                && self.exists(&key)?
+            {
+                keys.push(Reply::Bulk(Some(key)));
+            }
+        }
+        Ok(Reply::Array(vec![Reply::Bulk(Some(next.to_string())), Reply::Array(keys)]))
+    }
+
+    /// Remove `key` if its expiry time has passed, rather than wait for the
+    /// next sweep.
+    fn remove_if_expired(&mut self, key: &str) -> Result<()> {
+        if let Some(deadline) = self.state.expiries.get(key) {
+            if *deadline <= Instant::now() {
+                self.state.expiries.remove(key);
+                self.engine.remove(key.to_owned())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl State {
+    fn save_cursor(&mut self, last_key: String) -> u64 {
+        self.last_cursor += 1;
+        self.cursors.insert(self.last_cursor, last_key);
+        if self.cursors.len() > MAX_CURSORS {
+            self.cursors.pop_first();
+        }
+        self.last_cursor
+    }
+}
+
+/// When a key given `ttl` now expires, or `None` if that is too far in the
+/// future to represent.
+fn deadline_after(ttl: Duration) -> Option<Instant> {
+    Instant::now().checked_add(ttl)
+}
+
+fn invalid_expire_time(command: &str) -> Reply {
+    Reply::Error(format!("ERR invalid expire time in '{}' command", command))
+}
+
+fn syntax_error() -> Reply {
+    Reply::Error("ERR syntax error".to_owned())
+}
+
+fn not_an_integer() -> Reply {
+    Reply::Error("ERR value is not an integer or out of range".to_owned())
+}
+
+fn err_reply(err: &CaveyError) -> Reply {
+    Reply::Error(format!("ERR {}", err).replace(['\r', '\n'], " "))
+}
+
+/// Read a command: either an array of bulk strings, as sent by Redis
+/// clients, or an inline command, as typed into telnet.  `None` once the
+/// client closes the connection.  Commands longer than `max_size` bytes are
+/// refused before they are read in full.
+fn read_command<R: BufRead>(reader: &mut R, max_size: usize) -> Result<Option<Vec<Vec<u8>>>> {
+    let mut remaining = max_size;
+    let line = match read_line(reader, &mut remaining)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let args = line
+            .split(u8::is_ascii_whitespace)
+            .filter(|arg| !arg.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(args));
+    }
+    let count = parse_length(&line[1..])?;
+    // Each argument takes at least four bytes, which bounds the allocation.
+    let mut args = Vec::with_capacity(count.min(remaining / 4));
+    for _ in 0..count {
+        let line = read_line(reader, &mut remaining)?.ok_or_else(truncated)?;
+        if line.first() != Some(&b'$') {
+            return Err(CaveyError::Protocol("expected '$'".to_owned()));
+        }
+        let len = parse_length(&line[1..])?;
+        if len + 2 > remaining {
+            return Err(too_large(max_size));
+        }
+        remaining -= len + 2;
+        // Grow the argument as it arrives, rather than trusting the length.
+        let mut arg = Vec::new();
+        reader.take(len as u64 + 2).read_to_end(&mut arg)?;
+        if arg.len() < len + 2 {
+            return Err(truncated());
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(CaveyError::Protocol("expected CRLF after bulk string".to_owned()));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Read a line, without its line ending.
+fn read_line<R: BufRead>(reader: &mut R, remaining: &mut usize) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    reader.take(*remaining as u64).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(if line.len() == *remaining { too_large(*remaining) } else { truncated() });
+    }
+    *remaining -= line.len();
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_length(digits: &[u8]) -> Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .ok_or_else(|| CaveyError::Protocol("invalid length".to_owned()))
+}
+
+fn truncated() -> CaveyError {
+    CaveyError::Protocol("unexpected end of stream".to_owned())
+}
+
+fn too_large(max_size: usize) -> CaveyError {
+    CaveyError::TooLarge(format!("command exceeds limit of {} bytes", max_size))
+}
+
+fn write_reply<W: Write>(writer: &mut W, reply: &Reply) -> io::Result<()> {
+    match reply {
+        Reply::Status(status) => write!(writer, "+{}\r\n", status),
+        Reply::Error(msg) => write!(writer, "-{}\r\n", msg),
+        Reply::Integer(n) => write!(writer, ":{}\r\n", n),
+        Reply::Bulk(None) => writer.write_all(b"$-1\r\n"),
+        Reply::Bulk(Some(value)) => {
+            write!(writer, "${}\r\n", value.len())?;
+            writer.write_all(value.as_bytes())?;
+            writer.write_all(b"\r\n")
+        }
+        Reply::Array(items) => {
+            write!(writer, "*{}\r\n", items.len())?;
+            for item in items {
+                write_reply(writer, item)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// The part of a glob pattern before its first wildcard, which every
+/// matching key starts with.
+fn literal_prefix(pattern: &str) -> &str {
+    match pattern.find(['*', '?', '[', '\\']) {
+        Some(end) => &pattern[..end],
+        None => pattern,
+    }
+}
+
+/// Match `text` against a Redis glob pattern, supporting `*`, `?`, `[...]`
+/// classes and `\` escapes.  On a mismatch, only the most recent `*` is
+/// retried, so the time taken is bounded by the product of the lengths.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the latest `*`: the pattern after it, and the
+    // next text position for it to absorb.
+    let mut backtrack = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            backtrack = Some((p, t));
+        } else if let Some(len) = match_one(&pattern[p..], text[t]) {
+            p += len;
+            t += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            p = star_p;
+            t = star_t + 1;
+            backtrack = Some((star_p, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// If the pattern element at the start of `pattern` matches `c`, the length
+/// of that element.
+fn match_one(pattern: &[u8], c: u8) -> Option<usize> {
+    match *pattern.first()? {
+        b'?' => Some(1),
+        b'\\' if pattern.len() > 1 => (pattern[1] == c).then_some(2),
+        b'[' => {
+            let negate = pattern.get(1) == Some(&b'^');
+            let mut i = if negate { 2 } else { 1 };
+            let mut matched = false;
+            while i < pattern.len() && pattern[i] != b']' {
+                if pattern[i] == b'\\' && i + 1 < pattern.len() {
+                    matched |= pattern[i + 1] == c;
+                    i += 2;
+                } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+                    let (low, high) = (pattern[i].min(pattern[i + 2]), pattern[i].max(pattern[i + 2]));
+                    matched |= low <= c && c <= high;
+                    i += 3;
+                } else {
+                    matched |= pattern[i] == c;
+                    i += 1;
+                }
+            }
+            // Include the closing bracket, if there is one.
+            (matched != negate).then_some((i + 1).min(pattern.len()))
+        }
+        literal => (literal == c).then_some(1),
+    }
+}
@@ -1,53 +1,362 @@
-use std::net::TcpListener;
-use std::io::prelude::*;
+use std::ops::DerefMut;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::io::{self, prelude::*};
 
-use bincode::{deserialize_from, serialize_into};
 use log::{trace, debug, error};
 
-use crate::CaveyEngine;
+use crate::acl::{Access, Acl};
+use crate::{in_keyspace, CaveyEngine, CaveyError, Listener, TransactionalStore};
 use crate::Result;
-use crate::protocol::{ClientMessage, ServerMessage};
+use crate::protocol::{encode_frame, read_frame, ClientMessage, Handshake, ServerMessage, MAX_FRAME_SIZE};
+use crate::watch::{open_watch, stream_events};
+use crate::utils::lock;
 
-pub fn run_server(socket: &mut TcpListener, engine: &mut dyn CaveyEngine) -> Result<()> {
-    for stream in socket.incoming() {
-        match stream {
-            Ok(mut stream) => {
-                trace!("connection accepted");
-                handle_connection(&mut stream, engine)?;
+/// Entries returned per scan, however many a request asks for.
+const MAX_SCAN_LIMIT: usize = 1000;
+
+/// Limits and access control a server enforces on the requests it receives.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// The largest frame accepted.  A client sending a larger one is
+    /// disconnected.
+    pub max_frame_size: u32,
+    /// Requests with a longer key are refused.
+    pub max_key_size: usize,
+    /// Requests with a longer value are refused.
+    pub max_value_size: usize,
+    /// If set, clients must authenticate, and may make only the requests
+    /// their principal is granted.
+    pub acl: Option<Arc<Acl>>,
+    /// The directory backups are taken into and restored from.  Clients name
+    /// a backup by its path within it; if unset, they can't back up or
+    /// restore.
+    pub backup_dir: Option<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            max_frame_size: MAX_FRAME_SIZE,
+            max_key_size: 64 << 10,
+            max_value_size: 8 << 20,
+            acl: None,
+            backup_dir: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Check the keys and values in a request against the limits.
+    pub(crate) fn check_limits(&self, msg: &ClientMessage) -> Result<()> {
+        match msg {
+            ClientMessage::Get { key, .. }
+            | ClientMessage::Remove { key, .. }
+            | ClientMessage::RemoveReturningOld { key, .. }
+            | ClientMessage::GetVersioned { key, .. } => self.check_key(key),
+            ClientMessage::Put { key, value, .. } | ClientMessage::PutReturningOld { key, value, .. } => {
+                self.check_key(key)?;
+                self.check_value(value)
             }
-            Err(err) => {
-                error!("connection failed: {}", err);
+            ClientMessage::Commit { changeset, .. } => {
+                for key in changeset.reads.keys() {
+                    self.check_key(key)?;
+                }
+                for (key, value) in &changeset.writes {
+                    self.check_key(key)?;
+                    if let Some(value) = value {
+                        self.check_value(value)?;
+                    }
+                }
+                Ok(())
             }
+            ClientMessage::Scan { prefix, after, .. } => {
+                self.check_key(prefix)?;
+                after.as_deref().map_or(Ok(()), |after| self.check_key(after))
+            }
+            ClientMessage::Watch { prefix, .. } => self.check_key(prefix),
+            ClientMessage::CreateKeyspace { .. }
+            | ClientMessage::DropKeyspace { .. }
+            | ClientMessage::ListKeyspaces
+            | ClientMessage::Ping
+            | ClientMessage::Authenticate { .. }
+            | ClientMessage::ClusterStatus
+            | ClientMessage::AddMember { .. }
+            | ClientMessage::RemoveMember { .. }
+            | ClientMessage::Backup { .. }
+            | ClientMessage::Restore { .. } => Ok(()),
+        }
+    }
+
+    /// Replace the backup named in a `Backup` or `Restore` request by its
+    /// path within `backup_dir`, so that clients can't write or read data
+    /// directories elsewhere on the server's machine.
+    pub(crate) fn resolve_backup(&self, msg: ClientMessage) -> Result<ClientMessage> {
+        match msg {
+            ClientMessage::Backup { dest } => Ok(ClientMessage::Backup { dest: self.backup_path(&dest)? }),
+            ClientMessage::Restore { src } => Ok(ClientMessage::Restore { src: self.backup_path(&src)? }),
+            msg => Ok(msg),
+        }
+    }
+
+    fn backup_path(&self, name: &str) -> Result<String> {
+        let dir = self
+            .backup_dir
+            .as_ref()
+            .ok_or_else(|| CaveyError::Unsupported("backups without a backup directory".to_owned()))?;
+        let path = Path::new(name);
+        let mut components = path.components().peekable();
+        if components.peek().is_none() || !components.all(|component| matches!(component, Component::Normal(_))) {
+            return Err(CaveyError::PermissionDenied(format!(
+                "backup {} must be a relative path without ..",
+                name
+            )));
+        }
+        dir.join(path)
+            .into_os_string()
+            .into_string()
+            .map_err(|_| CaveyError::Internal("backup directory is not valid UTF-8".to_owned()))
+    }
+
+    pub(crate) fn check_key(&self, key: &str) -> Result<()> {
+        if key.len() > self.max_key_size {
+            return Err(CaveyError::TooLarge(format!(
+                "key of {} bytes exceeds limit of {} bytes",
+                key.len(), self.max_key_size
+            )));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_value(&self, value: &str) -> Result<()> {
+        if value.len() > self.max_value_size {
+            return Err(CaveyError::TooLarge(format!(
+                "value of {} bytes exceeds limit of {} bytes",
+                value.len(), self.max_value_size
+            )));
         }
+        Ok(())
     }
+}
+
+/// Serve clients on `socket` with the default `ServerConfig`.
+pub fn run_server<L: Listener>(socket: &mut L, engine: &mut dyn CaveyEngine) -> Result<()> {
+    run_server_with_config(socket, engine, &ServerConfig::default())
+}
+
+/// Serve clients on `socket`, a `TcpListener` or `UnixListener`, with a
+/// thread for each connection.  Requests are applied to `engine` one at a
+/// time.
+///
+/// A failure on one connection, such as a malformed or oversized request,
+/// closes only that connection.
+pub fn run_server_with_config<L: Listener>(socket: &mut L, engine: &mut dyn CaveyEngine, config: &ServerConfig) -> Result<()> {
+    serve(socket, &Mutex::new(engine), config)
+}
+
+/// Like `run_server_with_config`, but with an engine which other frontends,
+/// such as `serve_resp`, may share.
+pub fn serve<'e, L, E>(socket: &L, engine: &Mutex<E>, config: &ServerConfig) -> Result<()>
+where
+    L: Listener,
+    E: DerefMut<Target = dyn CaveyEngine + 'e> + Send,
+{
+    thread::scope(|scope| {
+        loop {
+            match socket.accept() {
+                Ok(mut stream) => {
+                    trace!("connection accepted");
+                    scope.spawn(move || {
+                        if let Err(err) = handle_connection(&mut stream, engine, config) {
+                            error!("connection failed: {}", err);
+                        }
+                    });
+                }
+                Err(err) => {
+                    error!("connection failed: {}", err);
+                }
+            }
+        }
+    });
     Ok(())
 }
 
-fn handle_connection<R: Read + Write>(stream: &mut R, engine: &mut dyn CaveyEngine) -> Result<()> {
-    let msg: ClientMessage = deserialize_from(&mut *stream)?;
-    debug!("caveyd: received msg: {:?}", msg);
-    let response = match msg {
-        ClientMessage::Get { key } => {
-            match engine.get(key) {
+/// Serve requests from one client until it closes the connection.
+fn handle_connection<'e, R, E>(stream: &mut R, engine: &Mutex<E>, config: &ServerConfig) -> Result<()>
+where
+    R: Read + Write,
+    E: DerefMut<Target = dyn CaveyEngine + 'e>,
+{
+    let local = Handshake::local(config.max_frame_size);
+    let peer = Handshake::read_from(stream)?;
+    // Answer even an incompatible client, so it can report the mismatch.
+    stream.write_all(&local.encode())?;
+    local.check_compatible(&peer)?;
+    let mut access = Access::new(config);
+    loop {
+        let msg: ClientMessage = match read_frame(stream, local.max_frame_size) {
+            Ok(msg) => msg,
+            Err(CaveyError::Io(ref err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                trace!("connection closed");
+                return Ok(());
+            }
+            Err(err @ CaveyError::Io(_)) => return Err(err),
+            // The rest of the stream can't be trusted after a bad frame.
+            Err(err) => {
+                stream.write_all(&encode_response(&ServerMessage::from(&err), &peer)?).ok();
+                return Err(err);
+            }
+        };
+        debug!("caveyd: received msg: {:?}", msg);
+        let response = match msg {
+            ClientMessage::Authenticate { token } => authenticate(&mut access, &token.0),
+            // A watch takes over the connection.
+            msg @ ClientMessage::Watch { .. } => {
+                let watch = config
+                    .check_limits(&msg)
+                    .and_then(|()| open_watch(&msg, &mut **lock(engine), &access));
+                match watch {
+                    Ok((feed, filter, after)) => return stream_events(stream, &feed, &filter, after, &peer),
+                    Err(err) => err.into(),
+                }
+            }
+            msg => match config.check_limits(&msg).and_then(|()| config.resolve_backup(msg)) {
+                Ok(msg) => dispatch(msg, &mut **lock(engine), &access),
+                Err(err) => err.into(),
+            },
+        };
+        stream.write_all(&encode_response(&response, &peer)?)?;
+    }
+}
+
+/// Encode a response frame.  A response too large for the client is replaced
+/// by an error, so the client isn't left waiting.
+pub(crate) fn encode_response(response: &ServerMessage, peer: &Handshake) -> Result<Vec<u8>> {
+    encode_frame(response, peer.max_frame_size)
+        .or_else(|err| encode_frame(&ServerMessage::from(err), peer.max_frame_size))
+}
+
+/// Answer an `Authenticate` request, which changes what the rest of the
+/// connection's requests may do.
+pub(crate) fn authenticate(access: &mut Access, token: &str) -> ServerMessage {
+    match access.authenticate(token) {
+        Ok(()) => ServerMessage::Success { value: None },
+        Err(err) => err.into(),
+    }
+}
+
+/// Apply a request to `engine`, if `access` allows it.
+pub(crate) fn dispatch(msg: ClientMessage, engine: &mut dyn CaveyEngine, access: &Access) -> ServerMessage {
+    if let Err(err) = access.authorize(&msg) {
+        return err.into();
+    }
+    match msg {
+        ClientMessage::Get { keyspace, key } => {
+            match in_keyspace(engine, keyspace.as_deref()).and_then(|engine| engine.get(key)) {
                 Ok( value ) => ServerMessage::Success { value },
-                Err( err ) => ServerMessage::Error { err: format!("{}", err) },
+                Err( err ) => err.into(),
             }
         },
-        ClientMessage::Put { key, value } => {
-            match engine.put(key, value) {
+        ClientMessage::Put { keyspace, key, value } => {
+            match in_keyspace(engine, keyspace.as_deref()).and_then(|engine| engine.put(key, value)) {
                 Ok(()) => ServerMessage::Success { value: None },
-                Err(err) => ServerMessage::Error { err: format!("{}", err) },
+                Err(err) => err.into(),
             }
         },
-        ClientMessage::Remove { key } => {
-            match engine.remove(key) {
-                Ok(()) => ServerMessage::Success {value: None },
-                Err(err) => ServerMessage::Error { err: format!("{}", err) },
+        ClientMessage::Remove { keyspace, key } => {
+            match in_keyspace(engine, keyspace.as_deref()).and_then(|engine| engine.remove(key)) {
+                Ok(removed) => ServerMessage::Removed { removed },
+                Err(err) => err.into(),
 
             }
 
         },
-    };
-    serialize_into(stream, &response)?;
-    Ok(())
+        ClientMessage::PutReturningOld { keyspace, key, value } => {
+            match in_keyspace(engine, keyspace.as_deref()).and_then(|engine| engine.put_returning_old(key, value)) {
+                Ok(value) => ServerMessage::Success { value },
+                Err(err) => err.into(),
+            }
+        },
+        ClientMessage::RemoveReturningOld { keyspace, key } => {
+            match in_keyspace(engine, keyspace.as_deref()).and_then(|engine| engine.remove_returning_old(key)) {
+                Ok(value) => ServerMessage::Success { value },
+                Err(err) => err.into(),
+            }
+        },
+        ClientMessage::GetVersioned { keyspace, key } => {
+            match in_keyspace(engine, keyspace.as_deref()).and_then(|engine| engine.get_versioned(key)) {
+                Ok((value, version)) => ServerMessage::Versioned { value, version },
+                Err(err) => err.into(),
+            }
+        },
+        ClientMessage::Commit { keyspace, changeset } => {
+            match in_keyspace(engine, keyspace.as_deref()).and_then(|engine| engine.apply_changeset(changeset)) {
+                Ok(()) => ServerMessage::Success { value: None },
+                Err(err) => err.into(),
+            }
+        },
+        ClientMessage::CreateKeyspace { keyspace } => {
+            match engine.create_keyspace(&keyspace) {
+                Ok(()) => ServerMessage::Success { value: None },
+                Err(err) => err.into(),
+            }
+        },
+        ClientMessage::DropKeyspace { keyspace } => {
+            match engine.drop_keyspace(&keyspace) {
+                Ok(()) => ServerMessage::Success { value: None },
+                Err(err) => err.into(),
+            }
+        },
+        ClientMessage::Ping => ServerMessage::Success { value: None },
+        ClientMessage::Authenticate { .. } => {
+            CaveyError::Protocol("authentication is handled by the connection".to_owned()).into()
+        },
+        ClientMessage::Scan { keyspace, prefix, after, limit } => {
+            let limit = (limit as usize).min(MAX_SCAN_LIMIT);
+            match in_keyspace(engine, keyspace.as_deref()).and_then(|engine| engine.scan(&prefix, after.as_deref(), limit)) {
+                Ok(entries) => ServerMessage::Entries { entries },
+                Err(err) => err.into(),
+            }
+        },
+        ClientMessage::ListKeyspaces => {
+            match engine.list_keyspaces() {
+                Ok(names) => ServerMessage::Keyspaces { names },
+                Err(err) => err.into(),
+            }
+        },
+        ClientMessage::ClusterStatus => {
+            match engine.cluster_status() {
+                Ok(status) => ServerMessage::Cluster { status },
+                Err(err) => err.into(),
+            }
+        },
+        ClientMessage::AddMember { member } => {
+            match engine.add_member(member) {
+                Ok(()) => ServerMessage::Success { value: None },
+                Err(err) => err.into(),
+            }
+        },
+        ClientMessage::RemoveMember { id } => {
+            match engine.remove_member(&id) {
+                Ok(()) => ServerMessage::Success { value: None },
+                Err(err) => err.into(),
+            }
+        },
+        ClientMessage::Backup { dest } => {
+            match engine.backup(Path::new(&dest)) {
+                Ok(()) => ServerMessage::Success { value: None },
+                Err(err) => err.into(),
+            }
+        },
+        ClientMessage::Restore { src } => {
+            match engine.restore(Path::new(&src)) {
+                Ok(()) => ServerMessage::Success { value: None },
+                Err(err) => err.into(),
+            }
+        },
+        ClientMessage::Watch { .. } => {
+            CaveyError::Protocol("watches are handled by the connection".to_owned()).into()
+        },
+    }
 }
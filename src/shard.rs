@@ -0,0 +1,276 @@
+//! Spreading keys over several servers, each holding a share of them.
+//!
+//! Keys are placed by consistent hashing: each shard owns `VIRTUAL_NODES`
+//! points on a ring of hashes, and a key belongs to the shard owning the
+//! first point at or after the key's hash.  Adding or removing a shard only
+//! moves the keys that hash next to its points, about one shard's worth.
+//!
+//! A shard's points depend only on its name, so clients given the same
+//! names route keys the same way.  Keyspaces exist on every shard, and a
+//! transaction can't span shards.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
+
+use log::{debug, info};
+
+use crate::{CaveyClient, CaveyError, ClientConfig, Result, Transport, Version};
+
+/// Points each shard owns on the ring.  More spread keys more evenly.
+const VIRTUAL_NODES: u32 = 160;
+
+/// Keys scanned at a time while rebalancing.
+const REBALANCE_PAGE: u32 = 1000;
+
+/// A 64-bit hash that doesn't change between processes or releases, as
+/// every client must place keys alike: FNV-1a, mixed by the MurmurHash3
+/// finalizer, since FNV alone leaves similar names' points bunched.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// A client of several servers, each holding the keys hashed to it.
+pub struct ShardedClient<T: Transport = SocketAddr> {
+    // Each point on the ring, with the name of the shard owning it.
+    ring: BTreeMap<u64, String>,
+    shards: BTreeMap<String, CaveyClient<T>>,
+    config: ClientConfig,
+    keyspace: Option<String>,
+}
+
+impl ShardedClient {
+    /// Connect to the servers at `addrs`, naming each shard by its address.
+    pub fn new(addrs: &[SocketAddr]) -> Result<ShardedClient> {
+        ShardedClient::with_config(addrs, ClientConfig::default())
+    }
+
+    pub fn with_config(addrs: &[SocketAddr], config: ClientConfig) -> Result<ShardedClient> {
+        ShardedClient::with_shards(addrs.iter().map(|addr| (addr.to_string(), *addr)), config)
+    }
+}
+
+impl<T: Transport> ShardedClient<T> {
+    /// Connect to each named shard over its transport.
+    pub fn with_shards<I: IntoIterator<Item = (String, T)>>(shards: I, config: ClientConfig) -> Result<ShardedClient<T>> {
+        let mut client = ShardedClient {
+            ring: BTreeMap::new(),
+            shards: BTreeMap::new(),
+            config,
+            keyspace: None,
+        };
+        for (name, transport) in shards {
+            client.connect(name, transport)?;
+        }
+        if client.shards.is_empty() {
+            return Err(CaveyError::Unsupported("sharding over no shards".to_owned()));
+        }
+        Ok(client)
+    }
+
+    /// Direct subsequent requests to the named keyspace, or to the default
+    /// keyspace if `None`.
+    pub fn set_keyspace(&mut self, keyspace: Option<String>) {
+        self.keyspace = keyspace;
+    }
+
+    /// The names of the shards.
+    pub fn shards(&self) -> impl Iterator<Item = &str> {
+        self.shards.keys().map(String::as_str)
+    }
+
+    /// The name of the shard `key` belongs to.
+    pub fn shard_for(&self, key: &str) -> &str {
+        let point = hash(key.as_bytes());
+        let (_, name) = self
+            .ring
+            .range(point..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .expect("a sharded client has shards");
+        name
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.client_for(&key).get(key)
+    }
+
+    pub fn put(&mut self, key: String, value: String) -> Result<()> {
+        self.client_for(&key).put(key, value)
+    }
+
+    /// Remove `key`, returning whether it was present.
+    pub fn remove(&mut self, key: String) -> Result<bool> {
+        self.client_for(&key).remove(key)
+    }
+
+    pub fn put_returning_old(&mut self, key: String, value: String) -> Result<Option<String>> {
+        self.client_for(&key).put_returning_old(key, value)
+    }
+
+    pub fn remove_returning_old(&mut self, key: String) -> Result<Option<String>> {
+        self.client_for(&key).remove_returning_old(key)
+    }
+
+    /// The value of `key` with its version, which is only comparable with
+    /// other versions from the same shard.
+    pub fn get_versioned(&mut self, key: String) -> Result<(Option<String>, Option<Version>)> {
+        self.client_for(&key).get_versioned(key)
+    }
+
+    /// Up to `limit` keys starting with `prefix`, with their values, in key
+    /// order, gathered from every shard.  Only keys ordered after `after`
+    /// are returned, so passing the last key of one page gets the next.
+    pub fn scan(&mut self, prefix: String, after: Option<String>, limit: u32) -> Result<Vec<(String, String)>> {
+        let mut entries = Vec::new();
+        for client in self.clients() {
+            entries.extend(client.scan(prefix.clone(), after.clone(), limit)?);
+        }
+        entries.sort();
+        entries.truncate(limit as usize);
+        Ok(entries)
+    }
+
+    /// Create a keyspace on every shard.
+    pub fn create_keyspace(&mut self, keyspace: String) -> Result<()> {
+        for client in self.clients() {
+            client.create_keyspace(keyspace.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Drop a keyspace from every shard.
+    pub fn drop_keyspace(&mut self, keyspace: String) -> Result<()> {
+        for client in self.clients() {
+            client.drop_keyspace(keyspace.clone())?;
+        }
+        Ok(())
+    }
+
+    /// The keyspaces on any shard.
+    pub fn list_keyspaces(&mut self) -> Result<Vec<String>> {
+        let mut names = BTreeSet::new();
+        for client in self.clients() {
+            names.extend(client.list_keyspaces()?);
+        }
+        Ok(names.into_iter().collect())
+    }
+
+    /// Add a shard, and move the keys that now belong to it from the
+    /// others, returning how many moved.
+    ///
+    /// Keys are copied before they are removed, so a rebalance that fails
+    /// part way can be finished by running it again.  The shard is already
+    /// present then, so `transport` is ignored and the keys left behind are
+    /// moved over the existing connection.  Writes by clients still routing
+    /// keys the old way while keys move can be lost, so stop writers first.
+    pub fn add_shard(&mut self, name: String, transport: T) -> Result<usize> {
+        let others: Vec<String> = self.shards.keys().filter(|other| **other != name).cloned().collect();
+        if self.shards.contains_key(&name) {
+            info!("resuming rebalance onto shard {}", name);
+        } else {
+            info!("adding shard {}", name);
+            self.connect(name, transport)?;
+        }
+        let mut moved = 0;
+        for other in others {
+            moved += self.migrate(&other)?;
+        }
+        Ok(moved)
+    }
+
+    /// Remove a shard, moving its keys to the others, and returning how many
+    /// moved.  The same caveats apply as to `add_shard`.
+    pub fn remove_shard(&mut self, name: &str) -> Result<usize> {
+        if !self.shards.contains_key(name) {
+            return Err(CaveyError::NotFound);
+        }
+        if self.shards.len() == 1 {
+            return Err(CaveyError::Unsupported("removing the last shard".to_owned()));
+        }
+        info!("removing shard {}", name);
+        self.ring.retain(|_, owner| owner != name);
+        let moved = self.migrate(name)?;
+        self.shards.remove(name);
+        Ok(moved)
+    }
+
+    fn connect(&mut self, name: String, transport: T) -> Result<()> {
+        let client = CaveyClient::with_transport(transport, self.config.clone())?;
+        for vnode in 0..VIRTUAL_NODES {
+            self.ring.insert(hash(format!("{}#{}", name, vnode).as_bytes()), name.clone());
+        }
+        self.shards.insert(name, client);
+        Ok(())
+    }
+
+    /// The client for the shard `key` belongs to, set to the keyspace.
+    fn client_for(&mut self, key: &str) -> &mut CaveyClient<T> {
+        let name = self.shard_for(key).to_owned();
+        let client = self.shards.get_mut(&name).unwrap();
+        client.set_keyspace(self.keyspace.clone());
+        client
+    }
+
+    /// Every shard's client, set to the keyspace.
+    fn clients(&mut self) -> impl Iterator<Item = &mut CaveyClient<T>> {
+        for client in self.shards.values_mut() {
+            client.set_keyspace(self.keyspace.clone());
+        }
+        self.shards.values_mut()
+    }
+
+    /// Move the keys in the shard `from` that belong elsewhere on the ring
+    /// to where they belong, in every keyspace.
+    fn migrate(&mut self, from: &str) -> Result<usize> {
+        let mut keyspaces = vec![None];
+        let source = self.shards.get_mut(from).unwrap();
+        source.set_keyspace(None);
+        for name in source.list_keyspaces()? {
+            for (shard, client) in &mut self.shards {
+                match client.create_keyspace(name.clone()) {
+                    Ok(()) => debug!("created keyspace {} on shard {}", name, shard),
+                    Err(CaveyError::KeyspaceExists(_)) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            keyspaces.push(Some(name));
+        }
+        let mut moved = 0;
+        for keyspace in keyspaces {
+            let mut after = None;
+            loop {
+                let source = self.shards.get_mut(from).unwrap();
+                source.set_keyspace(keyspace.clone());
+                // The server may return fewer keys than asked for, so only
+                // an empty page means the end.
+                let page = source.scan(String::new(), after.take(), REBALANCE_PAGE)?;
+                if page.is_empty() {
+                    break;
+                }
+                after = page.last().map(|(key, _)| key.clone());
+                for (key, value) in page {
+                    let owner = self.shard_for(&key).to_owned();
+                    if owner == from {
+                        continue;
+                    }
+                    let dest = self.shards.get_mut(&owner).unwrap();
+                    dest.set_keyspace(keyspace.clone());
+                    dest.put(key.clone(), value)?;
+                    self.shards.get_mut(from).unwrap().remove(key)?;
+                    moved += 1;
+                }
+            }
+        }
+        info!("moved {} keys from shard {}", moved, from);
+        Ok(moved)
+    }
+}
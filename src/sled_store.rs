@@ -1,26 +1,264 @@
+use std::collections::{BTreeMap, HashMap};
 use std::fs::create_dir_all;
+use std::ops::Bound;
 use std::path::Path;
+use std::sync::Arc;
 
-use failure::format_err;
-use sled::Db;
+use sled::{Db, Tree};
 
-use crate::{utils::check_engine, CaveyEngine, Result};
+use crate::backup::create_dest;
+use crate::{utils::{check_engine, check_keyspace_name, scan_start}, CaveyEngine, CaveyError, ChangeFeed, Result, Version};
+use crate::{transaction, Changeset};
 
-pub struct SledStore(Db);
+// Name sled gives its default tree, which holds the default keyspace.
+const DEFAULT_TREE: &[u8] = b"__sled__default";
+
+// Trees named with a colon, which keyspace names can't contain, hold the
+// store's bookkeeping: the version of each key in a keyspace, and the
+// writes of each keyspace's transaction being applied.
+const VERSIONS_PREFIX: &str = "versions:";
+const PENDING_TREE: &[u8] = b"pending:";
+
+pub struct SledStore {
+    db: Db,
+    default: SledTree,
+    keyspaces: HashMap<String, SledTree>,
+}
+
+/// One keyspace, stored in its own sled `Tree`.
+struct SledTree {
+    db: Db,
+    tree: Arc<Tree>,
+    // sled does not version its values, so versions are handed out from its
+    // persistent id generator as keys are written, and kept in a tree of
+    // their own.  Keys written before versions were kept have version 0,
+    // which no write ever gets.
+    versions: Arc<Tree>,
+    // sled can't write several keys atomically, so a transaction's writes
+    // are saved here, under the keyspace's name, until all are applied.
+    pending: Arc<Tree>,
+    // Whether `pending` may hold writes for this keyspace.
+    has_pending: bool,
+    // Shared by every tree in the store.
+    feed: Arc<ChangeFeed>,
+    // The name of the keyspace served, or `None` for the default keyspace.
+    keyspace: Option<String>,
+}
 
 impl SledStore {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<SledStore> {
         let datadir = path.as_ref().join("data");
         create_dir_all(&datadir)?;
         check_engine(&datadir, b"sled")?;
-        Ok(SledStore(Db::start_default(&datadir)?))
+        let db = Db::start_default(&datadir)?;
+        let feed = Arc::new(ChangeFeed::new());
+        let mut keyspaces = HashMap::new();
+        for name in db.tree_names() {
+            if name != DEFAULT_TREE && !name.contains(&b':') {
+                let name = String::from_utf8(name)?;
+                keyspaces.insert(name.clone(), SledTree::open(&db, feed.clone(), Some(name))?);
+            }
+        }
+        Ok(SledStore {
+            default: SledTree::open(&db, feed, None)?,
+            db,
+            keyspaces,
+        })
+    }
+}
+
+impl SledTree {
+    /// Open the trees serving `keyspace`, finishing any transaction a crash
+    /// interrupted.
+    fn open(db: &Db, feed: Arc<ChangeFeed>, keyspace: Option<String>) -> Result<SledTree> {
+        let tree = match &keyspace {
+            Some(name) => db.open_tree(name)?,
+            None => Arc::new(Tree::clone(db)),
+        };
+        let mut tree = SledTree {
+            db: db.clone(),
+            tree,
+            versions: db.open_tree(versions_tree(keyspace.as_deref()))?,
+            pending: db.open_tree(PENDING_TREE)?,
+            has_pending: true,
+            feed,
+            keyspace,
+        };
+        tree.finish_pending()?;
+        Ok(tree)
+    }
+
+    /// The key the keyspace's pending writes are saved under.
+    fn pending_key(&self) -> &[u8] {
+        self.keyspace.as_deref().unwrap_or("").as_bytes()
+    }
+
+    /// Apply the writes of a transaction that an error or crash
+    /// interrupted.  Until this succeeds, the keyspace serves nothing else,
+    /// so none of its writes are seen without the others.
+    fn finish_pending(&mut self) -> Result<()> {
+        if !self.has_pending {
+            return Ok(());
+        }
+        if let Some(saved) = self.pending.get(self.pending_key())? {
+            let writes: BTreeMap<String, Option<String>> = bincode::deserialize(&saved)?;
+            for (key, value) in writes {
+                match value {
+                    Some(value) => {
+                        self.set(key, value)?;
+                    }
+                    None => {
+                        self.del(key)?;
+                    }
+                }
+            }
+            self.pending.del(self.pending_key())?;
+            self.pending.flush()?;
+        }
+        self.has_pending = false;
+        Ok(())
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        // The version changes first, so a crash before the value does can
+        // only fail a transaction that read the key, never let one miss
+        // the change.
+        let version = self.db.generate_id()? + 1;
+        self.versions.set(key.as_bytes(), version.to_be_bytes().to_vec())?;
+        let old = self.tree.set(key.as_bytes(), value.as_bytes())?;
+        self.tree.flush()?;
+        self.feed.publish(self.keyspace.as_deref(), key, Some(value));
+        match old {
+            Some(ivec) => Ok(Some(String::from_utf8(ivec.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn del(&mut self, key: String) -> Result<Option<String>> {
+        match self.tree.del(&key)? {
+            Some(ivec) => {
+                self.versions.del(&key)?;
+                self.tree.flush()?;
+                self.feed.publish(self.keyspace.as_deref(), key, None);
+                Ok(Some(String::from_utf8(ivec.to_vec())?))
+            }
+            None => Ok(None),
+        }
     }
 }
 
+/// The name of the tree holding the versions of the keys in `keyspace`.
+fn versions_tree(keyspace: Option<&str>) -> String {
+    format!("{}{}", VERSIONS_PREFIX, keyspace.unwrap_or(""))
+}
+
 impl CaveyEngine for SledStore {
     fn get(&mut self, key: String) -> Result<Option<String>> {
-        //self.0.flush()?;
-        if let Some(ivec) = self.0.get(key)? {
+        self.default.get(key)
+    }
+
+    fn put(&mut self, key: String, value: String) -> Result<()> {
+        self.default.put(key, value)
+    }
+
+    fn remove(&mut self, key: String) -> Result<bool> {
+        self.default.remove(key)
+    }
+
+    fn put_returning_old(&mut self, key: String, value: String) -> Result<Option<String>> {
+        self.default.put_returning_old(key, value)
+    }
+
+    fn remove_returning_old(&mut self, key: String) -> Result<Option<String>> {
+        self.default.remove_returning_old(key)
+    }
+
+    fn version(&mut self, key: &str) -> Result<Option<Version>> {
+        self.default.version(key)
+    }
+
+    fn scan(&mut self, prefix: &str, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.default.scan(prefix, after, limit)
+    }
+
+    fn apply_changeset(&mut self, changeset: Changeset) -> Result<()> {
+        self.default.apply_changeset(changeset)
+    }
+
+    fn keyspace(&mut self, name: &str) -> Result<&mut dyn CaveyEngine> {
+        match self.keyspaces.get_mut(name) {
+            Some(tree) => Ok(tree),
+            None => Err(CaveyError::KeyspaceNotFound(name.to_owned())),
+        }
+    }
+
+    fn create_keyspace(&mut self, name: &str) -> Result<()> {
+        check_keyspace_name(name)?;
+        if self.keyspaces.contains_key(name) {
+            return Err(CaveyError::KeyspaceExists(name.to_owned()));
+        }
+        let tree = SledTree::open(&self.db, self.default.feed.clone(), Some(name.to_owned()))?;
+        self.db.flush()?;
+        self.keyspaces.insert(name.to_owned(), tree);
+        Ok(())
+    }
+
+    fn drop_keyspace(&mut self, name: &str) -> Result<()> {
+        if let Some(tree) = self.keyspaces.remove(name) {
+            self.db.drop_tree(name.as_bytes())?;
+            self.db.drop_tree(versions_tree(Some(name)).as_bytes())?;
+            tree.pending.del(name)?;
+            self.db.flush()?;
+            Ok(())
+        } else {
+            Err(CaveyError::KeyspaceNotFound(name.to_owned()))
+        }
+    }
+
+    fn list_keyspaces(&mut self) -> Result<Vec<String>> {
+        let mut names: Vec<_> = self.keyspaces.keys().cloned().collect();
+        names.sort();
+        Ok(names)
+    }
+
+    /// Copies every tree into a new database in `dest`.
+    fn backup(&mut self, dest: &Path) -> Result<()> {
+        self.default.finish_pending()?;
+        for keyspace in self.keyspaces.values_mut() {
+            keyspace.finish_pending()?;
+        }
+        create_dest(dest)?;
+        let datadir = dest.join("data");
+        create_dir_all(&datadir)?;
+        check_engine(&datadir, b"sled")?;
+        let backup = Db::start_default(&datadir)?;
+        copy_tree(&self.default.tree, &backup)?;
+        copy_tree(&self.default.versions, &*backup.open_tree(versions_tree(None))?)?;
+        for (name, keyspace) in &self.keyspaces {
+            copy_tree(&keyspace.tree, &*backup.open_tree(name)?)?;
+            copy_tree(&keyspace.versions, &*backup.open_tree(versions_tree(Some(name)))?)?;
+        }
+        backup.flush()?;
+        Ok(())
+    }
+
+    fn change_feed(&mut self) -> Result<Arc<ChangeFeed>> {
+        Ok(self.default.feed.clone())
+    }
+}
+
+fn copy_tree(from: &Tree, to: &Tree) -> Result<()> {
+    for entry in from.iter() {
+        let (key, value) = entry?;
+        to.set(key, value)?;
+    }
+    Ok(())
+}
+
+impl CaveyEngine for SledTree {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.finish_pending()?;
+        if let Some(ivec) = self.tree.get(key)? {
             Ok(Some(String::from_utf8(ivec.to_vec())?))
         } else {
             Ok(None)
@@ -28,23 +266,69 @@ impl CaveyEngine for SledStore {
     }
 
     fn put(&mut self, key: String, value: String) -> Result<()> {
-        self.0.set(key, value.as_bytes())?;
-        self.0.flush()?;
+        self.put_returning_old(key, value)?;
         Ok(())
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.0.del(key)?.is_some() {
-            self.0.flush()?;
-            Ok(())
-        } else {
-            Err(format_err!("Key not found"))
+    fn remove(&mut self, key: String) -> Result<bool> {
+        Ok(self.remove_returning_old(key)?.is_some())
+    }
+
+    fn put_returning_old(&mut self, key: String, value: String) -> Result<Option<String>> {
+        self.finish_pending()?;
+        self.set(key, value)
+    }
+
+    fn remove_returning_old(&mut self, key: String) -> Result<Option<String>> {
+        self.finish_pending()?;
+        self.del(key)
+    }
+
+    fn version(&mut self, key: &str) -> Result<Option<Version>> {
+        self.finish_pending()?;
+        if !self.tree.contains_key(key)? {
+            return Ok(None);
+        }
+        match self.versions.get(key)? {
+            Some(ivec) if ivec.len() == 8 => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&ivec);
+                Ok(Some(Version::from_be_bytes(bytes)))
+            }
+            Some(_) => Err(CaveyError::Corruption(format!("malformed version for key {}", key))),
+            None => Ok(Some(0)),
+        }
+    }
+
+    fn scan(&mut self, prefix: &str, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.finish_pending()?;
+        let start = scan_start(prefix, after).map(str::as_bytes);
+        let mut entries = Vec::new();
+        for entry in self.tree.range::<&[u8], _>((start, Bound::Unbounded)) {
+            let (key, value) = entry?;
+            if !key.starts_with(prefix.as_bytes()) || entries.len() == limit {
+                break;
+            }
+            entries.push((String::from_utf8(key)?, String::from_utf8(value.to_vec())?));
         }
+        Ok(entries)
+    }
+
+    /// The writes are saved together before any is applied, so if applying
+    /// them fails part way, or the store stops, the rest are applied before
+    /// the keyspace serves anything else.
+    fn apply_changeset(&mut self, changeset: Changeset) -> Result<()> {
+        self.finish_pending()?;
+        transaction::validate(self, &changeset)?;
+        self.pending.set(self.pending_key(), bincode::serialize(&changeset.writes)?)?;
+        self.pending.flush()?;
+        self.has_pending = true;
+        self.finish_pending()
     }
 }
 
 impl Drop for SledStore {
     fn drop(&mut self) {
-        self.0.flush().ok();
+        self.db.flush().ok();
     }
-}
\ No newline at end of file
+}
@@ -9,6 +9,11 @@ use byteorder::{ReadBytesExt, WriteBytesExt};
 
 type Entry = (Vec<u8>, Vec<u8>);
 
+pub(crate) const MAGIC: &[u8; 4] = b"sst\0";
+
+/// The magic, then the footer's offset and length as `u64`s.
+pub(crate) const HEADER_SIZE: u64 = 20;
+
 // Each entry will be u32/u32/Vec<u8>(Key)/Vec<u8>(Value)
 pub struct SSTable {
     path: PathBuf,
@@ -31,10 +36,10 @@ impl SSTable {
         let mut offset = 0;
         let path = path.as_ref();
         let mut writer = BufWriter::new(File::create(path)?);
-        writer.write_all(&b"sst\0"[..])?;
+        writer.write_all(MAGIC)?;
         writer.write_u64::<LittleEndian>(0)?; // location of offsets
         writer.write_u64::<LittleEndian>(0)?; // count of offsets
-        offset += 20;
+        offset += HEADER_SIZE;
         let mut written = false;
         for &(ref key, ref value) in iter {
             written = true;
@@ -82,6 +87,11 @@ impl SSTable {
         })
     }
 
+    /// Where each entry starts, from the footer.
+    pub(crate) fn offsets(&self) -> &[u64] {
+        &self.offsets
+    }
+
     pub fn at(&self, offset: u64) -> io::Result<SSTableCursor> {
         let mut f = File::open(&self.path)?;
         f.seek(io::SeekFrom::Start(offset))?;
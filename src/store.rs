@@ -2,22 +2,34 @@ use std::collections::BTreeMap;
 use std::ffi::OsStr;
 use std::fs::{create_dir_all, File, OpenOptions};
 use std::io::{prelude::*, BufReader, BufWriter, SeekFrom};
+use std::ops::Bound;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use failure::{format_err};
 use serde::{Deserialize, Serialize};
 
-use crate::CaveyEngine;
-use crate::utils::check_engine;
+use crate::{transaction, CaveyEngine, CaveyError, Changeset, ChangeFeed, Version};
+use crate::backup::{copy_files, create_dest};
+use crate::utils::{check_engine, check_keyspace_name, scan_start};
 use super::Result;
 
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum LogRecord {
     Put { key: String, value: String },
     Remove { key: String },
+    /// The `len` records after this one were written by one transaction,
+    /// and apply together or not at all.
+    Batch { len: usize },
+}
+
+/// A batch read from the log, which applies once all its records are read.
+struct PendingBatch {
+    start: u64,
+    len: usize,
+    records: Vec<(LogRecord, u64)>,
 }
 
 
@@ -28,6 +40,13 @@ pub struct CaveyStore {
     file: BufWriter<File>,
     file_version: usize,
     entries: usize,
+    // Named keyspaces, each with its own keymap and log under
+    // `keyspaces/<name>`.  `None` for the stores serving those keyspaces.
+    keyspaces: Option<BTreeMap<String, CaveyStore>>,
+    // Shared by the store and its keyspaces.
+    feed: Arc<ChangeFeed>,
+    // The name of the keyspace served, or `None` for the default keyspace.
+    keyspace: Option<String>,
 }
 
 /// When a new command comes in, Add to WAL and in-memory BTree.  When size > 4MB, compact from in-memory
@@ -37,7 +56,25 @@ pub struct CaveyStore {
 /// (or the oldest one in the case of L0.
 impl CaveyStore {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<CaveyStore> {
-        let datadir = path.as_ref().join("data");
+        let path = path.as_ref();
+        let feed = Arc::new(ChangeFeed::new());
+        let mut store = CaveyStore::open_keyspace(path, feed.clone(), None)?;
+        let mut keyspaces = BTreeMap::new();
+        let keyspace_dir = path.join("keyspaces");
+        if keyspace_dir.exists() {
+            for entry in std::fs::read_dir(&keyspace_dir)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let store = CaveyStore::open_keyspace(&entry.path(), feed.clone(), Some(name.clone()))?;
+                keyspaces.insert(name, store);
+            }
+        }
+        store.keyspaces = Some(keyspaces);
+        Ok(store)
+    }
+
+    fn open_keyspace(path: &Path, feed: Arc<ChangeFeed>, keyspace: Option<String>) -> Result<CaveyStore> {
+        let datadir = path.join("data");
         create_dir_all(&datadir)?;
         check_engine(&datadir, b"cavey")?;
         let candidates = std::fs::read_dir(&datadir)?
@@ -67,19 +104,41 @@ impl CaveyStore {
         let mut offset = reader.seek(SeekFrom::Start(0))?;
 
         let mut entries = 0;
+        let mut batch: Option<PendingBatch> = None;
         for line in BufReader::new(&mut reader).lines() {
             let line = line?;
             let cmd = serde_json::from_str(&line)?;
+            let start = offset;
+            offset += line.len() as u64 + 1;
+            entries += 1;
             match cmd {
-                LogRecord::Put { key, .. } => {
-                    keymap.insert(key, (PathBuf::from(&filename), offset));
+                LogRecord::Batch { .. } if batch.is_some() => {
+                    return Err(CaveyError::Corruption(format!("batch inside a batch at offset {}", start)));
                 }
-                LogRecord::Remove { key } => {
-                    keymap.remove(&key);
+                LogRecord::Batch { len } => {
+                    if len > 0 {
+                        batch = Some(PendingBatch { start, len, records: Vec::with_capacity(len.min(1024)) });
+                    }
                 }
+                cmd => match &mut batch {
+                    Some(pending) => {
+                        pending.records.push((cmd, start));
+                        if pending.records.len() == pending.len {
+                            for (cmd, start) in batch.take().unwrap().records {
+                                replay(&mut keymap, cmd, &filename, start);
+                            }
+                        }
+                    }
+                    None => replay(&mut keymap, cmd, &filename, start),
+                },
             }
-            offset += line.len() as u64 + 1;
-            entries += 1;
+        }
+        // A batch cut short by a crash was never acknowledged, so drop it,
+        // before later writes are appended and mistaken for the rest of it.
+        if let Some(pending) = batch {
+            file.get_ref().set_len(pending.start)?;
+            file.seek(SeekFrom::End(0))?;
+            entries -= pending.records.len() + 1;
         }
         Ok(CaveyStore {
             datadir,
@@ -87,9 +146,53 @@ impl CaveyStore {
             keymap,
             entries,
             file_version,
+            keyspaces: None,
+            feed,
+            keyspace,
         })
     }
 
+    /// Write `records` to the log in one append, after a `Batch` record
+    /// counting them, and index them.
+    fn append_batch(&mut self, records: Vec<LogRecord>) -> Result<()> {
+        let start = self.file.stream_position()?;
+        let mut buf = Vec::new();
+        serde_json::to_writer(&mut buf, &LogRecord::Batch { len: records.len() })?;
+        buf.push(b'\n');
+        let mut offsets = Vec::with_capacity(records.len());
+        for record in &records {
+            offsets.push(start + buf.len() as u64);
+            serde_json::to_writer(&mut buf, record)?;
+            buf.push(b'\n');
+        }
+        // Bypass the buffer, so a failed write can be cut off before
+        // anything else is appended after it.
+        if let Err(err) = self.file.get_mut().write_all(&buf) {
+            self.file.get_ref().set_len(start)?;
+            self.file.seek(SeekFrom::End(0))?;
+            return Err(err.into());
+        }
+        self.entries += records.len() + 1;
+        let filename = self.current_file();
+        for (record, offset) in records.into_iter().zip(offsets) {
+            match record {
+                LogRecord::Put { key, value } => {
+                    self.keymap.insert(key.clone(), (filename.clone(), offset));
+                    self.feed.publish(self.keyspace.as_deref(), key, Some(value));
+                }
+                LogRecord::Remove { key } => {
+                    self.keymap.remove(&key);
+                    self.feed.publish(self.keyspace.as_deref(), key, None);
+                }
+                LogRecord::Batch { .. } => {}
+            }
+        }
+        if self.should_compact() {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
     fn should_compact(&mut self) -> bool {
         (self.entries >= 500) && (self.entries > (10 * self.keymap.len()))
     }
@@ -149,10 +252,42 @@ impl CaveyStore {
         Ok(())
     }
 
+    fn keyspace_path(&self, name: &str) -> PathBuf {
+        self.datadir.with_file_name("keyspaces").join(name)
+    }
+
+    fn keyspaces(&mut self) -> Result<&mut BTreeMap<String, CaveyStore>> {
+        self.keyspaces
+            .as_mut()
+            .ok_or_else(|| CaveyError::Unsupported("nested keyspaces".to_owned()))
+    }
+
     fn current_file(&self) -> PathBuf {
         self.datadir.join(format!("{:08x}", self.file_version))
     }
 
+    /// A key's version is the position of its latest record in the log.  Log
+    /// files are never reused, so neither are positions, but compaction moves
+    /// every record and so bumps every version.
+    fn position_version(filename: &Path, offset: u64) -> Result<Version> {
+        let file_version =
+            u64::from_str_radix(&filename.file_name().unwrap_or_default().to_string_lossy(), 0x10)?;
+        Ok(file_version << 40 | offset)
+    }
+
+}
+
+/// Apply a record read from the log at `offset` in `filename` to `keymap`.
+fn replay(keymap: &mut BTreeMap<String, (PathBuf, u64)>, record: LogRecord, filename: &Path, offset: u64) {
+    match record {
+        LogRecord::Put { key, .. } => {
+            keymap.insert(key, (filename.to_owned(), offset));
+        }
+        LogRecord::Remove { key } => {
+            keymap.remove(&key);
+        }
+        LogRecord::Batch { .. } => {}
+    }
 }
 
 impl CaveyEngine for CaveyStore {
@@ -166,9 +301,10 @@ impl CaveyEngine for CaveyStore {
                 .next();
             match cmd {
                 Some(Ok(LogRecord::Put { value, .. })) => Ok(Some(value)),
-                Some(Ok(LogRecord::Remove { .. })) => Err(format_err!("unexpected remove")),
-                Some(Err(err)) => Err(err)?,
-                None => Err(format_err!("unexpected eof")),
+                Some(Ok(LogRecord::Remove { .. })) => Err(CaveyError::Corruption("unexpected remove".to_owned())),
+                Some(Ok(LogRecord::Batch { .. })) => Err(CaveyError::Corruption("unexpected batch".to_owned())),
+                Some(Err(err)) => Err(err.into()),
+                None => Err(CaveyError::Corruption("unexpected eof".to_owned())),
             }
         } else {
             Ok(None)
@@ -176,30 +312,130 @@ impl CaveyEngine for CaveyStore {
     }
 
     fn put(&mut self, key: String, value: String) -> Result<()> {
-        let keycopy = key.clone();
         let cmd = LogRecord::Put { key, value };
         let offset = self.file.seek(SeekFrom::Current(0))?;
         serde_json::to_writer(&mut self.file, &cmd)?;
         self.file.write_all(b"\n")?;
         self.file.flush()?;
         self.entries += 1;
-        self.keymap.insert(keycopy, (self.current_file(), offset));
+        if let LogRecord::Put { key, value } = cmd {
+            self.keymap.insert(key.clone(), (self.current_file(), offset));
+            self.feed.publish(self.keyspace.as_deref(), key, Some(value));
+        }
         if self.should_compact() {
             self.compact()?;
         }
         Ok(())
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
+    fn remove(&mut self, key: String) -> Result<bool> {
+        if !self.keymap.contains_key(&key) {
+            return Ok(false);
+        }
         let cmd = LogRecord::Remove { key: key.clone() };
         serde_json::to_writer(&mut self.file, &cmd)?;
         self.file.write_all(b"\n")?;
         self.file.flush()?;
         self.entries += 1;
-        self.keymap
-            .remove(&key)
-            .and(Some(()))
-            .ok_or_else(|| format_err!("Key not found"))
+        self.keymap.remove(&key);
+        self.feed.publish(self.keyspace.as_deref(), key, None);
+        Ok(true)
+    }
+
+    fn version(&mut self, key: &str) -> Result<Option<Version>> {
+        match self.keymap.get(key) {
+            Some((filename, offset)) => Ok(Some(CaveyStore::position_version(filename, *offset)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn scan(&mut self, prefix: &str, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        let keys: Vec<String> = self
+            .keymap
+            .range::<str, _>((scan_start(prefix, after), Bound::Unbounded))
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .take(limit)
+            .cloned()
+            .collect();
+        let mut entries = Vec::with_capacity(keys.len());
+        for key in keys {
+            match self.get(key.clone())? {
+                Some(value) => entries.push((key, value)),
+                None => return Err(CaveyError::Corruption(format!("missing value for key {}", key))),
+            }
+        }
+        Ok(entries)
+    }
+
+    /// The writes go to the log together, so a crash part way through
+    /// leaves none of them applied.
+    fn apply_changeset(&mut self, changeset: Changeset) -> Result<()> {
+        transaction::validate(self, &changeset)?;
+        let keymap = &self.keymap;
+        let records: Vec<LogRecord> = changeset
+            .writes
+            .into_iter()
+            .filter_map(|(key, value)| match value {
+                Some(value) => Some(LogRecord::Put { key, value }),
+                None if keymap.contains_key(&key) => Some(LogRecord::Remove { key }),
+                None => None,
+            })
+            .collect();
+        if records.is_empty() {
+            return Ok(());
+        }
+        self.append_batch(records)
+    }
+
+    fn keyspace(&mut self, name: &str) -> Result<&mut dyn CaveyEngine> {
+        match self.keyspaces()?.get_mut(name) {
+            Some(store) => Ok(store),
+            None => Err(CaveyError::KeyspaceNotFound(name.to_owned())),
+        }
+    }
+
+    fn create_keyspace(&mut self, name: &str) -> Result<()> {
+        check_keyspace_name(name)?;
+        let path = self.keyspace_path(name);
+        let keyspaces = self.keyspaces()?;
+        if keyspaces.contains_key(name) {
+            return Err(CaveyError::KeyspaceExists(name.to_owned()));
+        }
+        let store = CaveyStore::open_keyspace(&path, self.feed.clone(), Some(name.to_owned()))?;
+        self.keyspaces()?.insert(name.to_owned(), store);
+        Ok(())
+    }
+
+    fn drop_keyspace(&mut self, name: &str) -> Result<()> {
+        let path = self.keyspace_path(name);
+        match self.keyspaces()?.remove(name) {
+            Some(store) => {
+                drop(store);
+                std::fs::remove_dir_all(path)?;
+                Ok(())
+            }
+            None => Err(CaveyError::KeyspaceNotFound(name.to_owned())),
+        }
+    }
+
+    fn list_keyspaces(&mut self) -> Result<Vec<String>> {
+        Ok(self.keyspaces()?.keys().cloned().collect())
+    }
+
+    /// Every write is flushed to the log as it is made, so copying the log
+    /// files copies every write.
+    fn backup(&mut self, dest: &Path) -> Result<()> {
+        create_dest(dest)?;
+        copy_files(&self.datadir, &dest.join("data"))?;
+        for (name, store) in self.keyspaces()?.iter() {
+            copy_files(&store.datadir, &dest.join("keyspaces").join(name).join("data"))?;
+        }
+        Ok(())
+    }
+
+    fn change_feed(&mut self) -> Result<Arc<ChangeFeed>> {
+        Ok(self.feed.clone())
     }
 
 }
@@ -0,0 +1,149 @@
+//! TLS for the native protocol over TCP, with rustls.
+//!
+//! Clients trust only the CA they are given, not the system's roots, so a
+//! deployment can pin its own CA.  Servers may also require clients to
+//! present a certificate signed by a CA of their own (mutual TLS).
+
+use std::convert::TryFrom;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConnection, RootCertStore, ServerConnection, StreamOwned};
+
+use crate::{ClientConfig, Listener, Result, Transport};
+
+/// Server settings: present the certificate chain in `cert` with the private
+/// key in `key`, both PEM files.  With `client_ca`, clients must present a
+/// certificate signed by one of the CAs in that PEM file.
+pub fn server_tls_config(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<Arc<rustls::ServerConfig>> {
+    let builder = rustls::ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_input)?;
+    let builder = match client_ca {
+        Some(path) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(load_roots(path)?), provider())
+                .build()
+                .map_err(invalid_input)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(load_certs(cert)?, load_key(key)?)
+        .map_err(invalid_input)?;
+    Ok(Arc::new(config))
+}
+
+/// Client settings: trust servers whose certificates are signed by one of the
+/// CAs in the PEM file `ca`.  With `client_cert`, a certificate chain and
+/// private key, authenticate to servers that require it.
+pub fn client_tls_config(ca: &Path, client_cert: Option<(&Path, &Path)>) -> Result<Arc<rustls::ClientConfig>> {
+    let builder = rustls::ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_input)?
+        .with_root_certificates(load_roots(ca)?);
+    let config = match client_cert {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(invalid_input)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|err| invalid_input(format!("{}: {}", path.display(), err)))?;
+    if certs.is_empty() {
+        return Err(invalid_input(format!("{}: no certificates found", path.display())));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|err| invalid_input(format!("{}: {}", path.display(), err)))
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(|err| invalid_input(format!("{}: {}", path.display(), err)))?;
+    }
+    Ok(roots)
+}
+
+/// Bad TLS settings are reported like other bad files.
+fn invalid_input<E: ToString>(err: E) -> crate::CaveyError {
+    io::Error::new(io::ErrorKind::InvalidInput, err.to_string()).into()
+}
+
+/// A TCP listener whose connections are encrypted.  The TLS handshake
+/// happens on each connection's own thread, so a slow client doesn't hold up
+/// others.
+pub struct TlsListener {
+    listener: TcpListener,
+    config: Arc<rustls::ServerConfig>,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, config: Arc<rustls::ServerConfig>) -> TlsListener {
+        TlsListener { listener, config }
+    }
+}
+
+impl Listener for TlsListener {
+    type Stream = StreamOwned<ServerConnection, TcpStream>;
+
+    fn accept(&self) -> io::Result<Self::Stream> {
+        let (stream, _) = self.listener.accept()?;
+        let conn = ServerConnection::new(self.config.clone()).map_err(io::Error::other)?;
+        Ok(StreamOwned::new(conn, stream))
+    }
+
+    fn set_timeout(stream: &Self::Stream, timeout: Option<Duration>) -> io::Result<()> {
+        TcpListener::set_timeout(&stream.sock, timeout)
+    }
+}
+
+/// A server reached over TLS: its address, the name its certificate must be
+/// issued to, and the client's TLS settings.
+#[derive(Clone, Debug)]
+pub struct TlsAddr {
+    addr: SocketAddr,
+    server_name: ServerName<'static>,
+    config: Arc<rustls::ClientConfig>,
+}
+
+impl TlsAddr {
+    /// `server_name` is a DNS name or IP address.
+    pub fn new(addr: SocketAddr, server_name: &str, config: Arc<rustls::ClientConfig>) -> Result<TlsAddr> {
+        let server_name = ServerName::try_from(server_name.to_owned()).map_err(invalid_input)?;
+        Ok(TlsAddr {
+            addr,
+            server_name,
+            config,
+        })
+    }
+}
+
+impl Transport for TlsAddr {
+    type Stream = StreamOwned<ClientConnection, TcpStream>;
+
+    fn connect(&self, config: &ClientConfig) -> io::Result<Self::Stream> {
+        let stream = self.addr.connect(config)?;
+        let conn = ClientConnection::new(self.config.clone(), self.server_name.clone()).map_err(io::Error::other)?;
+        Ok(StreamOwned::new(conn, stream))
+    }
+}
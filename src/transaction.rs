@@ -0,0 +1,131 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{CaveyClient, CaveyEngine, CaveyError, Result, Transport};
+
+/// Identifies a single write to a key.  Versions are only compared for
+/// equality, so an engine is free to choose any scheme that never hands out
+/// the same version twice.
+pub type Version = u64;
+
+/// The reads and buffered writes of a transaction, as submitted on commit.
+///
+/// `reads` holds the version of each key the transaction observed (`None`
+/// for keys that were absent), and `writes` holds the final value of each
+/// key it wrote (`None` for removes).
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Changeset {
+    pub reads: BTreeMap<String, Option<Version>>,
+    pub writes: BTreeMap<String, Option<String>>,
+}
+
+/// A store that optimistic transactions can run against: either a local
+/// `CaveyEngine`, or a remote one through `CaveyClient`.
+pub trait TransactionalStore {
+    /// Get the value of `key` together with its current version.
+    fn get_versioned(&mut self, key: String) -> Result<(Option<String>, Option<Version>)>;
+
+    /// Atomically validate the read set of `changeset` and apply its writes.
+    fn commit_changeset(&mut self, changeset: Changeset) -> Result<()>;
+
+    /// Start a new transaction against this store.
+    fn begin(&mut self) -> Transaction<'_, Self> {
+        Transaction {
+            store: self,
+            changeset: Changeset::default(),
+        }
+    }
+}
+
+impl<E: CaveyEngine + ?Sized> TransactionalStore for E {
+    fn get_versioned(&mut self, key: String) -> Result<(Option<String>, Option<Version>)> {
+        let version = self.version(&key)?;
+        Ok((self.get(key)?, version))
+    }
+
+    fn commit_changeset(&mut self, changeset: Changeset) -> Result<()> {
+        self.apply_changeset(changeset)
+    }
+}
+
+impl<T: Transport> TransactionalStore for CaveyClient<T> {
+    fn get_versioned(&mut self, key: String) -> Result<(Option<String>, Option<Version>)> {
+        CaveyClient::get_versioned(self, key)
+    }
+
+    fn commit_changeset(&mut self, changeset: Changeset) -> Result<()> {
+        self.commit(changeset)
+    }
+}
+
+/// An interactive, serializable transaction.
+///
+/// Reads go to the store and record the version they saw.  Writes are
+/// buffered until `commit`, which fails with a conflict if any key that was
+/// read has been written by someone else in the meantime.  Dropping the
+/// transaction (or calling `rollback`) discards the buffered writes.
+pub struct Transaction<'a, S: TransactionalStore + ?Sized> {
+    store: &'a mut S,
+    changeset: Changeset,
+}
+
+impl<'a, S: TransactionalStore + ?Sized> Transaction<'a, S> {
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.changeset.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        let (value, version) = self.store.get_versioned(key.clone())?;
+        // Keep the first version seen, so a key that changes between two reads
+        // of the same transaction is still caught on commit.
+        self.changeset.reads.entry(key).or_insert(version);
+        Ok(value)
+    }
+
+    pub fn put(&mut self, key: String, value: String) {
+        self.changeset.writes.insert(key, Some(value));
+    }
+
+    pub fn remove(&mut self, key: String) {
+        self.changeset.writes.insert(key, None);
+    }
+
+    pub fn commit(self) -> Result<()> {
+        if self.changeset.writes.is_empty() && self.changeset.reads.len() <= 1 {
+            // A lone read is trivially serializable.
+            return Ok(());
+        }
+        self.store.commit_changeset(self.changeset)
+    }
+
+    pub fn rollback(self) {}
+}
+
+/// Validate `changeset` against `engine` and apply its writes one at a
+/// time.  An error part way through leaves the earlier writes applied, so
+/// engines that can apply writes together override `apply_changeset`.
+///
+/// Callers must hold exclusive access to the engine for the whole call, which
+/// `&mut` guarantees.
+pub(crate) fn apply<E: CaveyEngine + ?Sized>(engine: &mut E, changeset: Changeset) -> Result<()> {
+    validate(engine, &changeset)?;
+    for (key, value) in changeset.writes {
+        match value {
+            Some(value) => engine.put(key, value)?,
+            None => {
+                engine.remove(key)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fail with a conflict if any key `changeset` read has changed version.
+pub(crate) fn validate<E: CaveyEngine + ?Sized>(engine: &mut E, changeset: &Changeset) -> Result<()> {
+    for (key, version) in &changeset.reads {
+        if engine.version(key)? != *version {
+            return Err(CaveyError::Conflict(key.clone()));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,135 @@
+//! The transports the native protocol runs over: TCP, or a Unix socket for
+//! clients on the same host, where filesystem permissions control access.
+
+use std::future::Future;
+use std::io::{self, prelude::*};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::ClientConfig;
+
+/// A socket a server accepts connections on.
+pub trait Listener: Sync {
+    type Stream: Read + Write + Send;
+
+    fn accept(&self) -> io::Result<Self::Stream>;
+
+    /// Bound how long reads and writes on `stream` wait, or lift the bound
+    /// with `None`.
+    fn set_timeout(stream: &Self::Stream, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept(&self) -> io::Result<TcpStream> {
+        TcpListener::accept(self).map(|(stream, _)| stream)
+    }
+
+    fn set_timeout(stream: &TcpStream, timeout: Option<Duration>) -> io::Result<()> {
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)
+    }
+}
+
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    fn accept(&self) -> io::Result<UnixStream> {
+        UnixListener::accept(self).map(|(stream, _)| stream)
+    }
+
+    fn set_timeout(stream: &UnixStream, timeout: Option<Duration>) -> io::Result<()> {
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)
+    }
+}
+
+/// A socket the async server accepts connections on.
+pub trait AsyncListener: Send + Sync {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    fn accept(&self) -> impl Future<Output = io::Result<Self::Stream>> + Send;
+}
+
+impl AsyncListener for tokio::net::TcpListener {
+    type Stream = tokio::net::TcpStream;
+
+    async fn accept(&self) -> io::Result<tokio::net::TcpStream> {
+        tokio::net::TcpListener::accept(self).await.map(|(stream, _)| stream)
+    }
+}
+
+impl AsyncListener for tokio::net::UnixListener {
+    type Stream = tokio::net::UnixStream;
+
+    async fn accept(&self) -> io::Result<tokio::net::UnixStream> {
+        tokio::net::UnixListener::accept(self).await.map(|(stream, _)| stream)
+    }
+}
+
+/// Where a client connects to: a `SocketAddr` for TCP, or a `UnixAddr`.
+pub trait Transport {
+    type Stream: Read + Write;
+
+    /// Open a connection, applying the timeouts in `config`.
+    fn connect(&self, config: &ClientConfig) -> io::Result<Self::Stream>;
+
+    /// Where to reach the server at `addr`, a leader a cluster node has
+    /// redirected the client to, if this transport can.
+    fn redirect(&self, _addr: &str) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        None
+    }
+}
+
+impl Transport for SocketAddr {
+    type Stream = TcpStream;
+
+    fn connect(&self, config: &ClientConfig) -> io::Result<TcpStream> {
+        let stream = match config.connect_timeout {
+            Some(timeout) => TcpStream::connect_timeout(self, timeout)?,
+            None => TcpStream::connect(self)?,
+        };
+        stream.set_read_timeout(config.read_timeout)?;
+        stream.set_write_timeout(config.write_timeout)?;
+        Ok(stream)
+    }
+
+    fn redirect(&self, addr: &str) -> Option<SocketAddr> {
+        addr.parse().ok()
+    }
+}
+
+/// The path of a server's Unix socket.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnixAddr(PathBuf);
+
+impl UnixAddr {
+    pub fn new<P: Into<PathBuf>>(path: P) -> UnixAddr {
+        UnixAddr(path.into())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Transport for UnixAddr {
+    type Stream = UnixStream;
+
+    /// Connecting to a local socket doesn't wait on the network, so
+    /// `connect_timeout` doesn't apply.
+    fn connect(&self, config: &ClientConfig) -> io::Result<UnixStream> {
+        let stream = UnixStream::connect(&self.0)?;
+        stream.set_read_timeout(config.read_timeout)?;
+        stream.set_write_timeout(config.write_timeout)?;
+        Ok(stream)
+    }
+}
@@ -1,13 +1,16 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::ops::Bound;
 use std::path::Path;
-use failure::format_err;
-use crate::Result;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use crate::{CaveyError, Result};
 
 pub(crate) fn check_engine(datadir: &Path, expected: &[u8]) -> Result<()> {
     let enginepath = datadir.join(".engine");
     if enginepath.exists() {
         let engine = std::fs::read(enginepath)?;
         if engine != expected {
-            return Err(format_err!("cavey error: wrong data format: {}", String::from_utf8_lossy(&engine)));
+            return Err(CaveyError::WrongEngine(String::from_utf8_lossy(&engine).into_owned()));
         }
     } else {
         std::fs::write(enginepath, expected)?;
@@ -15,3 +18,44 @@ pub(crate) fn check_engine(datadir: &Path, expected: &[u8]) -> Result<()> {
     Ok(())
 
 }
+
+/// Keyspace names become directory and tree names, so keep them to a safe
+/// alphabet.  Names starting with `__` are reserved, as sled names its
+/// default tree `__sled__default`.
+pub(crate) fn check_keyspace_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && !name.starts_with("__")
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-');
+    if valid {
+        Ok(())
+    } else {
+        Err(CaveyError::InvalidKeyspace(name.to_owned()))
+    }
+}
+
+/// Where a scan for keys starting with `prefix` and ordered after `after`
+/// begins.
+pub(crate) fn scan_start<'a>(prefix: &'a str, after: Option<&'a str>) -> Bound<&'a str> {
+    match after {
+        Some(after) if after >= prefix => Bound::Excluded(after),
+        _ => Bound::Included(prefix),
+    }
+}
+
+/// Lock `mutex`, even if a thread panicked while holding it, so that one bad
+/// request can't lock every other connection out of a shared engine.
+pub(crate) fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Replace the file at `path` with `contents`, so a crash leaves one or
+/// the other.
+pub(crate) fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
@@ -0,0 +1,330 @@
+//! Change feeds: the puts and removes made to an engine, kept for a while so
+//! clients can watch keys change, and pick up where they left off after
+//! reconnecting.
+//!
+//! Each event has a sequence number.  Numbers start from the time the feed
+//! was created, in microseconds, so they keep rising across restarts as long
+//! as writes average under a million a second.  A feed keeps its latest
+//! `FEED_BYTES` of events; resuming from an event no longer kept fails with
+//! `Expired`, and the client should re-read the keys it cares about before
+//! watching again.
+//!
+//! Engines publish events from their write paths.  sled's own subscribers
+//! aren't used: they deliver events on another thread after the write has
+//! returned, and one watching a dropped tree blocks forever.
+
+use std::collections::VecDeque;
+use std::io::{self, prelude::*};
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::acl::Access;
+use crate::protocol::{read_frame, ClientMessage, Handshake, ServerMessage, MAX_FRAME_SIZE};
+use crate::server::encode_response;
+use crate::utils::lock;
+use crate::{in_keyspace, CaveyEngine, CaveyError, Result, Transport};
+
+/// Bytes of events a feed keeps, counting keys and values.
+const FEED_BYTES: usize = 16 << 20;
+
+/// A watch with no events to send sends an empty batch this often, so
+/// clients can tell the connection is alive.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A put or remove of a key.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Event {
+    pub seq: u64,
+    pub keyspace: Option<String>,
+    pub key: String,
+    /// The value put, or `None` for a remove.
+    pub value: Option<String>,
+}
+
+impl Event {
+    fn size(&self) -> usize {
+        self.keyspace.as_ref().map_or(0, String::len) + self.key.len() + self.value.as_ref().map_or(0, String::len)
+    }
+}
+
+/// The events an engine has published, shared by its keyspaces.
+#[derive(Debug)]
+pub struct ChangeFeed {
+    state: Mutex<FeedState>,
+    published: Condvar,
+    // Wakes watches served from the async server.
+    notify: tokio::sync::watch::Sender<u64>,
+}
+
+#[derive(Debug)]
+struct FeedState {
+    next_seq: u64,
+    events: VecDeque<Event>,
+    bytes: usize,
+}
+
+impl FeedState {
+    /// The index of the event after `after`, if every event after it is
+    /// kept.
+    fn first_after(&self, after: u64) -> Result<usize> {
+        let first = self.next_seq - self.events.len() as u64;
+        // An event later than any published is from before a restart.
+        if after.saturating_add(1) < first || after >= self.next_seq {
+            return Err(CaveyError::Expired(after));
+        }
+        Ok((after + 1 - first) as usize)
+    }
+}
+
+impl Default for ChangeFeed {
+    fn default() -> ChangeFeed {
+        ChangeFeed::new()
+    }
+}
+
+impl ChangeFeed {
+    pub fn new() -> ChangeFeed {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let next_seq = now.as_micros() as u64;
+        ChangeFeed {
+            state: Mutex::new(FeedState {
+                next_seq,
+                events: VecDeque::new(),
+                bytes: 0,
+            }),
+            published: Condvar::new(),
+            notify: tokio::sync::watch::channel(next_seq).0,
+        }
+    }
+
+    /// Record a put of `value` to `key`, or a remove if `value` is `None`.
+    pub fn publish(&self, keyspace: Option<&str>, key: String, value: Option<String>) {
+        let mut state = lock(&self.state);
+        let event = Event {
+            seq: state.next_seq,
+            keyspace: keyspace.map(str::to_owned),
+            key,
+            value,
+        };
+        state.next_seq += 1;
+        state.bytes += event.size();
+        state.events.push_back(event);
+        while state.bytes > FEED_BYTES && state.events.len() > 1 {
+            let dropped = state.events.pop_front().unwrap();
+            state.bytes -= dropped.size();
+        }
+        let latest = state.next_seq - 1;
+        drop(state);
+        self.published.notify_all();
+        self.notify.send_replace(latest);
+    }
+
+    /// The sequence number of the latest event, which a new watch starts
+    /// after.
+    pub fn latest(&self) -> u64 {
+        lock(&self.state).next_seq - 1
+    }
+
+    /// Check that the events after `after` are all kept.
+    fn check_kept(&self, after: u64) -> Result<()> {
+        lock(&self.state).first_after(after).map(drop)
+    }
+
+    /// Up to `budget` bytes of the events after `after` that `filter`
+    /// matches, with the sequence number the next read should start after.
+    fn read(&self, state: &FeedState, filter: &Filter, after: u64, budget: usize) -> Result<(Vec<Event>, u64)> {
+        let first = state.first_after(after)?;
+        let (mut events, mut seq, mut bytes) = (Vec::new(), after, 0);
+        for event in state.events.iter().skip(first) {
+            if filter.matches(event) {
+                if !events.is_empty() && bytes + event.size() > budget {
+                    break;
+                }
+                bytes += event.size();
+                events.push(event.clone());
+            }
+            seq = event.seq;
+        }
+        Ok((events, seq))
+    }
+
+    /// Like `read`, but wait up to `timeout` for a matching event.
+    fn wait(&self, filter: &Filter, after: u64, budget: usize, timeout: Duration) -> Result<(Vec<Event>, u64)> {
+        let deadline = Instant::now() + timeout;
+        let mut state = lock(&self.state);
+        let mut after = after;
+        loop {
+            let (events, seq) = self.read(&state, filter, after, budget)?;
+            let now = Instant::now();
+            if !events.is_empty() || now >= deadline {
+                return Ok((events, seq));
+            }
+            after = seq;
+            state = self.published.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+}
+
+/// Which events a watch reports.
+pub(crate) struct Filter {
+    pub keyspace: Option<String>,
+    pub prefix: String,
+}
+
+impl Filter {
+    fn matches(&self, event: &Event) -> bool {
+        event.keyspace == self.keyspace && event.key.starts_with(&self.prefix)
+    }
+}
+
+/// Check a `Watch` request, returning the feed to stream, which events to
+/// stream from it, and the sequence number to stream them after.
+pub(crate) fn open_watch(
+    msg: &ClientMessage,
+    engine: &mut dyn CaveyEngine,
+    access: &Access,
+) -> Result<(Arc<ChangeFeed>, Filter, u64)> {
+    access.authorize(msg)?;
+    match msg {
+        ClientMessage::Watch { keyspace, prefix, after } => {
+            in_keyspace(engine, keyspace.as_deref())?;
+            let feed = engine.change_feed()?;
+            let after = after.unwrap_or_else(|| feed.latest());
+            feed.check_kept(after)?;
+            let filter = Filter {
+                keyspace: keyspace.clone(),
+                prefix: prefix.clone(),
+            };
+            Ok((feed, filter, after))
+        }
+        other => Err(CaveyError::Protocol(format!("unexpected request {:?}", other))),
+    }
+}
+
+/// Stream the events after `after` to a client that sent `Watch`, until it
+/// disconnects.  The first batch is sent at once, even if empty, and an
+/// error such as `Expired` ends the stream.
+pub(crate) fn stream_events<W: Write>(
+    stream: &mut W,
+    feed: &ChangeFeed,
+    filter: &Filter,
+    mut after: u64,
+    peer: &Handshake,
+) -> Result<()> {
+    let budget = peer.max_frame_size as usize / 2;
+    let mut timeout = Duration::from_secs(0);
+    loop {
+        let response = match feed.wait(filter, after, budget, timeout) {
+            Ok((events, seq)) => {
+                after = seq;
+                ServerMessage::Events { events, seq }
+            }
+            Err(err) => {
+                stream.write_all(&encode_response(&err.into(), peer)?)?;
+                return Ok(());
+            }
+        };
+        stream.write_all(&encode_response(&response, peer)?)?;
+        timeout = HEARTBEAT_INTERVAL;
+    }
+}
+
+/// `stream_events` for the async server.
+pub(crate) async fn stream_events_async<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    feed: &ChangeFeed,
+    filter: &Filter,
+    mut after: u64,
+    peer: &Handshake,
+) -> Result<()> {
+    let budget = peer.max_frame_size as usize / 2;
+    let mut published = feed.notify.subscribe();
+    let mut send_empty = true;
+    loop {
+        published.borrow_and_update();
+        let result = {
+            let state = lock(&feed.state);
+            feed.read(&state, filter, after, budget)
+        };
+        let (events, seq) = match result {
+            Ok(read) => read,
+            Err(err) => {
+                stream.write_all(&encode_response(&err.into(), peer)?).await?;
+                return Ok(());
+            }
+        };
+        after = seq;
+        let more = !events.is_empty();
+        if more || send_empty {
+            stream.write_all(&encode_response(&ServerMessage::Events { events, seq }, peer)?).await?;
+        }
+        // A full batch may leave events behind, so read again at once.
+        send_empty = !more && tokio::time::timeout(HEARTBEAT_INTERVAL, published.changed()).await.is_err();
+    }
+}
+
+/// A stream of events from `CaveyClient::watch`.  As an iterator it ends
+/// after the first error.
+pub struct Watch<T: Transport = SocketAddr> {
+    socket: T::Stream,
+    pending: VecDeque<Event>,
+    seq: u64,
+    failed: bool,
+}
+
+impl<T: Transport> Watch<T> {
+    pub(crate) fn new(socket: T::Stream, events: Vec<Event>, seq: u64) -> Watch<T> {
+        Watch {
+            socket,
+            pending: events.into(),
+            seq,
+            failed: false,
+        }
+    }
+
+    /// Where to resume watching from: every matching event up to this
+    /// sequence number has been returned.
+    pub fn seq(&self) -> u64 {
+        match self.pending.front() {
+            Some(event) => event.seq - 1,
+            None => self.seq,
+        }
+    }
+
+    /// Wait for the next event.
+    pub fn next_event(&mut self) -> Result<Event> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(event);
+            }
+            let msg = match read_frame::<_, ServerMessage>(&mut self.socket, MAX_FRAME_SIZE) {
+                Ok(msg) => msg,
+                // The server sends heartbeats, so it has gone away.
+                Err(CaveyError::Io(err)) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    return Err(CaveyError::Timeout);
+                }
+                Err(err) => return Err(err),
+            };
+            let (events, seq) = msg.into_events()?;
+            self.pending.extend(events);
+            self.seq = seq;
+        }
+    }
+}
+
+impl<T: Transport> Iterator for Watch<T> {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Result<Event>> {
+        if self.failed {
+            return None;
+        }
+        let result = self.next_event();
+        self.failed = result.is_err();
+        Some(result)
+    }
+}
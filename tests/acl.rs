@@ -0,0 +1,187 @@
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use cavey::{Acl, AsyncCaveyClient, CaveyClient, CaveyError, CaveyStore, ClientConfig, Result, ServerConfig};
+use cavey::{CaveyEngine, TransactionalStore};
+use tempfile::TempDir;
+
+const ACL: &str = r#"{
+    "principals": [
+        {
+            "name": "team-a",
+            "token": "token-a",
+            "grants": [
+                {"prefix": "team-a/", "ops": ["get", "put", "remove", "scan"]},
+                {"keyspace": "shared", "ops": ["get", "scan"]},
+                {"keyspace": "team-a", "ops": ["admin"]}
+            ]
+        },
+        {
+            "name": "writer",
+            "token": "token-w",
+            "grants": [{"ops": ["put"]}]
+        },
+        {
+            "name": "admin",
+            "token": "token-admin",
+            "grants": [{"keyspace": "*", "ops": ["get", "put", "remove", "scan", "admin"]}]
+        }
+    ]
+}"#;
+
+fn config() -> ServerConfig {
+    ServerConfig {
+        acl: Some(Arc::new(Acl::from_json(ACL).unwrap())),
+        ..ServerConfig::default()
+    }
+}
+
+fn spawn_server(temp_dir: &TempDir) -> Result<SocketAddr> {
+    let mut listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let path = temp_dir.path().to_owned();
+    thread::spawn(move || {
+        let mut store = CaveyStore::open(path).unwrap();
+        store.create_keyspace("shared").unwrap();
+        store.keyspace("shared").unwrap().put("motd".to_owned(), "hello".to_owned()).unwrap();
+        cavey::run_server_with_config(&mut listener, &mut store, &config()).unwrap();
+    });
+    Ok(addr)
+}
+
+fn connect(addr: SocketAddr, token: &str) -> Result<CaveyClient> {
+    let config = ClientConfig {
+        token: Some(token.to_owned()),
+        ..ClientConfig::default()
+    };
+    CaveyClient::with_config(addr, config)
+}
+
+fn denied<T: std::fmt::Debug>(result: Result<T>) -> bool {
+    match result {
+        Err(CaveyError::PermissionDenied(_)) => true,
+        other => panic!("expected permission denied, got {:?}", other),
+    }
+}
+
+#[test]
+fn authentication_required() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let mut client = CaveyClient::new(addr)?;
+    client.ping()?;
+    match client.get("team-a/key1".to_owned()) {
+        Err(CaveyError::Unauthenticated) => {}
+        other => panic!("expected unauthenticated, got {:?}", other),
+    }
+    match connect(addr, "wrong") {
+        Err(CaveyError::Unauthenticated) => {}
+        other => panic!("expected unauthenticated, got {:?}", other.map(|_| ())),
+    }
+    Ok(())
+}
+
+#[test]
+fn prefix_grants() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+    let mut client = connect(addr, "token-a")?;
+
+    client.put("team-a/key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("team-a/key1".to_owned())?, Some("value1".to_owned()));
+    assert!(denied(client.put("team-b/key1".to_owned(), "value1".to_owned())));
+    assert!(denied(client.get("team-b/key1".to_owned())));
+
+    assert_eq!(client.scan("team-a/".to_owned(), None, 10)?.len(), 1);
+    assert!(denied(client.scan("team".to_owned(), None, 10)));
+
+    // Transactions are checked key by key
+    let mut txn = client.begin();
+    txn.put("team-a/key2".to_owned(), "value2".to_owned());
+    txn.put("team-b/key2".to_owned(), "value2".to_owned());
+    assert!(denied(txn.commit()));
+    assert_eq!(client.get("team-a/key2".to_owned())?, None);
+    assert!(client.remove("team-a/key1".to_owned())?);
+    Ok(())
+}
+
+#[test]
+fn keyspace_grants() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+    let mut client = connect(addr, "token-a")?;
+
+    client.set_keyspace(Some("shared".to_owned()));
+    assert_eq!(client.get("motd".to_owned())?, Some("hello".to_owned()));
+    assert!(denied(client.put("motd".to_owned(), "bye".to_owned())));
+
+    client.create_keyspace("team-a".to_owned())?;
+    assert!(denied(client.create_keyspace("team-b".to_owned())));
+    // Administering a keyspace doesn't grant access to its keys
+    client.set_keyspace(Some("team-a".to_owned()));
+    assert!(denied(client.put("key1".to_owned(), "value1".to_owned())));
+
+    let mut admin = connect(addr, "token-admin")?;
+    admin.set_keyspace(Some("team-a".to_owned()));
+    admin.put("key1".to_owned(), "value1".to_owned())?;
+    admin.drop_keyspace("team-a".to_owned())?;
+    Ok(())
+}
+
+// Requests that return an old value need permission to read it
+#[test]
+fn returning_old_needs_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+    let mut client = connect(addr, "token-w")?;
+
+    client.put("key1".to_owned(), "value1".to_owned())?;
+    assert!(denied(client.get("key1".to_owned())));
+    assert!(denied(client.put_returning_old("key1".to_owned(), "value2".to_owned())));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn async_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().to_owned();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_io().build().unwrap();
+        runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            sender.send(listener.local_addr().unwrap()).unwrap();
+            let store = CaveyStore::open(path).unwrap();
+            cavey::run_async_server_with_config(listener, Box::new(store), config()).await.unwrap();
+        });
+    });
+    let addr = receiver.recv().unwrap();
+
+    let client = AsyncCaveyClient::connect(addr).await?;
+    match client.get("team-a/key1".to_owned()).await {
+        Err(CaveyError::Unauthenticated) => {}
+        other => panic!("expected unauthenticated, got {:?}", other),
+    }
+    client.authenticate("token-a".to_owned()).await?;
+    client.put("team-a/key1".to_owned(), "value1".to_owned()).await?;
+    match client.put("team-b/key1".to_owned(), "value1".to_owned()).await {
+        Err(CaveyError::PermissionDenied(_)) => {}
+        other => panic!("expected permission denied, got {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn invalid_acls() {
+    let duplicate = r#"{"principals": [
+        {"name": "a", "token": "same", "grants": []},
+        {"name": "b", "token": "same", "grants": []}
+    ]}"#;
+    assert!(Acl::from_json(duplicate).is_err());
+    let unknown_op = r#"{"principals": [{"name": "a", "token": "t", "grants": [{"ops": ["delete"]}]}]}"#;
+    assert!(Acl::from_json(unknown_op).is_err());
+    let empty_token = r#"{"principals": [{"name": "a", "token": "", "grants": []}]}"#;
+    assert!(Acl::from_json(empty_token).is_err());
+}
@@ -0,0 +1,175 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use cavey::{CaveyEngine, CaveyError, CaveyStore, LogRecord, Result, SegmentFormat, SegmentRecord, SledStore};
+use tempfile::TempDir;
+
+/// The log of the default keyspace in `dir`.
+fn log_path(dir: &Path) -> PathBuf {
+    dir.join("data").join("00000000")
+}
+
+fn append(path: &Path, bytes: &[u8]) -> Result<()> {
+    OpenOptions::new().append(true).open(path)?.write_all(bytes)?;
+    Ok(())
+}
+
+/// An SSTable holding `entries`, in order.
+fn sstable(entries: &[(&str, &str)]) -> Vec<u8> {
+    let mut offsets = Vec::new();
+    let mut body = Vec::new();
+    for (key, value) in entries {
+        offsets.push(20 + body.len() as u64);
+        body.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        body.extend_from_slice(key.as_bytes());
+        body.extend_from_slice(value.as_bytes());
+    }
+    let mut table = b"sst\0".to_vec();
+    table.extend_from_slice(&(20 + body.len() as u64).to_le_bytes());
+    table.extend_from_slice(&(offsets.len() as u64).to_le_bytes());
+    table.extend(body);
+    for offset in offsets {
+        table.extend_from_slice(&offset.to_le_bytes());
+    }
+    table
+}
+
+#[test]
+fn inspect_counts_live_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = CaveyStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.put(format!("key{}", i), "value".to_owned())?;
+    }
+    for i in 0..5 {
+        store.put(format!("key{}", i), "changed".to_owned())?;
+    }
+    store.remove("key9".to_owned())?;
+    store.create_keyspace("team-a")?;
+    store.keyspace("team-a")?.put("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let reports = cavey::inspect(temp_dir.path())?;
+    assert_eq!(reports.len(), 2);
+    let default = &reports[0];
+    assert_eq!(default.keyspace, None);
+    assert_eq!(default.format, SegmentFormat::Log);
+    assert_eq!(default.size, fs::metadata(log_path(temp_dir.path()))?.len());
+    assert_eq!((default.records, default.live_records), (16, 9));
+    assert!(default.live_bytes > 0 && default.live_bytes < default.size);
+    assert!(default.active);
+    assert_eq!(default.problem, None);
+    assert_eq!(reports[1].keyspace, Some("team-a".to_owned()));
+    assert_eq!(reports[1].live_records, 1);
+
+    let mut records = Vec::new();
+    cavey::read_segment(&log_path(temp_dir.path()), |record| records.push(record))?;
+    match &records[15] {
+        SegmentRecord::Log { record: LogRecord::Remove { key }, .. } => assert_eq!(key, "key9"),
+        other => panic!("expected the remove last, got {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn repair_truncates_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    CaveyStore::open(temp_dir.path())?.put("key1".to_owned(), "value1".to_owned())?;
+    let log = log_path(temp_dir.path());
+    let size = fs::metadata(&log)?.len();
+    let torn = br#"{"put":{"key":"key2","val"#;
+    append(&log, torn)?;
+    assert!(CaveyStore::open(temp_dir.path()).is_err());
+
+    let problem = cavey::inspect(temp_dir.path())?[0].problem.clone().expect("the torn write is reported");
+    assert_eq!(problem.offset, size);
+    assert!(problem.tail);
+    assert_eq!(cavey::repair(&log)?, torn.len() as u64);
+    assert_eq!(fs::metadata(&log)?.len(), size);
+    assert_eq!(cavey::repair(&log)?, 0);
+    assert_eq!(CaveyStore::open(temp_dir.path())?.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A transaction cut off part way through is dropped, not half applied
+#[test]
+fn incomplete_batch_is_dropped() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    CaveyStore::open(temp_dir.path())?.put("key1".to_owned(), "value1".to_owned())?;
+    let log = log_path(temp_dir.path());
+    let size = fs::metadata(&log)?.len();
+    append(&log, b"{\"batch\":{\"len\":2}}\n{\"put\":{\"key\":\"key1\",\"value\":\"changed\"}}\n")?;
+
+    let problem = cavey::inspect(temp_dir.path())?[0].problem.clone().expect("the incomplete batch is reported");
+    assert_eq!(problem.offset, size);
+    assert!(problem.tail);
+    let mut store = CaveyStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(fs::metadata(&log)?.len(), size);
+    store.put("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+    let mut store = CaveyStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn repair_keeps_records_after_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    CaveyStore::open(temp_dir.path())?.put("key1".to_owned(), "value1".to_owned())?;
+    let log = log_path(temp_dir.path());
+    append(&log, b"garbage\n{\"put\":{\"key\":\"key2\",\"value\":\"value2\"}}\n")?;
+
+    let report = cavey::read_segment(&log, |_| {})?;
+    assert_eq!(report.records, 1);
+    assert!(!report.problem.expect("the garbage is reported").tail);
+    match cavey::repair(&log) {
+        Err(CaveyError::Corruption(msg)) => assert!(msg.contains("followed by whole records"), "unexpected error {}", msg),
+        other => panic!("expected the log to be left alone, got {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn checks_sstables() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("table");
+    fs::write(&path, sstable(&[("a", "1"), ("b", "2")]))?;
+    let mut entries = Vec::new();
+    let report = cavey::read_segment(&path, |record| match record {
+        SegmentRecord::Table { offset, key, value } => entries.push((offset, key, value)),
+        other => panic!("expected a table entry, got {:?}", other),
+    })?;
+    assert_eq!(report.format, SegmentFormat::SSTable);
+    assert_eq!(report.problem, None);
+    assert_eq!(entries, vec![(20, b"a".to_vec(), b"1".to_vec()), (30, b"b".to_vec(), b"2".to_vec())]);
+
+    fs::write(&path, sstable(&[("b", "1"), ("a", "2")]))?;
+    let problem = cavey::read_segment(&path, |_| {})?.problem.expect("the order is checked");
+    assert_eq!((problem.offset, problem.message.as_str()), (30, "key out of order"));
+
+    let mut table = sstable(&[("a", "1")]);
+    table.truncate(table.len() - 4);
+    fs::write(&path, table)?;
+    assert!(cavey::read_segment(&path, |_| {})?.problem.is_some());
+    match cavey::repair(&path) {
+        Err(CaveyError::Unsupported(_)) => {}
+        other => panic!("expected SSTables not to be repaired, got {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn inspect_refuses_sled_data() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(SledStore::open(temp_dir.path())?);
+    match cavey::inspect(temp_dir.path()) {
+        Err(CaveyError::WrongEngine(engine)) => assert_eq!(engine, "sled"),
+        other => panic!("expected sled data to be refused, got {:?}", other),
+    }
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+
+use cavey::{AsyncCaveyClient, CaveyStore, Result};
+use tempfile::TempDir;
+
+fn spawn_server(temp_dir: &TempDir) -> Result<SocketAddr> {
+    let mut listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let path = temp_dir.path().to_owned();
+    thread::spawn(move || {
+        let mut store = CaveyStore::open(path).unwrap();
+        cavey::run_server(&mut listener, &mut store).unwrap();
+    });
+    Ok(addr)
+}
+
+#[tokio::test]
+async fn get_put_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let client = AsyncCaveyClient::connect(spawn_server(&temp_dir)?).await?;
+
+    client.put("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, Some("value1".to_owned()));
+    assert_eq!(
+        client.put_returning_old("key1".to_owned(), "value2".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert!(client.remove("key1".to_owned()).await?);
+    assert!(!client.remove("key1".to_owned()).await?);
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+    Ok(())
+}
+
+// Many requests in flight at once on one connection each get their own response
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let client = AsyncCaveyClient::connect(spawn_server(&temp_dir)?).await?;
+
+    let puts: Vec<_> = (0..100)
+        .map(|i| {
+            let client = client.clone();
+            tokio::spawn(async move { client.put(format!("key{}", i), format!("value{}", i)).await })
+        })
+        .collect();
+    for put in puts {
+        put.await.unwrap()?;
+    }
+
+    let gets: Vec<_> = (0..100)
+        .map(|i| {
+            let client = client.clone();
+            tokio::spawn(async move { (i, client.get(format!("key{}", i)).await) })
+        })
+        .collect();
+    for get in gets {
+        let (i, value) = get.await.unwrap();
+        assert_eq!(value?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
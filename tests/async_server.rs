@@ -0,0 +1,134 @@
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+
+use cavey::{AsyncCaveyClient, CaveyClient, CaveyEngine, CaveyStore, Result, Version};
+use tempfile::TempDir;
+
+fn spawn_async_server(temp_dir: &TempDir) -> Result<SocketAddr> {
+    let path = temp_dir.path().to_owned();
+    spawn_async_server_with(move || Box::new(CaveyStore::open(path).unwrap()))
+}
+
+fn spawn_async_server_with<F>(open: F) -> Result<SocketAddr>
+where
+    F: FnOnce() -> Box<dyn CaveyEngine> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_io()
+            .max_blocking_threads(2)
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            sender.send(listener.local_addr().unwrap()).unwrap();
+            cavey::run_async_server(listener, open()).await.unwrap();
+        });
+    });
+    Ok(receiver.recv().unwrap())
+}
+
+// Idle connections don't hold up other clients
+#[test]
+fn many_idle_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_async_server(&temp_dir)?;
+
+    let idle = (0..500)
+        .map(|_| TcpStream::connect(addr))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let mut client = CaveyClient::new(addr)?;
+    client.put("key1".to_owned(), "value1".to_owned())?;
+    let mut other = CaveyClient::new(addr)?;
+    assert_eq!(other.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(idle);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn pipelined_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let client = AsyncCaveyClient::connect(spawn_async_server(&temp_dir)?).await?;
+
+    let puts: Vec<_> = (0..100)
+        .map(|i| {
+            let client = client.clone();
+            tokio::spawn(async move { client.put(format!("key{}", i), format!("value{}", i)).await })
+        })
+        .collect();
+    for put in puts {
+        put.await.unwrap()?;
+    }
+    for i in 0..100 {
+        assert_eq!(client.get(format!("key{}", i)).await?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// A client sending an oversized frame is disconnected, without affecting
+// other clients
+#[test]
+fn oversized_frame_closes_only_its_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_async_server(&temp_dir)?;
+    let mut client = CaveyClient::new(addr)?;
+    client.put("key1".to_owned(), "value1".to_owned())?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    let mut hello = [0; 14];
+    stream.write_all(b"CAVY\x00\x01\x00\x00\x00\x00\x00\x10\x00\x00")?;
+    stream.read_exact(&mut hello)?;
+    stream.write_all(&u32::MAX.to_be_bytes())?;
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).ok();
+    assert!(!reply.is_empty(), "expected an error response");
+
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+/// An engine with a bug: reading the key "panic" panics.
+struct PanickingEngine(CaveyStore);
+
+impl CaveyEngine for PanickingEngine {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        assert_ne!(key, "panic", "engine bug");
+        self.0.get(key)
+    }
+
+    fn put(&mut self, key: String, value: String) -> Result<()> {
+        self.0.put(key, value)
+    }
+
+    fn remove(&mut self, key: String) -> Result<bool> {
+        self.0.remove(key)
+    }
+
+    fn version(&mut self, key: &str) -> Result<Option<Version>> {
+        self.0.version(key)
+    }
+
+    fn scan(&mut self, prefix: &str, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.0.scan(prefix, after, limit)
+    }
+}
+
+// A panic in the engine fails only the request that caused it
+#[test]
+fn engine_panic_does_not_poison_the_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().to_owned();
+    let addr = spawn_async_server_with(move || Box::new(PanickingEngine(CaveyStore::open(path).unwrap())))?;
+    let mut client = CaveyClient::new(addr)?;
+    client.put("key1".to_owned(), "value1".to_owned())?;
+
+    assert!(CaveyClient::new(addr)?.get("panic".to_owned()).is_err());
+
+    let mut other = CaveyClient::new(addr)?;
+    assert_eq!(other.get("key1".to_owned())?, Some("value1".to_owned()));
+    other.put("key2".to_owned(), "value2".to_owned())?;
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::thread;
+
+use cavey::{CaveyClient, CaveyEngine, CaveyError, CaveyStore, Result, ServerConfig, SledStore};
+use tempfile::TempDir;
+
+/// Serve `engine`, taking backups into `backup_dir`.
+fn spawn_server<E: CaveyEngine + 'static>(engine: E, backup_dir: &Path) -> Result<SocketAddr> {
+    let mut listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let mut engine = engine;
+    let config = ServerConfig {
+        backup_dir: Some(backup_dir.to_owned()),
+        ..ServerConfig::default()
+    };
+    thread::spawn(move || cavey::run_server_with_config(&mut listener, &mut engine, &config).unwrap());
+    Ok(addr)
+}
+
+/// Back up a live server, change its data, and restore the backup.
+fn backs_up_and_restores(addr: SocketAddr, backup_dir: &Path) -> Result<()> {
+    let mut client = CaveyClient::new(addr)?;
+    for i in 0..1500 {
+        client.put(format!("key{}", i), format!("value{}", i))?;
+    }
+    client.create_keyspace("team-a".to_owned())?;
+    client.set_keyspace(Some("team-a".to_owned()));
+    client.put("key1".to_owned(), "team-a/value1".to_owned())?;
+    client.set_keyspace(None);
+
+    let dest = "nightly/backup".to_owned();
+    client.backup(dest.clone())?;
+    assert!(backup_dir.join("nightly/backup").is_dir());
+    match client.backup(dest.clone()) {
+        Err(CaveyError::Io(err)) => assert!(err.to_string().contains("not empty"), "unexpected error {}", err),
+        other => panic!("expected the existing backup to be kept, got {:?}", other),
+    }
+
+    client.put("key1".to_owned(), "changed".to_owned())?;
+    client.put("new".to_owned(), "value".to_owned())?;
+    client.drop_keyspace("team-a".to_owned())?;
+    client.create_keyspace("team-b".to_owned())?;
+
+    client.restore(dest)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key1499".to_owned())?, Some("value1499".to_owned()));
+    assert_eq!(client.get("new".to_owned())?, None);
+    assert_eq!(client.list_keyspaces()?, vec!["team-a".to_owned()]);
+    client.set_keyspace(Some("team-a".to_owned()));
+    assert_eq!(client.get("key1".to_owned())?, Some("team-a/value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn backup_restore_cavey_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    backs_up_and_restores(spawn_server(CaveyStore::open(temp_dir.path())?, backup_dir.path())?, backup_dir.path())
+}
+
+#[test]
+fn backup_restore_sled_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    backs_up_and_restores(spawn_server(SledStore::open(temp_dir.path())?, backup_dir.path())?, backup_dir.path())
+}
+
+// A backup is a data directory the engine can open directly
+#[test]
+fn backup_opens_as_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest = backup_dir.path().join("backup");
+    {
+        let mut store = SledStore::open(temp_dir.path())?;
+        store.put("key1".to_owned(), "value1".to_owned())?;
+        store.create_keyspace("team-a")?;
+        store.keyspace("team-a")?.put("key1".to_owned(), "team-a/value1".to_owned())?;
+        store.backup(&dest)?;
+    }
+    let mut store = SledStore::open(&dest)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.keyspace("team-a")?.get("key1".to_owned())?, Some("team-a/value1".to_owned()));
+    drop(store);
+
+    // A backup can be restored into the other engine
+    let mut other = CaveyStore::open(backup_dir.path().join("kvs"))?;
+    other.restore(&dest)?;
+    assert_eq!(other.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(other.keyspace("team-a")?.get("key1".to_owned())?, Some("team-a/value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn restore_needs_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = CaveyStore::open(temp_dir.path())?;
+    store.put("key1".to_owned(), "value1".to_owned())?;
+    match store.restore(&temp_dir.path().join("missing")) {
+        Err(CaveyError::Io(err)) => assert_eq!(err.kind(), std::io::ErrorKind::NotFound),
+        other => panic!("expected no backup, got {:?}", other),
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Clients can only name backups within the server's backup directory
+#[test]
+fn backups_confined_to_backup_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(CaveyStore::open(temp_dir.path().join("data"))?, backup_dir.path())?;
+    let mut client = CaveyClient::new(addr)?;
+    client.put("key1".to_owned(), "value1".to_owned())?;
+
+    let outside = temp_dir.path().join("outside").to_string_lossy().into_owned();
+    for name in [outside.as_str(), "../outside", "nightly/../../outside", ""].iter() {
+        match client.backup(name.to_string()) {
+            Err(CaveyError::PermissionDenied(_)) => {}
+            other => panic!("expected backup to {:?} to be refused, got {:?}", name, other),
+        }
+        match client.restore(name.to_string()) {
+            Err(CaveyError::PermissionDenied(_)) => {}
+            other => panic!("expected restore from {:?} to be refused, got {:?}", name, other),
+        }
+    }
+    assert!(!temp_dir.path().join("outside").exists());
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Without a backup directory, there are no backups
+    let mut listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let mut store = CaveyStore::open(temp_dir.path().join("other"))?;
+    thread::spawn(move || cavey::run_server(&mut listener, &mut store).unwrap());
+    match CaveyClient::new(addr)?.backup("backup".to_owned()) {
+        Err(CaveyError::Unsupported(_)) => {}
+        other => panic!("expected backups to be unsupported, got {:?}", other),
+    }
+    Ok(())
+}
@@ -1,7 +1,8 @@
 use assert_cmd::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -272,7 +273,7 @@ fn cli_access_server(engine: &str, addr: &str) {
         .args(&["--addr", addr, "rm", "key2"])
         .current_dir(&temp_dir)
         .assert()
-        .failure()
+        .code(2)
         .stderr(contains("Key not found"));
 
     Command::cargo_bin("cavey")
@@ -345,3 +346,622 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_async_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+    let mut child = Command::cargo_bin("caveyd")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr, "--server", "async"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", addr, "put", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", addr, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", addr, "rm", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stderr(contains("Key not found"));
+
+    child.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_access_unix_socket() {
+    let temp_dir = TempDir::new().unwrap();
+    let sock = temp_dir.path().join("cavey.sock");
+    let sock = sock.to_str().unwrap();
+    // The second server replaces the socket the first left behind
+    for value in &["value1", "value2"] {
+        let mut child = Command::cargo_bin("caveyd")
+            .unwrap()
+            .args(&["--engine", "kvs", "--unix", sock])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        Command::cargo_bin("cavey")
+            .unwrap()
+            .args(&["--unix", sock, "put", "key1", value])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(is_empty());
+
+        Command::cargo_bin("cavey")
+            .unwrap()
+            .args(&["--unix", sock, "get", "key1"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(format!("{}\n", value));
+
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    }
+}
+
+#[test]
+fn cli_access_tls_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let ca_key = rcgen::KeyPair::generate().unwrap();
+    let mut params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+    params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    let ca = params.self_signed(&ca_key).unwrap();
+    let key = rcgen::KeyPair::generate().unwrap();
+    let params = rcgen::CertificateParams::new(vec!["127.0.0.1".to_owned()]).unwrap();
+    let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+    fs::write(temp_dir.path().join("ca.pem"), ca.pem()).unwrap();
+    fs::write(temp_dir.path().join("cert.pem"), cert.pem()).unwrap();
+    fs::write(temp_dir.path().join("key.pem"), key.serialize_pem()).unwrap();
+
+    let addr = "127.0.0.1:4010";
+    let mut child = Command::cargo_bin("caveyd")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr, "--tls-cert", "cert.pem", "--tls-key", "key.pem"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", addr, "--tls-ca", "ca.pem", "put", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", addr, "--tls-ca", "ca.pem", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    // Without TLS, the client can't talk to the server
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", addr, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    child.kill().expect("server exited before killed");
+}
+
+// The async server can't serve TLS, so refuses before opening anything
+#[test]
+fn cli_async_server_rejects_tls() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("caveyd")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4032", "--server", "async"])
+        .args(&["--tls-cert", "cert.pem", "--tls-key", "key.pem"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("--server async"));
+    assert!(!temp_dir.path().join(".engine").exists());
+}
+
+#[test]
+fn cli_access_server_with_acl() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("acl.json"),
+        r#"{"principals": [{"name": "app", "token": "secret", "grants": [{"prefix": "app/", "ops": ["get", "put"]}]}]}"#,
+    )
+    .unwrap();
+    let addr = "127.0.0.1:4011";
+    let mut child = Command::cargo_bin("caveyd")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr, "--acl", "acl.json"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", addr, "--token", "secret", "put", "app/key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", addr, "get", "app/key1"])
+        .env("CAVEY_TOKEN", "secret")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", addr, "--token", "secret", "put", "other/key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("PermissionDenied"));
+
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", addr, "get", "app/key1"])
+        .env_remove("CAVEY_TOKEN")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Unauthenticated"));
+
+    child.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_access_replica() {
+    let primary_dir = TempDir::new().unwrap();
+    let replica_dir = TempDir::new().unwrap();
+    let (primary, replica) = ("127.0.0.1:4012", "127.0.0.1:4014");
+    let mut primary_child = Command::cargo_bin("caveyd")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", primary, "--replication-addr", "127.0.0.1:4013"])
+        .current_dir(&primary_dir)
+        .spawn()
+        .unwrap();
+    let mut replica_child = Command::cargo_bin("caveyd")
+        .unwrap()
+        .args(&["--engine", "sled", "--addr", replica, "--replica-of", "127.0.0.1:4013"])
+        .current_dir(&replica_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", primary, "put", "key1", "value1"])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", replica, "get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", replica, "put", "key1", "value2"])
+        .assert()
+        .failure()
+        .stderr(contains("ReadOnly"));
+
+    primary_child.kill().expect("server exited before killed");
+    replica_child.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_cluster_failover() {
+    let addrs = ["127.0.0.1:4015", "127.0.0.1:4016", "127.0.0.1:4017"];
+    let raft_addrs = ["127.0.0.1:4018", "127.0.0.1:4019", "127.0.0.1:4020"];
+    let cluster: Vec<String> = raft_addrs.iter().zip(&addrs).map(|(raft, addr)| format!("{}={}", raft, addr)).collect();
+    let cluster = cluster.join(",");
+    let dirs: Vec<TempDir> = addrs.iter().map(|_| TempDir::new().unwrap()).collect();
+    let mut children: Vec<_> = (0..3)
+        .map(|i| {
+            let engine = if i % 2 == 0 { "kvs" } else { "sled" };
+            Command::cargo_bin("caveyd")
+                .unwrap()
+                .args(&["--engine", engine, "--addr", addrs[i], "--raft-addr", raft_addrs[i], "--cluster", &cluster])
+                .current_dir(&dirs[i])
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    thread::sleep(Duration::from_secs(2));
+
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", addrs[0], "put", "key1", "value1"])
+        .assert()
+        .success();
+
+    let output = Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", addrs[1], "cluster", "status"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let leader = raft_addrs
+        .iter()
+        .position(|raft| stdout.contains(&format!("leader: {}", raft)))
+        .expect("no leader in cluster status");
+    children[leader].kill().expect("server exited before killed");
+    children[leader].wait().unwrap();
+    thread::sleep(Duration::from_secs(3));
+
+    let survivor = addrs[(leader + 1) % 3];
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", survivor, "get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", survivor, "put", "key2", "value2"])
+        .assert()
+        .success();
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", addrs[(leader + 2) % 3], "get", "key2"])
+        .assert()
+        .success()
+        .stdout("value2\n");
+
+    for (i, child) in children.iter_mut().enumerate() {
+        if i != leader {
+            child.kill().expect("server exited before killed");
+        }
+    }
+}
+
+#[test]
+fn cli_shard_rebalance() {
+    let addrs = ["127.0.0.1:4021", "127.0.0.1:4022", "127.0.0.1:4023"];
+    let dirs: Vec<TempDir> = addrs.iter().map(|_| TempDir::new().unwrap()).collect();
+    let mut children: Vec<_> = (0..3)
+        .map(|i| {
+            Command::cargo_bin("caveyd")
+                .unwrap()
+                .args(&["--engine", "kvs", "--addr", addrs[i]])
+                .current_dir(&dirs[i])
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    thread::sleep(Duration::from_secs(1));
+
+    let two = addrs[..2].join(",");
+    let three = addrs.join(",");
+    for i in 0..10 {
+        Command::cargo_bin("cavey")
+            .unwrap()
+            .args(&["--shards", &two, "put", &format!("key{}", i), &format!("value{}", i)])
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--shards", &two, "shard", "add", addrs[2]])
+        .assert()
+        .success()
+        .stdout(contains("moved"));
+    for i in 0..10 {
+        Command::cargo_bin("cavey")
+            .unwrap()
+            .args(&["--shards", &three, "get", &format!("key{}", i)])
+            .assert()
+            .success()
+            .stdout(format!("value{}\n", i));
+    }
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", addrs[0], "shard", "add", addrs[2]])
+        .assert()
+        .failure()
+        .stderr(contains("--shards"));
+
+    for child in &mut children {
+        child.kill().expect("server exited before killed");
+    }
+}
+
+#[test]
+fn cli_watch() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("caveyd")
+        .unwrap()
+        .args(&["--engine", "sled", "--addr", "127.0.0.1:4024"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let mut watch = Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4024", "watch", "user/"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let (sender, receiver) = mpsc::channel();
+    let stdout = watch.stdout.take().unwrap();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            sender.send(line.unwrap()).unwrap();
+        }
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    for args in [&["put", "user/1", "alice"][..], &["put", "other", "ignored"], &["rm", "user/1"]] {
+        Command::cargo_bin("cavey")
+            .unwrap()
+            .args(&["--addr", "127.0.0.1:4024"])
+            .args(args)
+            .assert()
+            .success();
+    }
+    let put = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(put.ends_with(" put user/1 alice"), "unexpected event {:?}", put);
+    let rm = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(rm.ends_with(" rm user/1"), "unexpected event {:?}", rm);
+
+    // Resuming after the put streams just the remove
+    let seq = put.split(' ').next().unwrap();
+    let mut resumed = Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4024", "watch", "user/", "--after", seq])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut line = String::new();
+    BufReader::new(resumed.stdout.take().unwrap()).read_line(&mut line).unwrap();
+    assert_eq!(line.trim_end(), rm);
+
+    resumed.kill().expect("watch exited before killed");
+    watch.kill().expect("watch exited before killed");
+    server.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_backup_restore() {
+    let temp_dir = TempDir::new().unwrap();
+    let restored_dir = TempDir::new().unwrap();
+    let backup = temp_dir.path().join("backups").join("backup");
+    let mut server = Command::cargo_bin("caveyd")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4025", "--backup-dir", "backups"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4025", "put", "key1", "value1"])
+        .assert()
+        .success();
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4025", "backup", "backup"])
+        .assert()
+        .success();
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4025", "put", "key1", "value2"])
+        .assert()
+        .success();
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4025", "restore", "backup"])
+        .assert()
+        .success();
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4025", "get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    server.kill().expect("server exited before killed");
+
+    // A new server can start from the backup, even with the other engine
+    let mut restored = Command::cargo_bin("caveyd")
+        .unwrap()
+        .args(&["--engine", "sled", "--addr", "127.0.0.1:4026", "--restore-from", backup.to_str().unwrap()])
+        .current_dir(&restored_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4026", "get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    restored.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_dump_load() {
+    let source_dir = TempDir::new().unwrap();
+    let target_dir = TempDir::new().unwrap();
+    let dump = source_dir.path().join("dump.bin");
+    let mut source = Command::cargo_bin("caveyd")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4027"])
+        .current_dir(&source_dir)
+        .spawn()
+        .unwrap();
+    let mut target = Command::cargo_bin("caveyd")
+        .unwrap()
+        .args(&["--engine", "sled", "--addr", "127.0.0.1:4028"])
+        .current_dir(&target_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4027", "put", "key1", "value1"])
+        .assert()
+        .success();
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4027", "dump"])
+        .assert()
+        .success()
+        .stdout(contains(r#"{"entry":{"keyspace":null,"key":"key1","value":"value1"}}"#));
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4027", "dump", "--format", "binary", dump.to_str().unwrap()])
+        .assert()
+        .success();
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4028", "load", dump.to_str().unwrap()])
+        .assert()
+        .success();
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4028", "get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    source.kill().expect("server exited before killed");
+    target.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("db");
+    fs::create_dir(&db).unwrap();
+    let mut server = Command::cargo_bin("caveyd")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4029"])
+        .current_dir(&db)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4029", "put", "key1", "value1"])
+        .assert()
+        .success();
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+
+    Command::cargo_bin("caveyd")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs", "--to", "sled", db.to_str().unwrap()])
+        .assert()
+        .success()
+        .stderr(contains("migrated 1 entries"));
+    Command::cargo_bin("caveyd")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs", "--to", "sled", db.to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(contains("already exists"));
+
+    let mut server = Command::cargo_bin("caveyd")
+        .unwrap()
+        .args(&["--engine", "sled", "--addr", "127.0.0.1:4030"])
+        .current_dir(temp_dir.path().join("db-sled"))
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4030", "get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    server.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_admin() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("caveyd")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4031"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("cavey")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4031", "put", "key1", "value1"])
+        .assert()
+        .success();
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+
+    let dir = temp_dir.path().to_str().unwrap();
+    let log = temp_dir.path().join("data").join("00000000");
+    Command::cargo_bin("cavey-admin")
+        .unwrap()
+        .args(&["inspect", dir])
+        .assert()
+        .success()
+        .stdout(contains("1 records, 1 live (100% of bytes)"));
+    Command::cargo_bin("cavey-admin")
+        .unwrap()
+        .args(&["dump", log.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout("0 put key1 value1\n");
+
+    fs::OpenOptions::new().append(true).open(&log).unwrap().write_all(b"{\"put\"").unwrap();
+    Command::cargo_bin("cavey-admin")
+        .unwrap()
+        .args(&["fsck", dir])
+        .assert()
+        .failure()
+        .stdout(contains("a torn write"));
+    Command::cargo_bin("cavey-admin")
+        .unwrap()
+        .args(&["fsck", "--repair", dir])
+        .assert()
+        .success()
+        .stdout(contains("truncated 6 bytes"));
+    Command::cargo_bin("cavey-admin")
+        .unwrap()
+        .args(&["fsck", dir])
+        .assert()
+        .success()
+        .stdout("1 segments ok\n");
+}
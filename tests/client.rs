@@ -0,0 +1,126 @@
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use cavey::{CaveyClient, CaveyError, CaveyStore, ClientConfig, Result, RetryPolicy};
+use tempfile::TempDir;
+
+fn spawn_server(temp_dir: &TempDir) -> Result<SocketAddr> {
+    let mut listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let path = temp_dir.path().to_owned();
+    thread::spawn(move || {
+        let mut store = CaveyStore::open(path).unwrap();
+        cavey::run_server(&mut listener, &mut store).unwrap();
+    });
+    Ok(addr)
+}
+
+/// A server that accepts connections and completes the handshake, but never
+/// responds to requests, returning the number of connections accepted so far.
+fn spawn_hung_server() -> Result<(SocketAddr, Arc<AtomicUsize>)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+    thread::spawn(move || {
+        let mut streams = Vec::new();
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            // Echo the client's handshake back as our own.
+            let mut hello = [0; 14];
+            stream.read_exact(&mut hello).unwrap();
+            stream.write_all(&hello).unwrap();
+            streams.push(stream);
+        }
+    });
+    Ok((addr, accepted))
+}
+
+fn short_timeouts() -> ClientConfig {
+    ClientConfig {
+        read_timeout: Some(Duration::from_millis(100)),
+        retry: RetryPolicy {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(20),
+        },
+        ..ClientConfig::default()
+    }
+}
+
+#[test]
+fn returning_old_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut client = CaveyClient::new(spawn_server(&temp_dir)?)?;
+
+    assert_eq!(client.put_returning_old("key1".to_owned(), "value1".to_owned())?, None);
+    assert_eq!(
+        client.put_returning_old("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(client.remove_returning_old("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(client.remove_returning_old("key1".to_owned())?, None);
+    assert!(!client.remove("key1".to_owned())?);
+    Ok(())
+}
+
+// Each connection is served on its own thread, so an open client doesn't
+// block others
+#[test]
+fn concurrent_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+    let mut first = CaveyClient::new(addr)?;
+    let mut second = CaveyClient::new(addr)?;
+
+    first.put("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(second.get("key1".to_owned())?, Some("value1".to_owned()));
+    second.put("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(first.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Idempotent requests are retried on a new connection before timing out
+#[test]
+fn get_retries_then_times_out() -> Result<()> {
+    let (addr, accepted) = spawn_hung_server()?;
+    let mut client = CaveyClient::with_config(addr, short_timeouts())?;
+    match client.get("key1".to_owned()) {
+        Err(CaveyError::Timeout) => {}
+        other => panic!("expected timeout, got {:?}", other),
+    }
+    assert_eq!(accepted.load(Ordering::SeqCst), 3);
+    Ok(())
+}
+
+// Non-idempotent requests time out without being retried
+#[test]
+fn remove_fails_fast() -> Result<()> {
+    let (addr, accepted) = spawn_hung_server()?;
+    let mut client = CaveyClient::with_config(addr, short_timeouts())?;
+    match client.remove("key1".to_owned()) {
+        Err(CaveyError::Timeout) => {}
+        other => panic!("expected timeout, got {:?}", other),
+    }
+    assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    Ok(())
+}
+
+
+// A server that never completes the handshake times the client out
+#[test]
+fn handshake_times_out() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    match CaveyClient::with_config(addr, short_timeouts()) {
+        Err(CaveyError::Timeout) => {}
+        other => panic!("expected timeout, got {:?}", other.map(drop)),
+    }
+    drop(listener);
+    Ok(())
+}
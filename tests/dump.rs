@@ -0,0 +1,133 @@
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+
+use cavey::{CaveyClient, CaveyEngine, CaveyError, CaveyStore, DumpFormat, Result, SledStore};
+use tempfile::TempDir;
+
+fn fill(engine: &mut dyn CaveyEngine) -> Result<()> {
+    for i in 0..1500 {
+        engine.put(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    engine.put("unicode".to_owned(), "línea\nnueva \"quoted\"".to_owned())?;
+    engine.create_keyspace("team-a")?;
+    engine.keyspace("team-a")?.put("key0000".to_owned(), "team-a/value0".to_owned())?;
+    engine.create_keyspace("empty")?;
+    Ok(())
+}
+
+fn check(engine: &mut dyn CaveyEngine) -> Result<()> {
+    assert_eq!(engine.get("key0000".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(engine.get("key1499".to_owned())?, Some("value1499".to_owned()));
+    assert_eq!(engine.get("unicode".to_owned())?, Some("línea\nnueva \"quoted\"".to_owned()));
+    assert_eq!(engine.list_keyspaces()?, vec!["empty".to_owned(), "team-a".to_owned()]);
+    assert_eq!(engine.keyspace("team-a")?.get("key0000".to_owned())?, Some("team-a/value0".to_owned()));
+    Ok(())
+}
+
+fn moves_between_engines(format: DumpFormat) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut kvs = CaveyStore::open(temp_dir.path().join("kvs"))?;
+    fill(&mut kvs)?;
+    let mut dump = Vec::new();
+    assert_eq!(cavey::dump(&mut kvs, &mut dump, format)?, 1502);
+
+    let mut sled = SledStore::open(temp_dir.path().join("sled"))?;
+    assert_eq!(cavey::load(&mut sled, &dump[..])?, 1502);
+    check(&mut sled)?;
+
+    // And back again
+    let mut redump = Vec::new();
+    cavey::dump(&mut sled, &mut redump, format)?;
+    let mut kvs = CaveyStore::open(temp_dir.path().join("kvs2"))?;
+    cavey::load(&mut kvs, &redump[..])?;
+    check(&mut kvs)
+}
+
+#[test]
+fn json_moves_between_engines() -> Result<()> {
+    moves_between_engines(DumpFormat::Json)
+}
+
+#[test]
+fn binary_moves_between_engines() -> Result<()> {
+    moves_between_engines(DumpFormat::Binary)
+}
+
+#[test]
+fn json_is_one_record_per_line() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = CaveyStore::open(temp_dir.path())?;
+    store.put("key1".to_owned(), "value1".to_owned())?;
+    let mut dump = Vec::new();
+    cavey::dump(&mut store, &mut dump, DumpFormat::Json)?;
+    let lines: Vec<serde_json::Value> = String::from_utf8(dump)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(
+        lines,
+        vec![
+            serde_json::json!({"cavey_dump": 1}),
+            serde_json::json!({"entry": {"keyspace": null, "key": "key1", "value": "value1"}}),
+            serde_json::json!({"end": {"entries": 1}}),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn detects_damage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = CaveyStore::open(temp_dir.path().join("source"))?;
+    fill(&mut store)?;
+    let mut target = SledStore::open(temp_dir.path().join("target"))?;
+    let mut dump = Vec::new();
+    for format in [DumpFormat::Json, DumpFormat::Binary] {
+        dump.clear();
+        cavey::dump(&mut store, &mut dump, format)?;
+        match cavey::load(&mut target, &dump[..dump.len() - 10]) {
+            Err(CaveyError::Corruption(_)) => {}
+            other => panic!("expected corruption, got {:?}", other),
+        }
+    }
+
+    // Binary records are checksummed
+    let middle = dump.len() / 2;
+    dump[middle] ^= 0x01;
+    match cavey::load(&mut target, &dump[..]) {
+        Err(CaveyError::Corruption(_)) => {}
+        other => panic!("expected corruption, got {:?}", other),
+    }
+    match cavey::load(&mut target, &b"key1 value1\n"[..]) {
+        Err(CaveyError::Corruption(msg)) => assert_eq!(msg, "not a cavey dump"),
+        other => panic!("expected corruption, got {:?}", other),
+    }
+    Ok(())
+}
+
+fn spawn_server<E: CaveyEngine + 'static>(engine: E) -> Result<SocketAddr> {
+    let mut listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let mut engine = engine;
+    thread::spawn(move || cavey::run_server(&mut listener, &mut engine).unwrap());
+    Ok(addr)
+}
+
+#[test]
+fn client_dumps_and_loads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut source = CaveyStore::open(temp_dir.path().join("source"))?;
+    fill(&mut source)?;
+    let source = spawn_server(source)?;
+    let target = spawn_server(SledStore::open(temp_dir.path().join("target"))?)?;
+
+    let mut dump = Vec::new();
+    assert_eq!(CaveyClient::new(source)?.dump(&mut dump, DumpFormat::Binary)?, 1502);
+    let mut client = CaveyClient::new(target)?;
+    assert_eq!(client.load(&dump[..])?, 1502);
+    assert_eq!(client.get("key1499".to_owned())?, Some("value1499".to_owned()));
+    client.set_keyspace(Some("team-a".to_owned()));
+    assert_eq!(client.get("key0000".to_owned())?, Some("team-a/value0".to_owned()));
+    Ok(())
+}
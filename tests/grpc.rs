@@ -0,0 +1,168 @@
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use cavey::proto::cavey_client::CaveyClient as GrpcClient;
+use cavey::proto::*;
+use cavey::{CaveyClient, CaveyEngine, CaveyStore, ServerConfig};
+use tempfile::TempDir;
+use tonic::transport::Channel;
+use tonic::Code;
+
+/// Serve gRPC and the native protocol from one store, returning the native
+/// and gRPC addresses.
+fn spawn_servers(temp_dir: &TempDir, config: ServerConfig) -> (SocketAddr, SocketAddr) {
+    let native = TcpListener::bind("127.0.0.1:0").unwrap();
+    let grpc = TcpListener::bind("127.0.0.1:0").unwrap();
+    grpc.set_nonblocking(true).unwrap();
+    let addrs = (native.local_addr().unwrap(), grpc.local_addr().unwrap());
+    let mut store = CaveyStore::open(temp_dir.path()).unwrap();
+    store.create_keyspace("team-a").unwrap();
+    let engine = Arc::new(Mutex::new(Box::new(store) as Box<dyn CaveyEngine>));
+    {
+        let engine = engine.clone();
+        let config = config.clone();
+        thread::spawn(move || cavey::serve(&native, &*engine, &config).unwrap());
+    }
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_io().build().unwrap();
+        runtime.block_on(async {
+            let listener = tokio::net::TcpListener::from_std(grpc).unwrap();
+            cavey::serve_grpc(listener, engine, config).await.unwrap();
+        });
+    });
+    addrs
+}
+
+async fn connect(addr: SocketAddr) -> GrpcClient<Channel> {
+    let channel = Channel::from_shared(format!("http://{}", addr)).unwrap().connect().await.unwrap();
+    GrpcClient::new(channel)
+}
+
+fn get(key: &str) -> GetRequest {
+    GetRequest {
+        keyspace: String::new(),
+        key: key.to_owned(),
+    }
+}
+
+fn put(key: &str, value: &str) -> PutRequest {
+    PutRequest {
+        keyspace: String::new(),
+        key: key.to_owned(),
+        value: value.to_owned(),
+    }
+}
+
+#[tokio::test]
+async fn get_put_remove() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (_, addr) = spawn_servers(&temp_dir, ServerConfig::default());
+    let mut client = connect(addr).await;
+
+    client.put(put("key1", "value1")).await.unwrap();
+    let value = client.get(get("key1")).await.unwrap().into_inner().value;
+    assert_eq!(value, Some("value1".to_owned()));
+
+    let remove = RemoveRequest {
+        keyspace: String::new(),
+        key: "key1".to_owned(),
+    };
+    assert!(client.remove(remove.clone()).await.unwrap().into_inner().removed);
+    assert!(!client.remove(remove).await.unwrap().into_inner().removed);
+    assert_eq!(client.get(get("key1")).await.unwrap().into_inner().value, None);
+}
+
+#[tokio::test]
+async fn scan_pages() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (_, addr) = spawn_servers(&temp_dir, ServerConfig::default());
+    let mut client = connect(addr).await;
+    for key in &["user:1", "user:2", "user:3", "other"] {
+        client.put(put(key, key)).await.unwrap();
+    }
+
+    let mut request = ScanRequest {
+        keyspace: String::new(),
+        prefix: "user:".to_owned(),
+        after: None,
+        limit: 2,
+    };
+    let page = client.scan(request.clone()).await.unwrap().into_inner();
+    let keys: Vec<_> = page.entries.iter().map(|entry| &entry.key[..]).collect();
+    assert_eq!(keys, ["user:1", "user:2"]);
+    assert_eq!(page.next.as_deref(), Some("user:2"));
+
+    request.after = page.next;
+    let page = client.scan(request).await.unwrap().into_inner();
+    assert_eq!(
+        page.entries,
+        [Entry {
+            key: "user:3".to_owned(),
+            value: "user:3".to_owned()
+        }]
+    );
+    assert_eq!(page.next, None);
+}
+
+#[tokio::test]
+async fn batch() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (_, addr) = spawn_servers(&temp_dir, ServerConfig::default());
+    let mut client = connect(addr).await;
+    client.put(put("key1", "value1")).await.unwrap();
+
+    let write = |key: &str, value: Option<&str>| Write {
+        key: key.to_owned(),
+        value: value.map(str::to_owned),
+    };
+    let batch = BatchRequest {
+        keyspace: String::new(),
+        writes: vec![
+            write("key1", None),
+            write("key2", Some("first")),
+            write("key2", Some("second")),
+        ],
+    };
+    client.batch(batch).await.unwrap();
+    assert_eq!(client.get(get("key1")).await.unwrap().into_inner().value, None);
+    let value = client.get(get("key2")).await.unwrap().into_inner().value;
+    assert_eq!(value, Some("second".to_owned()));
+}
+
+#[tokio::test]
+async fn keyspaces_and_errors() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = ServerConfig {
+        max_value_size: 16,
+        ..ServerConfig::default()
+    };
+    let (_, addr) = spawn_servers(&temp_dir, config);
+    let mut client = connect(addr).await;
+
+    let mut request = put("key1", "a");
+    request.keyspace = "team-a".to_owned();
+    client.put(request).await.unwrap();
+    assert_eq!(client.get(get("key1")).await.unwrap().into_inner().value, None);
+
+    let mut request = get("key1");
+    request.keyspace = "missing".to_owned();
+    assert_eq!(client.get(request).await.unwrap_err().code(), Code::NotFound);
+    let status = client.put(put("key1", &"x".repeat(17))).await.unwrap_err();
+    assert_eq!(status.code(), Code::ResourceExhausted);
+}
+
+#[tokio::test]
+async fn shared_with_native_protocol() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (native, addr) = spawn_servers(&temp_dir, ServerConfig::default());
+    let mut client = connect(addr).await;
+
+    client.put(put("key1", "value1")).await.unwrap();
+    let value = tokio::task::spawn_blocking(move || {
+        CaveyClient::new(native).unwrap().get("key1".to_owned()).unwrap()
+    })
+    .await
+    .unwrap();
+    assert_eq!(value, Some("value1".to_owned()));
+}
@@ -0,0 +1,182 @@
+use std::io::{prelude::*, BufReader};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::thread;
+
+use cavey::{CaveyEngine, CaveyStore, Result, ServerConfig};
+use serde_json::{json, Value};
+use tempfile::TempDir;
+
+fn spawn_http_server(temp_dir: &TempDir, config: ServerConfig) -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let path = temp_dir.path().to_owned();
+    thread::spawn(move || {
+        let mut store = CaveyStore::open(path).unwrap();
+        store.create_keyspace("team-a").unwrap();
+        let engine: Mutex<&mut dyn CaveyEngine> = Mutex::new(&mut store);
+        cavey::serve_http(&listener, &engine, &config).unwrap();
+    });
+    Ok(addr)
+}
+
+/// Read a response, returning its status and its body parsed as JSON, or
+/// `Null` if it has none.
+fn read_response<R: BufRead>(reader: &mut R) -> Result<(u16, Value)> {
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    let status = status_line.split(' ').nth(1).unwrap().parse().unwrap();
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').unwrap();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.trim().parse().unwrap();
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    let body = if body.is_empty() { Value::Null } else { serde_json::from_slice(&body).unwrap() };
+    Ok((status, body))
+}
+
+fn request(addr: SocketAddr, method: &str, target: &str, body: Option<Value>) -> Result<(u16, Value)> {
+    let mut stream = TcpStream::connect(addr)?;
+    let body = body.map(|body| body.to_string()).unwrap_or_default();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method,
+        target,
+        body.len(),
+        body
+    )?;
+    read_response(&mut BufReader::new(stream))
+}
+
+#[test]
+fn get_put_delete() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_http_server(&temp_dir, ServerConfig::default())?;
+
+    assert_eq!(request(addr, "PUT", "/v1/kv/key1", Some(json!({"value": "value1"})))?.0, 204);
+    assert_eq!(
+        request(addr, "GET", "/v1/kv/key1", None)?,
+        (200, json!({"key": "key1", "value": "value1"}))
+    );
+    assert_eq!(request(addr, "DELETE", "/v1/kv/key1", None)?.0, 204);
+
+    let (status, body) = request(addr, "GET", "/v1/kv/key1", None)?;
+    assert_eq!(status, 404);
+    assert_eq!(body["error"]["code"], "not_found");
+    assert_eq!(request(addr, "DELETE", "/v1/kv/key1", None)?.0, 404);
+    Ok(())
+}
+
+// Keys are percent-decoded, and may contain slashes
+#[test]
+fn encoded_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_http_server(&temp_dir, ServerConfig::default())?;
+
+    request(addr, "PUT", "/v1/kv/a%20b/c", Some(json!({"value": "value1"})))?;
+    assert_eq!(
+        request(addr, "GET", "/v1/kv/a%20b%2Fc", None)?,
+        (200, json!({"key": "a b/c", "value": "value1"}))
+    );
+    Ok(())
+}
+
+#[test]
+fn list_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_http_server(&temp_dir, ServerConfig::default())?;
+    for key in &["user:1", "user:2", "user:3", "other"] {
+        request(addr, "PUT", &format!("/v1/kv/{}", key), Some(json!({"value": key})))?;
+    }
+
+    let (status, page) = request(addr, "GET", "/v1/kv?prefix=user%3A&limit=2", None)?;
+    assert_eq!(status, 200);
+    assert_eq!(
+        page,
+        json!({
+            "items": [{"key": "user:1", "value": "user:1"}, {"key": "user:2", "value": "user:2"}],
+            "next": "user:2",
+        })
+    );
+    let (_, page) = request(addr, "GET", "/v1/kv?prefix=user:&limit=2&after=user:2", None)?;
+    assert_eq!(page, json!({"items": [{"key": "user:3", "value": "user:3"}], "next": null}));
+    Ok(())
+}
+
+#[test]
+fn keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_http_server(&temp_dir, ServerConfig::default())?;
+
+    request(addr, "PUT", "/v1/kv/key1?keyspace=team-a", Some(json!({"value": "a"})))?;
+    assert_eq!(request(addr, "GET", "/v1/kv/key1", None)?.0, 404);
+    assert_eq!(request(addr, "GET", "/v1/kv/key1?keyspace=team-a", None)?.1["value"], "a");
+
+    let (status, body) = request(addr, "GET", "/v1/kv/key1?keyspace=missing", None)?;
+    assert_eq!(status, 404);
+    assert_eq!(body["error"]["code"], "keyspace_not_found");
+    Ok(())
+}
+
+#[test]
+fn bad_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = ServerConfig {
+        max_value_size: 16,
+        ..ServerConfig::default()
+    };
+    let addr = spawn_http_server(&temp_dir, config)?;
+
+    let (status, body) = request(addr, "PUT", "/v1/kv/key1", Some(json!("value1")))?;
+    assert_eq!(status, 400);
+    assert_eq!(body["error"]["code"], "bad_request");
+    let (status, body) = request(addr, "PUT", "/v1/kv/key1", Some(json!({"value": "x".repeat(17)})))?;
+    assert_eq!(status, 413);
+    assert_eq!(body["error"]["code"], "too_large");
+    assert_eq!(request(addr, "POST", "/v1/kv/key1", None)?.0, 405);
+    assert_eq!(request(addr, "GET", "/v2/kv/key1", None)?.0, 404);
+    Ok(())
+}
+
+// A body shorter than its Content-Length claims is refused once it ends
+#[test]
+fn truncated_body() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_http_server(&temp_dir, ServerConfig::default())?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    write!(stream, "PUT /v1/kv/key1 HTTP/1.1\r\nContent-Length: 16000000\r\n\r\n{{\"value\"")?;
+    stream.shutdown(Shutdown::Write)?;
+    let (status, body) = read_response(&mut reader)?;
+    assert_eq!(status, 400);
+    assert_eq!(body["error"]["code"], "bad_request");
+    Ok(())
+}
+
+// Connections are kept open between requests unless the client asks otherwise
+#[test]
+fn keep_alive() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_http_server(&temp_dir, ServerConfig::default())?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let body = json!({"value": "value1"}).to_string();
+    write!(stream, "PUT /v1/kv/key1 HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body)?;
+    assert_eq!(read_response(&mut reader)?.0, 204);
+    write!(stream, "GET /v1/kv/key1 HTTP/1.1\r\n\r\n")?;
+    assert_eq!(read_response(&mut reader)?.1["value"], "value1");
+    Ok(())
+}
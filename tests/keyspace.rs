@@ -0,0 +1,103 @@
+use std::net::TcpListener;
+use std::thread;
+
+use cavey::{CaveyClient, CaveyError, CaveyEngine, CaveyStore, Result, SledStore};
+use tempfile::TempDir;
+
+// Keys in different keyspaces don't collide, and keyspaces survive a reopen
+#[test]
+fn keyspaces_are_separate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = CaveyStore::open(temp_dir.path())?;
+    store.create_keyspace("team-a")?;
+    store.create_keyspace("team_b")?;
+
+    store.put("key1".to_owned(), "default".to_owned())?;
+    store.keyspace("team-a")?.put("key1".to_owned(), "a".to_owned())?;
+    store.keyspace("team_b")?.put("key1".to_owned(), "b".to_owned())?;
+
+    drop(store);
+    let mut store = CaveyStore::open(temp_dir.path())?;
+    assert_eq!(store.list_keyspaces()?, vec!["team-a".to_owned(), "team_b".to_owned()]);
+    assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+    assert_eq!(store.keyspace("team-a")?.get("key1".to_owned())?, Some("a".to_owned()));
+    assert_eq!(store.keyspace("team_b")?.get("key1".to_owned())?, Some("b".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn drop_keyspace() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = CaveyStore::open(temp_dir.path())?;
+    store.create_keyspace("scratch")?;
+    store.keyspace("scratch")?.put("key1".to_owned(), "value1".to_owned())?;
+    store.drop_keyspace("scratch")?;
+
+    assert!(store.keyspace("scratch").is_err());
+    assert!(store.drop_keyspace("scratch").is_err());
+    store.create_keyspace("scratch")?;
+    assert_eq!(store.keyspace("scratch")?.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn invalid_keyspace_names() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = CaveyStore::open(temp_dir.path())?;
+    assert!(store.create_keyspace("").is_err());
+    assert!(store.create_keyspace("../escape").is_err());
+    assert!(store.create_keyspace("with space").is_err());
+    store.create_keyspace("once")?;
+    assert!(store.create_keyspace("once").is_err());
+    Ok(())
+}
+
+// Sled's default tree can't be opened, or dropped, as a keyspace
+#[test]
+fn reserved_keyspace_names() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = SledStore::open(temp_dir.path())?;
+    store.put("key1".to_owned(), "value1".to_owned())?;
+    match store.create_keyspace("__sled__default") {
+        Err(CaveyError::InvalidKeyspace(name)) => assert_eq!(name, "__sled__default"),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert!(store.drop_keyspace("__sled__default").is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.list_keyspaces()?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn keyspaces_over_network() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let path = temp_dir.path().to_owned();
+    thread::spawn(move || {
+        let mut store = CaveyStore::open(path).unwrap();
+        cavey::run_server(&mut listener, &mut store).unwrap();
+    });
+
+    let mut client = CaveyClient::new(addr)?;
+    client.create_keyspace("team-a".to_owned())?;
+    assert_eq!(client.list_keyspaces()?, vec!["team-a".to_owned()]);
+    client.put("key1".to_owned(), "default".to_owned())?;
+
+    client.set_keyspace(Some("team-a".to_owned()));
+    assert_eq!(client.get("key1".to_owned())?, None);
+    client.put("key1".to_owned(), "a".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("a".to_owned()));
+
+    client.set_keyspace(Some("missing".to_owned()));
+    match client.get("key1".to_owned()) {
+        Err(CaveyError::KeyspaceNotFound(name)) => assert_eq!(name, "missing"),
+        other => panic!("expected KeyspaceNotFound, got {:?}", other),
+    }
+
+    client.set_keyspace(None);
+    assert_eq!(client.get("key1".to_owned())?, Some("default".to_owned()));
+    client.drop_keyspace("team-a".to_owned())?;
+    assert!(client.list_keyspaces()?.is_empty());
+    Ok(())
+}
@@ -1,4 +1,4 @@
-use cavey::{CaveyStore, CaveyEngine, Result};
+use cavey::{CaveyError, CaveyStore, CaveyEngine, Result};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -65,7 +65,16 @@ fn get_non_existent_value() -> Result<()> {
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let mut store = CaveyStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+    let log_size = |temp_dir: &TempDir| -> u64 {
+        WalkDir::new(temp_dir.path().join("data"))
+            .into_iter()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum()
+    };
+    store.put("key2".to_owned(), "value2".to_owned())?;
+    let before = log_size(&temp_dir);
+    assert!(!store.remove("key1".to_owned())?);
+    assert_eq!(log_size(&temp_dir), before);
     Ok(())
 }
 
@@ -74,7 +83,7 @@ fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let mut store = CaveyStore::open(temp_dir.path())?;
     store.put("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove("key1".to_owned()).is_ok());
+    assert!(store.remove("key1".to_owned())?);
     assert_eq!(store.get("key1".to_owned())?, None);
     Ok(())
 }
@@ -124,3 +133,55 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Opening a data directory created by another engine fails with WrongEngine
+#[test]
+fn wrong_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::create_dir_all(temp_dir.path().join("data"))?;
+    std::fs::write(temp_dir.path().join("data").join(".engine"), b"sled")?;
+    match CaveyStore::open(temp_dir.path()) {
+        Err(CaveyError::WrongEngine(engine)) => assert_eq!(engine, "sled"),
+        other => panic!("expected WrongEngine, got {:?}", other.map(|_| ())),
+    }
+    Ok(())
+}
+
+#[test]
+fn returning_old_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = CaveyStore::open(temp_dir.path())?;
+    assert_eq!(store.put_returning_old("key1".to_owned(), "value1".to_owned())?, None);
+    assert_eq!(
+        store.put_returning_old("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.remove_returning_old("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.remove_returning_old("key1".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Scans page through keys with a prefix in order
+#[test]
+fn scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = CaveyStore::open(temp_dir.path())?;
+    for key in &["a", "b1", "b2", "b3", "c"] {
+        store.put(key.to_string(), format!("value-{}", key))?;
+    }
+    store.remove("b2".to_owned())?;
+
+    assert_eq!(
+        store.scan("b", None, 10)?,
+        vec![
+            ("b1".to_owned(), "value-b1".to_owned()),
+            ("b3".to_owned(), "value-b3".to_owned()),
+        ]
+    );
+    assert_eq!(store.scan("", None, 2)?.len(), 2);
+    assert_eq!(store.scan("", Some("b1"), 2)?[0].0, "b3");
+    assert_eq!(store.scan("b", Some("a"), 10)?.len(), 2);
+    assert!(store.scan("b", Some("b3"), 10)?.is_empty());
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use cavey::{CaveyEngine, CaveyError, CaveyStore, Result, SledStore};
+use tempfile::TempDir;
+
+fn fill(engine: &mut dyn CaveyEngine) -> Result<()> {
+    for i in 0..1500 {
+        engine.put(format!("key{}", i), format!("value{}", i))?;
+    }
+    engine.remove("key0".to_owned())?;
+    engine.create_keyspace("team-a")?;
+    engine.keyspace("team-a")?.put("key1".to_owned(), "team-a/value1".to_owned())?;
+    engine.create_keyspace("empty")?;
+    Ok(())
+}
+
+fn check(engine: &mut dyn CaveyEngine) -> Result<()> {
+    assert_eq!(engine.get("key0".to_owned())?, None);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key1499".to_owned())?, Some("value1499".to_owned()));
+    assert_eq!(engine.scan("", None, 2000)?.len(), 1499);
+    let mut keyspaces = engine.list_keyspaces()?;
+    keyspaces.sort();
+    assert_eq!(keyspaces, vec!["empty".to_owned(), "team-a".to_owned()]);
+    assert_eq!(engine.keyspace("team-a")?.get("key1".to_owned())?, Some("team-a/value1".to_owned()));
+    Ok(())
+}
+
+/// sled releases its file lock from background threads some time after
+/// the store is dropped, so wait before opening the directory again.
+fn wait_for_unlock() {
+    thread::sleep(Duration::from_secs(1));
+}
+
+fn migrates(src: &Path, from: &str, to: &str) -> Result<()> {
+    fill(&mut *cavey::open_engine(from, src)?)?;
+    wait_for_unlock();
+    let dest = src.with_file_name(format!("{}-{}", src.file_name().unwrap().to_string_lossy(), to));
+    assert_eq!(cavey::migrate(src, from, &dest, to, |_| {})?, 1500);
+    wait_for_unlock();
+    check(&mut *cavey::open_engine(to, &dest)?)?;
+    wait_for_unlock();
+    // The source is left as it was
+    check(&mut *cavey::open_engine(from, src)?)?;
+    Ok(())
+}
+
+#[test]
+fn migrate_kvs_to_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    migrates(&temp_dir.path().join("db"), "kvs", "sled")?;
+    match CaveyStore::open(temp_dir.path().join("db-sled")) {
+        Err(CaveyError::WrongEngine(engine)) => assert_eq!(engine, "sled"),
+        other => panic!("expected a sled directory, got {:?}", other.map(drop)),
+    }
+    Ok(())
+}
+
+#[test]
+fn migrate_sled_to_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    migrates(&temp_dir.path().join("db"), "sled", "kvs")?;
+    match SledStore::open(temp_dir.path().join("db-kvs")) {
+        Err(CaveyError::WrongEngine(engine)) => assert_eq!(engine, "cavey"),
+        other => panic!("expected a kvs directory, got {:?}", other.map(drop)),
+    }
+    Ok(())
+}
+
+#[test]
+fn migrate_refuses_bad_paths() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let src = temp_dir.path().join("db");
+    let dest = temp_dir.path().join("dest");
+    match cavey::migrate(&src, "kvs", &dest, "sled", |_| {}) {
+        Err(CaveyError::Io(err)) => assert!(err.to_string().contains("no data"), "unexpected error {}", err),
+        other => panic!("expected a missing source to fail, got {:?}", other),
+    }
+
+    CaveyStore::open(&src)?.put("key1".to_owned(), "value1".to_owned())?;
+    std::fs::create_dir(&dest)?;
+    match cavey::migrate(&src, "kvs", &dest, "sled", |_| {}) {
+        Err(CaveyError::Io(err)) => assert!(err.to_string().contains("already exists"), "unexpected error {}", err),
+        other => panic!("expected an existing destination to be kept, got {:?}", other),
+    }
+    std::fs::remove_dir(&dest)?;
+
+    // Opening the source with the wrong engine fails without leaving
+    // anything behind
+    match cavey::migrate(&src, "sled", &dest, "kvs", |_| {}) {
+        Err(CaveyError::WrongEngine(engine)) => assert_eq!(engine, "cavey"),
+        other => panic!("expected the wrong engine to fail, got {:?}", other),
+    }
+    assert!(!dest.exists());
+    assert!(!temp_dir.path().join("dest.migrating").exists());
+
+    cavey::migrate(&src, "kvs", &dest, "sled", |_| {})?;
+    wait_for_unlock();
+    assert_eq!(SledStore::open(&dest)?.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use std::net::{SocketAddr, TcpListener};
+use std::process::{Child, Command};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use assert_cmd::prelude::*;
+use cavey::{CaveyPool, CaveyStore, PoolConfig, Result};
+use tempfile::TempDir;
+
+fn spawn_server(temp_dir: &TempDir) -> Result<SocketAddr> {
+    let mut listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let path = temp_dir.path().to_owned();
+    thread::spawn(move || {
+        let mut store = CaveyStore::open(path).unwrap();
+        cavey::run_server(&mut listener, &mut store).unwrap();
+    });
+    Ok(addr)
+}
+
+/// A caveyd process, killed on drop.
+struct Caveyd(Child);
+
+impl Drop for Caveyd {
+    fn drop(&mut self) {
+        self.0.kill().expect("server exited before killed");
+        self.0.wait().unwrap();
+    }
+}
+
+fn spawn_caveyd(temp_dir: &TempDir, addr: &str) -> Caveyd {
+    let child = Command::cargo_bin("caveyd")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Caveyd(child)
+}
+
+#[test]
+fn shared_between_threads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = PoolConfig {
+        min_connections: 2,
+        max_connections: 4,
+        ..PoolConfig::default()
+    };
+    let pool = Arc::new(CaveyPool::new(spawn_server(&temp_dir)?, config)?);
+    assert_eq!(pool.connections(), 2);
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let pool = pool.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..5 {
+                    let key = format!("key{}-{}", thread_id, i);
+                    pool.put(key.clone(), format!("value{}", i))?;
+                    assert_eq!(pool.get(key.clone())?, Some(format!("value{}", i)));
+                    assert!(pool.remove(key)?);
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert!(pool.connections() <= 4);
+    Ok(())
+}
+
+#[test]
+fn idle_connections_expire() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = PoolConfig {
+        min_connections: 1,
+        max_connections: 4,
+        idle_timeout: Duration::from_millis(100),
+        ..PoolConfig::default()
+    };
+    let pool = Arc::new(CaveyPool::new(spawn_server(&temp_dir)?, config)?);
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let pool = pool.clone();
+            thread::spawn(move || pool.get("key".to_owned()))
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    thread::sleep(Duration::from_millis(200));
+    pool.get("key".to_owned())?;
+    assert_eq!(pool.connections(), 1);
+    Ok(())
+}
+
+// Connections broken by a server restart are replaced transparently
+#[test]
+fn reconnect_after_restart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4007";
+    let server = spawn_caveyd(&temp_dir, addr);
+    let config = PoolConfig {
+        health_check_interval: Duration::from_secs(3600),
+        ..PoolConfig::default()
+    };
+    let pool = CaveyPool::new(addr.parse::<SocketAddr>().unwrap(), config)?;
+    pool.put("key1".to_owned(), "value1".to_owned())?;
+
+    drop(server);
+    let _server = spawn_caveyd(&temp_dir, addr);
+
+    assert_eq!(pool.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,168 @@
+use std::io::prelude::*;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::thread;
+
+use cavey::{CaveyClient, CaveyError, CaveyStore, Result, ServerConfig};
+use tempfile::TempDir;
+
+fn spawn_server(temp_dir: &TempDir) -> Result<SocketAddr> {
+    spawn_server_with_config(temp_dir, ServerConfig::default())
+}
+
+fn spawn_server_with_config(temp_dir: &TempDir, config: ServerConfig) -> Result<SocketAddr> {
+    let mut listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let path = temp_dir.path().to_owned();
+    thread::spawn(move || {
+        let mut store = CaveyStore::open(path).unwrap();
+        cavey::run_server_with_config(&mut listener, &mut store, &config).unwrap();
+    });
+    Ok(addr)
+}
+
+/// A handshake as sent on the wire: magic, version, feature flags and
+/// maximum frame size.
+fn handshake(version: u16) -> Vec<u8> {
+    let mut buf = b"CAVY".to_vec();
+    buf.extend_from_slice(&version.to_be_bytes());
+    buf.extend_from_slice(&0u32.to_be_bytes());
+    buf.extend_from_slice(&(1u32 << 20).to_be_bytes());
+    buf
+}
+
+#[test]
+fn client_rejects_other_version() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut hello = [0; 14];
+        stream.read_exact(&mut hello).unwrap();
+        stream.write_all(&handshake(99)).unwrap();
+    });
+    match CaveyClient::new(addr) {
+        Err(CaveyError::Protocol(msg)) => assert!(msg.contains("version"), "{}", msg),
+        Err(err) => panic!("expected protocol error, got {:?}", err),
+        Ok(_) => panic!("expected protocol error"),
+    }
+    Ok(())
+}
+
+// The server answers a client speaking another version before hanging up,
+// so the client can report the mismatch
+#[test]
+fn server_rejects_other_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut stream = TcpStream::connect(spawn_server(&temp_dir)?)?;
+    stream.write_all(&handshake(99))?;
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply)?;
+    assert_eq!(reply.len(), 14);
+    assert_eq!(&reply[..4], b"CAVY");
+    assert_eq!(&reply[4..6], &1u16.to_be_bytes());
+    Ok(())
+}
+
+#[test]
+fn server_rejects_other_protocols() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut stream = TcpStream::connect(spawn_server(&temp_dir)?)?;
+    stream.write_all(b"GET / HTTP/1.1\r\n")?;
+    let mut reply = Vec::new();
+    // The server may reset the connection rather than close it cleanly.
+    stream.read_to_end(&mut reply).ok();
+    assert!(reply.is_empty());
+    Ok(())
+}
+
+// A message over the maximum frame size fails without breaking the client
+#[test]
+fn oversized_frame() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut client = CaveyClient::new(spawn_server(&temp_dir)?)?;
+    let value = "x".repeat(17 << 20);
+    match client.put("key1".to_owned(), value) {
+        Err(CaveyError::TooLarge(msg)) => assert!(msg.contains("frame size"), "{}", msg),
+        other => panic!("expected size error, got {:?}", other),
+    }
+    client.put("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn key_and_value_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = ServerConfig {
+        max_key_size: 8,
+        max_value_size: 16,
+        ..ServerConfig::default()
+    };
+    let mut client = CaveyClient::new(spawn_server_with_config(&temp_dir, config)?)?;
+    match client.put("x".repeat(9), "value1".to_owned()) {
+        Err(CaveyError::TooLarge(msg)) => assert!(msg.contains("key"), "{}", msg),
+        other => panic!("expected size error, got {:?}", other),
+    }
+    match client.put("key1".to_owned(), "x".repeat(17)) {
+        Err(CaveyError::TooLarge(msg)) => assert!(msg.contains("value"), "{}", msg),
+        other => panic!("expected size error, got {:?}", other),
+    }
+    client.put("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+/// Open a raw connection, complete the handshake and send `frame`, returning
+/// everything the server sends back before closing the connection.
+fn send_raw_frame(addr: SocketAddr, frame: &[u8]) -> Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&handshake(1))?;
+    let mut hello = [0; 14];
+    stream.read_exact(&mut hello)?;
+    stream.write_all(frame)?;
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).ok();
+    Ok(reply)
+}
+
+// A client announcing a huge frame or string is disconnected, without
+// affecting other clients
+#[test]
+fn malicious_requests_close_only_their_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+    let mut client = CaveyClient::new(addr)?;
+    client.put("key1".to_owned(), "value1".to_owned())?;
+
+    // A 4GB frame.
+    let reply = send_raw_frame(addr, &u32::MAX.to_be_bytes())?;
+    assert!(!reply.is_empty(), "expected an error response");
+
+    // A small frame holding a `Get` whose key claims to be 4GB long.
+    let mut payload = vec![0; 5];
+    payload.extend_from_slice(&(1u64 << 32).to_le_bytes());
+    let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&payload);
+    let reply = send_raw_frame(addr, &frame)?;
+    assert!(!reply.is_empty(), "expected an error response");
+
+    // Garbage.
+    let mut frame = 4u32.to_be_bytes().to_vec();
+    frame.extend_from_slice(&[0xff; 4]);
+    send_raw_frame(addr, &frame)?;
+
+    // A frame that stops short of its announced length.
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&handshake(1))?;
+    stream.read_exact(&mut [0; 14])?;
+    stream.write_all(&(1u32 << 20).to_be_bytes())?;
+    stream.write_all(&[0; 3])?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).ok();
+    assert!(reply.is_empty(), "expected the connection to be closed");
+
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(CaveyClient::new(addr)?.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
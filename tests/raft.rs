@@ -0,0 +1,316 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cavey::{CaveyClient, CaveyEngine, CaveyError, CaveyStore, Member, RaftConfig, RaftNode, Result, ServerConfig};
+use cavey::{SledStore, TransactionalStore, Version};
+use tempfile::TempDir;
+
+struct Node {
+    node: RaftNode,
+    member: Member,
+}
+
+impl Node {
+    fn addr(&self) -> SocketAddr {
+        self.member.addr.parse().unwrap()
+    }
+}
+
+fn listen() -> (TcpListener, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    (listener, addr)
+}
+
+/// Start `count` nodes, the first `bootstrap` of them forming the cluster,
+/// serving the native protocol and raft.
+fn spawn_nodes(temp_dir: &TempDir, count: usize, bootstrap: usize, compact_threshold: usize) -> Vec<Node> {
+    let listeners: Vec<_> = (0..count).map(|_| (listen(), listen())).collect();
+    let members: Vec<Member> = listeners
+        .iter()
+        .map(|((_, id), (_, addr))| Member {
+            id: id.clone(),
+            addr: addr.clone(),
+        })
+        .collect();
+    listeners
+        .into_iter()
+        .zip(members.clone())
+        .enumerate()
+        .map(|(i, (((raft, _), (native, _)), member))| {
+            let dir = temp_dir.path().join(format!("node{}", i));
+            let engine: Box<dyn CaveyEngine> = if i % 2 == 0 {
+                Box::new(CaveyStore::open(&dir).unwrap())
+            } else {
+                Box::new(SledStore::open(&dir).unwrap())
+            };
+            let mut config = RaftConfig::new(member.id.clone(), member.addr.clone(), dir);
+            config.election_timeout = Duration::from_millis(300);
+            config.heartbeat_interval = Duration::from_millis(50);
+            config.compact_threshold = compact_threshold;
+            let initial = if i < bootstrap { &members[..bootstrap] } else { &[] };
+            let node = RaftNode::open(config, engine, initial).unwrap();
+            {
+                let node = node.clone();
+                thread::spawn(move || node.serve(&raft).unwrap());
+            }
+            let engine: Mutex<Box<dyn CaveyEngine>> = Mutex::new(Box::new(node.engine()));
+            thread::spawn(move || cavey::serve(&native, &engine, &ServerConfig::default()).unwrap());
+            Node { node, member }
+        })
+        .collect()
+}
+
+fn wait_for<F: FnMut() -> bool>(what: &str, mut done: F) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+fn leader(nodes: &[Node]) -> &Node {
+    let mut leader = None;
+    wait_for("a leader", || {
+        leader = nodes.iter().find(|node| {
+            let status = node.node.status();
+            status.leader.as_ref() == Some(&status.id)
+        });
+        leader.is_some()
+    });
+    leader.unwrap()
+}
+
+#[test]
+fn replicates_through_leader() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let nodes = spawn_nodes(&temp_dir, 3, 3, 1000);
+    let leader = leader(&nodes);
+    let follower = nodes.iter().find(|node| node.member != leader.member).unwrap();
+
+    // The follower redirects the client to the leader
+    let mut client = CaveyClient::new(follower.addr())?;
+    client.put("key1".to_owned(), "value1".to_owned())?;
+    client.create_keyspace("team-a".to_owned())?;
+    client.set_keyspace(Some("team-a".to_owned()));
+    client.put("key1".to_owned(), "team-a/value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("team-a/value1".to_owned()));
+    client.set_keyspace(None);
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(client.remove("key1".to_owned())?);
+    assert!(!client.remove("key1".to_owned())?);
+
+    let commit_index = leader.node.status().commit_index;
+    for node in &nodes {
+        wait_for("followers to apply", || node.node.status().applied_index >= commit_index);
+    }
+
+    // Followers don't serve requests themselves
+    match follower.node.engine().get("key1".to_owned()) {
+        Err(CaveyError::NotLeader(Some(addr))) => assert_eq!(addr, leader.member.addr),
+        other => panic!("expected not leader, got {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn transactions_check_reads_on_leader() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let nodes = spawn_nodes(&temp_dir, 3, 3, 1000);
+    let mut client = CaveyClient::new(nodes[0].addr())?;
+    client.put("balance".to_owned(), "10".to_owned())?;
+
+    let mut txn = client.begin();
+    assert_eq!(txn.get("balance".to_owned())?, Some("10".to_owned()));
+    txn.put("balance".to_owned(), "20".to_owned());
+    let mut other = CaveyClient::new(nodes[1].addr())?;
+    other.put("balance".to_owned(), "15".to_owned())?;
+    match txn.commit() {
+        Err(CaveyError::Conflict(key)) => assert_eq!(key, "balance"),
+        other => panic!("expected conflict, got {:?}", other),
+    }
+
+    let mut txn = client.begin();
+    txn.get("balance".to_owned())?;
+    txn.put("balance".to_owned(), "25".to_owned());
+    txn.commit()?;
+    assert_eq!(other.get("balance".to_owned())?, Some("25".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn adds_and_removes_members() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let nodes = spawn_nodes(&temp_dir, 3, 2, 1000);
+    let mut client = CaveyClient::new(nodes[0].addr())?;
+    client.put("key1".to_owned(), "value1".to_owned())?;
+
+    client.add_member(nodes[2].member.clone())?;
+    let status = client.cluster_status()?;
+    assert_eq!(status.members.len(), 3);
+    let commit_index = leader(&nodes).node.status().commit_index;
+    wait_for("the new member to catch up", || {
+        let status = nodes[2].node.status();
+        status.members.len() == 3 && status.applied_index >= commit_index
+    });
+
+    let leader = leader(&nodes);
+    let removed = nodes.iter().find(|node| node.member != leader.member).unwrap();
+    client.remove_member(removed.member.id.clone())?;
+    let status = leader.node.status();
+    assert_eq!(status.members.len(), 2);
+    assert!(!status.members.contains(&removed.member));
+
+    // The two remaining members still make a majority
+    client.put("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+/// An engine whose puts of `poison` fail, as if its disk had.
+struct FailingEngine {
+    store: CaveyStore,
+}
+
+impl CaveyEngine for FailingEngine {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.store.get(key)
+    }
+
+    fn put(&mut self, key: String, value: String) -> Result<()> {
+        if key == "poison" {
+            return Err(io::Error::other("disk failed").into());
+        }
+        self.store.put(key, value)
+    }
+
+    fn remove(&mut self, key: String) -> Result<bool> {
+        self.store.remove(key)
+    }
+
+    fn version(&mut self, key: &str) -> Result<Option<Version>> {
+        self.store.version(key)
+    }
+
+    fn scan(&mut self, prefix: &str, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.store.scan(prefix, after, limit)
+    }
+}
+
+/// Start a cluster of one node, which leads once elected.  The node has
+/// the same id each time, so it can be restarted.
+fn spawn_single(temp_dir: &TempDir, engine: Box<dyn CaveyEngine>, compact_threshold: usize) -> RaftNode {
+    let id = "node1".to_owned();
+    let member = Member {
+        id: id.clone(),
+        addr: "127.0.0.1:1".to_owned(),
+    };
+    let mut config = RaftConfig::new(id, member.addr.clone(), temp_dir.path().to_owned());
+    config.election_timeout = Duration::from_millis(100);
+    config.compact_threshold = compact_threshold;
+    let node = RaftNode::open(config, engine, &[member]).unwrap();
+    wait_for("a leader", || node.status().leader.is_some());
+    node
+}
+
+// A node whose engine fails to apply an entry stops taking part
+#[test]
+fn stops_when_apply_fails() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = CaveyStore::open(temp_dir.path())?;
+    let node = spawn_single(&temp_dir, Box::new(FailingEngine { store }), 1000);
+    let mut engine = node.engine();
+    engine.put("key1".to_owned(), "value1".to_owned())?;
+    assert!(engine.put("poison".to_owned(), "value".to_owned()).is_err());
+
+    let status = node.status();
+    assert!(status.failure.expect("the failure is reported").contains("disk failed"));
+    assert_eq!(status.leader, None);
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(node.status().leader, None, "a stopped node stood for election");
+    match engine.put("key2".to_owned(), "value2".to_owned()) {
+        Err(CaveyError::Internal(msg)) => assert!(msg.contains("stopped"), "unexpected error {}", msg),
+        other => panic!("expected the node to have stopped, got {:?}", other),
+    }
+    Ok(())
+}
+
+// Applied entries are dropped from the log, which the node restarts from
+#[test]
+fn compacts_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let node = spawn_single(&temp_dir, Box::new(CaveyStore::open(temp_dir.path())?), 10);
+    let mut engine = node.engine();
+    for i in 0..50 {
+        engine.put(format!("key{}", i), format!("value{}", i))?;
+    }
+    let log = fs::read_to_string(temp_dir.path().join("raft").join("log"))?;
+    assert!(log.lines().count() <= 11, "the log holds {} lines", log.lines().count());
+
+    let node = spawn_single(&temp_dir, Box::new(CaveyStore::open(temp_dir.path())?), 10);
+    let mut engine = node.engine();
+    engine.put("key50".to_owned(), "value50".to_owned())?;
+    for i in 0..=50 {
+        assert_eq!(engine.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// A member added after the entries it needs are dropped is sent a snapshot
+#[test]
+fn sends_snapshot_to_new_member() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let nodes = spawn_nodes(&temp_dir, 3, 2, 10);
+    let mut client = CaveyClient::new(nodes[0].addr())?;
+    client.create_keyspace("team-a".to_owned())?;
+    for i in 0..50 {
+        client.put(format!("key{}", i), format!("value{}", i))?;
+    }
+    client.set_keyspace(Some("team-a".to_owned()));
+    client.put("key1".to_owned(), "team-a/value1".to_owned())?;
+    client.set_keyspace(None);
+
+    client.add_member(nodes[2].member.clone())?;
+    let commit_index = leader(&nodes).node.status().commit_index;
+    wait_for("the new member to catch up", || nodes[2].node.status().applied_index >= commit_index);
+
+    // Only the new member is left to serve the data
+    let leader = leader(&nodes);
+    let other = nodes[..2].iter().find(|node| node.member != leader.member).unwrap();
+    client.remove_member(other.member.id.clone())?;
+    client.put("key50".to_owned(), "value50".to_owned())?;
+    client.remove_member(leader.member.id.clone())?;
+    let new_leader = &nodes[2];
+    wait_for("the new member to lead", || new_leader.node.status().leader.as_ref() == Some(&new_leader.member.id));
+    let mut client = CaveyClient::new(new_leader.addr())?;
+    for i in 0..=50 {
+        assert_eq!(client.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    client.set_keyspace(Some("team-a".to_owned()));
+    assert_eq!(client.get("key1".to_owned())?, Some("team-a/value1".to_owned()));
+    Ok(())
+}
+
+// An entry cut short by a crash is dropped, so the node can append after it
+// and restart again
+#[test]
+fn restarts_after_torn_append() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let node = spawn_single(&temp_dir, Box::new(CaveyStore::open(temp_dir.path())?), 1000);
+    node.engine().put("key1".to_owned(), "value1".to_owned())?;
+    let log = temp_dir.path().join("raft").join("log");
+    OpenOptions::new().append(true).open(&log)?.write_all(br#"{"term":1,"comm"#)?;
+
+    let node = spawn_single(&temp_dir, Box::new(CaveyStore::open(temp_dir.path())?), 1000);
+    node.engine().put("key2".to_owned(), "value2".to_owned())?;
+
+    let node = spawn_single(&temp_dir, Box::new(CaveyStore::open(temp_dir.path())?), 1000);
+    let mut engine = node.engine();
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,209 @@
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cavey::{CaveyClient, CaveyEngine, CaveyError, CaveyStore, ClientConfig, Primary, ReadOnly, Replica};
+use cavey::{ReplicationLog, Result, ServerConfig, SharedEngine, SledStore, TransactionalStore};
+use tempfile::TempDir;
+
+/// Serve a primary's native protocol and replication stream, returning
+/// their addresses.
+fn spawn_primary(temp_dir: &TempDir, log_size: usize) -> (SocketAddr, SocketAddr) {
+    let native = TcpListener::bind("127.0.0.1:0").unwrap();
+    let replication = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addrs = (native.local_addr().unwrap(), replication.local_addr().unwrap());
+    let log = Arc::new(ReplicationLog::open(temp_dir.path().join("replication.log"), log_size).unwrap());
+    let store = CaveyStore::open(temp_dir.path().join("primary")).unwrap();
+    let engine: SharedEngine = Arc::new(Mutex::new(Box::new(Primary::new(Box::new(store), log.clone()).unwrap())));
+    {
+        let engine = engine.clone();
+        thread::spawn(move || cavey::serve(&native, &*engine, &ServerConfig::default()).unwrap());
+    }
+    thread::spawn(move || cavey::serve_replication(&replication, &*engine, &log, &ServerConfig::default()).unwrap());
+    addrs
+}
+
+fn replica(temp_dir: &TempDir, primary: SocketAddr) -> (Replica, SharedEngine) {
+    let store = SledStore::open(temp_dir.path().join("replica")).unwrap();
+    let engine: SharedEngine = Arc::new(Mutex::new(Box::new(store)));
+    let position_file = temp_dir.path().join("replica.json");
+    (Replica::new(primary, engine.clone(), position_file, ClientConfig::default()).unwrap(), engine)
+}
+
+fn get(engine: &SharedEngine, keyspace: Option<&str>, key: &str) -> Option<String> {
+    let mut engine = engine.lock().unwrap();
+    let engine = match keyspace {
+        Some(name) => engine.keyspace(name).unwrap(),
+        None => &mut **engine,
+    };
+    engine.get(key.to_owned()).unwrap()
+}
+
+#[test]
+fn replicates_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (addr, replication) = spawn_primary(&temp_dir, 1000);
+    let mut client = CaveyClient::new(addr)?;
+    client.put("key1".to_owned(), "value1".to_owned())?;
+    client.put("key2".to_owned(), "value2".to_owned())?;
+    client.remove("key2".to_owned())?;
+    client.create_keyspace("team-a".to_owned())?;
+    client.set_keyspace(Some("team-a".to_owned()));
+    client.put("key1".to_owned(), "team-a/value1".to_owned())?;
+    let mut txn = client.begin();
+    txn.put("key3".to_owned(), "value3".to_owned());
+    txn.commit()?;
+
+    let (replica, engine) = replica(&temp_dir, replication);
+    let position = replica.catch_up()?;
+    assert_eq!(replica.position()?, Some(position));
+    assert_eq!(get(&engine, None, "key1"), Some("value1".to_owned()));
+    assert_eq!(get(&engine, None, "key2"), None);
+    assert_eq!(get(&engine, Some("team-a"), "key1"), Some("team-a/value1".to_owned()));
+    assert_eq!(get(&engine, Some("team-a"), "key3"), Some("value3".to_owned()));
+
+    client.set_keyspace(None);
+    client.put("key1".to_owned(), "value1b".to_owned())?;
+    client.drop_keyspace("team-a".to_owned())?;
+    let later = replica.catch_up()?;
+    assert_eq!(later.index, position.index + 2);
+    assert_eq!(get(&engine, None, "key1"), Some("value1b".to_owned()));
+    assert!(engine.lock().unwrap().list_keyspaces()?.is_empty());
+    Ok(())
+}
+
+// A replica picks up where it left off, without a snapshot
+#[test]
+fn resumes_from_position() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (addr, replication) = spawn_primary(&temp_dir, 1000);
+    let mut client = CaveyClient::new(addr)?;
+    client.put("key1".to_owned(), "value1".to_owned())?;
+
+    let (replica, engine) = replica(&temp_dir, replication);
+    replica.catch_up()?;
+    // A snapshot would discard this
+    engine.lock().unwrap().put("local".to_owned(), "value".to_owned())?;
+    client.put("key2".to_owned(), "value2".to_owned())?;
+    replica.catch_up()?;
+    assert_eq!(get(&engine, None, "key2"), Some("value2".to_owned()));
+    assert_eq!(get(&engine, None, "local"), Some("value".to_owned()));
+    Ok(())
+}
+
+// The log outlives the primary, so a replica resumes across a restart
+#[test]
+fn resumes_after_primary_restart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (addr, replication) = spawn_primary(&temp_dir, 1000);
+    CaveyClient::new(addr)?.put("key1".to_owned(), "value1".to_owned())?;
+    let (replica, engine) = replica(&temp_dir, replication);
+    let position = replica.catch_up()?;
+    engine.lock().unwrap().put("local".to_owned(), "value".to_owned())?;
+
+    // The first primary is left idle, standing in for one that stopped
+    let (addr, replication) = spawn_primary(&temp_dir, 1000);
+    CaveyClient::new(addr)?.put("key2".to_owned(), "value2".to_owned())?;
+    let replica = Replica::new(replication, engine.clone(), temp_dir.path().join("replica.json"), ClientConfig::default())?;
+    let later = replica.catch_up()?;
+    assert_eq!(later.log_id, position.log_id);
+    assert_eq!(later.index, position.index + 1);
+    assert_eq!(get(&engine, None, "key2"), Some("value2".to_owned()));
+    assert_eq!(get(&engine, None, "local"), Some("value".to_owned()));
+    Ok(())
+}
+
+// A change logged but not applied before the primary stopped is applied on restart
+#[test]
+fn primary_reapplies_last_change() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("replication.log");
+    let log = Arc::new(ReplicationLog::open(&path, 1000)?);
+    let mut primary = Primary::new(Box::new(CaveyStore::open(temp_dir.path().join("first"))?), log)?;
+    primary.put("key1".to_owned(), "value1".to_owned())?;
+    primary.put("key2".to_owned(), "value2".to_owned())?;
+    assert!(!primary.remove("missing".to_owned())?);
+    drop(primary);
+
+    let log = Arc::new(ReplicationLog::open(&path, 1000)?);
+    assert_eq!(log.position().index, 2);
+    let mut primary = Primary::new(Box::new(CaveyStore::open(temp_dir.path().join("second"))?), log)?;
+    assert_eq!(primary.get("key1".to_owned())?, None);
+    assert_eq!(primary.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn snapshot_when_too_far_behind() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (addr, replication) = spawn_primary(&temp_dir, 4);
+    let mut client = CaveyClient::new(addr)?;
+    client.create_keyspace("empty".to_owned())?;
+    client.put("key0".to_owned(), "value0".to_owned())?;
+
+    let (replica, engine) = replica(&temp_dir, replication);
+    replica.catch_up()?;
+    engine.lock().unwrap().put("local".to_owned(), "value".to_owned())?;
+    for i in 1..10 {
+        client.put(format!("key{}", i), format!("value{}", i))?;
+    }
+    client.remove("key0".to_owned())?;
+    replica.catch_up()?;
+    assert_eq!(get(&engine, None, "local"), None);
+    assert_eq!(get(&engine, None, "key0"), None);
+    for i in 1..10 {
+        assert_eq!(get(&engine, None, &format!("key{}", i)), Some(format!("value{}", i)));
+    }
+    assert_eq!(engine.lock().unwrap().list_keyspaces()?, vec!["empty".to_owned()]);
+    Ok(())
+}
+
+#[test]
+fn replica_follows_primary() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (addr, replication) = spawn_primary(&temp_dir, 1000);
+    let (replica, engine) = replica(&temp_dir, replication);
+    thread::spawn(move || replica.run());
+
+    let mut client = CaveyClient::new(addr)?;
+    client.put("key1".to_owned(), "value1".to_owned())?;
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while get(&engine, None, "key1").is_none() {
+        assert!(Instant::now() < deadline, "replica didn't apply the write");
+        thread::sleep(Duration::from_millis(20));
+    }
+    Ok(())
+}
+
+#[test]
+fn replica_serves_reads_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (primary, replication) = spawn_primary(&temp_dir, 1000);
+    let mut primary = CaveyClient::new(primary)?;
+    primary.put("key1".to_owned(), "value1".to_owned())?;
+    primary.create_keyspace("team-a".to_owned())?;
+    let (replica, engine) = replica(&temp_dir, replication);
+    replica.catch_up()?;
+
+    let mut listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || cavey::run_server(&mut listener, &mut ReadOnly::new(engine)).unwrap());
+    let mut client = CaveyClient::new(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    match client.put("key1".to_owned(), "value2".to_owned()) {
+        Err(CaveyError::ReadOnly) => {}
+        other => panic!("expected read only, got {:?}", other),
+    }
+    match client.create_keyspace("team-b".to_owned()) {
+        Err(CaveyError::ReadOnly) => {}
+        other => panic!("expected read only, got {:?}", other),
+    }
+    client.set_keyspace(Some("team-a".to_owned()));
+    assert_eq!(client.get("key1".to_owned())?, None);
+    match client.remove("key1".to_owned()) {
+        Err(CaveyError::ReadOnly) => {}
+        other => panic!("expected read only, got {:?}", other),
+    }
+    Ok(())
+}
@@ -0,0 +1,302 @@
+use std::collections::BTreeSet;
+use std::io::{prelude::*, BufReader};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use cavey::{CaveyClient, CaveyEngine, CaveyStore, Result, ServerConfig};
+use tempfile::TempDir;
+
+/// Serve both protocols from one store, returning the native and RESP
+/// addresses.
+fn spawn_servers(temp_dir: &TempDir, config: ServerConfig) -> Result<(SocketAddr, SocketAddr)> {
+    let native = TcpListener::bind("127.0.0.1:0")?;
+    let resp = TcpListener::bind("127.0.0.1:0")?;
+    let addrs = (native.local_addr()?, resp.local_addr()?);
+    let path = temp_dir.path().to_owned();
+    thread::spawn(move || {
+        let mut store = CaveyStore::open(path).unwrap();
+        let engine: Mutex<&mut dyn CaveyEngine> = Mutex::new(&mut store);
+        thread::scope(|scope| {
+            scope.spawn(|| cavey::serve_resp(&resp, &engine, &config).unwrap());
+            cavey::serve(&native, &engine, &config).unwrap();
+        });
+    });
+    Ok(addrs)
+}
+
+fn connect(temp_dir: &TempDir) -> Result<Connection> {
+    let (_, resp) = spawn_servers(temp_dir, ServerConfig::default())?;
+    Connection::new(resp)
+}
+
+#[derive(Debug, PartialEq)]
+enum Value {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Value>),
+}
+
+fn bulk(value: &str) -> Value {
+    Value::Bulk(Some(value.to_owned()))
+}
+
+fn ok() -> Value {
+    Value::Status("OK".to_owned())
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn new(addr: SocketAddr) -> Result<Connection> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        })
+    }
+
+    fn command(&mut self, args: &[&str]) -> Result<Value> {
+        let mut request = format!("*{}\r\n", args.len());
+        for arg in args {
+            request += &format!("${}\r\n{}\r\n", arg.len(), arg);
+        }
+        self.writer.write_all(request.as_bytes())?;
+        self.read()
+    }
+
+    fn read(&mut self) -> Result<Value> {
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        let (kind, rest) = line.split_at(1);
+        Ok(match kind {
+            "+" => Value::Status(rest.to_owned()),
+            "-" => Value::Error(rest.to_owned()),
+            ":" => Value::Integer(rest.parse().unwrap()),
+            "$" if rest == "-1" => Value::Bulk(None),
+            "$" => {
+                let mut value = vec![0; rest.parse::<usize>().unwrap() + 2];
+                self.reader.read_exact(&mut value)?;
+                value.truncate(value.len() - 2);
+                Value::Bulk(Some(String::from_utf8(value).unwrap()))
+            }
+            "*" => {
+                let count: usize = rest.parse().unwrap();
+                Value::Array((0..count).map(|_| self.read()).collect::<Result<_>>()?)
+            }
+            _ => panic!("unexpected reply {:?}", line),
+        })
+    }
+}
+
+#[test]
+fn get_set_del_exists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut conn = connect(&temp_dir)?;
+
+    assert_eq!(conn.command(&["PING"])?, Value::Status("PONG".to_owned()));
+    assert_eq!(conn.command(&["set", "key1", "value1"])?, ok());
+    assert_eq!(conn.command(&["GET", "key1"])?, bulk("value1"));
+    assert_eq!(conn.command(&["EXISTS", "key1", "key2", "key1"])?, Value::Integer(2));
+    assert_eq!(conn.command(&["DEL", "key1", "key2"])?, Value::Integer(1));
+    assert_eq!(conn.command(&["GET", "key1"])?, Value::Bulk(None));
+    assert_eq!(conn.command(&["EXISTS", "key1"])?, Value::Integer(0));
+    Ok(())
+}
+
+#[test]
+fn mget_mset() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut conn = connect(&temp_dir)?;
+
+    assert_eq!(conn.command(&["MSET", "key1", "value1", "key2", "value2"])?, ok());
+    assert_eq!(
+        conn.command(&["MGET", "key1", "missing", "key2"])?,
+        Value::Array(vec![bulk("value1"), Value::Bulk(None), bulk("value2")])
+    );
+    match conn.command(&["MSET", "key1"])? {
+        Value::Error(msg) => assert!(msg.contains("wrong number of arguments"), "{}", msg),
+        other => panic!("expected error, got {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut conn = connect(&temp_dir)?;
+    for i in 0..25 {
+        conn.command(&["SET", &format!("user:{}", i), "value"])?;
+    }
+    for i in 0..5 {
+        conn.command(&["SET", &format!("other:{}", i), "value"])?;
+    }
+
+    let mut cursor = "0".to_owned();
+    let mut keys = BTreeSet::new();
+    loop {
+        match conn.command(&["SCAN", &cursor, "MATCH", "user:*", "COUNT", "7"])? {
+            Value::Array(mut reply) => {
+                let page = reply.pop().unwrap();
+                let next = reply.pop().unwrap();
+                if let Value::Array(page) = page {
+                    for key in page {
+                        if let Value::Bulk(Some(key)) = key {
+                            assert!(key.starts_with("user:"));
+                            assert!(keys.insert(key));
+                        }
+                    }
+                }
+                match next {
+                    Value::Bulk(Some(next)) => cursor = next,
+                    other => panic!("expected cursor, got {:?}", other),
+                }
+            }
+            other => panic!("expected array, got {:?}", other),
+        }
+        if cursor == "0" {
+            break;
+        }
+    }
+    assert_eq!(keys.len(), 25);
+
+    // Patterns with wildcards before any literal text scan everything
+    match conn.command(&["SCAN", "0", "MATCH", "*:[0-2]", "COUNT", "100"])? {
+        Value::Array(reply) => match &reply[1] {
+            Value::Array(page) => assert_eq!(page.len(), 6),
+            other => panic!("expected array, got {:?}", other),
+        },
+        other => panic!("expected array, got {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn expire() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut conn = connect(&temp_dir)?;
+
+    conn.command(&["SET", "key1", "value1"])?;
+    assert_eq!(conn.command(&["EXPIRE", "key1", "1"])?, Value::Integer(1));
+    assert_eq!(conn.command(&["EXPIRE", "missing", "1"])?, Value::Integer(0));
+    assert_eq!(conn.command(&["SET", "key2", "value2", "PX", "100"])?, ok());
+    // Overwriting a key clears its expiry time
+    conn.command(&["SET", "key3", "value3", "EX", "1"])?;
+    conn.command(&["SET", "key3", "value3"])?;
+    assert_eq!(conn.command(&["GET", "key1"])?, bulk("value1"));
+
+    thread::sleep(Duration::from_millis(1200));
+    assert_eq!(conn.command(&["GET", "key1"])?, Value::Bulk(None));
+    assert_eq!(conn.command(&["EXISTS", "key2"])?, Value::Integer(0));
+    assert_eq!(conn.command(&["GET", "key3"])?, bulk("value3"));
+    Ok(())
+}
+
+// Expiry times too far in the future are refused, leaving the key untouched
+#[test]
+fn huge_expire_times() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut conn = connect(&temp_dir)?;
+
+    conn.command(&["SET", "key1", "value1"])?;
+    assert_eq!(
+        conn.command(&["EXPIRE", "key1", "9223372036854775807"])?,
+        Value::Error("ERR invalid expire time in 'expire' command".to_owned())
+    );
+    assert_eq!(
+        conn.command(&["SET", "key2", "value2", "EX", "18446744073709551615"])?,
+        Value::Error("ERR invalid expire time in 'set' command".to_owned())
+    );
+    assert_eq!(conn.command(&["GET", "key1"])?, bulk("value1"));
+    assert_eq!(conn.command(&["GET", "key2"])?, Value::Bulk(None));
+    // The server is still usable
+    assert_eq!(conn.command(&["SET", "key3", "value3"])?, ok());
+    assert_eq!(conn.command(&["GET", "key3"])?, bulk("value3"));
+    Ok(())
+}
+
+// Expired keys are removed from the engine, even if never read again
+#[test]
+fn expired_keys_are_swept() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (native, resp) = spawn_servers(&temp_dir, ServerConfig::default())?;
+    let mut conn = Connection::new(resp)?;
+    let mut client = CaveyClient::new(native)?;
+
+    conn.command(&["SET", "key1", "value1", "PX", "100"])?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(client.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn shared_with_native_protocol() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (native, resp) = spawn_servers(&temp_dir, ServerConfig::default())?;
+    let mut conn = Connection::new(resp)?;
+    let mut client = CaveyClient::new(native)?;
+
+    client.put("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(conn.command(&["GET", "key1"])?, bulk("value1"));
+    conn.command(&["SET", "key2", "value2"])?;
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Inline commands, as typed into telnet, may be pipelined
+#[test]
+fn inline_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut conn = connect(&temp_dir)?;
+
+    conn.writer.write_all(b"PING\r\nSET key1 value1\r\nGET key1\r\nFLUSHALL\r\n")?;
+    assert_eq!(conn.read()?, Value::Status("PONG".to_owned()));
+    assert_eq!(conn.read()?, ok());
+    assert_eq!(conn.read()?, bulk("value1"));
+    match conn.read()? {
+        Value::Error(msg) => assert!(msg.contains("unknown command"), "{}", msg),
+        other => panic!("expected error, got {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = ServerConfig {
+        max_frame_size: 1024,
+        max_value_size: 16,
+        ..ServerConfig::default()
+    };
+    let (_, resp) = spawn_servers(&temp_dir, config)?;
+    let mut conn = Connection::new(resp)?;
+
+    match conn.command(&["SET", "key1", &"x".repeat(17)])? {
+        Value::Error(msg) => assert!(msg.contains("value"), "{}", msg),
+        other => panic!("expected error, got {:?}", other),
+    }
+    assert_eq!(conn.command(&["SET", "key1", "value1"])?, ok());
+
+    // A command announcing more than the frame size is refused, and the
+    // connection closed
+    conn.writer.write_all(b"*2\r\n$3\r\nGET\r\n$100000\r\n")?;
+    match conn.read()? {
+        Value::Error(msg) => assert!(msg.contains("exceeds limit"), "{}", msg),
+        other => panic!("expected error, got {:?}", other),
+    }
+    let mut rest = Vec::new();
+    conn.reader.read_to_end(&mut rest).ok();
+    assert!(rest.is_empty());
+
+    let mut conn = Connection::new(resp)?;
+    assert_eq!(conn.command(&["GET", "key1"])?, bulk("value1"));
+    Ok(())
+}
@@ -0,0 +1,192 @@
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use cavey::{CaveyClient, CaveyEngine, CaveyError, CaveyStore, Result, ShardedClient, SledStore, Version};
+use tempfile::TempDir;
+
+fn spawn_server(temp_dir: &TempDir, i: usize) -> Result<SocketAddr> {
+    let mut listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let path = temp_dir.path().join(format!("shard{}", i));
+    thread::spawn(move || {
+        if i % 2 == 1 {
+            cavey::run_server(&mut listener, &mut SledStore::open(path).unwrap()).unwrap();
+        } else {
+            cavey::run_server(&mut listener, &mut CaveyStore::open(path).unwrap()).unwrap();
+        }
+    });
+    Ok(addr)
+}
+
+/// The keys held by the server at `addr`.
+fn keys_on(addr: SocketAddr, keyspace: Option<&str>) -> Result<Vec<String>> {
+    let mut client = CaveyClient::new(addr)?;
+    client.set_keyspace(keyspace.map(str::to_owned));
+    Ok(client.scan(String::new(), None, 1000)?.into_iter().map(|(key, _)| key).collect())
+}
+
+/// Check that every key is where `client` routes it, and nowhere else.
+fn check_placement(client: &ShardedClient, addrs: &[SocketAddr], keyspace: Option<&str>, count: usize) -> Result<()> {
+    let mut total = 0;
+    for addr in addrs {
+        let keys = keys_on(*addr, keyspace)?;
+        for key in &keys {
+            assert_eq!(client.shard_for(key), addr.to_string(), "{} is on the wrong shard", key);
+        }
+        total += keys.len();
+    }
+    assert_eq!(total, count);
+    Ok(())
+}
+
+#[test]
+fn routes_keys_across_shards() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addrs: Vec<_> = (0..3).map(|i| spawn_server(&temp_dir, i)).collect::<Result<_>>()?;
+    let mut client = ShardedClient::new(&addrs)?;
+    for i in 0..300 {
+        client.put(format!("key{:03}", i), format!("value{}", i))?;
+    }
+    for addr in &addrs {
+        assert!(!keys_on(*addr, None)?.is_empty(), "shard {} holds no keys", addr);
+    }
+    check_placement(&client, &addrs, None, 300)?;
+
+    // Another client with the same shards places keys alike
+    let mut other = ShardedClient::new(&[addrs[2], addrs[0], addrs[1]])?;
+    for i in 0..300 {
+        assert_eq!(other.get(format!("key{:03}", i))?, Some(format!("value{}", i)));
+    }
+    assert!(other.remove("key000".to_owned())?);
+    assert_eq!(client.get("key000".to_owned())?, None);
+
+    let page = client.scan("key".to_owned(), Some("key009".to_owned()), 5)?;
+    let keys: Vec<_> = page.into_iter().map(|(key, _)| key).collect();
+    assert_eq!(keys, vec!["key010", "key011", "key012", "key013", "key014"]);
+    Ok(())
+}
+
+#[test]
+fn keyspaces_span_shards() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addrs: Vec<_> = (0..2).map(|i| spawn_server(&temp_dir, i)).collect::<Result<_>>()?;
+    let mut client = ShardedClient::new(&addrs)?;
+    client.create_keyspace("team-a".to_owned())?;
+    assert_eq!(client.list_keyspaces()?, vec!["team-a".to_owned()]);
+    for addr in &addrs {
+        assert_eq!(CaveyClient::new(*addr)?.list_keyspaces()?, vec!["team-a".to_owned()]);
+    }
+    client.set_keyspace(Some("team-a".to_owned()));
+    client.put("key1".to_owned(), "team-a/value1".to_owned())?;
+    client.set_keyspace(None);
+    assert_eq!(client.get("key1".to_owned())?, None);
+    client.drop_keyspace("team-a".to_owned())?;
+    assert!(client.list_keyspaces()?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn rebalances_when_shards_change() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addrs: Vec<_> = (0..3).map(|i| spawn_server(&temp_dir, i)).collect::<Result<_>>()?;
+    let mut client = ShardedClient::new(&addrs[..2])?;
+    client.create_keyspace("team-a".to_owned())?;
+    for i in 0..300 {
+        client.put(format!("key{:03}", i), format!("value{}", i))?;
+    }
+    client.set_keyspace(Some("team-a".to_owned()));
+    for i in 0..30 {
+        client.put(format!("key{:03}", i), format!("team-a/value{}", i))?;
+    }
+
+    // Only the keys that now belong to the new shard move
+    let moved = client.add_shard(addrs[2].to_string(), addrs[2])?;
+    let on_new = keys_on(addrs[2], None)?.len() + keys_on(addrs[2], Some("team-a"))?.len();
+    assert_eq!(moved, on_new);
+    assert!(moved > 0 && moved < 330, "moved {} of 330 keys", moved);
+    check_placement(&client, &addrs, None, 300)?;
+    check_placement(&client, &addrs, Some("team-a"), 30)?;
+
+    let moved = client.remove_shard(&addrs[0].to_string())?;
+    assert!(moved > 0);
+    assert!(keys_on(addrs[0], None)?.is_empty());
+    assert_eq!(client.shards().count(), 2);
+    check_placement(&client, &addrs[1..], None, 300)?;
+    check_placement(&client, &addrs[1..], Some("team-a"), 30)?;
+    for i in 0..30 {
+        assert_eq!(client.get(format!("key{:03}", i))?, Some(format!("team-a/value{}", i)));
+    }
+    client.set_keyspace(None);
+    for i in 0..300 {
+        assert_eq!(client.get(format!("key{:03}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+/// An engine whose puts fail once it holds `capacity` keys.
+struct LimitedEngine {
+    store: CaveyStore,
+    capacity: Arc<AtomicUsize>,
+    len: usize,
+}
+
+impl CaveyEngine for LimitedEngine {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.store.get(key)
+    }
+
+    fn put(&mut self, key: String, value: String) -> Result<()> {
+        if self.len >= self.capacity.load(Ordering::SeqCst) {
+            return Err(CaveyError::Internal("disk full".to_owned()));
+        }
+        self.len += 1;
+        self.store.put(key, value)
+    }
+
+    fn remove(&mut self, key: String) -> Result<bool> {
+        self.store.remove(key)
+    }
+
+    fn version(&mut self, key: &str) -> Result<Option<Version>> {
+        self.store.version(key)
+    }
+
+    fn scan(&mut self, prefix: &str, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.store.scan(prefix, after, limit)
+    }
+}
+
+// A rebalance that fails part way is finished by adding the shard again
+#[test]
+fn resumes_failed_rebalance() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addrs: Vec<_> = (0..2).map(|i| spawn_server(&temp_dir, i)).collect::<Result<_>>()?;
+    let mut listener = TcpListener::bind("127.0.0.1:0")?;
+    let new_addr = listener.local_addr()?;
+    let capacity = Arc::new(AtomicUsize::new(10));
+    let mut engine = LimitedEngine {
+        store: CaveyStore::open(temp_dir.path().join("new"))?,
+        capacity: capacity.clone(),
+        len: 0,
+    };
+    thread::spawn(move || cavey::run_server(&mut listener, &mut engine).unwrap());
+
+    let mut client = ShardedClient::new(&addrs)?;
+    for i in 0..300 {
+        client.put(format!("key{:03}", i), format!("value{}", i))?;
+    }
+    assert!(client.add_shard(new_addr.to_string(), new_addr).is_err());
+    assert_eq!(keys_on(new_addr, None)?.len(), 10);
+
+    capacity.store(usize::MAX, Ordering::SeqCst);
+    let moved = client.add_shard(new_addr.to_string(), new_addr)?;
+    assert_eq!(moved + 10, keys_on(new_addr, None)?.len());
+    let all = [addrs[0], addrs[1], new_addr];
+    check_placement(&client, &all, None, 300)?;
+    for i in 0..300 {
+        assert_eq!(client.get(format!("key{:03}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
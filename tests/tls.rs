@@ -0,0 +1,152 @@
+use std::fs;
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+
+use cavey::{CaveyClient, CaveyStore, ClientConfig, Result, TlsAddr, TlsListener};
+use rcgen::{BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+use tempfile::TempDir;
+
+/// A CA, written to `dir`, that can issue certificates.
+struct Ca {
+    dir: PathBuf,
+    name: String,
+    cert: rcgen::Certificate,
+    key: KeyPair,
+}
+
+impl Ca {
+    fn new(dir: &TempDir, name: &str) -> Ca {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let cert = params.self_signed(&key).unwrap();
+        let ca = Ca {
+            dir: dir.path().to_owned(),
+            name: name.to_owned(),
+            cert,
+            key,
+        };
+        fs::write(ca.path("ca.pem"), ca.cert.pem()).unwrap();
+        ca
+    }
+
+    fn path(&self, file: &str) -> PathBuf {
+        self.dir.join(format!("{}-{}", self.name, file))
+    }
+
+    /// Issue a certificate for `names`, returning the paths of it and its
+    /// key.
+    fn issue(&self, file: &str, names: &[&str], usage: ExtendedKeyUsagePurpose) -> (PathBuf, PathBuf) {
+        let key = KeyPair::generate().unwrap();
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        let mut params = CertificateParams::new(names).unwrap();
+        params.extended_key_usages = vec![usage];
+        let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+        let paths = (self.path(&format!("{}.pem", file)), self.path(&format!("{}-key.pem", file)));
+        fs::write(&paths.0, cert.pem()).unwrap();
+        fs::write(&paths.1, key.serialize_pem()).unwrap();
+        paths
+    }
+}
+
+fn spawn_tls_server(temp_dir: &TempDir, config: Arc<rustls::ServerConfig>) -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let path = temp_dir.path().to_owned();
+    thread::spawn(move || {
+        let mut store = CaveyStore::open(path).unwrap();
+        cavey::run_server(&mut TlsListener::new(listener, config), &mut store).unwrap();
+    });
+    Ok(addr)
+}
+
+fn connect(addr: SocketAddr, config: Arc<rustls::ClientConfig>) -> Result<CaveyClient<TlsAddr>> {
+    let transport = TlsAddr::new(addr, "localhost", config)?;
+    CaveyClient::with_transport(transport, ClientConfig::default())
+}
+
+#[test]
+fn tls_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let ca = Ca::new(&temp_dir, "server");
+    let (cert, key) = ca.issue("localhost", &["localhost"], ExtendedKeyUsagePurpose::ServerAuth);
+    let addr = spawn_tls_server(&temp_dir, cavey::server_tls_config(&cert, &key, None)?)?;
+
+    let mut client = connect(addr, cavey::client_tls_config(&ca.path("ca.pem"), None)?)?;
+    client.put("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // An IP address works as the server name, if the certificate names it
+    let (cert, key) = ca.issue("ip", &["127.0.0.1"], ExtendedKeyUsagePurpose::ServerAuth);
+    let addr = spawn_tls_server(&temp_dir, cavey::server_tls_config(&cert, &key, None)?)?;
+    let transport = TlsAddr::new(addr, "127.0.0.1", cavey::client_tls_config(&ca.path("ca.pem"), None)?)?;
+    let mut client = CaveyClient::with_transport(transport, ClientConfig::default())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Clients trust only the CA they are pinned to
+#[test]
+fn untrusted_server_is_refused() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let ca = Ca::new(&temp_dir, "server");
+    let other = Ca::new(&temp_dir, "other");
+    let (cert, key) = ca.issue("localhost", &["localhost"], ExtendedKeyUsagePurpose::ServerAuth);
+    let addr = spawn_tls_server(&temp_dir, cavey::server_tls_config(&cert, &key, None)?)?;
+
+    assert!(connect(addr, cavey::client_tls_config(&other.path("ca.pem"), None)?).is_err());
+
+    // Nor does a certificate for another name pass
+    let transport = TlsAddr::new(addr, "example.com", cavey::client_tls_config(&ca.path("ca.pem"), None)?)?;
+    assert!(CaveyClient::with_transport(transport, ClientConfig::default()).is_err());
+    Ok(())
+}
+
+#[test]
+fn plaintext_client_is_refused() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let ca = Ca::new(&temp_dir, "server");
+    let (cert, key) = ca.issue("localhost", &["localhost"], ExtendedKeyUsagePurpose::ServerAuth);
+    let addr = spawn_tls_server(&temp_dir, cavey::server_tls_config(&cert, &key, None)?)?;
+
+    assert!(CaveyClient::new(addr).is_err());
+    // The server is unaffected
+    let mut client = connect(addr, cavey::client_tls_config(&ca.path("ca.pem"), None)?)?;
+    client.put("key1".to_owned(), "value1".to_owned())?;
+    Ok(())
+}
+
+#[test]
+fn mutual_tls() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server_ca = Ca::new(&temp_dir, "server");
+    let client_ca = Ca::new(&temp_dir, "client");
+    let other = Ca::new(&temp_dir, "other");
+    let (cert, key) = server_ca.issue("localhost", &["localhost"], ExtendedKeyUsagePurpose::ServerAuth);
+    let config = cavey::server_tls_config(&cert, &key, Some(&client_ca.path("ca.pem")))?;
+    let addr = spawn_tls_server(&temp_dir, config)?;
+    let trust = server_ca.path("ca.pem");
+
+    let (cert, key) = client_ca.issue("app", &["app"], ExtendedKeyUsagePurpose::ClientAuth);
+    let mut client = connect(addr, cavey::client_tls_config(&trust, Some((&cert, &key)))?)?;
+    client.put("key1".to_owned(), "value1".to_owned())?;
+
+    assert!(connect(addr, cavey::client_tls_config(&trust, None)?).is_err());
+    let (cert, key) = other.issue("app", &["app"], ExtendedKeyUsagePurpose::ClientAuth);
+    assert!(connect(addr, cavey::client_tls_config(&trust, Some((&cert, &key)))?).is_err());
+    Ok(())
+}
+
+#[test]
+fn bad_files() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let ca = Ca::new(&temp_dir, "server");
+    let (cert, key) = ca.issue("localhost", &["localhost"], ExtendedKeyUsagePurpose::ServerAuth);
+
+    // A key where the certificate should be, and vice versa
+    assert!(cavey::server_tls_config(&key, &key, None).is_err());
+    assert!(cavey::server_tls_config(&cert, &cert, None).is_err());
+    assert!(cavey::client_tls_config(&temp_dir.path().join("missing.pem"), None).is_err());
+}
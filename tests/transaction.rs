@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+use std::net::TcpListener;
+use std::path::Path;
+use std::thread;
+
+use cavey::{Changeset, CaveyClient, CaveyError, CaveyEngine, CaveyStore, Result, SledStore, TransactionalStore};
+use tempfile::TempDir;
+
+fn open(path: &Path, sled: bool) -> Result<Box<dyn CaveyEngine>> {
+    if sled {
+        Ok(Box::new(SledStore::open(path)?))
+    } else {
+        Ok(Box::new(CaveyStore::open(path)?))
+    }
+}
+
+// Buffered writes are invisible until commit, and visible to the transaction itself
+#[test]
+fn commit_applies_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = CaveyStore::open(temp_dir.path())?;
+    store.put("key1".to_owned(), "value1".to_owned())?;
+
+    let mut txn = store.begin();
+    assert_eq!(txn.get("key1".to_owned())?, Some("value1".to_owned()));
+    txn.put("key2".to_owned(), "value2".to_owned());
+    txn.remove("key1".to_owned());
+    assert_eq!(txn.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(txn.get("key1".to_owned())?, None);
+    txn.commit()?;
+
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn rollback_discards_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = CaveyStore::open(temp_dir.path())?;
+
+    let mut txn = store.begin();
+    txn.put("key1".to_owned(), "value1".to_owned());
+    txn.rollback();
+
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// A write to a key in the read set between read and commit fails the commit
+#[test]
+fn conflicting_write_aborts_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = CaveyStore::open(temp_dir.path())?;
+    store.put("counter".to_owned(), "1".to_owned())?;
+
+    let (value, version) = store.get_versioned("counter".to_owned())?;
+    assert_eq!(value, Some("1".to_owned()));
+    store.put("counter".to_owned(), "1".to_owned())?;
+
+    let mut changeset = Changeset::default();
+    changeset.reads.insert("counter".to_owned(), version);
+    changeset.writes.insert("counter".to_owned(), Some("2".to_owned()));
+    assert!(store.commit_changeset(changeset).is_err());
+    assert_eq!(store.get("counter".to_owned())?, Some("1".to_owned()));
+
+    // A read of an absent key conflicts with a concurrent insert
+    let (_, version) = store.get_versioned("missing".to_owned())?;
+    assert_eq!(version, None);
+    store.put("missing".to_owned(), "now present".to_owned())?;
+    let mut changeset = Changeset::default();
+    changeset.reads.insert("missing".to_owned(), version);
+    changeset.writes.insert("other".to_owned(), Some("value".to_owned()));
+    assert!(store.commit_changeset(changeset).is_err());
+    assert_eq!(store.get("other".to_owned())?, None);
+    Ok(())
+}
+
+// Commits apply all their writes, and versions outlive the store
+#[test]
+fn commits_survive_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for &sled in &[false, true] {
+        let path = temp_dir.path().join(if sled { "sled" } else { "cavey" });
+        let mut store = open(&path, sled)?;
+        store.put("key1".to_owned(), "value1".to_owned())?;
+        store.put("key2".to_owned(), "value2".to_owned())?;
+        let (_, version) = store.get_versioned("key1".to_owned())?;
+        let mut changeset = Changeset::default();
+        changeset.reads.insert("key1".to_owned(), version);
+        changeset.writes.insert("key1".to_owned(), Some("changed".to_owned()));
+        changeset.writes.insert("key2".to_owned(), None);
+        changeset.writes.insert("key3".to_owned(), Some("value3".to_owned()));
+        store.commit_changeset(changeset)?;
+        let (_, version) = store.get_versioned("key1".to_owned())?;
+        drop(store);
+
+        let mut store = open(&path, sled)?;
+        assert_eq!(store.get("key1".to_owned())?, Some("changed".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get_versioned("key1".to_owned())?.1, version);
+        store.put("key1".to_owned(), "changed again".to_owned())?;
+        let mut changeset = Changeset::default();
+        changeset.reads.insert("key1".to_owned(), version);
+        changeset.writes.insert("key4".to_owned(), Some("value4".to_owned()));
+        assert!(store.commit_changeset(changeset).is_err());
+        assert_eq!(store.get("key4".to_owned())?, None);
+    }
+    Ok(())
+}
+
+#[test]
+fn transaction_over_network() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let path = temp_dir.path().to_owned();
+    thread::spawn(move || {
+        let mut store = CaveyStore::open(path).unwrap();
+        cavey::run_server(&mut listener, &mut store).unwrap();
+    });
+
+    let mut client = CaveyClient::new(addr)?;
+    client.put("balance".to_owned(), "10".to_owned())?;
+
+    let mut txn = client.begin();
+    let balance: u32 = txn.get("balance".to_owned())?.unwrap().parse().unwrap();
+    txn.put("balance".to_owned(), format!("{}", balance - 3));
+    txn.commit()?;
+    assert_eq!(client.get("balance".to_owned())?, Some("7".to_owned()));
+
+    let (_, version) = client.get_versioned("balance".to_owned())?;
+    client.put("balance".to_owned(), "5".to_owned())?;
+    let mut changeset = Changeset::default();
+    changeset.reads.insert("balance".to_owned(), version);
+    changeset.writes.insert("balance".to_owned(), Some("0".to_owned()));
+    match client.commit(changeset) {
+        Err(CaveyError::Conflict(key)) => assert_eq!(key, "balance"),
+        other => panic!("expected a conflict, got {:?}", other),
+    }
+    assert_eq!(client.get("balance".to_owned())?, Some("5".to_owned()));
+    Ok(())
+}
+
+// Sled saves a default keyspace transaction whole before applying it, so one
+// a crash cut short is finished when the store is reopened
+#[test]
+fn sled_finishes_interrupted_default_transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = SledStore::open(temp_dir.path())?;
+    store.put("key1".to_owned(), "value1".to_owned())?;
+    let mut changeset = Changeset::default();
+    changeset.writes.insert("key1".to_owned(), Some("changed".to_owned()));
+    changeset.writes.insert("key2".to_owned(), Some("value2".to_owned()));
+    store.commit_changeset(changeset)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // Leave writes saved but not applied, as a crash part way through would
+    let mut writes = BTreeMap::new();
+    writes.insert("key1".to_owned(), None);
+    writes.insert("key3".to_owned(), Some("value3".to_owned()));
+    let db = sled::Db::start_default(temp_dir.path().join("data"))?;
+    db.open_tree(b"pending:")?.set(b"", bincode::serialize(&writes)?)?;
+    db.flush()?;
+    drop(db);
+
+    let mut store = SledStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use std::os::unix::net::UnixListener;
+use std::sync::mpsc;
+use std::thread;
+
+use cavey::{CaveyClient, CaveyStore, ClientConfig, Result, TransactionalStore, UnixAddr};
+use tempfile::TempDir;
+
+#[test]
+fn unix_socket() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("cavey.sock");
+    let mut listener = UnixListener::bind(&path)?;
+    let data = temp_dir.path().to_owned();
+    thread::spawn(move || {
+        let mut store = CaveyStore::open(data).unwrap();
+        cavey::run_server(&mut listener, &mut store).unwrap();
+    });
+
+    let mut client = CaveyClient::with_transport(UnixAddr::new(&path), ClientConfig::default())?;
+    client.put("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(client.remove("key1".to_owned())?);
+
+    // Transactions work over any transport
+    let mut txn = client.begin();
+    txn.put("key2".to_owned(), "value2".to_owned());
+    txn.commit()?;
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn async_unix_socket() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("cavey.sock");
+    let data = temp_dir.path().to_owned();
+    let (sender, receiver) = mpsc::channel();
+    {
+        let path = path.clone();
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_multi_thread().enable_io().build().unwrap();
+            runtime.block_on(async {
+                let listener = tokio::net::UnixListener::bind(path).unwrap();
+                sender.send(()).unwrap();
+                let store = CaveyStore::open(data).unwrap();
+                cavey::run_async_server(listener, Box::new(store)).await.unwrap();
+            });
+        });
+    }
+    receiver.recv().unwrap();
+
+    let mut client = CaveyClient::with_transport(UnixAddr::new(path), ClientConfig::default())?;
+    client.put("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,153 @@
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+
+use cavey::{CaveyClient, CaveyEngine, CaveyError, CaveyStore, Event, Result, SledStore};
+use tempfile::TempDir;
+
+fn spawn_server<E: CaveyEngine + 'static>(engine: E) -> Result<SocketAddr> {
+    let mut listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let mut engine = engine;
+    thread::spawn(move || cavey::run_server(&mut listener, &mut engine).unwrap());
+    Ok(addr)
+}
+
+fn spawn_async_server(path: &Path) -> Result<SocketAddr> {
+    let path = path.to_owned();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_io()
+            .enable_time()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            sender.send(listener.local_addr().unwrap()).unwrap();
+            let store = SledStore::open(path).unwrap();
+            cavey::run_async_server(listener, Box::new(store)).await.unwrap();
+        });
+    });
+    Ok(receiver.recv().unwrap())
+}
+
+fn put_event(key: &str, value: &str) -> (String, Option<String>) {
+    (key.to_owned(), Some(value.to_owned()))
+}
+
+fn rm_event(key: &str) -> (String, Option<String>) {
+    (key.to_owned(), None)
+}
+
+fn summary(event: Event) -> (String, Option<String>) {
+    (event.key, event.value)
+}
+
+/// Watch "user/" in the default keyspace while making changes in and out of
+/// it.
+fn streams_matching_changes(addr: SocketAddr) -> Result<()> {
+    let mut client = CaveyClient::new(addr)?;
+    client.create_keyspace("team-a".to_owned())?;
+    client.put("user/1".to_owned(), "before".to_owned())?;
+    let mut watch = CaveyClient::new(addr)?.watch("user/".to_owned(), None)?;
+
+    client.put("user/1".to_owned(), "alice".to_owned())?;
+    client.put("other".to_owned(), "ignored".to_owned())?;
+    client.set_keyspace(Some("team-a".to_owned()));
+    client.put("user/1".to_owned(), "ignored".to_owned())?;
+    client.set_keyspace(None);
+    client.put_returning_old("user/2".to_owned(), "bob".to_owned())?;
+    assert!(client.remove("user/1".to_owned())?);
+    assert!(!client.remove("user/1".to_owned())?);
+    client.remove_returning_old("user/2".to_owned())?;
+
+    let expected = vec![put_event("user/1", "alice"), put_event("user/2", "bob"), rm_event("user/1"), rm_event("user/2")];
+    let mut seq = 0;
+    for expected in expected {
+        let event = watch.next_event()?;
+        assert!(event.seq > seq, "sequence numbers rise");
+        seq = event.seq;
+        assert_eq!(event.keyspace, None);
+        assert_eq!(summary(event), expected);
+    }
+    assert_eq!(watch.seq(), seq);
+    Ok(())
+}
+
+#[test]
+fn streams_changes_cavey_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    streams_matching_changes(spawn_server(CaveyStore::open(temp_dir.path())?)?)
+}
+
+#[test]
+fn streams_changes_sled_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    streams_matching_changes(spawn_server(SledStore::open(temp_dir.path())?)?)
+}
+
+#[test]
+fn streams_changes_async_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    streams_matching_changes(spawn_async_server(temp_dir.path())?)
+}
+
+#[test]
+fn watches_keyspace() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(SledStore::open(temp_dir.path())?)?;
+    let mut client = CaveyClient::new(addr)?;
+    let mut watcher = CaveyClient::new(addr)?;
+    watcher.set_keyspace(Some("team-a".to_owned()));
+    match watcher.watch(String::new(), None) {
+        Err(CaveyError::KeyspaceNotFound(name)) => assert_eq!(name, "team-a"),
+        Err(err) => panic!("expected keyspace not found, got {:?}", err),
+        Ok(_) => panic!("expected keyspace not found, got a watch"),
+    }
+
+    client.create_keyspace("team-a".to_owned())?;
+    let mut watch = watcher.watch(String::new(), None)?;
+    client.put("key1".to_owned(), "ignored".to_owned())?;
+    client.set_keyspace(Some("team-a".to_owned()));
+    client.put("key1".to_owned(), "value1".to_owned())?;
+    let event = watch.next_event()?;
+    assert_eq!(event.keyspace.as_deref(), Some("team-a"));
+    assert_eq!(summary(event), put_event("key1", "value1"));
+    Ok(())
+}
+
+#[test]
+fn resumes_after_seq() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(CaveyStore::open(temp_dir.path())?)?;
+    let mut client = CaveyClient::new(addr)?;
+    let mut watch = client.watch(String::new(), None)?;
+    client.put("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(summary(watch.next_event()?), put_event("key1", "value1"));
+    let seq = watch.seq();
+    drop(watch);
+
+    // Changes made while disconnected are streamed on resuming
+    client.put("key2".to_owned(), "value2".to_owned())?;
+    client.remove("key1".to_owned())?;
+    let events: Vec<_> = client.watch(String::new(), Some(seq))?.take(2).collect::<Result<_>>()?;
+    let events: Vec<_> = events.into_iter().map(summary).collect();
+    assert_eq!(events, vec![put_event("key2", "value2"), rm_event("key1")]);
+
+    // Events from before the feed began aren't kept
+    match client.watch(String::new(), Some(1)) {
+        Err(CaveyError::Expired(1)) => {}
+        Err(err) => panic!("expected expired, got {:?}", err),
+        Ok(_) => panic!("expected expired, got a watch"),
+    }
+    // Nor are events from after it ends, such as from before a restart
+    match client.watch(String::new(), Some(u64::MAX - 1)) {
+        Err(CaveyError::Expired(_)) => {}
+        Err(err) => panic!("expected expired, got {:?}", err),
+        Ok(_) => panic!("expected expired, got a watch"),
+    }
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}